@@ -12,6 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use core::ops::Range;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use axaddrspace::{GuestPhysAddr, GuestVirtAddr, HostPhysAddr};
+use axerrno::AxResult;
+use axvisor_api::memory::phys_to_virt;
+use x86_64::structures::idt::PageFaultErrorCode;
+
+use crate::exception::GuestException;
+
 #[derive(Debug)]
 /// The information of guest page walk.
 pub struct GuestPageWalkInfo {
@@ -39,3 +49,965 @@ pub struct GuestPageWalkInfo {
     /// Guest page table Supervisor mode execution protection
     pub is_smep_on: bool,
 }
+
+/// Access to the guest physical memory holding the guest page tables.
+pub trait GuestPageTableMemory {
+    /// Read the paging-structure entry of `size` bytes (4 or 8) at `gpa`.
+    fn read_entry(&mut self, gpa: GuestPhysAddr, size: usize) -> AxResult<u64>;
+    /// Replace the paging-structure entry of `size` bytes (4 or 8) at `gpa`
+    /// with `new` if it still holds `current`, atomically as the processor
+    /// sets the accessed and dirty flags. Returns whether it was replaced.
+    fn compare_exchange_entry(
+        &mut self,
+        gpa: GuestPhysAddr,
+        size: usize,
+        current: u64,
+        new: u64,
+    ) -> AxResult<bool>;
+}
+
+/// Why a guest page walk failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageWalkError {
+    /// The access causes a page fault in the guest, with this error code.
+    PageFault(PageFaultErrorCode),
+    /// The paging-structure entry at this address cannot be accessed.
+    BadEntry(GuestPhysAddr),
+}
+
+impl PageWalkError {
+    /// The #PF to raise in the guest for an access to `gva`, if the walk
+    /// ended in a page fault.
+    pub fn exception(&self, gva: GuestVirtAddr) -> Option<GuestException> {
+        match self {
+            Self::PageFault(err_code) => Some(GuestException::PageFault {
+                err_code: err_code.bits() as u32,
+                cr2: gva.as_usize() as u64,
+            }),
+            Self::BadEntry(_) => None,
+        }
+    }
+}
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_USER: u64 = 1 << 2;
+const PTE_ACCESSED: u64 = 1 << 5;
+const PTE_DIRTY: u64 = 1 << 6;
+const PTE_PAGE_SIZE: u64 = 1 << 7;
+const PTE_NO_EXECUTE: u64 = 1 << 63;
+/// Address bits of 64-bit entries, assuming a 52-bit MAXPHYADDR.
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// Address bits of 32-bit entries.
+const PTE32_ADDR_MASK: u64 = 0xffff_f000;
+
+impl GuestPageWalkInfo {
+    /// Translate the guest linear address `gva` to a guest physical address
+    /// by walking the guest page tables in `mem`, for the access described by
+    /// `self`. (SDM Vol. 3A, Section 4.3 to 4.6)
+    ///
+    /// All of 2-level (32-bit), 3-level (PAE), 4-level and 5-level paging are
+    /// supported, with 4-MByte, 2-MByte and 1-GByte pages. The accessed flags,
+    /// and the dirty flag on writes, are set once the access is allowed; the
+    /// walk starts again if an entry changed in the meantime.
+    ///
+    /// `is_smap_on` is taken as the effective SMAP state, that is, it should
+    /// be cleared for explicit supervisor accesses with `RFLAGS.AC` set. The
+    /// PAE PDPTEs are read from memory rather than from the PDPTE registers.
+    /// Protection keys and bits above MAXPHYADDR are not checked, and `gva`
+    /// must be canonical.
+    pub fn translate(
+        &self,
+        gva: GuestVirtAddr,
+        mem: &mut impl GuestPageTableMemory,
+    ) -> Result<GuestPhysAddr, PageWalkError> {
+        loop {
+            if let Some(gpa) = self.walk(gva, mem)? {
+                return Ok(gpa);
+            }
+        }
+    }
+
+    /// Walk the guest page tables once for [`GuestPageWalkInfo::translate`].
+    /// Returns `None` if an entry changed before its flags were set.
+    fn walk(
+        &self,
+        gva: GuestVirtAddr,
+        mem: &mut impl GuestPageTableMemory,
+    ) -> Result<Option<GuestPhysAddr>, PageWalkError> {
+        let va = gva.as_usize() as u64;
+        if self.level == 0 {
+            // Paging is disabled.
+            return Ok(Some(GuestPhysAddr::from(va as usize)));
+        }
+
+        let legacy = self.level == 2;
+        let (entry_size, addr_mask) = if legacy {
+            (4, PTE32_ADDR_MASK)
+        } else {
+            (8, PTE_ADDR_MASK)
+        };
+        let mut err_code = PageFaultErrorCode::empty();
+        if self.is_write_access {
+            err_code |= PageFaultErrorCode::CAUSED_BY_WRITE;
+        }
+        if self.is_user_mode_access {
+            err_code |= PageFaultErrorCode::USER_MODE;
+        }
+        // EFER.NXE has no effect on 32-bit paging. (SDM Vol. 3A, Section 4.7)
+        if self.is_inst_fetch && ((self.nxe && !legacy) || self.is_smep_on) {
+            err_code |= PageFaultErrorCode::INSTRUCTION_FETCH;
+        }
+        let fault = |flags: PageFaultErrorCode| Err(PageWalkError::PageFault(err_code | flags));
+
+        // The PAE PDPT is 32-byte aligned, other tables are page aligned.
+        let mut table = if self.level == 3 {
+            self.top_entry as u64 & 0xffff_ffe0
+        } else {
+            self.top_entry as u64 & addr_mask
+        };
+        let mut user = true;
+        let mut writable = true;
+        let mut executable = true;
+        // Entries whose accessed flag is set on success: (gpa, value).
+        let mut walked = [(0u64, 0u64); 5];
+        let mut walked_count = 0;
+
+        for level in (1..=self.level).rev() {
+            let shift = 12 + self.width as usize * (level - 1);
+            let index_mask = if level == 3 && self.level == 3 {
+                0b11 // The PAE PDPT has 4 entries.
+            } else {
+                (1 << self.width) - 1
+            };
+            let entry_gpa = table + ((va >> shift) & index_mask) * entry_size as u64;
+            let entry = mem
+                .read_entry(GuestPhysAddr::from(entry_gpa as usize), entry_size)
+                .map_err(|_| PageWalkError::BadEntry(GuestPhysAddr::from(entry_gpa as usize)))?;
+            if entry & PTE_PRESENT == 0 {
+                return fault(PageFaultErrorCode::empty());
+            }
+
+            let reserved = if legacy {
+                0
+            } else if level == 3 && self.level == 3 {
+                // PAE PDPTEs hold no access rights. (SDM Vol. 3A, Table 4-8)
+                0b1_1110_0110 | PTE_NO_EXECUTE
+            } else {
+                let mut reserved = if self.nxe { 0 } else { PTE_NO_EXECUTE };
+                if level >= 4 {
+                    reserved |= PTE_PAGE_SIZE;
+                }
+                reserved
+            };
+            if entry & reserved != 0 {
+                return fault(
+                    PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::MALFORMED_TABLE,
+                );
+            }
+
+            let pdpte = level == 3 && self.level == 3;
+            if !pdpte {
+                user &= entry & PTE_USER != 0;
+                writable &= entry & PTE_WRITABLE != 0;
+                executable &= !self.nxe || entry & PTE_NO_EXECUTE == 0;
+                walked[walked_count] = (entry_gpa, entry);
+                walked_count += 1;
+            }
+
+            let large = entry & PTE_PAGE_SIZE != 0
+                && match level {
+                    2 => !legacy || self.pse,
+                    3 => self.level >= 4,
+                    _ => false,
+                };
+            if level == 1 || large {
+                let page_mask = (1u64 << shift) - 1;
+                let frame = if !large {
+                    entry & addr_mask
+                } else if legacy {
+                    // PSE-36: bits 20:13 hold bits 39:32 of the address.
+                    (entry & 0xffc0_0000) | (((entry >> 13) & 0xff) << 32)
+                } else {
+                    // Bits 12 (PAT) up to the page size are reserved.
+                    if entry & page_mask & !0x1fff != 0 {
+                        return fault(
+                            PageFaultErrorCode::PROTECTION_VIOLATION
+                                | PageFaultErrorCode::MALFORMED_TABLE,
+                        );
+                    }
+                    entry & addr_mask & !page_mask
+                };
+                if !self.is_access_allowed(user, writable, executable) {
+                    return fault(PageFaultErrorCode::PROTECTION_VIOLATION);
+                }
+
+                // Set the accessed flags, and the dirty flag of the leaf entry.
+                for (i, &(gpa, value)) in walked[..walked_count].iter().enumerate() {
+                    let mut new = value | PTE_ACCESSED;
+                    if i == walked_count - 1 && self.is_write_access {
+                        new |= PTE_DIRTY;
+                    }
+                    if new != value {
+                        let gpa = GuestPhysAddr::from(gpa as usize);
+                        let exchanged = mem
+                            .compare_exchange_entry(gpa, entry_size, value, new)
+                            .map_err(|_| PageWalkError::BadEntry(gpa))?;
+                        if !exchanged {
+                            return Ok(None);
+                        }
+                    }
+                }
+                let gpa = GuestPhysAddr::from((frame | (va & page_mask)) as usize);
+                return Ok(Some(gpa));
+            }
+            table = entry & addr_mask;
+        }
+        unreachable!()
+    }
+
+    /// Check the access rights accumulated along the walk against the
+    /// access. (SDM Vol. 3A, Section 4.6)
+    fn is_access_allowed(&self, user: bool, writable: bool, executable: bool) -> bool {
+        if self.is_user_mode_access {
+            user && (!self.is_write_access || writable) && (!self.is_inst_fetch || executable)
+        } else if self.is_inst_fetch {
+            executable && !(user && self.is_smep_on)
+        } else {
+            let smap = user && self.is_smap_on;
+            !smap && (!self.is_write_access || writable || !self.wp)
+        }
+    }
+}
+
+/// The result of an access to guest memory through a guest virtual address.
+pub type GuestAccessResult<T = ()> = Result<T, GuestAccessError>;
+
+/// Why an access to guest memory through a guest virtual address failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestAccessError {
+    /// The guest page walk fails, and the access would raise this #PF in the
+    /// guest.
+    PageFault(GuestException),
+    /// The guest physical address is not mapped in the EPT, or not with the
+    /// permissions required by the access.
+    NotMapped(GuestPhysAddr),
+    /// The access is outside the segment limit or not allowed by the segment
+    /// rights, and would raise this #GP or #SS in the guest.
+    Segmentation(GuestException),
+    /// The range of linear addresses from this one wraps around the end of
+    /// the address space.
+    Wraparound(GuestVirtAddr),
+}
+
+impl GuestAccessError {
+    /// Convert a failed guest page walk for an access to `gva`.
+    pub fn from_walk(err: PageWalkError, gva: GuestVirtAddr) -> Self {
+        match err.exception(gva) {
+            Some(exception) => Self::PageFault(exception),
+            None => match err {
+                PageWalkError::BadEntry(gpa) => Self::NotMapped(gpa),
+                PageWalkError::PageFault(_) => unreachable!(),
+            },
+        }
+    }
+}
+
+const EPT_READ: u64 = 1 << 0;
+const EPT_WRITE: u64 = 1 << 1;
+const EPT_EXECUTE: u64 = 1 << 2;
+const EPT_PAGE_SIZE: u64 = 1 << 7;
+const EPT_DIRTY: u64 = 1 << 9;
+/// Ignored by the processor, marks entries write-protected for dirty logging.
+const EPT_WRITE_PROTECTED: u64 = 1 << 11;
+const EPT_SUPPRESS_VE: u64 = 1 << 63;
+
+/// Translate `gpa` to a host physical address through the EPT referenced by
+/// `eptp`, for a write access if `write` and a read otherwise. Returns `None`
+/// if `gpa` is not mapped with the required permission.
+/// (SDM Vol. 3C, Section 29.3.2)
+pub(crate) fn ept_translate(eptp: u64, gpa: GuestPhysAddr, write: bool) -> Option<HostPhysAddr> {
+    let required = if write {
+        EPT_READ | EPT_WRITE
+    } else {
+        EPT_READ
+    };
+    let gpa = gpa.as_usize() as u64;
+    let levels = ((eptp >> 3) & 0x7) as usize + 1;
+    let mut table = eptp & PTE_ADDR_MASK;
+    for level in (1..=levels).rev() {
+        let shift = 12 + 9 * (level - 1);
+        let entry_hpa = table + ((gpa >> shift) & 0x1ff) * 8;
+        let entry_ptr = phys_to_virt(HostPhysAddr::from(entry_hpa as usize)).as_ptr_of::<u64>();
+        // SAFETY: the EPT is owned by the hypervisor and mapped in the host.
+        let entry = unsafe { entry_ptr.read_volatile() };
+        if entry & required != required {
+            return None;
+        }
+        if level == 1 || (entry & EPT_PAGE_SIZE != 0 && level <= 3) {
+            let page_mask = (1u64 << shift) - 1;
+            let hpa = (entry & PTE_ADDR_MASK & !page_mask) | (gpa & page_mask);
+            return Some(HostPhysAddr::from(hpa as usize));
+        }
+        table = entry & PTE_ADDR_MASK;
+    }
+    None
+}
+
+/// Set the bits of `bitmap` for the 4-KiB pages from `start` which are dirty
+/// in the EPT referenced by `eptp`, and clear their dirty flags. Bit `i`
+/// stands for the page at `start + i * 4 KiB`. Returns the number of dirty
+/// pages found. (SDM Vol. 3C, Section 29.3.5)
+///
+/// The EPT translations must be invalidated afterwards, or the processor may
+/// not set the dirty flags again.
+pub(crate) fn ept_harvest_dirty(eptp: u64, start: GuestPhysAddr, bitmap: &mut [u64]) -> usize {
+    let start = start.as_usize() as u64 & !0xfff;
+    let end = start + bitmap.len() as u64 * 64 * 0x1000;
+    let mut count = 0;
+    for_each_ept_leaf(eptp, start..end, &mut |entry, pages| {
+        if entry.fetch_and(!EPT_DIRTY, Ordering::AcqRel) & EPT_DIRTY != 0 {
+            for page in (pages.start.max(start)..pages.end.min(end)).step_by(0x1000) {
+                let bit = ((page - start) >> 12) as usize;
+                bitmap[bit / 64] |= 1 << (bit % 64);
+                count += 1;
+            }
+        }
+    });
+    count
+}
+
+/// Clear the dirty flags of the EPT leaf entries mapping `range`.
+pub(crate) fn ept_clear_dirty(eptp: u64, range: Range<u64>) {
+    for_each_ept_leaf(eptp, range, &mut |entry, _| {
+        entry.fetch_and(!EPT_DIRTY, Ordering::AcqRel);
+    });
+}
+
+/// Remove write permission from the writable EPT leaf entries mapping
+/// `range`, marking them with [`EPT_WRITE_PROTECTED`] for
+/// [`ept_unprotect`]. Returns the number of entries changed.
+pub(crate) fn ept_write_protect(eptp: u64, range: Range<u64>) -> usize {
+    let mut count = 0;
+    for_each_ept_leaf(eptp, range, &mut |entry, _| {
+        let protect = |value: u64| {
+            (value & EPT_WRITE != 0).then_some(value & !EPT_WRITE | EPT_WRITE_PROTECTED)
+        };
+        if entry
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, protect)
+            .is_ok()
+        {
+            count += 1;
+        }
+    });
+    count
+}
+
+/// Give back write permission to the EPT leaf entry mapping `gpa`, if it was
+/// removed by [`ept_write_protect`] or [`ept_unprotect_all`]. Returns the
+/// guest physical addresses mapped by the entry.
+pub(crate) fn ept_unprotect(eptp: u64, gpa: u64) -> Option<Range<u64>> {
+    let mut unprotected = None;
+    for_each_ept_leaf(eptp, gpa..gpa + 1, &mut |entry, pages| {
+        if entry.fetch_and(!EPT_WRITE_PROTECTED, Ordering::AcqRel) & EPT_WRITE_PROTECTED != 0 {
+            entry.fetch_or(EPT_WRITE, Ordering::AcqRel);
+            unprotected = Some(pages);
+        }
+    });
+    unprotected
+}
+
+/// Whether the EPT leaf entry mapping `gpa` is write-protected by
+/// [`ept_write_protect`], i.e., writable once the write is logged.
+pub(crate) fn ept_write_protected(eptp: u64, gpa: u64) -> bool {
+    let mut protected = false;
+    for_each_ept_leaf(eptp, gpa..gpa + 1, &mut |entry, _| {
+        protected = entry.load(Ordering::Acquire) & EPT_WRITE_PROTECTED != 0;
+    });
+    protected
+}
+
+/// Give back write permission to all EPT leaf entries write-protected by
+/// [`ept_write_protect`].
+pub(crate) fn ept_unprotect_all(eptp: u64) {
+    for_each_ept_leaf(eptp, 0..u64::MAX, &mut |entry, _| {
+        if entry.fetch_and(!EPT_WRITE_PROTECTED, Ordering::AcqRel) & EPT_WRITE_PROTECTED != 0 {
+            entry.fetch_or(EPT_WRITE, Ordering::AcqRel);
+        }
+    });
+}
+
+/// Set the suppress-#VE bit of the present leaf entries mapping `range` if
+/// `suppress`, and clear it otherwise. Returns the number of entries changed.
+/// (SDM Vol. 3C, Section 26.5.7.1)
+///
+/// Not-present entries are left zeroed, as the owner of the EPT considers
+/// the other ones in use, so an EPT violation on them is never suppressed.
+pub(crate) fn ept_set_suppress_ve(eptp: u64, range: Range<u64>, suppress: bool) -> usize {
+    let mut count = 0;
+    for_each_ept_leaf(eptp, range, &mut |entry, _| {
+        let old = if suppress {
+            entry.fetch_or(EPT_SUPPRESS_VE, Ordering::AcqRel)
+        } else {
+            entry.fetch_and(!EPT_SUPPRESS_VE, Ordering::AcqRel)
+        };
+        if (old & EPT_SUPPRESS_VE != 0) != suppress {
+            count += 1;
+        }
+    });
+    count
+}
+
+/// Call `f` on each present leaf entry of the EPT referenced by `eptp` which
+/// maps guest physical addresses in `range`, with the addresses it maps.
+fn for_each_ept_leaf(eptp: u64, range: Range<u64>, f: &mut impl FnMut(&AtomicU64, Range<u64>)) {
+    let levels = ((eptp >> 3) & 0x7) as usize + 1;
+    for_each_leaf_in_table(eptp & PTE_ADDR_MASK, levels, 0, &range, f);
+}
+
+/// Call `f` on the present leaf entries of an EPT paging structure.
+fn for_each_leaf_in_table(
+    table: u64,
+    level: usize,
+    table_gpa: u64,
+    range: &Range<u64>,
+    f: &mut impl FnMut(&AtomicU64, Range<u64>),
+) {
+    let shift = 12 + 9 * (level - 1);
+    for index in 0..512u64 {
+        let gpa = table_gpa + (index << shift);
+        let gpa_end = gpa + (1 << shift);
+        if gpa_end <= range.start || gpa >= range.end {
+            continue;
+        }
+        let entry_hpa = table + index * 8;
+        let entry_ptr = phys_to_virt(HostPhysAddr::from(entry_hpa as usize)).as_mut_ptr();
+        // SAFETY: the EPT is owned by the hypervisor and mapped in the host,
+        // and the processor updates its entries atomically.
+        let entry = unsafe { AtomicU64::from_ptr(entry_ptr as *mut u64) };
+        let value = entry.load(Ordering::Acquire);
+        if value & (EPT_READ | EPT_WRITE | EPT_EXECUTE) == 0 {
+            continue;
+        }
+        if level == 1 || (value & EPT_PAGE_SIZE != 0 && level <= 3) {
+            f(entry, gpa..gpa_end);
+        } else {
+            for_each_leaf_in_table(value & PTE_ADDR_MASK, level - 1, gpa, range, f);
+        }
+    }
+}
+
+/// Guest physical memory accessed through the EPT, for walking guest page
+/// tables.
+pub(crate) struct EptGuestMemory {
+    /// The EPT pointer.
+    pub eptp: u64,
+    /// Whether the accessed and dirty flags set by the walk are written back,
+    /// otherwise the guest page tables are left untouched.
+    pub update_flags: bool,
+}
+
+impl EptGuestMemory {
+    fn entry_ptr(&self, gpa: GuestPhysAddr, write: bool) -> AxResult<*mut u8> {
+        let hpa = ept_translate(self.eptp, gpa, write).ok_or(axerrno::AxError::NotFound)?;
+        Ok(phys_to_virt(hpa).as_mut_ptr())
+    }
+}
+
+impl GuestPageTableMemory for EptGuestMemory {
+    fn read_entry(&mut self, gpa: GuestPhysAddr, size: usize) -> AxResult<u64> {
+        let ptr = self.entry_ptr(gpa, false)?;
+        // SAFETY: `ptr` maps guest memory, and entries are naturally aligned.
+        Ok(unsafe {
+            match size {
+                4 => (ptr as *const u32).read_volatile() as u64,
+                _ => (ptr as *const u64).read_volatile(),
+            }
+        })
+    }
+
+    fn compare_exchange_entry(
+        &mut self,
+        gpa: GuestPhysAddr,
+        size: usize,
+        current: u64,
+        new: u64,
+    ) -> AxResult<bool> {
+        if !self.update_flags {
+            return Ok(true);
+        }
+        let ptr = self.entry_ptr(gpa, true)?;
+        // SAFETY: `ptr` maps guest memory, which the guest accesses
+        // atomically too, and entries are naturally aligned.
+        let exchanged = unsafe {
+            match size {
+                4 => AtomicU32::from_ptr(ptr as *mut u32)
+                    .compare_exchange(
+                        current as u32,
+                        new as u32,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    )
+                    .is_ok(),
+                _ => AtomicU64::from_ptr(ptr as *mut u64)
+                    .compare_exchange(current, new, Ordering::AcqRel, Ordering::Acquire)
+                    .is_ok(),
+            }
+        };
+        Ok(exchanged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap;
+
+    /// Guest memory holding paging-structure entries only.
+    #[derive(Default)]
+    struct TestMemory {
+        entries: BTreeMap<u64, u64>,
+        /// An entry changed by another processor right before the next
+        /// flag update: (gpa, value).
+        race: Option<(u64, u64)>,
+    }
+
+    impl TestMemory {
+        fn map(&mut self, gpa: u64, value: u64) {
+            self.entries.insert(gpa, value);
+        }
+
+        fn get(&self, gpa: u64) -> u64 {
+            self.entries[&gpa]
+        }
+    }
+
+    impl GuestPageTableMemory for TestMemory {
+        fn read_entry(&mut self, gpa: GuestPhysAddr, _size: usize) -> AxResult<u64> {
+            let gpa = gpa.as_usize() as u64;
+            // Tables below 16 MiB are backed, reading zeroes where unset.
+            if gpa >= 0x100_0000 {
+                return axerrno::ax_err!(NotFound);
+            }
+            Ok(self.entries.get(&gpa).copied().unwrap_or(0))
+        }
+
+        fn compare_exchange_entry(
+            &mut self,
+            gpa: GuestPhysAddr,
+            _size: usize,
+            current: u64,
+            new: u64,
+        ) -> AxResult<bool> {
+            if let Some((gpa, value)) = self.race.take() {
+                self.entries.insert(gpa, value);
+            }
+            let entry = self.entries.entry(gpa.as_usize() as u64).or_default();
+            if *entry != current {
+                return Ok(false);
+            }
+            *entry = new;
+            Ok(true)
+        }
+    }
+
+    const P: u64 = PTE_PRESENT;
+    const W: u64 = PTE_WRITABLE;
+    const U: u64 = PTE_USER;
+    const PS: u64 = PTE_PAGE_SIZE;
+    const NX: u64 = PTE_NO_EXECUTE;
+
+    fn info(level: usize, top_entry: usize) -> GuestPageWalkInfo {
+        GuestPageWalkInfo {
+            top_entry,
+            level,
+            width: if level == 2 { 10 } else { 9 },
+            is_user_mode_access: false,
+            is_write_access: false,
+            is_inst_fetch: false,
+            pse: true,
+            wp: true,
+            nxe: true,
+            is_smap_on: false,
+            is_smep_on: false,
+        }
+    }
+
+    fn translate(
+        info: &GuestPageWalkInfo,
+        mem: &mut TestMemory,
+        gva: usize,
+    ) -> Result<usize, PageWalkError> {
+        info.translate(GuestVirtAddr::from(gva), mem)
+            .map(|gpa| gpa.as_usize())
+    }
+
+    fn pf(bits: u64) -> Result<usize, PageWalkError> {
+        Err(PageWalkError::PageFault(
+            PageFaultErrorCode::from_bits_truncate(bits),
+        ))
+    }
+
+    /// 4-level tables mapping 0x40_1000 to 0x8000 (4 KiB), 0x60_0000 to
+    /// 0x20_0000 (2 MiB, supervisor, read-only) and 0x4000_0000 to
+    /// 0x8000_0000 (1 GiB, no-execute).
+    fn four_level() -> TestMemory {
+        let mut mem = TestMemory::default();
+        mem.map(0x1000, 0x2000 | P | W | U); // PML4[0]
+        mem.map(0x2000, 0x3000 | P | W | U); // PDPT[0]
+        mem.map(0x2008, 0x8000_0000 | P | W | U | PS | NX); // PDPT[1]
+        mem.map(0x3000 + 2 * 8, 0x4000 | P | W | U); // PD[2]
+        mem.map(0x3000 + 3 * 8, 0x20_0000 | P | PS); // PD[3]
+        mem.map(0x4000 + 8, 0x8000 | P | W | U); // PT[1]
+        mem
+    }
+
+    #[test]
+    fn test_no_paging() {
+        let mut mem = TestMemory::default();
+        assert_eq!(translate(&info(0, 0), &mut mem, 0x1234), Ok(0x1234));
+    }
+
+    #[test]
+    fn test_four_level() {
+        let mut mem = four_level();
+        let info = info(4, 0x1000);
+        assert_eq!(translate(&info, &mut mem, 0x40_1abc), Ok(0x8abc));
+        assert_eq!(translate(&info, &mut mem, 0x61_2345), Ok(0x21_2345));
+        assert_eq!(translate(&info, &mut mem, 0x4123_4567), Ok(0x8123_4567));
+        // Not present.
+        assert_eq!(translate(&info, &mut mem, 0x80_0000), pf(0));
+    }
+
+    #[test]
+    fn test_accessed_dirty() {
+        let mut mem = four_level();
+        let mut info = info(4, 0x1000);
+        translate(&info, &mut mem, 0x40_1000).unwrap();
+        assert_ne!(mem.get(0x1000) & PTE_ACCESSED, 0);
+        assert_ne!(mem.get(0x4008) & PTE_ACCESSED, 0);
+        assert_eq!(mem.get(0x4008) & PTE_DIRTY, 0);
+
+        info.is_write_access = true;
+        translate(&info, &mut mem, 0x40_1000).unwrap();
+        assert_ne!(mem.get(0x4008) & PTE_DIRTY, 0);
+        // Only the leaf entry is dirty.
+        assert_eq!(mem.get(0x3010) & PTE_DIRTY, 0);
+
+        // Nothing is set on faults.
+        let before = mem.get(0x3018);
+        info.wp = true;
+        assert_eq!(translate(&info, &mut mem, 0x60_0000), pf(0b11));
+        assert_eq!(mem.get(0x3018), before);
+    }
+
+    #[test]
+    fn test_entry_changed_during_walk() {
+        let mut mem = four_level();
+        let info = info(4, 0x1000);
+        // The PTE is remapped before its accessed flag is set: the walk
+        // starts again, and uses the new frame.
+        mem.race = Some((0x4008, 0x9000 | P | W | U));
+        assert_eq!(translate(&info, &mut mem, 0x40_1abc), Ok(0x9abc));
+        assert_eq!(mem.get(0x4008), 0x9000 | P | W | U | PTE_ACCESSED);
+    }
+
+    #[test]
+    fn test_user_and_write_protection() {
+        let mut mem = four_level();
+        let mut info = info(4, 0x1000);
+
+        // Supervisor write to a read-only page depends on CR0.WP.
+        info.is_write_access = true;
+        assert_eq!(translate(&info, &mut mem, 0x60_0000), pf(0b11));
+        info.wp = false;
+        assert_eq!(translate(&info, &mut mem, 0x60_0000), Ok(0x20_0000));
+
+        // User accesses to supervisor pages always fault.
+        info.is_user_mode_access = true;
+        info.is_write_access = false;
+        assert_eq!(translate(&info, &mut mem, 0x60_0000), pf(0b101));
+        assert_eq!(translate(&info, &mut mem, 0x40_1000), Ok(0x8000));
+        // Not present, from user mode.
+        assert_eq!(translate(&info, &mut mem, 0x80_0000), pf(0b100));
+    }
+
+    #[test]
+    fn test_nx_smep_smap() {
+        let mut mem = four_level();
+        let mut info = info(4, 0x1000);
+
+        info.is_inst_fetch = true;
+        assert_eq!(translate(&info, &mut mem, 0x4000_0000), pf(0b1_0001));
+        assert_eq!(translate(&info, &mut mem, 0x40_1000), Ok(0x8000));
+        info.is_smep_on = true;
+        assert_eq!(translate(&info, &mut mem, 0x40_1000), pf(0b1_0001));
+        assert_eq!(translate(&info, &mut mem, 0x60_0000), Ok(0x20_0000));
+
+        info.is_inst_fetch = false;
+        info.is_smap_on = true;
+        assert_eq!(translate(&info, &mut mem, 0x40_1000), pf(0b1));
+        assert_eq!(translate(&info, &mut mem, 0x60_0000), Ok(0x20_0000));
+    }
+
+    #[test]
+    fn test_reserved_bits() {
+        let mut mem = four_level();
+        let mut info = info(4, 0x1000);
+        // NX is reserved without EFER.NXE.
+        info.nxe = false;
+        assert_eq!(translate(&info, &mut mem, 0x4000_0000), pf(0b1001));
+
+        // PS is reserved in PML4Es, and so are the low bits of large pages.
+        let mut info = self::info(4, 0x1000);
+        mem.map(0x1008, 0x5000 | P | PS);
+        assert_eq!(translate(&info, &mut mem, 0x80_0000_0000), pf(0b1001));
+        mem.map(0x3000 + 4 * 8, 0x20_2000 | P | PS);
+        info.is_write_access = true;
+        assert_eq!(translate(&info, &mut mem, 0x80_0000), pf(0b1011));
+    }
+
+    #[test]
+    fn test_five_level() {
+        let mut mem = four_level();
+        // PML5[1] -> PML4 at 0x1000, so that gva bit 48 selects it.
+        mem.map(0x9008, 0x1000 | P | W | U);
+        let info = info(5, 0x9000);
+        assert_eq!(translate(&info, &mut mem, 0x1_0000_0040_1234), Ok(0x8234));
+        assert_eq!(translate(&info, &mut mem, 0x40_1234), pf(0));
+    }
+
+    #[test]
+    fn test_pae() {
+        let mut mem = TestMemory::default();
+        mem.map(0x1020 + 8, 0x3000 | P); // PDPT[1], 32-byte aligned at 0x1020
+        mem.map(0x3000, 0x4000 | P | W); // PD[0]
+        mem.map(0x3008, 0x20_0000 | P | W | PS | NX); // PD[1]
+        mem.map(0x4000 + 5 * 8, 0x7000 | P | W); // PT[5]
+        let mut info = info(3, 0x1020);
+        assert_eq!(translate(&info, &mut mem, 0x4000_5678), Ok(0x7678));
+        assert_eq!(translate(&info, &mut mem, 0x4020_0010), Ok(0x20_0010));
+        assert_eq!(translate(&info, &mut mem, 0x8000_0000), pf(0));
+
+        // PDPTEs have no access rights.
+        mem.map(0x1028, 0x3000 | P | W);
+        assert_eq!(translate(&info, &mut mem, 0x4000_5678), pf(0b1001));
+        mem.map(0x1028, 0x3000 | P);
+
+        info.is_inst_fetch = true;
+        assert_eq!(translate(&info, &mut mem, 0x4020_0010), pf(0b1_0001));
+    }
+
+    #[test]
+    fn test_two_level() {
+        let mut mem = TestMemory::default();
+        mem.map(0x1000 + 4, 0x2000 | P | W | U); // PD[1]
+        mem.map(0x1000 + 8, 0x40_0000 | (0x3 << 13) | P | W | PS); // PD[2], PSE-36
+        mem.map(0x2000 + 3 * 4, 0x9000 | P | U); // PT[3]
+        let mut info = info(2, 0x1000);
+        info.nxe = false;
+        assert_eq!(translate(&info, &mut mem, 0x40_3abc), Ok(0x9abc));
+        assert_eq!(translate(&info, &mut mem, 0x80_1234), Ok(0x3_0040_1234));
+        info.pse = false;
+        // Without CR4.PSE, the PDE points to a page table at 0x40_0000.
+        assert_eq!(translate(&info, &mut mem, 0x80_1234), pf(0));
+
+        info.is_write_access = true;
+        info.is_user_mode_access = true;
+        assert_eq!(translate(&info, &mut mem, 0x40_3000), pf(0b111));
+
+        // EFER.NXE does not report instruction fetches with 32-bit paging.
+        info.nxe = true;
+        info.is_write_access = false;
+        info.is_inst_fetch = true;
+        assert_eq!(translate(&info, &mut mem, 0xc0_0000), pf(0b100));
+    }
+
+    #[test]
+    fn test_bad_entry() {
+        let mut mem = TestMemory::default();
+        let info = info(4, 0x200_0000);
+        assert_eq!(
+            translate(&info, &mut mem, 0x1000),
+            Err(PageWalkError::BadEntry(GuestPhysAddr::from(0x200_0000)))
+        );
+    }
+
+    /// A page-aligned host page, addressed by identity in the mock HAL.
+    #[repr(C, align(4096))]
+    struct HostPage([u64; 512]);
+
+    fn host_page() -> alloc::boxed::Box<HostPage> {
+        alloc::boxed::Box::new(HostPage([0; 512]))
+    }
+
+    fn hpa(page: &HostPage) -> u64 {
+        page as *const _ as u64
+    }
+
+    #[test]
+    fn test_ept_translate() {
+        let (mut pml4, mut pdpt, mut pd, mut pt) =
+            (host_page(), host_page(), host_page(), host_page());
+        let data = host_page();
+        pml4.0[0] = hpa(&pdpt) | 0b111;
+        pdpt.0[0] = hpa(&pd) | 0b111;
+        pdpt.0[1] = 0x4000_0000 | EPT_PAGE_SIZE | 0b101; // 1 GiB, read-only
+        pd.0[0] = hpa(&pt) | 0b111;
+        pt.0[2] = hpa(&data) | 0b111;
+        let eptp = hpa(&pml4) | (3 << 3);
+
+        let translate = |gpa: usize, write| {
+            ept_translate(eptp, GuestPhysAddr::from(gpa), write).map(|hpa| hpa.as_usize() as u64)
+        };
+        assert_eq!(translate(0x2123, true), Some(hpa(&data) + 0x123));
+        assert_eq!(translate(0x4012_3456, false), Some(0x4012_3456));
+        assert_eq!(translate(0x4012_3456, true), None);
+        assert_eq!(translate(0x3000, false), None);
+
+        // Guest page tables are read and updated through the EPT.
+        let mut mem = EptGuestMemory {
+            eptp,
+            update_flags: true,
+        };
+        assert_eq!(
+            mem.compare_exchange_entry(GuestPhysAddr::from(0x2008), 8, 0, 0xabcd),
+            Ok(true)
+        );
+        assert_eq!(data.0[1], 0xabcd);
+        assert_eq!(
+            mem.compare_exchange_entry(GuestPhysAddr::from(0x2008), 8, 0, 0x1234),
+            Ok(false)
+        );
+        assert_eq!(data.0[1], 0xabcd);
+        assert_eq!(mem.read_entry(GuestPhysAddr::from(0x200c), 4), Ok(0));
+        assert!(mem.read_entry(GuestPhysAddr::from(0x3000), 8).is_err());
+        assert!(
+            mem.compare_exchange_entry(GuestPhysAddr::from(0x4000_0000), 8, 0, 1)
+                .is_err()
+        );
+
+        // Without flag updates, the walk leaves the guest page tables alone.
+        let mut mem = EptGuestMemory {
+            eptp,
+            update_flags: false,
+        };
+        mem.compare_exchange_entry(GuestPhysAddr::from(0x2008), 8, 0xabcd, 0x1234)
+            .unwrap();
+        assert_eq!(data.0[1], 0xabcd);
+    }
+
+    #[test]
+    fn test_ept_harvest_dirty() {
+        let (mut pml4, mut pdpt, mut pd, mut pt) =
+            (host_page(), host_page(), host_page(), host_page());
+        pml4.0[0] = hpa(&pdpt) | 0b111;
+        pdpt.0[0] = hpa(&pd) | 0b111;
+        pd.0[0] = hpa(&pt) | 0b111;
+        pd.0[1] = 0x20_0000 | EPT_PAGE_SIZE | EPT_DIRTY | 0b111; // 2 MiB, dirty
+        pt.0[1] = 0x1000 | EPT_DIRTY | 0b111;
+        pt.0[2] = 0x2000 | 0b111;
+        pt.0[3] = 0x3000 | EPT_DIRTY | 0b111;
+        let eptp = hpa(&pml4) | (3 << 3) | (1 << 6);
+
+        // Pages 0x1000..0x20_1000: only 0x1000, 0x3000 and the first page of
+        // the 2 MiB one are in range.
+        let mut bitmap = [0u64; 8];
+        let count = ept_harvest_dirty(eptp, GuestPhysAddr::from(0x1000), &mut bitmap);
+        assert_eq!(count, 3);
+        assert_eq!(bitmap[0], 0b101);
+        assert_eq!(bitmap[7], 1 << 63);
+        assert_eq!(pt.0[1] & EPT_DIRTY, 0);
+        assert_eq!(pt.0[3] & EPT_DIRTY, 0);
+        assert_eq!(pd.0[1] & EPT_DIRTY, 0);
+
+        // The dirty flags were cleared.
+        let mut bitmap = [0u64; 8];
+        assert_eq!(
+            ept_harvest_dirty(eptp, GuestPhysAddr::from(0x1000), &mut bitmap),
+            0
+        );
+        assert_eq!(bitmap, [0; 8]);
+    }
+
+    #[test]
+    fn test_ept_write_protect() {
+        let (mut pml4, mut pdpt, mut pd, mut pt) =
+            (host_page(), host_page(), host_page(), host_page());
+        pml4.0[0] = hpa(&pdpt) | 0b111;
+        pdpt.0[0] = hpa(&pd) | 0b111;
+        pd.0[0] = hpa(&pt) | 0b111;
+        pd.0[1] = 0x20_0000 | EPT_PAGE_SIZE | 0b111;
+        pt.0[1] = 0x1000 | 0b111;
+        pt.0[2] = 0x2000 | 0b101; // read-only
+        let eptp = hpa(&pml4) | (3 << 3);
+
+        assert_eq!(ept_write_protect(eptp, 0..u64::MAX), 2);
+        assert_eq!(pt.0[1], 0x1000 | EPT_WRITE_PROTECTED | 0b101);
+        assert_eq!(pt.0[2], 0x2000 | 0b101);
+        assert!(ept_translate(eptp, GuestPhysAddr::from(0x1000), true).is_none());
+        assert!(ept_write_protected(eptp, 0x1234));
+        assert!(!ept_write_protected(eptp, 0x2000));
+        assert!(!ept_write_protected(eptp, 0x40_0000));
+
+        // Only entries protected for dirty logging are given write access.
+        assert_eq!(ept_unprotect(eptp, 0x1234), Some(0x1000..0x2000));
+        assert_eq!(pt.0[1], 0x1000 | 0b111);
+        assert_eq!(ept_unprotect(eptp, 0x1234), None);
+        assert_eq!(ept_unprotect(eptp, 0x2000), None);
+        assert_eq!(ept_unprotect(eptp, 0x23_4000), Some(0x20_0000..0x40_0000));
+
+        ept_write_protect(eptp, 0x1000..0x2000);
+        ept_unprotect_all(eptp);
+        assert_eq!(pt.0[1], 0x1000 | 0b111);
+        assert_eq!(pt.0[2], 0x2000 | 0b101);
+
+        pt.0[1] |= EPT_DIRTY;
+        ept_clear_dirty(eptp, 0x1000..0x2000);
+        assert_eq!(pt.0[1], 0x1000 | 0b111);
+    }
+
+    #[test]
+    fn test_ept_suppress_ve() {
+        let (mut pml4, mut pdpt, mut pd) = (host_page(), host_page(), host_page());
+        pml4.0[0] = hpa(&pdpt) | 0b111;
+        pdpt.0[0] = hpa(&pd) | 0b111;
+        pd.0[0] = EPT_PAGE_SIZE | 0b111;
+        pd.0[1] = 0x20_0000 | EPT_PAGE_SIZE | 0b111;
+        let eptp = hpa(&pml4) | (3 << 3);
+
+        // Only the present leaves, so that not-present entries stay unused.
+        assert_eq!(ept_set_suppress_ve(eptp, 0..u64::MAX, true), 2);
+        assert_eq!(pml4.0[0] & EPT_SUPPRESS_VE, 0);
+        assert_eq!(pd.0[1], 0x20_0000 | EPT_SUPPRESS_VE | EPT_PAGE_SIZE | 0b111);
+        assert_eq!(pd.0[2], 0);
+        assert_eq!(pml4.0[1], 0);
+
+        // A page mapped afterwards does not suppress #VE until set again.
+        pd.0[2] = 0x40_0000 | EPT_PAGE_SIZE | 0b111;
+        assert_eq!(
+            ept_translate(eptp, GuestPhysAddr::from(0x40_1000), true),
+            Some(HostPhysAddr::from(0x40_1000))
+        );
+        assert_eq!(ept_set_suppress_ve(eptp, 0x40_0000..0x60_0000, true), 1);
+        assert_eq!(pd.0[2], 0x40_0000 | EPT_SUPPRESS_VE | EPT_PAGE_SIZE | 0b111);
+
+        assert_eq!(ept_set_suppress_ve(eptp, 0x20_0000..0x80_0000, false), 2);
+        assert_eq!(pd.0[0] & EPT_SUPPRESS_VE, EPT_SUPPRESS_VE);
+        assert_eq!(pd.0[1], 0x20_0000 | EPT_PAGE_SIZE | 0b111);
+        assert_eq!(pd.0[3], 0);
+        assert_eq!(ept_set_suppress_ve(eptp, 0x20_0000..0x80_0000, false), 0);
+    }
+
+    #[test]
+    fn test_exception() {
+        let err = PageWalkError::PageFault(PageFaultErrorCode::CAUSED_BY_WRITE);
+        assert_eq!(
+            err.exception(GuestVirtAddr::from(0x1000)),
+            Some(GuestException::PageFault {
+                err_code: 2,
+                cr2: 0x1000
+            })
+        );
+    }
+}
@@ -0,0 +1,288 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use x86::irq::*;
+
+/// Vector of the control-protection exception (#CP), missing in [`x86::irq`].
+pub const CONTROL_PROTECTION_VECTOR: u8 = 21;
+
+/// An x86 exception to be raised in the guest, together with its payload.
+/// (SDM Vol. 3A, Section 6.3.1, Table 6-1)
+///
+/// NMIs are not exceptions and are not represented here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestException {
+    /// Divide error (#DE).
+    DivideError,
+    /// Debug exception (#DB).
+    Debug {
+        /// The B0-B3, BD, BS and BT bits to be reported in the guest DR6.
+        dr6: u64,
+    },
+    /// Breakpoint (#BP), raised by INT3.
+    Breakpoint,
+    /// Overflow (#OF), raised by INTO.
+    Overflow,
+    /// BOUND range exceeded (#BR).
+    BoundRange,
+    /// Invalid opcode (#UD).
+    InvalidOpcode,
+    /// Device not available (#NM).
+    DeviceNotAvailable,
+    /// Double fault (#DF). The error code is always zero.
+    DoubleFault,
+    /// Invalid TSS (#TS).
+    InvalidTss(u32),
+    /// Segment not present (#NP).
+    SegmentNotPresent(u32),
+    /// Stack-segment fault (#SS).
+    StackSegmentFault(u32),
+    /// General protection (#GP).
+    GeneralProtection(u32),
+    /// Page fault (#PF).
+    PageFault {
+        /// The page-fault error code.
+        err_code: u32,
+        /// The faulting linear address, to be loaded into the guest CR2.
+        cr2: u64,
+    },
+    /// x87 FPU floating-point error (#MF).
+    X87FloatingPoint,
+    /// Alignment check (#AC). The error code is always zero.
+    AlignmentCheck,
+    /// Machine check (#MC).
+    MachineCheck,
+    /// SIMD floating-point exception (#XM).
+    SimdFloatingPoint,
+    /// Virtualization exception (#VE).
+    Virtualization,
+    /// Control-protection exception (#CP).
+    ControlProtection(u32),
+}
+
+/// Exception classes used to decide how a second exception raised during the
+/// delivery of a first one is handled. (SDM Vol. 3A, Section 6.15, Table 6-4)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionClass {
+    /// Benign exceptions and interrupts.
+    Benign,
+    /// Contributory exceptions: #DE, #TS, #NP, #SS, #GP and #CP.
+    Contributory,
+    /// Page faults, and #VE which is handled the same way.
+    PageFault,
+    /// Double fault.
+    DoubleFault,
+}
+
+/// The outcome of raising an exception while another one is being delivered.
+/// (SDM Vol. 3A, Section 6.15, Table 6-5)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionMerge {
+    /// The second exception replaces the first one and is delivered normally.
+    Serial(GuestException),
+    /// A double fault (#DF) is delivered instead.
+    DoubleFault,
+    /// The processor enters shutdown, i.e., a triple fault occurs.
+    TripleFault,
+}
+
+impl GuestException {
+    /// The interrupt vector of this exception.
+    pub const fn vector(&self) -> u8 {
+        match self {
+            Self::DivideError => DIVIDE_ERROR_VECTOR,
+            Self::Debug { .. } => DEBUG_VECTOR,
+            Self::Breakpoint => BREAKPOINT_VECTOR,
+            Self::Overflow => OVERFLOW_VECTOR,
+            Self::BoundRange => BOUND_RANGE_EXCEEDED_VECTOR,
+            Self::InvalidOpcode => INVALID_OPCODE_VECTOR,
+            Self::DeviceNotAvailable => DEVICE_NOT_AVAILABLE_VECTOR,
+            Self::DoubleFault => DOUBLE_FAULT_VECTOR,
+            Self::InvalidTss(_) => INVALID_TSS_VECTOR,
+            Self::SegmentNotPresent(_) => SEGMENT_NOT_PRESENT_VECTOR,
+            Self::StackSegmentFault(_) => STACK_SEGEMENT_FAULT_VECTOR,
+            Self::GeneralProtection(_) => GENERAL_PROTECTION_FAULT_VECTOR,
+            Self::PageFault { .. } => PAGE_FAULT_VECTOR,
+            Self::X87FloatingPoint => X87_FPU_VECTOR,
+            Self::AlignmentCheck => ALIGNMENT_CHECK_VECTOR,
+            Self::MachineCheck => MACHINE_CHECK_VECTOR,
+            Self::SimdFloatingPoint => SIMD_FLOATING_POINT_VECTOR,
+            Self::Virtualization => VIRTUALIZATION_VECTOR,
+            Self::ControlProtection(_) => CONTROL_PROTECTION_VECTOR,
+        }
+    }
+
+    /// The error code pushed on the guest stack, or `None` if this exception
+    /// does not deliver one.
+    pub const fn error_code(&self) -> Option<u32> {
+        match *self {
+            Self::DoubleFault | Self::AlignmentCheck => Some(0),
+            Self::InvalidTss(err_code)
+            | Self::SegmentNotPresent(err_code)
+            | Self::StackSegmentFault(err_code)
+            | Self::GeneralProtection(err_code)
+            | Self::ControlProtection(err_code)
+            | Self::PageFault { err_code, .. } => Some(err_code),
+            _ => None,
+        }
+    }
+
+    /// Whether this exception is raised by a software instruction (INT3 or
+    /// INTO), which requires a VM-entry instruction length when injected.
+    pub const fn is_soft(&self) -> bool {
+        matches!(self, Self::Breakpoint | Self::Overflow)
+    }
+
+    /// The class of this exception. (SDM Vol. 3A, Section 6.15, Table 6-4)
+    pub const fn class(&self) -> ExceptionClass {
+        match self {
+            Self::DivideError
+            | Self::InvalidTss(_)
+            | Self::SegmentNotPresent(_)
+            | Self::StackSegmentFault(_)
+            | Self::GeneralProtection(_)
+            | Self::ControlProtection(_) => ExceptionClass::Contributory,
+            Self::PageFault { .. } | Self::Virtualization => ExceptionClass::PageFault,
+            Self::DoubleFault => ExceptionClass::DoubleFault,
+            _ => ExceptionClass::Benign,
+        }
+    }
+
+    /// Build an exception from its vector, error code and payload.
+    ///
+    /// `payload` is the CR2 value for #PF and the DR6 bits for #DB, and is
+    /// ignored otherwise. Returns `None` if `vector` is not an exception
+    /// vector (NMI, reserved vectors or interrupts).
+    pub const fn from_vector(vector: u8, err_code: Option<u32>, payload: u64) -> Option<Self> {
+        let err_code = match err_code {
+            Some(err_code) => err_code,
+            None => 0,
+        };
+        Some(match vector {
+            DIVIDE_ERROR_VECTOR => Self::DivideError,
+            DEBUG_VECTOR => Self::Debug { dr6: payload },
+            BREAKPOINT_VECTOR => Self::Breakpoint,
+            OVERFLOW_VECTOR => Self::Overflow,
+            BOUND_RANGE_EXCEEDED_VECTOR => Self::BoundRange,
+            INVALID_OPCODE_VECTOR => Self::InvalidOpcode,
+            DEVICE_NOT_AVAILABLE_VECTOR => Self::DeviceNotAvailable,
+            DOUBLE_FAULT_VECTOR => Self::DoubleFault,
+            INVALID_TSS_VECTOR => Self::InvalidTss(err_code),
+            SEGMENT_NOT_PRESENT_VECTOR => Self::SegmentNotPresent(err_code),
+            STACK_SEGEMENT_FAULT_VECTOR => Self::StackSegmentFault(err_code),
+            GENERAL_PROTECTION_FAULT_VECTOR => Self::GeneralProtection(err_code),
+            PAGE_FAULT_VECTOR => Self::PageFault {
+                err_code,
+                cr2: payload,
+            },
+            X87_FPU_VECTOR => Self::X87FloatingPoint,
+            ALIGNMENT_CHECK_VECTOR => Self::AlignmentCheck,
+            MACHINE_CHECK_VECTOR => Self::MachineCheck,
+            SIMD_FLOATING_POINT_VECTOR => Self::SimdFloatingPoint,
+            VIRTUALIZATION_VECTOR => Self::Virtualization,
+            CONTROL_PROTECTION_VECTOR => Self::ControlProtection(err_code),
+            _ => return None,
+        })
+    }
+
+    /// Decide what happens if `second` is raised while `self` is being
+    /// delivered. (SDM Vol. 3A, Section 6.15, Table 6-5)
+    pub const fn merge(&self, second: GuestException) -> ExceptionMerge {
+        use ExceptionClass::*;
+        match (self.class(), second.class()) {
+            // A contributory exception or a page fault during #DF delivery
+            // shuts the processor down.
+            (DoubleFault, Contributory | PageFault | DoubleFault) => ExceptionMerge::TripleFault,
+            (Contributory, Contributory) | (PageFault, Contributory | PageFault) => {
+                ExceptionMerge::DoubleFault
+            }
+            _ => ExceptionMerge::Serial(second),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GP: GuestException = GuestException::GeneralProtection(0);
+    const PF: GuestException = GuestException::PageFault {
+        err_code: 2,
+        cr2: 0xdead_b000,
+    };
+    const UD: GuestException = GuestException::InvalidOpcode;
+
+    #[test]
+    fn test_vector_round_trip() {
+        for vector in 0..32u8 {
+            match GuestException::from_vector(vector, Some(0x10), 0x1234) {
+                Some(exception) => assert_eq!(exception.vector(), vector),
+                None => assert!(matches!(vector, 2 | 9 | 15 | 22..=31)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_code() {
+        assert_eq!(GP.error_code(), Some(0));
+        assert_eq!(PF.error_code(), Some(2));
+        assert_eq!(GuestException::DoubleFault.error_code(), Some(0));
+        assert_eq!(UD.error_code(), None);
+        assert_eq!(GuestException::Debug { dr6: 1 }.error_code(), None);
+    }
+
+    #[test]
+    fn test_payload() {
+        assert_eq!(
+            GuestException::from_vector(14, Some(2), 0xdead_b000),
+            Some(PF)
+        );
+        assert_eq!(
+            GuestException::from_vector(1, None, 1 << 14),
+            Some(GuestException::Debug { dr6: 1 << 14 })
+        );
+    }
+
+    #[test]
+    fn test_class() {
+        assert_eq!(
+            GuestException::DivideError.class(),
+            ExceptionClass::Contributory
+        );
+        assert_eq!(GP.class(), ExceptionClass::Contributory);
+        assert_eq!(PF.class(), ExceptionClass::PageFault);
+        assert_eq!(UD.class(), ExceptionClass::Benign);
+        assert_eq!(GuestException::Breakpoint.class(), ExceptionClass::Benign);
+    }
+
+    #[test]
+    fn test_merge_table() {
+        // Benign first: always serial.
+        assert_eq!(UD.merge(GP), ExceptionMerge::Serial(GP));
+        assert_eq!(UD.merge(PF), ExceptionMerge::Serial(PF));
+        // Contributory first.
+        assert_eq!(GP.merge(UD), ExceptionMerge::Serial(UD));
+        assert_eq!(GP.merge(GP), ExceptionMerge::DoubleFault);
+        assert_eq!(GP.merge(PF), ExceptionMerge::Serial(PF));
+        // Page fault first.
+        assert_eq!(PF.merge(UD), ExceptionMerge::Serial(UD));
+        assert_eq!(PF.merge(GP), ExceptionMerge::DoubleFault);
+        assert_eq!(PF.merge(PF), ExceptionMerge::DoubleFault);
+        // Double fault first.
+        let df = GuestException::DoubleFault;
+        assert_eq!(df.merge(GP), ExceptionMerge::TripleFault);
+        assert_eq!(df.merge(PF), ExceptionMerge::TripleFault);
+        assert_eq!(df.merge(UD), ExceptionMerge::Serial(UD));
+    }
+}
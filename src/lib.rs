@@ -28,18 +28,30 @@ pub(crate) mod msr;
 #[macro_use]
 pub(crate) mod regs;
 mod ept;
+mod exception;
 
 cfg_if::cfg_if! {
     if #[cfg(feature = "vmx")] {
         mod vmx;
         use vmx as vender;
-        pub use vmx::{VmxExitInfo, VmxExitReason, VmxInterruptInfo, VmxIoExitInfo};
-
+        pub use vmx::{
+            CoreDump, CoreDumpSink, DirtyLogMode, EptConfig, EptMemoryType, EptShootdown,
+            ExceptionPolicy, GuestActivityState, GuestDescriptorTable, GuestSegment,
+            HW_BREAKPOINT_SLOTS, HwBreakpoint, HwBreakpointKind, SegmentAccess,
+            SegmentAccessRights, SegmentRegister, SwBreakpoint, SwBreakpointAddr, VcpuEvents,
+            VcpuMsrs, VcpuState, VmCpuMode, VmDirtyLog, VmxExceptionExitInfo, VmxExitEvent,
+            VmxExitInfo, VmxExitReason, VmxInterruptInfo, VmxIoExitInfo, VmxShutdownInfo,
+        };
+
+        pub use vmx::gdb;
         pub use vender::VmxArchVCpu;
         pub use vender::VmxArchPerCpuState;
     }
 }
 
-pub use ept::GuestPageWalkInfo;
-pub use regs::GeneralRegisters;
+pub use ept::{
+    GuestAccessError, GuestAccessResult, GuestPageTableMemory, GuestPageWalkInfo, PageWalkError,
+};
+pub use exception::{ExceptionClass, ExceptionMerge, GuestException};
+pub use regs::{ControlRegister, DebugRegister, GeneralRegisters};
 pub use vender::has_hardware_support;
@@ -15,10 +15,12 @@
 mod accessors;
 #[cfg(feature = "tracing")]
 mod diff;
+mod special;
 #[allow(unused_imports)]
 pub use accessors::*;
 #[cfg(feature = "tracing")]
 pub use diff::*;
+pub use special::{ControlRegister, DebugRegister};
 
 /// General-purpose registers for the 64-bit x86 architecture.
 ///
@@ -0,0 +1,45 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// A control register of the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlRegister {
+    /// CR0, system control flags.
+    Cr0,
+    /// CR2, the last page-fault linear address.
+    Cr2,
+    /// CR3, the paging-structure root.
+    Cr3,
+    /// CR4, architectural extension flags.
+    Cr4,
+    /// CR8, a view of bits 7:4 of the local APIC TPR.
+    Cr8,
+}
+
+/// A debug register of the guest. DR4 and DR5 are aliases of DR6 and DR7.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugRegister {
+    /// Breakpoint 0 linear address.
+    Dr0,
+    /// Breakpoint 1 linear address.
+    Dr1,
+    /// Breakpoint 2 linear address.
+    Dr2,
+    /// Breakpoint 3 linear address.
+    Dr3,
+    /// Debug status.
+    Dr6,
+    /// Debug control.
+    Dr7,
+}
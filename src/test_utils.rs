@@ -14,6 +14,7 @@
 
 #[cfg(test)]
 pub mod mock {
+    use alloc::vec::Vec;
     use axvisor_api::{api_impl, memory::MemoryIf};
     use memory_addr::{PhysAddr, VirtAddr};
     use spin::Mutex;
@@ -155,6 +156,24 @@ pub mod mock {
             state.reset_counter
         }
     }
+
+    // vCPUs kicked by EPT shootdowns, as (vm_id, vcpu_id)
+    static KICKS: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+    #[derive(Debug)]
+    pub struct MockShootdownIf;
+
+    impl MockShootdownIf {
+        // Record a vCPU kicked by an EPT shootdown
+        pub fn kick_vcpu(vm_id: usize, vcpu_id: usize) {
+            KICKS.lock().push((vm_id, vcpu_id));
+        }
+
+        // Take the vCPUs kicked so far
+        pub fn take_kicks() -> Vec<(usize, usize)> {
+            core::mem::take(&mut *KICKS.lock())
+        }
+    }
 }
 
 #[cfg(test)]
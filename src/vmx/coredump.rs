@@ -0,0 +1,488 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use axaddrspace::GuestPhysAddr;
+use axerrno::{AxResult, ax_err};
+use memory_addr::PAGE_SIZE_4K;
+
+use super::state::{FXSAVE_AREA_SIZE, VcpuState};
+
+/// Definitions of the ELF-64 object file format and of the Linux core file
+/// notes for x86-64.
+mod elf {
+    pub const EHDR_SIZE: usize = 64;
+    pub const PHDR_SIZE: usize = 56;
+    pub const ELFCLASS64: u8 = 2;
+    pub const ELFDATA2LSB: u8 = 1;
+    pub const EV_CURRENT: u8 = 1;
+    pub const ET_CORE: u16 = 4;
+    pub const EM_X86_64: u16 = 62;
+
+    pub const PT_LOAD: u32 = 1;
+    pub const PT_NOTE: u32 = 4;
+    pub const PF_RWX: u32 = 0b111;
+
+    pub const NT_PRSTATUS: u32 = 1;
+    pub const NT_FPREGSET: u32 = 2;
+    pub const NT_X86_XSTATE: u32 = 0x202;
+
+    /// The size of `struct elf_prstatus`, and the offsets of its
+    /// `pr_cursig`, `pr_pid`, `pr_reg` and `pr_fpvalid` fields.
+    pub const PRSTATUS_SIZE: usize = 336;
+    pub const PRSTATUS_CURSIG: usize = 12;
+    pub const PRSTATUS_PID: usize = 32;
+    pub const PRSTATUS_REG: usize = 112;
+    pub const PRSTATUS_FPVALID: usize = 328;
+    /// The offset of XCR0 in the software-reserved bytes of the XSAVE area
+    /// of a `NT_X86_XSTATE` note.
+    pub const XSTATE_XCR0: usize = 464;
+}
+
+/// The destination of a core file, such as a buffer, a file or a serial
+/// line.
+pub trait CoreDumpSink {
+    /// Write all of `data`.
+    fn write_all(&mut self, data: &[u8]) -> AxResult;
+}
+
+impl CoreDumpSink for Vec<u8> {
+    fn write_all(&mut self, data: &[u8]) -> AxResult {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// An ELF-64 core file of a guest, for post-mortem debugging with tools
+/// which read physical memory, e.g., crash.
+///
+/// Each vCPU, whose state is saved by
+/// [`VmxArchVCpu::save_state`](crate::VmxArchVCpu::save_state) once stopped,
+/// is described by `NT_PRSTATUS`, `NT_FPREGSET` and `NT_X86_XSTATE` notes,
+/// as a thread whose ID is the vCPU index plus one. The guest memory is in
+/// `PT_LOAD` segments, whose virtual and physical addresses are both the
+/// guest physical address: the file is physical only. GDB, which reads the
+/// segments by virtual address, can only follow the registers and memory of
+/// a guest whose paging is disabled or identity maps its memory.
+///
+/// ```ignore
+/// let states = vcpus.iter_mut().map(|vcpu| vcpu.save_state()).collect::<AxResult<Vec<_>>>()?;
+/// let dump = CoreDump::new(&states, &[GuestPhysAddr::from(0)..GuestPhysAddr::from(ram_size)]);
+/// dump.write(&mut file, |gpa, buf| read_guest_phys(gpa, buf))?;
+/// ```
+pub struct CoreDump<'a> {
+    vcpus: &'a [VcpuState],
+    regions: &'a [Range<GuestPhysAddr>],
+    signal: u8,
+}
+
+impl<'a> CoreDump<'a> {
+    /// A core file of the vCPUs `vcpus`, and of the guest memory in
+    /// `regions`.
+    pub fn new(vcpus: &'a [VcpuState], regions: &'a [Range<GuestPhysAddr>]) -> Self {
+        Self {
+            vcpus,
+            regions,
+            signal: 0,
+        }
+    }
+
+    /// Report the guest as terminated by the signal `signal`, e.g., 11
+    /// (SIGSEGV) after a fatal exception.
+    pub fn with_signal(mut self, signal: u8) -> Self {
+        self.signal = signal;
+        self
+    }
+
+    /// The size of the core file in bytes.
+    pub fn size(&self) -> usize {
+        self.segment_offsets()
+            .last()
+            .map_or(self.notes_end(), |&(offset, size)| offset + size)
+    }
+
+    /// Write the core file to `sink`, reading guest memory with `read`.
+    pub fn write<S, R>(&self, sink: &mut S, mut read: R) -> AxResult
+    where
+        S: CoreDumpSink + ?Sized,
+        R: FnMut(GuestPhysAddr, &mut [u8]) -> AxResult,
+    {
+        if self.regions.iter().any(|region| region.end < region.start) {
+            return ax_err!(InvalidInput, "invalid guest memory region");
+        }
+        let segments = self.segment_offsets();
+        let mut out = Vec::with_capacity(self.notes_end());
+        self.push_headers(&mut out, &segments);
+        for (index, vcpu) in self.vcpus.iter().enumerate() {
+            self.push_notes(&mut out, index, vcpu);
+        }
+        sink.write_all(&out)?;
+
+        let mut pos = out.len();
+        let mut buf = alloc::vec![0; PAGE_SIZE_4K];
+        for (region, &(offset, _)) in self.regions.iter().zip(&segments) {
+            buf.fill(0);
+            while pos < offset {
+                let len = (offset - pos).min(buf.len());
+                sink.write_all(&buf[..len])?;
+                pos += len;
+            }
+            let mut gpa = region.start;
+            while gpa < region.end {
+                // Read within a page at a time.
+                let len = (PAGE_SIZE_4K - gpa.as_usize() % PAGE_SIZE_4K).min(region.end - gpa);
+                read(gpa, &mut buf[..len])?;
+                sink.write_all(&buf[..len])?;
+                gpa += len;
+                pos += len;
+            }
+        }
+        Ok(())
+    }
+
+    /// The size of the notes of `vcpu`.
+    fn notes_size(vcpu: &VcpuState) -> usize {
+        let mut size = note_size("CORE", elf::PRSTATUS_SIZE);
+        if vcpu.fpu.len() >= FXSAVE_AREA_SIZE {
+            size += note_size("CORE", FXSAVE_AREA_SIZE);
+        }
+        if vcpu.fpu.len() > FXSAVE_AREA_SIZE {
+            size += note_size("LINUX", vcpu.fpu.len());
+        }
+        size
+    }
+
+    /// The offset of the notes, after the ELF header and the program headers.
+    fn notes_offset(&self) -> usize {
+        elf::EHDR_SIZE + elf::PHDR_SIZE * (1 + self.regions.len())
+    }
+
+    fn notes_end(&self) -> usize {
+        self.notes_offset() + self.vcpus.iter().map(Self::notes_size).sum::<usize>()
+    }
+
+    /// The offsets and sizes of the memory segments, placed after the notes
+    /// at offsets congruent to their addresses modulo the page size.
+    fn segment_offsets(&self) -> Vec<(usize, usize)> {
+        let mut pos = self.notes_end();
+        self.regions
+            .iter()
+            .map(|region| {
+                let size = region
+                    .end
+                    .as_usize()
+                    .saturating_sub(region.start.as_usize());
+                let offset =
+                    pos.next_multiple_of(PAGE_SIZE_4K) + region.start.as_usize() % PAGE_SIZE_4K;
+                pos = offset + size;
+                (offset, size)
+            })
+            .collect()
+    }
+
+    fn push_headers(&self, out: &mut Vec<u8>, segments: &[(usize, usize)]) {
+        // ELF header.
+        out.extend_from_slice(b"\x7fELF");
+        out.extend_from_slice(&[elf::ELFCLASS64, elf::ELFDATA2LSB, elf::EV_CURRENT]);
+        out.resize(16, 0); // OS ABI, ABI version and padding.
+        out.extend_from_slice(&elf::ET_CORE.to_le_bytes());
+        out.extend_from_slice(&elf::EM_X86_64.to_le_bytes());
+        out.extend_from_slice(&(elf::EV_CURRENT as u32).to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+        out.extend_from_slice(&(elf::EHDR_SIZE as u64).to_le_bytes()); // e_phoff
+        out.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        out.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        out.extend_from_slice(&(elf::EHDR_SIZE as u16).to_le_bytes());
+        out.extend_from_slice(&(elf::PHDR_SIZE as u16).to_le_bytes());
+        out.extend_from_slice(&(1 + self.regions.len() as u16).to_le_bytes());
+        out.extend_from_slice(&[0; 6]); // e_shentsize, e_shnum and e_shstrndx
+
+        // Program headers.
+        let notes_offset = self.notes_offset();
+        push_phdr(
+            out,
+            elf::PT_NOTE,
+            0,
+            notes_offset,
+            0,
+            self.notes_end() - notes_offset,
+            4,
+        );
+        for (region, &(offset, size)) in self.regions.iter().zip(segments) {
+            // Physical only: the guest virtual mappings are not described.
+            let gpa = region.start.as_usize();
+            push_phdr(
+                out,
+                elf::PT_LOAD,
+                elf::PF_RWX,
+                offset,
+                gpa,
+                size,
+                PAGE_SIZE_4K,
+            );
+        }
+    }
+
+    fn push_notes(&self, out: &mut Vec<u8>, index: usize, vcpu: &VcpuState) {
+        let regs = &vcpu.regs;
+        // `struct user_regs_struct`, as in `pr_reg`.
+        let user_regs = [
+            regs.r15,
+            regs.r14,
+            regs.r13,
+            regs.r12,
+            regs.rbp,
+            regs.rbx,
+            regs.r11,
+            regs.r10,
+            regs.r9,
+            regs.r8,
+            regs.rax,
+            regs.rcx,
+            regs.rdx,
+            regs.rsi,
+            regs.rdi,
+            u64::MAX, // orig_rax, not in a system call.
+            vcpu.rip,
+            vcpu.cs.selector as u64,
+            vcpu.rflags,
+            vcpu.rsp,
+            vcpu.ss.selector as u64,
+            vcpu.fs.base,
+            vcpu.gs.base,
+            vcpu.ds.selector as u64,
+            vcpu.es.selector as u64,
+            vcpu.fs.selector as u64,
+            vcpu.gs.selector as u64,
+        ];
+        let mut prstatus = [0; elf::PRSTATUS_SIZE];
+        prstatus[..4].copy_from_slice(&(self.signal as u32).to_le_bytes()); // si_signo
+        prstatus[elf::PRSTATUS_CURSIG..elf::PRSTATUS_CURSIG + 2]
+            .copy_from_slice(&(self.signal as u16).to_le_bytes());
+        prstatus[elf::PRSTATUS_PID..elf::PRSTATUS_PID + 4]
+            .copy_from_slice(&(index as u32 + 1).to_le_bytes());
+        for (reg, value) in prstatus[elf::PRSTATUS_REG..]
+            .chunks_exact_mut(8)
+            .zip(user_regs)
+        {
+            reg.copy_from_slice(&value.to_le_bytes());
+        }
+        let fpvalid = vcpu.fpu.len() >= FXSAVE_AREA_SIZE;
+        prstatus[elf::PRSTATUS_FPVALID..elf::PRSTATUS_FPVALID + 4]
+            .copy_from_slice(&(fpvalid as u32).to_le_bytes());
+        push_note(out, "CORE", elf::NT_PRSTATUS, &prstatus);
+
+        if fpvalid {
+            push_note(out, "CORE", elf::NT_FPREGSET, &vcpu.fpu[..FXSAVE_AREA_SIZE]);
+        }
+        if vcpu.fpu.len() > FXSAVE_AREA_SIZE {
+            // GDB finds the saved state components from XCR0, as Linux
+            // stores it in the software-reserved bytes.
+            let mut xsave = vcpu.fpu.clone();
+            xsave[elf::XSTATE_XCR0..elf::XSTATE_XCR0 + 8].copy_from_slice(&vcpu.xcr0.to_le_bytes());
+            push_note(out, "LINUX", elf::NT_X86_XSTATE, &xsave);
+        }
+    }
+}
+
+fn push_phdr(
+    out: &mut Vec<u8>,
+    ty: u32,
+    flags: u32,
+    offset: usize,
+    addr: usize,
+    size: usize,
+    align: usize,
+) {
+    out.extend_from_slice(&ty.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    // p_offset, p_vaddr, p_paddr, p_filesz, p_memsz and p_align.
+    for value in [offset, addr, addr, size, size, align] {
+        out.extend_from_slice(&(value as u64).to_le_bytes());
+    }
+}
+
+/// The size of a note named `name`, with `desc_size` bytes of data.
+fn note_size(name: &str, desc_size: usize) -> usize {
+    12 + (name.len() + 1).next_multiple_of(4) + desc_size.next_multiple_of(4)
+}
+
+fn push_note(out: &mut Vec<u8>, name: &str, ty: u32, desc: &[u8]) {
+    out.extend_from_slice(&(name.len() as u32 + 1).to_le_bytes());
+    out.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    out.extend_from_slice(&ty.to_le_bytes());
+    out.extend_from_slice(name.as_bytes());
+    out.resize(
+        out.len() + (name.len() + 1).next_multiple_of(4) - name.len(),
+        0,
+    );
+    out.extend_from_slice(desc);
+    out.resize(out.len().next_multiple_of(4), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use alloc::vec;
+
+    fn sample_vcpus() -> Vec<VcpuState> {
+        let mut vcpu = VcpuState::empty();
+        vcpu.regs.rax = 0x1234;
+        vcpu.regs.r15 = 0xf00d;
+        vcpu.rsp = 0xffff_8000_0000_8000;
+        vcpu.rip = 0xffff_8000_0010_0000;
+        vcpu.rflags = 0x246;
+        vcpu.cs.selector = 0x10;
+        vcpu.gs.base = 0xffff_8880_0000_0000;
+        vcpu.xcr0 = 0b111;
+        // The legacy region and the XSAVE header, then the AVX state.
+        vcpu.fpu = vec![0; 576 + 256];
+        vcpu.fpu[..2].copy_from_slice(&0x37fu16.to_le_bytes()); // FCW
+
+        let mut legacy = vcpu.clone();
+        legacy.xcr0 = 0;
+        legacy.fpu.truncate(FXSAVE_AREA_SIZE);
+        vec![vcpu, legacy]
+    }
+
+    fn sample_regions() -> Vec<Range<GuestPhysAddr>> {
+        vec![
+            GuestPhysAddr::from(0)..GuestPhysAddr::from(0x2000),
+            GuestPhysAddr::from(0x10_0800)..GuestPhysAddr::from(0x10_1a00),
+        ]
+    }
+
+    fn read_pattern(gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult {
+        for (index, byte) in buf.iter_mut().enumerate() {
+            *byte = ((gpa.as_usize() + index) >> 4) as u8;
+        }
+        Ok(())
+    }
+
+    fn u64_at(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_layout() {
+        let vcpus = sample_vcpus();
+        let regions = sample_regions();
+        let dump = CoreDump::new(&vcpus, &regions).with_signal(11);
+        let mut out = Vec::new();
+        dump.write(&mut out, read_pattern).unwrap();
+        assert_eq!(out.len(), dump.size());
+        assert_eq!(&out[..4], b"\x7fELF");
+        assert_eq!(u16::from_le_bytes([out[56], out[57]]), 3); // e_phnum
+
+        // The notes follow the program headers.
+        let notes_offset = 64 + 3 * 56;
+        assert_eq!(u64_at(&out, 64 + 8), notes_offset as u64);
+        let prstatus = &out[notes_offset + 20..];
+        assert_eq!(prstatus[elf::PRSTATUS_CURSIG], 11);
+        assert_eq!(prstatus[elf::PRSTATUS_PID], 1);
+        assert_eq!(u64_at(prstatus, elf::PRSTATUS_REG), 0xf00d); // r15
+        assert_eq!(u64_at(prstatus, elf::PRSTATUS_REG + 10 * 8), 0x1234); // rax
+        assert_eq!(
+            u64_at(prstatus, elf::PRSTATUS_REG + 16 * 8),
+            0xffff_8000_0010_0000
+        ); // rip
+        assert_eq!(prstatus[elf::PRSTATUS_FPVALID], 1);
+        let notes_size = u64_at(&out, 64 + 32) as usize;
+        assert_eq!(
+            notes_size,
+            2 * (20 + 336) + 2 * (20 + 512) + (20 + 576 + 256)
+        );
+
+        // The segments are at offsets congruent to their addresses.
+        for (index, region) in regions.iter().enumerate() {
+            let phdr = 64 + (index + 1) * 56;
+            let offset = u64_at(&out, phdr + 8) as usize;
+            let size = u64_at(&out, phdr + 32) as usize;
+            assert_eq!(u64_at(&out, phdr + 24), region.start.as_usize() as u64);
+            assert_eq!(
+                offset % PAGE_SIZE_4K,
+                region.start.as_usize() % PAGE_SIZE_4K
+            );
+            assert_eq!(size, region.end - region.start);
+            let mut expected = vec![0; size];
+            read_pattern(region.start, &mut expected).unwrap();
+            assert_eq!(out[offset..offset + size], expected);
+        }
+    }
+
+    #[test]
+    fn test_read_error() {
+        let vcpus = sample_vcpus();
+        let regions = sample_regions();
+        let mut out = Vec::new();
+        let result = CoreDump::new(&vcpus, &regions).write(&mut out, |gpa, buf| {
+            if gpa.as_usize() >= 0x10_0000 {
+                return ax_err!(BadAddress);
+            }
+            read_pattern(gpa, buf)
+        });
+        assert!(result.is_err());
+    }
+
+    /// Check the core file with readelf, when it is installed.
+    #[test]
+    fn test_readelf() {
+        use std::process::Command;
+        use std::string::String;
+
+        let vcpus = sample_vcpus();
+        let regions = sample_regions();
+        let mut out = Vec::new();
+        CoreDump::new(&vcpus, &regions)
+            .write(&mut out, read_pattern)
+            .unwrap();
+        let path = std::env::temp_dir().join(std::format!("x86_vcpu-core-{}", std::process::id()));
+        std::fs::write(&path, &out).unwrap();
+        let output = Command::new("readelf")
+            .args(["-h", "-l", "-n", "-W"])
+            .arg(&path)
+            .output();
+        std::fs::remove_file(&path).unwrap();
+        let Ok(output) = output else {
+            std::eprintln!("readelf not found, skipped");
+            return;
+        };
+        let text = String::from_utf8(output.stdout).unwrap();
+        assert!(output.status.success(), "{text}");
+        assert!(
+            output.stderr.is_empty(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert!(text.contains("CORE (Core file)"), "{text}");
+        assert!(text.contains("X86-64"), "{text}");
+        assert_eq!(text.matches("NT_PRSTATUS").count(), 2, "{text}");
+        assert_eq!(text.matches("NT_FPREGSET").count(), 2, "{text}");
+        assert_eq!(text.matches("NT_X86_XSTATE").count(), 1, "{text}");
+        assert!(
+            text.contains(
+                "0x001000 0x0000000000000000 0x0000000000000000 0x002000 0x002000 RWE 0x1000"
+            ),
+            "{text}"
+        );
+        assert!(
+            text.contains("0x0000000000100800 0x0000000000100800 0x001200 0x001200 RWE 0x1000"),
+            "{text}"
+        );
+    }
+}
@@ -0,0 +1,395 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::collections::BTreeMap;
+
+use axaddrspace::{GuestPhysAddr, GuestVirtAddr};
+use axerrno::{AxResult, ax_err};
+use bit_field::BitField;
+
+/// The number of hardware breakpoint slots, i.e., DR0 to DR3.
+pub const HW_BREAKPOINT_SLOTS: usize = 4;
+
+/// The value of DR6 on reset. (SDM Vol. 3B, Section 18.2.3)
+const DR6_INIT: u64 = 0xffff_0ff0;
+/// The value of DR7 on reset, with only the reserved bit 10 set.
+pub(crate) const DR7_INIT: u64 = 0x400;
+/// The local and global enable bits of DR7 for all breakpoints.
+const DR7_ENABLE_MASK: u64 = 0xff;
+
+/// The condition of a hardware breakpoint, in the R/W encoding of DR7.
+/// (SDM Vol. 3B, Section 18.2.4)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwBreakpointKind {
+    /// Break on instruction execution.
+    Execute = 0b00,
+    /// Break on data writes.
+    Write = 0b01,
+    /// Break on data reads or writes, but not instruction fetches.
+    ReadWrite = 0b11,
+}
+
+/// A hardware breakpoint or watchpoint installed by the VMM in the guest,
+/// see [`VmxVcpu::set_hw_breakpoint`](crate::VmxArchVCpu::set_hw_breakpoint).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HwBreakpoint {
+    /// The guest linear address, aligned to `len`.
+    pub addr: u64,
+    /// The breakpoint condition.
+    pub kind: HwBreakpointKind,
+    /// The length of the watched range: 1, 2, 4 or 8 bytes, and 1 for
+    /// execution breakpoints.
+    pub len: u8,
+}
+
+impl HwBreakpoint {
+    /// Check the length and alignment of the breakpoint.
+    pub fn check(&self) -> AxResult {
+        if !matches!(self.len, 1 | 2 | 4 | 8) {
+            return ax_err!(InvalidInput, "invalid breakpoint length");
+        }
+        if self.kind == HwBreakpointKind::Execute && self.len != 1 {
+            return ax_err!(InvalidInput, "execution breakpoints must be 1 byte long");
+        }
+        if self.addr % self.len as u64 != 0 {
+            return ax_err!(InvalidInput, "unaligned breakpoint address");
+        }
+        Ok(())
+    }
+
+    /// The DR7 bits enabling this breakpoint in `slot`, see [`slot_dr7_mask`].
+    fn dr7_bits(&self, slot: usize) -> u64 {
+        let len = match self.len {
+            1 => 0b00,
+            2 => 0b01,
+            8 => 0b10,
+            _ => 0b11,
+        };
+        let mut dr7 = 0;
+        dr7.set_bit(slot * 2, true); // Ln
+        dr7.set_bits(16 + slot * 4..18 + slot * 4, self.kind as u64);
+        dr7.set_bits(18 + slot * 4..20 + slot * 4, len);
+        dr7
+    }
+}
+
+/// The DR7 bits of `slot`: its local and global enable bits, and its R/W
+/// and LEN fields.
+fn slot_dr7_mask(slot: usize) -> u64 {
+    0b11 << (slot * 2) | 0b1111 << (16 + slot * 4)
+}
+
+/// The INT3 instruction, patched into guest memory at software breakpoints.
+pub(crate) const INT3: u8 = 0xcc;
+
+/// The address of a software breakpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwBreakpointAddr {
+    /// A guest linear address, translated with the current guest page
+    /// tables when the breakpoint is inserted or removed.
+    Virt(GuestVirtAddr),
+    /// A guest physical address.
+    Phys(GuestPhysAddr),
+}
+
+/// A software breakpoint installed by the VMM in the guest, see
+/// [`VmxVcpu::insert_sw_breakpoint`](crate::VmxArchVCpu::insert_sw_breakpoint).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwBreakpoint {
+    /// The guest physical address of the instruction, where INT3 is patched.
+    pub gpa: GuestPhysAddr,
+    /// The original first byte of the instruction.
+    pub orig: u8,
+}
+
+/// The software breakpoints of the VMM known to a vCPU.
+#[derive(Debug, Default)]
+pub(crate) struct SwBreakpoints {
+    /// The original bytes, by guest physical address.
+    saved: BTreeMap<usize, u8>,
+    /// The guest RIP of the breakpoint hit last, whose original instruction
+    /// is executed alone when the guest resumes there.
+    pub step_over: Option<usize>,
+}
+
+impl SwBreakpoints {
+    pub fn is_empty(&self) -> bool {
+        self.saved.is_empty()
+    }
+
+    pub fn get(&self, gpa: GuestPhysAddr) -> Option<SwBreakpoint> {
+        let &orig = self.saved.get(&gpa.as_usize())?;
+        Some(SwBreakpoint { gpa, orig })
+    }
+
+    pub fn insert(&mut self, breakpoint: SwBreakpoint) -> AxResult {
+        if self.saved.contains_key(&breakpoint.gpa.as_usize()) {
+            return ax_err!(AlreadyExists, "software breakpoint already inserted");
+        }
+        self.saved
+            .insert(breakpoint.gpa.as_usize(), breakpoint.orig);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, gpa: GuestPhysAddr) -> AxResult<SwBreakpoint> {
+        match self.saved.remove(&gpa.as_usize()) {
+            Some(orig) => Ok(SwBreakpoint { gpa, orig }),
+            None => ax_err!(NotFound, "no software breakpoint at this address"),
+        }
+    }
+}
+
+/// The guest debug registers, and the hardware breakpoints of the VMM.
+///
+/// The guest owns the debug registers until the VMM installs a breakpoint.
+/// Then DR0 to DR3 and DR7 hold the breakpoints of the VMM while the guest
+/// runs, #DB is intercepted, and guest accesses to the debug registers are
+/// emulated on the values kept here. Guest breakpoints stay armed in the
+/// slots the VMM does not use, and their hits are reflected to the guest.
+/// Those in the slots of the VMM are not armed until it removes its
+/// breakpoint, see [`DebugRegs::conflicts`].
+///
+/// While the guest owns them, DR0 to DR3 are switched lazily: MOV-DR exiting
+/// stays on until the guest accesses a debug register or enables a
+/// breakpoint in DR7. DR6 is always switched, as the processor updates it
+/// on debug exceptions delivered to the guest.
+#[derive(Debug)]
+pub(crate) struct DebugRegs {
+    /// Guest DR0 to DR3.
+    pub dr: [u64; 4],
+    /// Guest DR6.
+    pub dr6: u64,
+    /// Guest DR7 while the VMM owns the debug registers. The VMCS holds it
+    /// otherwise.
+    pub dr7: u64,
+    breakpoints: [Option<HwBreakpoint>; HW_BREAKPOINT_SLOTS],
+    /// Whether the guest DR0 to DR3 are loaded while the guest runs.
+    guest_loaded: bool,
+}
+
+impl DebugRegs {
+    pub fn new() -> Self {
+        Self {
+            dr: [0; 4],
+            dr6: DR6_INIT,
+            dr7: DR7_INIT,
+            breakpoints: [None; HW_BREAKPOINT_SLOTS],
+            guest_loaded: false,
+        }
+    }
+
+    /// Whether the VMM has installed a hardware breakpoint.
+    pub fn vmm_owned(&self) -> bool {
+        self.breakpoints.iter().any(Option::is_some)
+    }
+
+    pub fn breakpoint(&self, slot: usize) -> Option<HwBreakpoint> {
+        self.breakpoints[slot]
+    }
+
+    pub fn set_breakpoint(&mut self, slot: usize, breakpoint: Option<HwBreakpoint>) {
+        self.breakpoints[slot] = breakpoint;
+    }
+
+    /// The slots of the breakpoints enabled in the guest DR7, one bit per
+    /// slot.
+    fn guest_enabled(&self) -> u8 {
+        (0..HW_BREAKPOINT_SLOTS)
+            .filter(|&slot| self.dr7.get_bits(slot * 2..slot * 2 + 2) != 0)
+            .fold(0, |slots, slot| slots | 1 << slot)
+    }
+
+    /// The slots of the VMM breakpoints, one bit per slot.
+    fn vmm_slots(&self) -> u8 {
+        (0..HW_BREAKPOINT_SLOTS)
+            .filter(|&slot| self.breakpoints[slot].is_some())
+            .fold(0, |slots, slot| slots | 1 << slot)
+    }
+
+    /// The slots where a breakpoint enabled by the guest is not armed, as
+    /// the VMM uses them, one bit per slot.
+    pub fn conflicts(&self) -> u8 {
+        if self.vmm_owned() {
+            self.guest_enabled() & self.vmm_slots()
+        } else {
+            0
+        }
+    }
+
+    /// The DR7 value arming the breakpoints of the VMM, and those of the
+    /// guest in the other slots.
+    pub fn vmm_dr7(&self) -> u64 {
+        let guest_dr7 = (0..HW_BREAKPOINT_SLOTS)
+            .filter(|&slot| self.breakpoints[slot].is_none())
+            .fold(0, |dr7, slot| dr7 | self.dr7 & slot_dr7_mask(slot));
+        self.breakpoints
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, bp)| bp.map(|bp| bp.dr7_bits(slot)))
+            .fold(DR7_INIT | guest_dr7, |dr7, bits| dr7 | bits)
+    }
+
+    /// The slots of the VMM breakpoints hit, one bit per slot, from the B0
+    /// to B3 bits of a #DB exit qualification.
+    pub fn vmm_hits(&self, qualification: u64) -> u8 {
+        qualification.get_bits(0..HW_BREAKPOINT_SLOTS) as u8 & self.vmm_slots()
+    }
+
+    /// The slots of the guest breakpoints hit while the VMM owns the debug
+    /// registers, one bit per slot, from the B0 to B3 bits of a #DB exit
+    /// qualification.
+    pub fn guest_hits(&self, qualification: u64) -> u8 {
+        qualification.get_bits(0..HW_BREAKPOINT_SLOTS) as u8
+            & self.guest_enabled()
+            & !self.vmm_slots()
+    }
+
+    /// Whether one of `hits` is an execution breakpoint, which would hit
+    /// again when the guest resumes unless RFLAGS.RF is set.
+    pub fn hits_execute(&self, hits: u8) -> bool {
+        (0..HW_BREAKPOINT_SLOTS).any(|slot| {
+            hits.get_bit(slot)
+                && self.breakpoints[slot].is_some_and(|bp| bp.kind == HwBreakpointKind::Execute)
+        })
+    }
+
+    /// Let the guest access its debug registers without VM exits, from the
+    /// next VM entry.
+    pub fn set_guest_loaded(&mut self) {
+        self.guest_loaded = true;
+    }
+
+    /// Load the debug registers for the guest, given the guest DR7 in the
+    /// VMCS. Returns whether MOV-DR exiting is required.
+    pub fn switch_to_guest(&mut self, vmcs_dr7: u64) -> bool {
+        use x86::debugregs::{Dr6, dr0_write, dr1_write, dr2_write, dr3_write, dr6_write};
+
+        let dr = if self.vmm_owned() {
+            let mut dr = self.dr;
+            for (slot, bp) in self.breakpoints.iter().enumerate() {
+                if let Some(bp) = bp {
+                    dr[slot] = bp.addr;
+                }
+            }
+            Some(dr)
+        } else {
+            self.guest_loaded |= vmcs_dr7 & DR7_ENABLE_MASK != 0;
+            self.guest_loaded.then_some(self.dr)
+        };
+        // SAFETY: the host does not use the debug registers, and its DR7 is
+        // cleared on VM exits.
+        unsafe {
+            if let Some(dr) = dr {
+                dr0_write(dr[0] as _);
+                dr1_write(dr[1] as _);
+                dr2_write(dr[2] as _);
+                dr3_write(dr[3] as _);
+            }
+            dr6_write(Dr6::from_bits_truncate(self.dr6 as _));
+        }
+        self.vmm_owned() || !self.guest_loaded
+    }
+
+    /// Save the guest debug registers after a VM exit, given the guest DR7
+    /// in the VMCS.
+    pub fn switch_to_host(&mut self, vmcs_dr7: u64) {
+        use x86::debugregs::{dr0, dr1, dr2, dr3, dr6};
+
+        // SAFETY: reading the debug registers has no side effects.
+        unsafe {
+            if !self.vmm_owned() && self.guest_loaded {
+                self.dr = [dr0(), dr1(), dr2(), dr3()].map(|dr| dr as u64);
+                // Trap accesses again while no guest breakpoint is enabled.
+                self.guest_loaded = vmcs_dr7 & DR7_ENABLE_MASK != 0;
+            }
+            self.dr6 = dr6().bits() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breakpoint_check() {
+        let bp = |addr, kind, len| HwBreakpoint { addr, kind, len };
+        assert!(bp(0x1001, HwBreakpointKind::Execute, 1).check().is_ok());
+        assert!(bp(0x1000, HwBreakpointKind::Execute, 4).check().is_err());
+        assert!(bp(0x1008, HwBreakpointKind::Write, 8).check().is_ok());
+        assert!(bp(0x1004, HwBreakpointKind::ReadWrite, 8).check().is_err());
+        assert!(bp(0x1000, HwBreakpointKind::Write, 3).check().is_err());
+    }
+
+    #[test]
+    fn test_vmm_dr7() {
+        let mut regs = DebugRegs::new();
+        assert!(!regs.vmm_owned());
+        assert_eq!(regs.vmm_dr7(), DR7_INIT);
+
+        regs.set_breakpoint(
+            0,
+            Some(HwBreakpoint {
+                addr: 0xffff_8000_0000_1000,
+                kind: HwBreakpointKind::Execute,
+                len: 1,
+            }),
+        );
+        regs.set_breakpoint(
+            2,
+            Some(HwBreakpoint {
+                addr: 0x2000,
+                kind: HwBreakpointKind::Write,
+                len: 8,
+            }),
+        );
+        assert!(regs.vmm_owned());
+        // L0, L2, R/W2 = 01 and LEN2 = 10.
+        assert_eq!(regs.vmm_dr7(), 0x400 | 0b1 | 0b1 << 4 | 0b1001 << 24);
+
+        // B1 is set for a slot without a breakpoint.
+        assert_eq!(regs.vmm_hits(0b0111 | 1 << 14), 0b101);
+        assert!(regs.hits_execute(0b001));
+        assert!(!regs.hits_execute(0b100));
+
+        // The guest enables G1 with R/W1 = 01, and L2 taken by the VMM.
+        regs.dr7 = DR7_INIT | 0b10 << 2 | 0b1 << 20 | 0b1 << 4 | 0b1101 << 24;
+        assert_eq!(regs.conflicts(), 0b100);
+        assert_eq!(
+            regs.vmm_dr7(),
+            0x400 | 0b1 | 0b10 << 2 | 0b1 << 4 | 0b1 << 20 | 0b1001 << 24
+        );
+        assert_eq!(regs.guest_hits(0b0111), 0b010);
+        regs.dr7 = DR7_INIT;
+        assert_eq!(regs.conflicts(), 0);
+
+        regs.set_breakpoint(0, None);
+        regs.set_breakpoint(2, None);
+        assert!(!regs.vmm_owned());
+    }
+
+    #[test]
+    fn test_sw_breakpoints() {
+        let gpa = GuestPhysAddr::from_usize(0x10_0040);
+        let mut bps = SwBreakpoints::default();
+        assert!(bps.is_empty());
+        bps.insert(SwBreakpoint { gpa, orig: 0x55 }).unwrap();
+        assert!(bps.insert(SwBreakpoint { gpa, orig: INT3 }).is_err());
+        assert_eq!(bps.get(gpa), Some(SwBreakpoint { gpa, orig: 0x55 }));
+        assert_eq!(bps.get(gpa + 1), None);
+        assert_eq!(bps.remove(gpa).unwrap().orig, 0x55);
+        assert!(bps.remove(gpa).is_err());
+        assert!(bps.is_empty());
+    }
+}
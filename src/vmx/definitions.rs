@@ -249,6 +249,7 @@ pub enum VmxInterruptionType {
 impl VmxInterruptionType {
     /// Whether the exception/interrupt with `vector` has an error code.
     pub const fn vector_has_error_code(vector: u8) -> bool {
+        use crate::exception::CONTROL_PROTECTION_VECTOR;
         use x86::irq::*;
         matches!(
             vector,
@@ -259,6 +260,7 @@ impl VmxInterruptionType {
                 | GENERAL_PROTECTION_FAULT_VECTOR
                 | PAGE_FAULT_VECTOR
                 | ALIGNMENT_CHECK_VECTOR
+                | CONTROL_PROTECTION_VECTOR
         )
     }
 
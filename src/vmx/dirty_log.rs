@@ -0,0 +1,420 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::collections::BTreeSet;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use axaddrspace::HostPhysAddr;
+use axerrno::{AxResult, ax_err};
+use axvisor_api::memory::PhysFrame;
+use spin::Mutex;
+
+/// The number of GPAs in the page-modification log. (SDM Vol. 3C, Section
+/// 29.3.6)
+pub const PML_ENTRIES: usize = 512;
+
+/// How guest writes are tracked for dirty page logging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirtyLogMode {
+    /// The processor logs the GPAs written by the guest in the
+    /// page-modification log, when EPT dirty flags are set.
+    Pml,
+    /// The EPT is write-protected, and the first write to each page causes an
+    /// EPT violation which logs it.
+    WriteProtect,
+}
+
+/// The dirty page logging state of a VM, shared by its vCPUs, which share
+/// the EPT.
+///
+/// Each vCPU logs writes once given it with
+/// [`VmxVcpu::enable_dirty_log`](crate::VmxArchVCpu::enable_dirty_log), in the
+/// mode chosen by the first one. In [`DirtyLogMode::WriteProtect`] mode, the
+/// EPT is write-protected exactly while all the vCPUs log writes, as the
+/// others could not handle the write-protection faults. Until then, the
+/// writes of the other vCPUs are not seen, so all pages are reported dirty
+/// until harvested once the EPT is write-protected.
+#[derive(Debug)]
+pub struct VmDirtyLog {
+    vcpu_num: usize,
+    state: Mutex<VmDirtyLogState>,
+}
+
+#[derive(Debug, Default)]
+struct VmDirtyLogState {
+    /// How guest writes are tracked, while any vCPU logs them.
+    mode: Option<DirtyLogMode>,
+    /// The number of vCPUs logging writes.
+    enabled: usize,
+    /// The 4-KiB pages logged and not harvested yet.
+    logged: BTreeSet<u64>,
+    /// The ranges whose pages are all dirty, as their writes may not have
+    /// been logged, and not harvested yet since writes are tracked.
+    unknown: Vec<Range<u64>>,
+}
+
+impl VmDirtyLogState {
+    /// Whether the EPT is write-protected for logging.
+    fn write_protected(&self, vcpu_num: usize) -> bool {
+        self.mode == Some(DirtyLogMode::WriteProtect) && self.enabled == vcpu_num
+    }
+
+    /// Whether the writes to the pages are logged again once they are
+    /// tracked, i.e., their EPT dirty flag cleared or write permission
+    /// removed.
+    fn tracked(&self, vcpu_num: usize) -> bool {
+        self.mode == Some(DirtyLogMode::Pml) || self.write_protected(vcpu_num)
+    }
+
+    /// Report all pages dirty if some vCPUs write without being seen, i.e.,
+    /// the EPT is not write-protected while a vCPU logs writes in
+    /// [`DirtyLogMode::WriteProtect`] mode.
+    fn check_unlogged(&mut self, vcpu_num: usize) {
+        if self.mode == Some(DirtyLogMode::WriteProtect)
+            && self.enabled != 0
+            && !self.write_protected(vcpu_num)
+        {
+            self.unknown = alloc::vec![0..u64::MAX];
+        }
+    }
+}
+
+impl VmDirtyLog {
+    /// Create the dirty page logging state for the `vcpu_num` vCPUs of a VM.
+    pub fn new(vcpu_num: usize) -> Self {
+        Self {
+            vcpu_num,
+            state: Mutex::new(VmDirtyLogState::default()),
+        }
+    }
+
+    /// How guest writes are tracked, or `None` if no vCPU logs them.
+    pub fn mode(&self) -> Option<DirtyLogMode> {
+        self.state.lock().mode
+    }
+
+    /// Whether the EPT is write-protected, i.e., all vCPUs log writes in
+    /// [`DirtyLogMode::WriteProtect`] mode.
+    pub fn is_write_protected(&self) -> bool {
+        self.state.lock().write_protected(self.vcpu_num)
+    }
+
+    /// Start logging writes on a vCPU, in the mode of the VM if any vCPU
+    /// already logs them, and in `preferred` otherwise. Returns the mode
+    /// used. Runs `protect` if the EPT is to be write-protected now that all
+    /// vCPUs log writes, and otherwise reports all pages dirty until then.
+    pub(crate) fn attach(
+        &self,
+        preferred: DirtyLogMode,
+        protect: impl FnOnce(),
+    ) -> AxResult<DirtyLogMode> {
+        let mut state = self.state.lock();
+        if state.enabled == self.vcpu_num {
+            return ax_err!(BadState, "dirty page logging enabled on too many vCPUs");
+        }
+        let mode = *state.mode.get_or_insert(preferred);
+        if mode == DirtyLogMode::Pml && preferred != DirtyLogMode::Pml {
+            return ax_err!(Unsupported, "page-modification logging is not available");
+        }
+        state.enabled += 1;
+        if state.write_protected(self.vcpu_num) {
+            protect();
+        }
+        state.check_unlogged(self.vcpu_num);
+        Ok(mode)
+    }
+
+    /// Stop logging writes on a vCPU. Runs `unprotect` if the EPT was
+    /// write-protected, reporting all pages dirty from then on, and discards
+    /// the pages not harvested yet once no vCPU logs writes anymore.
+    pub(crate) fn detach(&self, unprotect: impl FnOnce()) {
+        let mut state = self.state.lock();
+        if state.write_protected(self.vcpu_num) {
+            unprotect();
+        }
+        state.enabled -= 1;
+        if state.enabled == 0 {
+            *state = VmDirtyLogState::default();
+        }
+        state.check_unlogged(self.vcpu_num);
+    }
+
+    /// Log the 4-KiB pages of `range`.
+    pub(crate) fn log(&self, range: Range<u64>) {
+        self.state
+            .lock()
+            .logged
+            .extend((range.start & !0xfff..range.end).step_by(0x1000));
+    }
+
+    /// Log the 4-KiB pages of `range`, whose EPT mappings changed, and run
+    /// `track` on it like [`VmDirtyLog::harvest`].
+    pub(crate) fn log_remapped(&self, range: Range<u64>, track: impl FnOnce(Range<u64>)) {
+        let mut state = self.state.lock();
+        state
+            .logged
+            .extend((range.start & !0xfff..range.end).step_by(0x1000));
+        if state.tracked(self.vcpu_num) {
+            track(range);
+        }
+    }
+
+    /// Take the logged pages from `start` that `bitmap` covers, bit `i`
+    /// standing for the page at `start + i * 4 KiB`, and set their bits.
+    /// Runs `track` on each page taken, so that its next write is logged,
+    /// unless writes are no longer tracked. The pages whose writes may not
+    /// have been logged are all taken too, and are only reported again once
+    /// writes are tracked. Returns the number of pages taken.
+    pub(crate) fn harvest(
+        &self,
+        start: u64,
+        bitmap: &mut [u64],
+        mut track: impl FnMut(u64),
+    ) -> usize {
+        let mut state = self.state.lock();
+        let start = start & !0xfff;
+        let end = start + bitmap.len() as u64 * 64 * 0x1000;
+        let pages: Vec<u64> = state.logged.range(start..end).copied().collect();
+        let tracked = state.tracked(self.vcpu_num);
+        let mut count = 0;
+        for &page in &pages {
+            state.logged.remove(&page);
+            let bit = ((page - start) >> 12) as usize;
+            bitmap[bit / 64] |= 1 << (bit % 64);
+            if tracked {
+                track(page);
+            }
+            if !state.unknown.iter().any(|range| range.contains(&page)) {
+                count += 1;
+            }
+        }
+        let mut unknown = Vec::new();
+        for range in core::mem::take(&mut state.unknown) {
+            let first = range.start.max(start);
+            let last = range.end.min(end);
+            for page in (first..last).step_by(0x1000) {
+                let bit = ((page - start) >> 12) as usize;
+                bitmap[bit / 64] |= 1 << (bit % 64);
+                count += 1;
+            }
+            // Once writes are tracked, the pages taken are dirty only if
+            // logged again.
+            if !tracked || first >= last {
+                unknown.push(range);
+                continue;
+            }
+            unknown.extend(
+                [range.start..first, last..range.end]
+                    .into_iter()
+                    .filter(|range| !range.is_empty()),
+            );
+        }
+        state.unknown = unknown;
+        count
+    }
+}
+
+/// The dirty page logging state of a vCPU.
+#[derive(Debug)]
+pub struct DirtyLog {
+    mode: DirtyLogMode,
+    /// The page-modification log, in PML mode.
+    pml_page: Option<PhysFrame>,
+    /// The logging state of the VM.
+    vm: Arc<VmDirtyLog>,
+}
+
+impl DirtyLog {
+    /// Create the logging state for `mode`, allocating the log page for PML.
+    pub fn new(mode: DirtyLogMode, vm: Arc<VmDirtyLog>) -> AxResult<Self> {
+        let pml_page = match mode {
+            DirtyLogMode::Pml => Some(PhysFrame::alloc_zero()?),
+            DirtyLogMode::WriteProtect => None,
+        };
+        Ok(Self { mode, pml_page, vm })
+    }
+
+    /// How guest writes are tracked.
+    pub fn mode(&self) -> DirtyLogMode {
+        self.mode
+    }
+
+    /// The logging state of the VM.
+    pub fn vm(&self) -> &VmDirtyLog {
+        &self.vm
+    }
+
+    /// The physical address of the page-modification log, in PML mode.
+    pub fn pml_addr(&self) -> Option<HostPhysAddr> {
+        self.pml_page.as_ref().map(PhysFrame::start_paddr)
+    }
+
+    /// Move the GPAs in the page-modification log into the logged pages, given
+    /// the current PML index. The index must then be reset to
+    /// `PML_ENTRIES - 1`.
+    pub fn drain_pml(&mut self, pml_index: u16) {
+        if let Some(page) = &self.pml_page {
+            // SAFETY: the frame holds `PML_ENTRIES` GPAs, and the processor
+            // does not write to it outside VMX non-root operation.
+            let entries = unsafe {
+                core::slice::from_raw_parts(page.as_mut_ptr() as *const u64, PML_ENTRIES)
+            };
+            Self::drain_entries(&mut self.vm.state.lock().logged, entries, pml_index);
+        }
+    }
+
+    /// The processor writes entries from the last one down, decrementing the
+    /// index, which wraps to 0xFFFF when the log is full.
+    fn drain_entries(logged: &mut BTreeSet<u64>, entries: &[u64], pml_index: u16) {
+        let first = match pml_index as usize {
+            index if index < PML_ENTRIES => index + 1,
+            _ => 0,
+        };
+        logged.extend(entries[first..].iter().map(|gpa| gpa & !0xfff));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    #[test]
+    fn test_drain_pml_entries() {
+        let mut entries = [0u64; PML_ENTRIES];
+        entries[511] = 0x1234;
+        entries[510] = 0x5000;
+        let mut logged = BTreeSet::new();
+        // Two entries written, the index points at the next free one.
+        DirtyLog::drain_entries(&mut logged, &entries, 509);
+        assert_eq!(logged.iter().copied().collect::<Vec<_>>(), [0x1000, 0x5000]);
+
+        // Empty, and full logs.
+        let mut logged = BTreeSet::new();
+        DirtyLog::drain_entries(&mut logged, &entries, 511);
+        assert!(logged.is_empty());
+        DirtyLog::drain_entries(&mut logged, &entries, 0xffff);
+        assert_eq!(logged.len(), 3);
+    }
+
+    #[test]
+    fn test_harvest() {
+        let log = VmDirtyLog::new(1);
+        assert_eq!(log.attach(DirtyLogMode::Pml, || {}), Ok(DirtyLogMode::Pml));
+        log.log(0x1800..0x3000);
+        log.log(0x20_0000..0x20_1000);
+        log.log(0x100_0000..0x100_1000);
+
+        let mut bitmap = [0u64; 8];
+        let mut tracked = Vec::new();
+        assert_eq!(
+            log.harvest(0x1000, &mut bitmap, |page| tracked.push(page)),
+            3
+        );
+        assert_eq!(tracked, [0x1000, 0x2000, 0x20_0000]);
+        assert_eq!(bitmap[0], 0b11);
+        assert_eq!(bitmap[7], 1 << 63);
+
+        // Harvested pages are gone, the others are kept.
+        let mut bitmap = [0u64; 8];
+        assert_eq!(log.harvest(0x1000, &mut bitmap, |_| {}), 0);
+        assert_eq!(log.harvest(0x100_0000, &mut bitmap, |_| {}), 1);
+        assert_eq!(bitmap[0], 1);
+
+        // Everything is discarded once no vCPU logs writes.
+        log.log(0x1000..0x2000);
+        log.detach(|| panic!("not write-protected"));
+        assert_eq!(log.mode(), None);
+        assert_eq!(log.harvest(0x1000, &mut bitmap, |_| {}), 0);
+    }
+
+    #[test]
+    fn test_write_protect() {
+        let log = VmDirtyLog::new(2);
+        let protected = Cell::new(false);
+        let protect = || {
+            assert!(!protected.replace(true));
+        };
+        let unprotect = || {
+            assert!(protected.replace(false));
+        };
+
+        // The EPT is only write-protected once all vCPUs log writes.
+        assert_eq!(
+            log.attach(DirtyLogMode::WriteProtect, protect),
+            Ok(DirtyLogMode::WriteProtect)
+        );
+        assert!(!protected.get() && !log.is_write_protected());
+        // The writes of the other vCPU are not seen, all pages are dirty.
+        let mut bitmap = [0u64; 1];
+        assert_eq!(log.harvest(0, &mut bitmap, |_| panic!("not tracked")), 64);
+        assert_eq!(bitmap[0], u64::MAX);
+        let mut bitmap = [0u64; 1];
+        assert_eq!(log.harvest(0, &mut bitmap, |_| {}), 64);
+        // In the mode of the VM.
+        assert_eq!(
+            log.attach(DirtyLogMode::Pml, protect),
+            Ok(DirtyLogMode::WriteProtect)
+        );
+        assert!(protected.get() && log.is_write_protected());
+        assert!(log.attach(DirtyLogMode::WriteProtect, protect).is_err());
+
+        // All pages are reported once more, as they could have been written
+        // before the EPT was write-protected.
+        log.log(0x2000..0x3000);
+        let mut bitmap = [0u64; 1];
+        let mut tracked = Vec::new();
+        assert_eq!(
+            log.harvest(0x1000, &mut bitmap, |page| tracked.push(page)),
+            64
+        );
+        assert_eq!((bitmap[0], tracked), (u64::MAX, alloc::vec![0x2000]));
+        let mut bitmap = [0u64; 1];
+        assert_eq!(log.harvest(0x1000, &mut bitmap, |_| {}), 0);
+        assert_eq!(log.harvest(0x40_0000, &mut bitmap, |_| {}), 64);
+        assert_eq!(log.harvest(0, &mut bitmap, |_| {}), 1);
+
+        // Remapped pages are logged, and protected again.
+        let mut remapped = None;
+        log.log_remapped(0x3000..0x5000, |range| remapped = Some(range));
+        assert_eq!(remapped, Some(0x3000..0x5000));
+        let mut bitmap = [0u64; 1];
+        let mut tracked = Vec::new();
+        assert_eq!(log.harvest(0, &mut bitmap, |page| tracked.push(page)), 2);
+        assert_eq!(tracked, [0x3000, 0x4000]);
+
+        // Protection is removed as soon as one vCPU stops, and harvested
+        // pages are no longer tracked but all dirty again.
+        log.detach(unprotect);
+        assert!(!protected.get() && !log.is_write_protected());
+        log.log(0x1000..0x2000);
+        assert_eq!(log.harvest(0, &mut bitmap, |_| panic!("not tracked")), 64);
+        log.log_remapped(0x3000..0x4000, |_| panic!("not write-protected"));
+        log.detach(unprotect);
+
+        // PML is not used by a VM without it.
+        assert_eq!(
+            log.attach(DirtyLogMode::WriteProtect, protect),
+            Ok(DirtyLogMode::WriteProtect)
+        );
+        log.detach(unprotect);
+        assert_eq!(
+            log.attach(DirtyLogMode::Pml, protect),
+            Ok(DirtyLogMode::Pml)
+        );
+        assert!(log.attach(DirtyLogMode::WriteProtect, protect).is_err());
+    }
+}
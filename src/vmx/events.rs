@@ -0,0 +1,611 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bit_field::BitField;
+
+use super::state::VcpuEvents;
+use super::vmcs::{InterruptibilityState, VmxInterruptInfo};
+use crate::exception::{ExceptionMerge, GuestException};
+
+/// An event to be injected into the guest on a VM entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmxEvent {
+    /// A hardware or software exception.
+    Exception(GuestException),
+    /// An event whose delivery was interrupted by a VM exit, injected again
+    /// as-is. (SDM Vol. 3C, Section 28.2.4)
+    Reinject(VmxInterruptInfo),
+    /// A non-maskable interrupt.
+    Nmi,
+    /// An external interrupt with the given vector.
+    Interrupt(u8),
+}
+
+/// How an exception raised in the guest is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionPolicy {
+    /// Delivered through the guest IDT, without a VM exit.
+    Deliver,
+    /// Intercepted, and reflected back to the guest by the vCPU itself.
+    Reflect,
+    /// Intercepted, and reported to the VMM as an exit event. The exception
+    /// is dropped unless the VMM injects it again.
+    Exit,
+}
+
+/// Per-vector exception policies, from which the exception bitmap is built,
+/// and the page-fault error-code filter. (SDM Vol. 3C, Section 25.6.3)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionIntercepts {
+    /// Intercepted vectors, i.e., the exception bitmap.
+    intercepted: u32,
+    /// Intercepted vectors that are reflected back to the guest.
+    reflected: u32,
+    /// Page-fault error-code mask.
+    pf_mask: u32,
+    /// Page-fault error-code match.
+    pf_match: u32,
+}
+
+impl ExceptionIntercepts {
+    /// Only #UD is intercepted, and reflected.
+    pub const fn new() -> Self {
+        const UD: u32 = 1 << x86::irq::INVALID_OPCODE_VECTOR;
+        Self {
+            intercepted: UD,
+            reflected: UD,
+            pf_mask: 0,
+            pf_match: 0,
+        }
+    }
+
+    /// Whether `vector` can be intercepted through the exception bitmap.
+    /// NMIs are controlled by NMI exiting instead.
+    pub const fn is_valid_vector(vector: u8) -> bool {
+        vector < 32 && vector != x86::irq::NONMASKABLE_INTERRUPT_VECTOR
+    }
+
+    /// The policy of `vector`.
+    pub fn policy(&self, vector: u8) -> ExceptionPolicy {
+        if !Self::is_valid_vector(vector) || !self.intercepted.get_bit(vector as usize) {
+            ExceptionPolicy::Deliver
+        } else if self.reflected.get_bit(vector as usize) {
+            ExceptionPolicy::Reflect
+        } else {
+            ExceptionPolicy::Exit
+        }
+    }
+
+    /// Set the policy of `vector`, which must be a valid vector.
+    pub fn set_policy(&mut self, vector: u8, policy: ExceptionPolicy) {
+        debug_assert!(Self::is_valid_vector(vector));
+        let bit = vector as usize;
+        self.intercepted
+            .set_bit(bit, policy != ExceptionPolicy::Deliver);
+        self.reflected
+            .set_bit(bit, policy == ExceptionPolicy::Reflect);
+    }
+
+    /// The exception bitmap to write into the VMCS.
+    pub fn bitmap(&self) -> u32 {
+        self.intercepted
+    }
+
+    /// Intercept exactly the vectors set in `bitmap`, which must not contain
+    /// NMI. Newly intercepted vectors get [`ExceptionPolicy::Exit`], and
+    /// already intercepted ones keep their policy.
+    pub fn set_bitmap(&mut self, bitmap: u32) {
+        debug_assert!(!bitmap.get_bit(x86::irq::NONMASKABLE_INTERRUPT_VECTOR as usize));
+        self.intercepted = bitmap;
+        self.reflected &= bitmap;
+    }
+
+    /// The page-fault error-code mask and match.
+    pub fn page_fault_filter(&self) -> (u32, u32) {
+        (self.pf_mask, self.pf_match)
+    }
+
+    /// Set the page-fault error-code mask and match, which select the
+    /// intercepted page faults while #PF is intercepted.
+    pub fn set_page_fault_filter(&mut self, mask: u32, match_: u32) {
+        self.pf_mask = mask;
+        self.pf_match = match_;
+    }
+
+    /// The page-fault error-code mask and match to write into the VMCS.
+    ///
+    /// With bit 14 of the bitmap clear, the processor intercepts the page
+    /// faults whose masked error code differs from the match, so the filter
+    /// is cleared while #PF is not intercepted: the policy of #PF then says
+    /// [`ExceptionPolicy::Deliver`] for all of them. (SDM Vol. 3C, Section 26.2)
+    pub fn vmcs_page_fault_filter(&self) -> (u32, u32) {
+        if self
+            .intercepted
+            .get_bit(x86::irq::PAGE_FAULT_VECTOR as usize)
+        {
+            (self.pf_mask, self.pf_match)
+        } else {
+            (0, 0)
+        }
+    }
+
+    /// Whether a page fault with `err_code` causes a VM exit: #PF must be
+    /// intercepted, and the masked error code equal the match.
+    pub fn intercepts_page_fault(&self, err_code: u32) -> bool {
+        self.intercepted
+            .get_bit(x86::irq::PAGE_FAULT_VECTOR as usize)
+            && err_code & self.pf_mask == self.pf_match
+    }
+}
+
+impl Default for ExceptionIntercepts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What to do on the next VM entry, as decided by [`PendingEvents::plan`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InjectionPlan {
+    /// The event to inject, if any.
+    pub event: Option<VmxEvent>,
+    /// The length of the instruction which raised the event, for the
+    /// VM-entry instruction-length field if it is a software one.
+    pub instr_len: u32,
+    /// Whether interrupt-window exiting is needed, as external interrupts
+    /// are still pending.
+    pub interrupt_window: bool,
+    /// Whether NMI-window exiting is needed, as an NMI is still pending.
+    pub nmi_window: bool,
+}
+
+/// Whether the guest accepts NMIs and external interrupts right now.
+/// (SDM Vol. 3C, Section 26.3.1.5)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventBlocking {
+    /// NMIs can be injected: no blocking by STI, MOV SS or NMI.
+    pub nmi_allowed: bool,
+    /// External interrupts can be injected: `RFLAGS.IF` is set and there is
+    /// no blocking by STI or MOV SS.
+    pub interrupt_allowed: bool,
+}
+
+impl EventBlocking {
+    /// Compute the blocking state from `RFLAGS.IF` and the guest
+    /// interruptibility state.
+    pub fn new(interrupt_flag: bool, state: InterruptibilityState) -> Self {
+        let shadow = state.intersects(
+            InterruptibilityState::BLOCKING_BY_STI | InterruptibilityState::BLOCKING_BY_MOV_SS,
+        );
+        Self {
+            nmi_allowed: !shadow && !state.contains(InterruptibilityState::BLOCKING_BY_NMI),
+            interrupt_allowed: interrupt_flag && !shadow,
+        }
+    }
+}
+
+/// Events pending injection into a vCPU, kept per class.
+///
+/// Events are injected one per VM entry, exceptions first, then events
+/// interrupted by the last VM exit, then NMIs, then external interrupts from
+/// the highest vector down. (SDM Vol. 3A, Section 6.9)
+#[derive(Debug, Default)]
+pub struct PendingEvents {
+    /// The pending exception, merged with any exception raised after it.
+    exception: Option<GuestException>,
+    /// The length of the instruction which raised the pending exception.
+    exception_len: u32,
+    /// The exception that ended exception merging in a triple fault.
+    triple_fault: Option<GuestException>,
+    /// A non-exception event whose delivery was interrupted by a VM exit.
+    reinject: Option<VmxInterruptInfo>,
+    /// The length of the instruction which raised the interrupted event.
+    reinject_len: u32,
+    /// Whether an NMI is pending. NMIs raised while one is pending are merged.
+    nmi: bool,
+    /// Pending external interrupts, one bit per vector.
+    interrupts: [u64; 4],
+}
+
+impl PendingEvents {
+    /// Create an empty set of pending events.
+    pub const fn new() -> Self {
+        Self {
+            exception: None,
+            exception_len: 0,
+            triple_fault: None,
+            reinject: None,
+            reinject_len: 0,
+            nmi: false,
+            interrupts: [0; 4],
+        }
+    }
+
+    /// Raise an exception, merging it with the pending one if any.
+    /// (SDM Vol. 3A, Section 6.15)
+    ///
+    /// `instr_len` is the length of the instruction which raised a software
+    /// exception (#BP or #OF), and is ignored for the other exceptions.
+    pub fn raise_exception(&mut self, exception: GuestException, instr_len: u32) -> ExceptionMerge {
+        let merged = match self.exception.take() {
+            Some(first) => first.merge(exception),
+            None => ExceptionMerge::Serial(exception),
+        };
+        match merged {
+            ExceptionMerge::Serial(exception) => {
+                self.exception = Some(exception);
+                self.exception_len = instr_len;
+            }
+            ExceptionMerge::DoubleFault => {
+                self.exception = Some(GuestException::DoubleFault);
+                self.exception_len = 0;
+            }
+            ExceptionMerge::TripleFault => self.triple_fault = Some(exception),
+        }
+        merged
+    }
+
+    /// The pending exception, if any.
+    pub fn exception(&self) -> Option<GuestException> {
+        self.exception
+    }
+
+    /// Return and clear the exception that caused a triple fault, if any.
+    pub fn take_triple_fault(&mut self) -> Option<GuestException> {
+        self.triple_fault.take()
+    }
+
+    /// Record an event interrupted by a VM exit, raised by an instruction of
+    /// `instr_len` bytes if it is a software one. Exceptions are kept as the
+    /// pending exception so that later exceptions can be merged with them.
+    pub fn set_vectoring(
+        &mut self,
+        info: VmxInterruptInfo,
+        exception: Option<GuestException>,
+        instr_len: u32,
+    ) {
+        match exception {
+            Some(exception) => {
+                self.exception = Some(exception);
+                self.exception_len = instr_len;
+            }
+            None => {
+                self.reinject = Some(info);
+                self.reinject_len = instr_len;
+            }
+        }
+    }
+
+    /// Mark an NMI pending.
+    pub fn queue_nmi(&mut self) {
+        self.nmi = true;
+    }
+
+    /// Mark the external interrupt `vector` pending.
+    pub fn queue_interrupt(&mut self, vector: u8) {
+        self.interrupts[vector as usize / 64].set_bit(vector as usize % 64, true);
+    }
+
+    /// Whether no event is pending.
+    pub fn is_empty(&self) -> bool {
+        self.exception.is_none()
+            && self.triple_fault.is_none()
+            && self.reinject.is_none()
+            && !self.nmi
+            && !self.has_interrupts()
+    }
+
+    /// Discard all pending events.
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Save the pending events, for a vCPU state snapshot.
+    pub fn save(&self) -> VcpuEvents {
+        VcpuEvents {
+            exception: self.exception,
+            exception_len: self.exception_len,
+            reinject: self.reinject,
+            reinject_len: self.reinject_len,
+            nmi: self.nmi,
+            interrupts: self.interrupts,
+        }
+    }
+
+    /// Replace the pending events with the saved `events`.
+    pub fn restore(&mut self, events: &VcpuEvents) {
+        *self = Self {
+            exception: events.exception,
+            exception_len: events.exception_len,
+            triple_fault: None,
+            reinject: events.reinject,
+            reinject_len: events.reinject_len,
+            nmi: events.nmi,
+            interrupts: events.interrupts,
+        };
+    }
+
+    fn has_interrupts(&self) -> bool {
+        self.interrupts.iter().any(|&bits| bits != 0)
+    }
+
+    fn pop_highest_interrupt(&mut self) -> Option<u8> {
+        let index = self.interrupts.iter().rposition(|&bits| bits != 0)?;
+        let bit = 63 - self.interrupts[index].leading_zeros() as usize;
+        self.interrupts[index].set_bit(bit, false);
+        Some((index * 64 + bit) as u8)
+    }
+
+    /// Pick the event to inject on the next VM entry, and tell which windows
+    /// must be opened for the events left pending.
+    pub fn plan(&mut self, blocking: EventBlocking) -> InjectionPlan {
+        let mut instr_len = 0;
+        let event = if let Some(exception) = self.exception.take() {
+            instr_len = core::mem::take(&mut self.exception_len);
+            Some(VmxEvent::Exception(exception))
+        } else if let Some(info) = self.reinject.take() {
+            instr_len = core::mem::take(&mut self.reinject_len);
+            Some(VmxEvent::Reinject(info))
+        } else if self.nmi && blocking.nmi_allowed {
+            self.nmi = false;
+            Some(VmxEvent::Nmi)
+        } else if blocking.interrupt_allowed {
+            self.pop_highest_interrupt().map(VmxEvent::Interrupt)
+        } else {
+            None
+        };
+        InjectionPlan {
+            event,
+            instr_len,
+            interrupt_window: self.has_interrupts(),
+            nmi_window: self.nmi,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vmx::definitions::VmxInterruptionType;
+
+    const OPEN: EventBlocking = EventBlocking {
+        nmi_allowed: true,
+        interrupt_allowed: true,
+    };
+    const STI_SHADOW: EventBlocking = EventBlocking {
+        nmi_allowed: false,
+        interrupt_allowed: false,
+    };
+
+    #[test]
+    fn test_blocking_from_state() {
+        let open = EventBlocking::new(true, InterruptibilityState::empty());
+        assert_eq!(open, OPEN);
+
+        let sti = EventBlocking::new(true, InterruptibilityState::BLOCKING_BY_STI);
+        assert_eq!(sti, STI_SHADOW);
+
+        let mov_ss = EventBlocking::new(true, InterruptibilityState::BLOCKING_BY_MOV_SS);
+        assert_eq!(mov_ss, STI_SHADOW);
+
+        let nmi = EventBlocking::new(true, InterruptibilityState::BLOCKING_BY_NMI);
+        assert!(!nmi.nmi_allowed);
+        assert!(nmi.interrupt_allowed);
+
+        let cli = EventBlocking::new(false, InterruptibilityState::empty());
+        assert!(cli.nmi_allowed);
+        assert!(!cli.interrupt_allowed);
+    }
+
+    #[test]
+    fn test_priority_order() {
+        let mut events = PendingEvents::new();
+        events.queue_interrupt(0x20);
+        events.queue_interrupt(0x80);
+        events.queue_nmi();
+        events.raise_exception(GuestException::InvalidOpcode, 0);
+
+        let order: [Option<VmxEvent>; 5] = core::array::from_fn(|_| events.plan(OPEN).event);
+        assert_eq!(
+            order,
+            [
+                Some(VmxEvent::Exception(GuestException::InvalidOpcode)),
+                Some(VmxEvent::Nmi),
+                Some(VmxEvent::Interrupt(0x80)),
+                Some(VmxEvent::Interrupt(0x20)),
+                None,
+            ]
+        );
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_sti_shadow_blocks_interrupts() {
+        let mut events = PendingEvents::new();
+        events.queue_interrupt(0x30);
+        events.queue_nmi();
+
+        let plan = events.plan(STI_SHADOW);
+        assert_eq!(plan.event, None);
+        assert!(plan.interrupt_window);
+        assert!(plan.nmi_window);
+
+        // Exceptions are not blocked by the shadow.
+        events.raise_exception(GuestException::GeneralProtection(0), 0);
+        let plan = events.plan(STI_SHADOW);
+        assert_eq!(
+            plan.event,
+            Some(VmxEvent::Exception(GuestException::GeneralProtection(0)))
+        );
+        assert!(plan.interrupt_window);
+        assert!(plan.nmi_window);
+    }
+
+    #[test]
+    fn test_window_closed_when_drained() {
+        let mut events = PendingEvents::new();
+        events.queue_interrupt(0x40);
+        let plan = events.plan(OPEN);
+        assert_eq!(plan.event, Some(VmxEvent::Interrupt(0x40)));
+        assert!(!plan.interrupt_window);
+        assert!(!plan.nmi_window);
+    }
+
+    #[test]
+    fn test_duplicate_interrupts_collapse() {
+        let mut events = PendingEvents::new();
+        events.queue_interrupt(0xff);
+        events.queue_interrupt(0xff);
+        assert_eq!(events.plan(OPEN).event, Some(VmxEvent::Interrupt(0xff)));
+        assert_eq!(events.plan(OPEN).event, None);
+    }
+
+    #[test]
+    fn test_reinject_before_nmi() {
+        let mut events = PendingEvents::new();
+        events.queue_nmi();
+        let info = VmxInterruptInfo::from(0x21, None);
+        events.set_vectoring(info, None, 0);
+        assert_eq!(events.plan(OPEN).event, Some(VmxEvent::Reinject(info)));
+        assert_eq!(events.plan(OPEN).event, Some(VmxEvent::Nmi));
+    }
+
+    #[test]
+    fn test_soft_event_length() {
+        let mut events = PendingEvents::new();
+        // An INT 0x80 interrupted by an EPT violation, then an INT3 raised
+        // while it is pending: each keeps the length of its instruction.
+        let info = VmxInterruptInfo {
+            vector: 0x80,
+            int_type: VmxInterruptionType::SoftIntr,
+            err_code: None,
+            valid: true,
+        };
+        events.set_vectoring(info, None, 2);
+        events.raise_exception(GuestException::Breakpoint, 1);
+        let plan = events.plan(OPEN);
+        assert_eq!(
+            plan.event,
+            Some(VmxEvent::Exception(GuestException::Breakpoint))
+        );
+        assert_eq!(plan.instr_len, 1);
+        let plan = events.plan(OPEN);
+        assert_eq!(plan.event, Some(VmxEvent::Reinject(info)));
+        assert_eq!(plan.instr_len, 2);
+        assert_eq!(events.plan(OPEN).instr_len, 0);
+    }
+
+    #[test]
+    fn test_exception_intercepts() {
+        let mut intercepts = ExceptionIntercepts::new();
+        assert_eq!(intercepts.bitmap(), 1 << 6);
+        assert_eq!(intercepts.policy(6), ExceptionPolicy::Reflect);
+        assert_eq!(intercepts.policy(3), ExceptionPolicy::Deliver);
+
+        intercepts.set_policy(3, ExceptionPolicy::Exit);
+        intercepts.set_policy(14, ExceptionPolicy::Reflect);
+        intercepts.set_policy(6, ExceptionPolicy::Deliver);
+        assert_eq!(intercepts.bitmap(), (1 << 3) | (1 << 14));
+        assert_eq!(intercepts.policy(3), ExceptionPolicy::Exit);
+        assert_eq!(intercepts.policy(14), ExceptionPolicy::Reflect);
+        assert_eq!(intercepts.policy(6), ExceptionPolicy::Deliver);
+
+        assert!(!ExceptionIntercepts::is_valid_vector(2));
+        assert!(!ExceptionIntercepts::is_valid_vector(32));
+        assert_eq!(intercepts.policy(2), ExceptionPolicy::Deliver);
+
+        intercepts.set_bitmap((1 << 1) | (1 << 14));
+        assert_eq!(intercepts.policy(1), ExceptionPolicy::Exit);
+        assert_eq!(intercepts.policy(3), ExceptionPolicy::Deliver);
+        assert_eq!(intercepts.policy(14), ExceptionPolicy::Reflect);
+    }
+
+    #[test]
+    fn test_page_fault_filter() {
+        const P: u32 = 1 << 0;
+        const W: u32 = 1 << 1;
+        const U: u32 = 1 << 2;
+        let mut intercepts = ExceptionIntercepts::new();
+        // Bit 14 clear with mask = match = 0: no page fault exits.
+        assert!(!intercepts.intercepts_page_fault(P | W));
+
+        // Bit 14 set: exit on user-mode writes only.
+        intercepts.set_policy(14, ExceptionPolicy::Exit);
+        intercepts.set_page_fault_filter(W | U, W | U);
+        assert_eq!(intercepts.page_fault_filter(), (W | U, W | U));
+        assert!(intercepts.intercepts_page_fault(P | W | U));
+        assert!(intercepts.intercepts_page_fault(W | U));
+        assert!(!intercepts.intercepts_page_fault(P | W));
+        assert!(!intercepts.intercepts_page_fault(U));
+
+        assert_eq!(intercepts.vmcs_page_fault_filter(), (W | U, W | U));
+
+        // Bit 14 clear: the filter does not match a kernel write, which the
+        // processor would intercept, so it is not written into the VMCS.
+        intercepts.set_policy(14, ExceptionPolicy::Deliver);
+        assert_eq!(intercepts.policy(14), ExceptionPolicy::Deliver);
+        assert_eq!(intercepts.page_fault_filter(), (W | U, W | U));
+        assert_eq!(intercepts.vmcs_page_fault_filter(), (0, 0));
+        assert!(!intercepts.intercepts_page_fault(W | U));
+        assert!(!intercepts.intercepts_page_fault(P | W));
+    }
+
+    #[test]
+    fn test_exception_merging() {
+        let mut events = PendingEvents::new();
+        let pf = GuestException::PageFault {
+            err_code: 0,
+            cr2: 0x1000,
+        };
+        events.set_vectoring(VmxInterruptInfo::from(14, Some(0)), Some(pf), 0);
+        assert_eq!(
+            events.raise_exception(GuestException::GeneralProtection(0), 0),
+            ExceptionMerge::DoubleFault
+        );
+        assert_eq!(events.exception(), Some(GuestException::DoubleFault));
+        assert_eq!(
+            events.raise_exception(GuestException::GeneralProtection(0), 0),
+            ExceptionMerge::TripleFault
+        );
+        assert_eq!(
+            events.take_triple_fault(),
+            Some(GuestException::GeneralProtection(0))
+        );
+        assert_eq!(events.take_triple_fault(), None);
+
+        events.queue_interrupt(0x20);
+        events.clear();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_save_restore() {
+        let mut events = PendingEvents::new();
+        events.raise_exception(GuestException::InvalidOpcode, 0);
+        events.queue_nmi();
+        events.queue_interrupt(0x20);
+        events.queue_interrupt(0xff);
+        let saved = events.save();
+        assert_eq!(saved.exception, Some(GuestException::InvalidOpcode));
+        assert_eq!(saved.interrupts[3], 1 << 63);
+
+        let mut restored = PendingEvents::new();
+        restored.restore(&saved);
+        assert_eq!(restored.save(), saved);
+        assert_eq!(
+            restored.plan(OPEN).event,
+            Some(VmxEvent::Exception(GuestException::InvalidOpcode))
+        );
+    }
+}
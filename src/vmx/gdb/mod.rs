@@ -0,0 +1,37 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A GDB remote serial protocol stub for debugging guests.
+//!
+//! [`GdbStub`] serves a GDB client over any byte stream implementing
+//! [`GdbConnection`], such as a UART or a pipe, for a [`GdbTarget`].
+//! [`VcpuSet`] is the target of a set of [`VmxArchVCpu`](crate::VmxArchVCpu)s,
+//! one thread per vCPU, with the registers described to GDB by an x86-64
+//! target description including the control registers and EFER.
+//!
+//! ```ignore
+//! let mut target = VcpuSet::new(&mut vcpus, |_, vcpu, exit| handle_exit(vcpu, exit));
+//! let end = GdbStub::new(uart).run(&mut target)?;
+//! target.clear_breakpoints()?;
+//! ```
+
+mod packet;
+mod regs;
+mod stub;
+mod vcpus;
+
+pub use self::packet::GdbConnection;
+pub use self::regs::GdbRegisters;
+pub use self::stub::{GdbResume, GdbSessionEnd, GdbStopReason, GdbStub, GdbTarget};
+pub use self::vcpus::VcpuSet;
@@ -0,0 +1,166 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::vec::Vec;
+
+use axerrno::AxResult;
+
+/// A byte stream to the GDB client, such as a UART or a pipe.
+pub trait GdbConnection {
+    /// Read a byte, waiting until one is available.
+    fn read(&mut self) -> AxResult<u8>;
+    /// Read a byte if one is available, without waiting. Used to catch
+    /// interrupt requests while the target runs.
+    fn try_read(&mut self) -> AxResult<Option<u8>>;
+    /// Write all of `data`.
+    fn write_all(&mut self, data: &[u8]) -> AxResult;
+    /// Flush the written bytes to the client.
+    fn flush(&mut self) -> AxResult {
+        Ok(())
+    }
+}
+
+/// The byte sent by the client to interrupt the running target (Ctrl-C).
+pub(super) const INTERRUPT: u8 = 0x03;
+
+/// Packet framing over a [`GdbConnection`]: `$data#checksum`, with
+/// acknowledgments until the client disables them.
+pub(super) struct PacketIo<C> {
+    pub conn: C,
+    pub no_ack: bool,
+}
+
+impl<C: GdbConnection> PacketIo<C> {
+    /// Read the next packet, skipping acknowledgments and interrupt requests
+    /// received while the target is stopped. Returns its data, still escaped.
+    pub fn read_packet(&mut self) -> AxResult<Vec<u8>> {
+        loop {
+            while self.conn.read()? != b'$' {}
+            let mut data = Vec::new();
+            loop {
+                match self.conn.read()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let sum = [self.conn.read()?, self.conn.read()?];
+            let valid = parse_hex(&sum) == Some(checksum(&data) as u64);
+            if !self.no_ack {
+                self.conn.write_all(if valid { b"+" } else { b"-" })?;
+                self.conn.flush()?;
+            }
+            if valid || self.no_ack {
+                return Ok(data);
+            }
+        }
+    }
+
+    /// Send a packet, escaping `data`, and wait for its acknowledgment.
+    pub fn write_packet(&mut self, data: &[u8]) -> AxResult {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        for &byte in data {
+            if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+                packet.extend_from_slice(&[b'}', byte ^ 0x20]);
+            } else {
+                packet.push(byte);
+            }
+        }
+        let sum = checksum(&packet[1..]);
+        packet.push(b'#');
+        push_hex(&mut packet, &[sum]);
+        loop {
+            self.conn.write_all(&packet)?;
+            self.conn.flush()?;
+            if self.no_ack {
+                return Ok(());
+            }
+            // Resend on a negative acknowledgment.
+            loop {
+                match self.conn.read()? {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// The modulo 256 sum of the packet data.
+pub(super) fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Append the lowercase hexadecimal encoding of `bytes` to `out`.
+pub(super) fn push_hex(out: &mut Vec<u8>, bytes: &[u8]) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for &byte in bytes {
+        out.extend_from_slice(&[DIGITS[byte as usize >> 4], DIGITS[byte as usize & 0xf]]);
+    }
+}
+
+/// Decode a hexadecimal byte string.
+pub(super) fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| parse_hex(pair).map(|byte| byte as u8))
+        .collect()
+}
+
+/// Parse a big-endian hexadecimal number, as used for addresses, lengths
+/// and register numbers.
+pub(super) fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    hex.iter().try_fold(0u64, |value, &digit| {
+        Some(value << 4 | (digit as char).to_digit(16)? as u64)
+    })
+}
+
+/// Remove the escapes of binary data, as in `X` packets.
+pub(super) fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'}' => out.extend(bytes.next().map(|&byte| byte ^ 0x20)),
+            _ => out.push(byte),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex() {
+        let mut out = Vec::new();
+        push_hex(&mut out, &[0x00, 0x5a, 0xff]);
+        assert_eq!(out, b"005aff");
+        assert_eq!(decode_hex(b"005aFF"), Some(alloc::vec![0x00, 0x5a, 0xff]));
+        assert_eq!(decode_hex(b"5a0"), None);
+        assert_eq!(decode_hex(b"zz"), None);
+        assert_eq!(parse_hex(b"ffffffff81000000"), Some(0xffff_ffff_8100_0000));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"10000000000000000"), None);
+        assert_eq!(unescape(b"a}\x03}]"), b"a#}");
+        assert_eq!(checksum(b"OK"), 0x9a);
+    }
+}
@@ -0,0 +1,366 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use axerrno::{AxResult, ax_err};
+
+use crate::vmx::state::FXSAVE_AREA_SIZE;
+
+/// The number of registers described to GDB.
+pub(super) const NUM_REGS: usize = 65;
+
+/// The GDB register numbers of the general-purpose registers, indexed by
+/// their encoding in instructions (RAX, RCX, RDX, RBX, RSP, RBP, RSI, RDI,
+/// R8 to R15).
+const GPR_REGNUMS: [usize; 16] = [0, 2, 3, 1, 7, 6, 4, 5, 8, 9, 10, 11, 12, 13, 14, 15];
+
+/// Offsets in the legacy region of the FXSAVE area, in its 64-bit format.
+/// (SDM Vol. 1, Section 10.5.1, Table 10-2)
+mod fxsave {
+    pub const FCW: usize = 0;
+    pub const FSW: usize = 2;
+    pub const FTW: usize = 4;
+    pub const FOP: usize = 6;
+    pub const FIP: usize = 8;
+    pub const FDP: usize = 16;
+    pub const MXCSR: usize = 24;
+    pub const ST: usize = 32;
+    pub const XMM: usize = 160;
+}
+
+/// The registers of a vCPU, in the layout described to GDB by the target
+/// description: the amd64 core and SSE registers, the FS and GS bases, then
+/// CR0, CR2, CR3, CR4, CR8 and IA32_EFER.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GdbRegisters {
+    /// RAX, RCX, RDX, RBX, RSP, RBP, RSI, RDI, R8 to R15, indexed by their
+    /// encoding in instructions.
+    pub gprs: [u64; 16],
+    /// RIP.
+    pub rip: u64,
+    /// RFLAGS, of which GDB has the low 32 bits.
+    pub rflags: u64,
+    /// CS, SS, DS, ES, FS and GS selectors.
+    pub selectors: [u16; 6],
+    /// FS base.
+    pub fs_base: u64,
+    /// GS base.
+    pub gs_base: u64,
+    /// CR0, CR2, CR3, CR4 and CR8.
+    pub cr: [u64; 5],
+    /// IA32_EFER.
+    pub efer: u64,
+    /// The legacy region of the FXSAVE or XSAVE area, holding the x87 and
+    /// SSE registers.
+    pub fxsave: [u8; FXSAVE_AREA_SIZE],
+}
+
+impl Default for GdbRegisters {
+    fn default() -> Self {
+        Self {
+            gprs: [0; 16],
+            rip: 0,
+            rflags: 0,
+            selectors: [0; 6],
+            fs_base: 0,
+            gs_base: 0,
+            cr: [0; 5],
+            efer: 0,
+            fxsave: [0; FXSAVE_AREA_SIZE],
+        }
+    }
+}
+
+impl GdbRegisters {
+    /// The size of register `regnum` in bytes, `None` if unknown.
+    pub(super) fn size(regnum: usize) -> Option<usize> {
+        Some(match regnum {
+            0..=16 => 8,
+            17..=23 => 4,
+            24..=31 => 10,
+            32..=39 => 4,
+            40..=55 => 16,
+            56 => 4,
+            57..NUM_REGS => 8,
+            _ => return None,
+        })
+    }
+
+    /// The offset of register `regnum` in the encoding.
+    pub(super) fn offset(regnum: usize) -> usize {
+        (0..regnum).filter_map(Self::size).sum()
+    }
+
+    /// Encode the registers in the GDB layout, as for a `g` reply.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(Self::offset(NUM_REGS));
+        let mut regs = [0u64; 16];
+        for (index, &regnum) in GPR_REGNUMS.iter().enumerate() {
+            regs[regnum] = self.gprs[index];
+        }
+        for value in regs {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out.extend_from_slice(&self.rip.to_le_bytes());
+        out.extend_from_slice(&(self.rflags as u32).to_le_bytes());
+        for selector in self.selectors {
+            out.extend_from_slice(&(selector as u32).to_le_bytes());
+        }
+        for i in 0..8 {
+            out.extend_from_slice(&self.fxsave[fxsave::ST + 16 * i..][..10]);
+        }
+        let fx16 =
+            |offset: usize| u16::from_le_bytes([self.fxsave[offset], self.fxsave[offset + 1]]);
+        let fx32 =
+            |offset: usize| u32::from_le_bytes(self.fxsave[offset..][..4].try_into().unwrap());
+        // The 64-bit format has no x87 instruction and operand selectors.
+        for value in [
+            fx16(fxsave::FCW) as u32,
+            fx16(fxsave::FSW) as u32,
+            self.full_tag_word() as u32,
+            0,
+            fx32(fxsave::FIP),
+            0,
+            fx32(fxsave::FDP),
+            fx16(fxsave::FOP) as u32,
+        ] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out.extend_from_slice(&self.fxsave[fxsave::XMM..][..16 * 16]);
+        out.extend_from_slice(&self.fxsave[fxsave::MXCSR..][..4]);
+        for value in [self.fs_base, self.gs_base]
+            .into_iter()
+            .chain(self.cr)
+            .chain([self.efer])
+        {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out
+    }
+
+    /// Update the registers from their GDB layout, as in a `G` packet.
+    pub fn decode(&mut self, data: &[u8]) -> AxResult {
+        if data.len() != Self::offset(NUM_REGS) {
+            return ax_err!(InvalidInput, "invalid register data size");
+        }
+        let mut pos = 0;
+        let mut take = |len: usize| {
+            let bytes = &data[pos..pos + len];
+            pos += len;
+            bytes
+        };
+        let u64_of = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+        let u32_of = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+
+        let mut regs = [0u64; 16];
+        for value in &mut regs {
+            *value = u64_of(take(8));
+        }
+        for (index, &regnum) in GPR_REGNUMS.iter().enumerate() {
+            self.gprs[index] = regs[regnum];
+        }
+        self.rip = u64_of(take(8));
+        self.rflags = (self.rflags & !0xffff_ffff) | u32_of(take(4)) as u64;
+        for selector in &mut self.selectors {
+            *selector = u32_of(take(4)) as u16;
+        }
+        for i in 0..8 {
+            self.fxsave[fxsave::ST + 16 * i..][..10].copy_from_slice(take(10));
+        }
+        let mut fpu = [0u32; 8];
+        for value in &mut fpu {
+            *value = u32_of(take(4));
+        }
+        let [fcw, fsw, ftag, _fiseg, fioff, _foseg, fooff, fop] = fpu;
+        self.fxsave[fxsave::FCW..][..2].copy_from_slice(&(fcw as u16).to_le_bytes());
+        self.fxsave[fxsave::FSW..][..2].copy_from_slice(&(fsw as u16).to_le_bytes());
+        self.fxsave[fxsave::FTW] = Self::abridged_tag_word(ftag as u16);
+        self.fxsave[fxsave::FIP..][..4].copy_from_slice(&fioff.to_le_bytes());
+        self.fxsave[fxsave::FDP..][..4].copy_from_slice(&fooff.to_le_bytes());
+        self.fxsave[fxsave::FOP..][..2].copy_from_slice(&(fop as u16 & 0x7ff).to_le_bytes());
+        self.fxsave[fxsave::XMM..][..16 * 16].copy_from_slice(take(16 * 16));
+        self.fxsave[fxsave::MXCSR..][..4].copy_from_slice(take(4));
+        self.fs_base = u64_of(take(8));
+        self.gs_base = u64_of(take(8));
+        for value in &mut self.cr {
+            *value = u64_of(take(8));
+        }
+        self.efer = u64_of(take(8));
+        Ok(())
+    }
+
+    /// The full x87 tag word, computed from the abridged one of FXSAVE and
+    /// the register contents. (SDM Vol. 1, Section 10.5.1.1)
+    fn full_tag_word(&self) -> u16 {
+        let top = (u16::from_le_bytes([self.fxsave[fxsave::FSW], self.fxsave[fxsave::FSW + 1]])
+            >> 11)
+            & 7;
+        let mut tags = 0;
+        for physical in 0..8 {
+            let tag = if self.fxsave[fxsave::FTW] & (1 << physical) == 0 {
+                0b11 // Empty
+            } else {
+                let st = (physical + 8 - top as usize) % 8;
+                let reg = &self.fxsave[fxsave::ST + 16 * st..][..10];
+                let mantissa = u64::from_le_bytes(reg[..8].try_into().unwrap());
+                let exponent = u16::from_le_bytes([reg[8], reg[9]]) & 0x7fff;
+                match exponent {
+                    0x7fff => 0b10,                   // Special
+                    0 if mantissa == 0 => 0b01,       // Zero
+                    0 => 0b10,                        // Special, denormal
+                    _ if mantissa >> 63 == 0 => 0b10, // Special, unnormal
+                    _ => 0b00,                        // Valid
+                }
+            };
+            tags |= tag << (2 * physical);
+        }
+        tags
+    }
+
+    /// The abridged tag word of FXSAVE for a full x87 tag word.
+    fn abridged_tag_word(tags: u16) -> u8 {
+        (0..8)
+            .filter(|physical| (tags >> (2 * physical)) & 0b11 != 0b11)
+            .fold(0, |abridged, physical| abridged | 1 << physical)
+    }
+}
+
+/// The target description of the registers in [`GdbRegisters`], returned
+/// for `qXfer:features:read:target.xml`.
+pub(super) fn target_xml() -> String {
+    const GPRS: [&str; 16] = [
+        "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12",
+        "r13", "r14", "r15",
+    ];
+    const X87: [&str; 8] = [
+        "fctrl", "fstat", "ftag", "fiseg", "fioff", "foseg", "fooff", "fop",
+    ];
+
+    fn reg(xml: &mut String, name: &str, bits: usize, ty: &str) {
+        write!(xml, r#"<reg name="{name}" bitsize="{bits}" type="{ty}"/>"#).unwrap();
+    }
+
+    let mut xml = String::from(concat!(
+        r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd">"#,
+        r#"<target version="1.0"><architecture>i386:x86-64</architecture>"#,
+        r#"<feature name="org.gnu.gdb.i386.core">"#,
+    ));
+    for name in GPRS {
+        let ty = match name {
+            "rbp" | "rsp" => "data_ptr",
+            _ => "int64",
+        };
+        reg(&mut xml, name, 64, ty);
+    }
+    reg(&mut xml, "rip", 64, "code_ptr");
+    for name in ["eflags", "cs", "ss", "ds", "es", "fs", "gs"] {
+        reg(&mut xml, name, 32, "int32");
+    }
+    for i in 0..8 {
+        reg(&mut xml, &format!("st{i}"), 80, "i387_ext");
+    }
+    for name in X87 {
+        reg(&mut xml, name, 32, "int");
+    }
+
+    xml.push_str(concat!(
+        r#"</feature><feature name="org.gnu.gdb.i386.sse">"#,
+        r#"<vector id="v4f" type="ieee_single" count="4"/>"#,
+        r#"<vector id="v2d" type="ieee_double" count="2"/>"#,
+        r#"<vector id="v16i8" type="int8" count="16"/>"#,
+        r#"<vector id="v8i16" type="int16" count="8"/>"#,
+        r#"<vector id="v4i32" type="int32" count="4"/>"#,
+        r#"<vector id="v2i64" type="int64" count="2"/>"#,
+        r#"<union id="vec128"><field name="v4_float" type="v4f"/>"#,
+        r#"<field name="v2_double" type="v2d"/><field name="v16_int8" type="v16i8"/>"#,
+        r#"<field name="v8_int16" type="v8i16"/><field name="v4_int32" type="v4i32"/>"#,
+        r#"<field name="v2_int64" type="v2i64"/><field name="uint128" type="uint128"/>"#,
+        r#"</union>"#,
+    ));
+    for i in 0..16 {
+        reg(&mut xml, &format!("xmm{i}"), 128, "vec128");
+    }
+    reg(&mut xml, "mxcsr", 32, "int");
+
+    xml.push_str(r#"</feature><feature name="org.gnu.gdb.i386.segments">"#);
+    reg(&mut xml, "fs_base", 64, "int64");
+    reg(&mut xml, "gs_base", 64, "int64");
+
+    // Not known to GDB, but shown by `info registers system`.
+    xml.push_str(r#"</feature><feature name="org.axvisor.x86.system">"#);
+    for name in ["cr0", "cr2", "cr3", "cr4", "cr8", "efer"] {
+        write!(
+            xml,
+            r#"<reg name="{name}" bitsize="64" type="int64" group="system"/>"#
+        )
+        .unwrap();
+    }
+    xml.push_str("</feature></target>");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(GdbRegisters::offset(16), 128); // rip
+        assert_eq!(GdbRegisters::offset(24), 164); // st0
+        assert_eq!(GdbRegisters::offset(40), 276); // xmm0
+        assert_eq!(GdbRegisters::offset(NUM_REGS), 600);
+        assert_eq!(GdbRegisters::size(NUM_REGS), None);
+        assert_eq!(GdbRegisters::default().encode().len(), 600);
+        assert_eq!(target_xml().matches("<reg ").count(), NUM_REGS);
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut regs = GdbRegisters {
+            gprs: core::array::from_fn(|i| 0x1000 + i as u64),
+            rip: 0xffff_ffff_8100_0000,
+            rflags: 0x246,
+            selectors: [0x10, 0x18, 0x18, 0x18, 0, 0],
+            fs_base: 0x7f00_0000,
+            gs_base: 0xffff_8880_0000_0000,
+            cr: [0x8005_0033, 0xdead_b000, 0x20_0000, 0x3406f8, 0xf],
+            efer: 0xd01,
+            ..Default::default()
+        };
+        // TOP = 7, ST0 = physical register 7 holds 1.0, the others are empty.
+        regs.fxsave[fxsave::FSW + 1] = 7 << 3;
+        regs.fxsave[fxsave::FTW] = 0x80;
+        regs.fxsave[fxsave::ST + 7] = 0x80;
+        regs.fxsave[fxsave::ST + 8..][..2].copy_from_slice(&0x3fffu16.to_le_bytes());
+        regs.fxsave[fxsave::XMM + 16] = 0x5a;
+        regs.fxsave[fxsave::MXCSR..][..4].copy_from_slice(&0x1f80u32.to_le_bytes());
+        assert_eq!(regs.full_tag_word(), 0x3fff);
+
+        let data = regs.encode();
+        // RBX is the second register for GDB, RIP follows R15.
+        assert_eq!(data[8..16], 0x1003u64.to_le_bytes());
+        assert_eq!(data[128..136], regs.rip.to_le_bytes());
+        let ftag = GdbRegisters::offset(34);
+        assert_eq!(data[ftag..ftag + 4], 0x3fffu32.to_le_bytes());
+
+        let mut decoded = GdbRegisters::default();
+        decoded.decode(&data).unwrap();
+        assert_eq!(decoded, regs);
+        assert!(decoded.decode(&data[1..]).is_err());
+    }
+}
@@ -0,0 +1,924 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use axerrno::{AxError, AxResult, LinuxError};
+
+use super::packet::{self, GdbConnection, INTERRUPT, PacketIo};
+use super::regs::{GdbRegisters, NUM_REGS, target_xml};
+use crate::vmx::{HwBreakpoint, HwBreakpointKind};
+
+/// The largest packet accepted from the client, reported in `qSupported`.
+const PACKET_SIZE: usize = 0x1000;
+
+/// Signal numbers of stop replies, as GDB knows them.
+pub(super) mod signal {
+    pub const SIGINT: u8 = 2;
+    pub const SIGILL: u8 = 4;
+    pub const SIGTRAP: u8 = 5;
+    pub const SIGFPE: u8 = 8;
+    pub const SIGSEGV: u8 = 11;
+}
+
+/// How the target is resumed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GdbResume {
+    /// Run all threads.
+    Continue,
+    /// Execute one instruction on this thread only.
+    Step(usize),
+}
+
+/// Why the target stopped. Threads are vCPU indices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GdbStopReason {
+    /// A software breakpoint was hit, the thread RIP is at the breakpoint.
+    SwBreakpoint(usize),
+    /// A hardware execution breakpoint was hit.
+    HwBreakpoint(usize),
+    /// A watchpoint was hit.
+    Watchpoint {
+        /// The thread which accessed the data.
+        thread: usize,
+        /// The watchpoint condition.
+        kind: HwBreakpointKind,
+        /// The watched address.
+        addr: u64,
+    },
+    /// The thread executed one instruction.
+    Step(usize),
+    /// The client interrupted the target.
+    Interrupted(usize),
+    /// The thread got a signal, e.g., for a guest exception.
+    Signal {
+        /// The thread.
+        thread: usize,
+        /// The GDB signal number.
+        signal: u8,
+    },
+    /// The guest exited, with this status.
+    Exited(u8),
+}
+
+impl GdbStopReason {
+    /// The thread which stopped, if any.
+    fn thread(&self) -> Option<usize> {
+        match *self {
+            Self::SwBreakpoint(thread)
+            | Self::HwBreakpoint(thread)
+            | Self::Watchpoint { thread, .. }
+            | Self::Step(thread)
+            | Self::Interrupted(thread)
+            | Self::Signal { thread, .. } => Some(thread),
+            Self::Exited(_) => None,
+        }
+    }
+}
+
+/// The guest debugged by a [`GdbStub`], whose threads are vCPUs.
+///
+/// Addresses are guest virtual addresses, translated with the page tables
+/// of the given thread.
+pub trait GdbTarget {
+    /// The number of threads. Their GDB thread IDs are the indices plus one.
+    fn threads(&self) -> usize;
+    /// Read the registers of `thread`.
+    fn read_registers(&mut self, thread: usize) -> AxResult<GdbRegisters>;
+    /// Write the registers of `thread`.
+    fn write_registers(&mut self, thread: usize, regs: &GdbRegisters) -> AxResult;
+    /// Read guest memory at `addr` into `buf`.
+    fn read_memory(&mut self, thread: usize, addr: u64, buf: &mut [u8]) -> AxResult;
+    /// Write `data` into guest memory at `addr`, ignoring the guest write
+    /// protection.
+    fn write_memory(&mut self, thread: usize, addr: u64, data: &[u8]) -> AxResult;
+    /// Insert a software breakpoint at `addr`.
+    fn insert_sw_breakpoint(&mut self, thread: usize, addr: u64) -> AxResult;
+    /// Remove the software breakpoint at `addr`.
+    fn remove_sw_breakpoint(&mut self, thread: usize, addr: u64) -> AxResult;
+    /// Insert a hardware breakpoint or watchpoint in all threads.
+    fn insert_hw_breakpoint(&mut self, breakpoint: HwBreakpoint) -> AxResult;
+    /// Remove a hardware breakpoint or watchpoint from all threads.
+    fn remove_hw_breakpoint(&mut self, breakpoint: HwBreakpoint) -> AxResult;
+    /// Resume the target until it stops. `interrupted` returns whether the
+    /// client asked to stop, and should be polled between runs.
+    fn resume(
+        &mut self,
+        resume: GdbResume,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> AxResult<GdbStopReason>;
+}
+
+/// How a debugging session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GdbSessionEnd {
+    /// The client detached, the target should keep running.
+    Detached,
+    /// The client killed the target.
+    Killed,
+    /// The guest exited, with this status.
+    Exited(u8),
+}
+
+/// What to do after a packet was handled.
+enum Action {
+    Reply(Vec<u8>),
+    Resume(GdbResume),
+    Detach,
+    Kill,
+}
+
+/// A GDB remote serial protocol stub, serving one client over a
+/// [`GdbConnection`] for a [`GdbTarget`].
+///
+/// It works in all-stop mode, with thread IDs starting from 1 for the first
+/// vCPU. Register and memory accesses go to the thread selected by `Hg`,
+/// initially the one that last stopped.
+pub struct GdbStub<C> {
+    io: PacketIo<C>,
+    /// The thread of register and memory accesses.
+    thread: usize,
+    /// The thread to step, for `s` packets.
+    step_thread: usize,
+    /// The last stop, reported again for `?`.
+    stop: GdbStopReason,
+}
+
+impl<C: GdbConnection> GdbStub<C> {
+    /// Create a stub for a client connected through `conn`.
+    pub fn new(conn: C) -> Self {
+        Self {
+            io: PacketIo {
+                conn,
+                no_ack: false,
+            },
+            thread: 0,
+            step_thread: 0,
+            stop: GdbStopReason::Signal {
+                thread: 0,
+                signal: signal::SIGTRAP,
+            },
+        }
+    }
+
+    /// The connection to the client.
+    pub fn connection(&mut self) -> &mut C {
+        &mut self.io.conn
+    }
+
+    /// Serve the client until it detaches or kills the target, or the guest
+    /// exits. The target must be stopped, and is resumed on the requests of
+    /// the client.
+    pub fn run(&mut self, target: &mut impl GdbTarget) -> AxResult<GdbSessionEnd> {
+        loop {
+            let packet = self.io.read_packet()?;
+            match self.handle_packet(&packet, target) {
+                Action::Reply(reply) => {
+                    self.io.write_packet(&reply)?;
+                    if packet == b"QStartNoAckMode" {
+                        self.io.no_ack = true;
+                    }
+                }
+                Action::Resume(resume) => {
+                    let conn = &mut self.io.conn;
+                    let mut interrupted = || matches!(conn.try_read(), Ok(Some(INTERRUPT)));
+                    self.stop = target.resume(resume, &mut interrupted)?;
+                    if let Some(thread) = self.stop.thread() {
+                        self.thread = thread;
+                        self.step_thread = thread;
+                    }
+                    self.io.write_packet(&self.stop_reply())?;
+                    if let GdbStopReason::Exited(status) = self.stop {
+                        return Ok(GdbSessionEnd::Exited(status));
+                    }
+                }
+                Action::Detach => {
+                    self.io.write_packet(b"OK")?;
+                    return Ok(GdbSessionEnd::Detached);
+                }
+                Action::Kill => return Ok(GdbSessionEnd::Killed),
+            }
+        }
+    }
+
+    /// The stop reply packet for the last stop.
+    fn stop_reply(&self) -> Vec<u8> {
+        let mut reply = Vec::new();
+        let (signal, thread) = match self.stop {
+            GdbStopReason::Exited(status) => {
+                reply.push(b'W');
+                packet::push_hex(&mut reply, &[status]);
+                return reply;
+            }
+            GdbStopReason::Interrupted(thread) => (signal::SIGINT, thread),
+            GdbStopReason::Signal { thread, signal } => (signal, thread),
+            GdbStopReason::SwBreakpoint(thread)
+            | GdbStopReason::HwBreakpoint(thread)
+            | GdbStopReason::Watchpoint { thread, .. }
+            | GdbStopReason::Step(thread) => (signal::SIGTRAP, thread),
+        };
+        reply.push(b'T');
+        packet::push_hex(&mut reply, &[signal]);
+        reply.extend_from_slice(&fmt_bytes(format_args!("thread:{:x};", thread + 1)));
+        match self.stop {
+            GdbStopReason::SwBreakpoint(_) => reply.extend_from_slice(b"swbreak:;"),
+            GdbStopReason::HwBreakpoint(_) => reply.extend_from_slice(b"hwbreak:;"),
+            GdbStopReason::Watchpoint { kind, addr, .. } => {
+                let name = match kind {
+                    HwBreakpointKind::ReadWrite => "awatch",
+                    _ => "watch",
+                };
+                reply.extend_from_slice(&fmt_bytes(format_args!("{name}:{addr:x};")));
+            }
+            _ => {}
+        }
+        reply
+    }
+
+    fn handle_packet(&mut self, packet: &[u8], target: &mut impl GdbTarget) -> Action {
+        let reply = |result: AxResult<Vec<u8>>| Action::Reply(result.unwrap_or_else(error_reply));
+        let Some((&command, args)) = packet.split_first() else {
+            return Action::Reply(Vec::new());
+        };
+        match command {
+            b'?' => Action::Reply(self.stop_reply()),
+            b'q' | b'Q' => reply(self.handle_query(packet, target)),
+            b'H' => reply(self.set_thread(args, target)),
+            b'T' => reply(parse_thread(args, target).map(|_| b"OK".to_vec())),
+            b'g' => reply(target.read_registers(self.thread).map(|regs| {
+                let mut out = Vec::new();
+                packet::push_hex(&mut out, &regs.encode());
+                out
+            })),
+            b'G' => reply(self.write_registers(args, target)),
+            b'p' => reply(self.read_register(args, target)),
+            b'P' => reply(self.write_register(args, target)),
+            b'm' => reply(self.read_memory(args, target)),
+            b'M' | b'X' => reply(self.write_memory(command, args, target)),
+            b'Z' | b'z' => reply(self.set_breakpoint(command == b'Z', args, target)),
+            b'c' | b'C' | b's' | b'S' => {
+                // An optional resume address follows the signal, if any.
+                let addr = match command {
+                    b'c' | b's' => args,
+                    _ => args.splitn(2, |&b| b == b';').nth(1).unwrap_or_default(),
+                };
+                if !addr.is_empty() {
+                    let result = packet::parse_hex(addr).ok_or(AxError::InvalidInput);
+                    if let Err(err) = result.and_then(|addr| self.set_rip(addr, target)) {
+                        return Action::Reply(error_reply(err));
+                    }
+                }
+                Action::Resume(match command {
+                    b's' | b'S' => GdbResume::Step(self.step_thread),
+                    _ => GdbResume::Continue,
+                })
+            }
+            b'v' => self.handle_v(packet, target),
+            b'D' => Action::Detach,
+            b'k' => Action::Kill,
+            // Unsupported, as an empty reply.
+            _ => Action::Reply(Vec::new()),
+        }
+    }
+
+    fn handle_query(&mut self, packet: &[u8], target: &mut impl GdbTarget) -> AxResult<Vec<u8>> {
+        const XFER_TARGET_XML: &[u8] = b"qXfer:features:read:target.xml:";
+        if packet.starts_with(b"qSupported") {
+            return Ok(fmt_bytes(format_args!(
+                "PacketSize={PACKET_SIZE:x};qXfer:features:read+;QStartNoAckMode+;\
+                 swbreak+;hwbreak+;vContSupported+"
+            )));
+        }
+        if let Some(args) = packet.strip_prefix(XFER_TARGET_XML) {
+            let (offset, len) = parse_pair(args, b',')?;
+            let xml = target_xml();
+            let start = (offset as usize).min(xml.len());
+            let end = start.saturating_add(len as usize).min(xml.len());
+            let mut reply = Vec::from(if end == xml.len() { b"l" } else { b"m" });
+            reply.extend_from_slice(&xml.as_bytes()[start..end]);
+            return Ok(reply);
+        }
+        Ok(match packet {
+            b"QStartNoAckMode" => b"OK".to_vec(),
+            b"qAttached" => b"1".to_vec(),
+            b"qC" => fmt_bytes(format_args!("QC{:x}", self.thread + 1)),
+            b"qfThreadInfo" => {
+                let mut reply = b"m".to_vec();
+                for thread in 0..target.threads() {
+                    if thread > 0 {
+                        reply.push(b',');
+                    }
+                    reply.extend_from_slice(&fmt_bytes(format_args!("{:x}", thread + 1)));
+                }
+                reply
+            }
+            b"qsThreadInfo" => b"l".to_vec(),
+            _ => Vec::new(),
+        })
+    }
+
+    fn handle_v(&mut self, packet: &[u8], target: &mut impl GdbTarget) -> Action {
+        if packet == b"vCont?" {
+            return Action::Reply(b"vCont;c;C;s;S".to_vec());
+        }
+        if packet.starts_with(b"vKill") {
+            return Action::Kill;
+        }
+        let Some(actions) = packet.strip_prefix(b"vCont;") else {
+            return Action::Reply(Vec::new());
+        };
+        // Step the first thread with a step action, and stop the others.
+        for action in actions.split(|&b| b == b';') {
+            let mut parts = action.splitn(2, |&b| b == b':');
+            let kind = parts.next().unwrap_or_default();
+            if kind.first().is_some_and(|&b| b == b's' || b == b'S') {
+                let thread = match parts.next() {
+                    Some(thread) => match parse_thread(thread, target) {
+                        Ok(Some(thread)) => thread,
+                        Ok(None) => self.step_thread,
+                        Err(err) => return Action::Reply(error_reply(err)),
+                    },
+                    None => self.step_thread,
+                };
+                return Action::Resume(GdbResume::Step(thread));
+            }
+        }
+        Action::Resume(GdbResume::Continue)
+    }
+
+    /// `Hg` and `Hc`: select the thread of later accesses or steps.
+    fn set_thread(&mut self, args: &[u8], target: &mut impl GdbTarget) -> AxResult<Vec<u8>> {
+        let (&op, thread) = args.split_first().ok_or(AxError::InvalidInput)?;
+        if let Some(thread) = parse_thread(thread, target)? {
+            match op {
+                b'g' => self.thread = thread,
+                b'c' => self.step_thread = thread,
+                _ => return Err(AxError::InvalidInput),
+            }
+        }
+        Ok(b"OK".to_vec())
+    }
+
+    fn write_registers(&mut self, args: &[u8], target: &mut impl GdbTarget) -> AxResult<Vec<u8>> {
+        let data = packet::decode_hex(args).ok_or(AxError::InvalidInput)?;
+        let mut regs = target.read_registers(self.thread)?;
+        regs.decode(&data)?;
+        target.write_registers(self.thread, &regs)?;
+        Ok(b"OK".to_vec())
+    }
+
+    fn read_register(&mut self, args: &[u8], target: &mut impl GdbTarget) -> AxResult<Vec<u8>> {
+        let regnum = packet::parse_hex(args).ok_or(AxError::InvalidInput)? as usize;
+        let size = GdbRegisters::size(regnum).ok_or(AxError::InvalidInput)?;
+        let offset = GdbRegisters::offset(regnum);
+        let regs = target.read_registers(self.thread)?.encode();
+        let mut reply = Vec::new();
+        packet::push_hex(&mut reply, &regs[offset..offset + size]);
+        Ok(reply)
+    }
+
+    fn write_register(&mut self, args: &[u8], target: &mut impl GdbTarget) -> AxResult<Vec<u8>> {
+        let (regnum, value) = split_at_byte(args, b'=')?;
+        let regnum = packet::parse_hex(regnum).ok_or(AxError::InvalidInput)? as usize;
+        let value = packet::decode_hex(value).ok_or(AxError::InvalidInput)?;
+        if regnum >= NUM_REGS || GdbRegisters::size(regnum) != Some(value.len()) {
+            return Err(AxError::InvalidInput);
+        }
+        let mut regs = target.read_registers(self.thread)?;
+        let mut data = regs.encode();
+        let offset = GdbRegisters::offset(regnum);
+        data[offset..offset + value.len()].copy_from_slice(&value);
+        regs.decode(&data)?;
+        target.write_registers(self.thread, &regs)?;
+        Ok(b"OK".to_vec())
+    }
+
+    fn set_rip(&mut self, rip: u64, target: &mut impl GdbTarget) -> AxResult {
+        let mut regs = target.read_registers(self.step_thread)?;
+        regs.rip = rip;
+        target.write_registers(self.step_thread, &regs)
+    }
+
+    fn read_memory(&mut self, args: &[u8], target: &mut impl GdbTarget) -> AxResult<Vec<u8>> {
+        let (addr, len) = parse_pair(args, b',')?;
+        let mut buf = alloc::vec![0; (len as usize).min(PACKET_SIZE / 2)];
+        target.read_memory(self.thread, addr, &mut buf)?;
+        let mut reply = Vec::new();
+        packet::push_hex(&mut reply, &buf);
+        Ok(reply)
+    }
+
+    fn write_memory(
+        &mut self,
+        command: u8,
+        args: &[u8],
+        target: &mut impl GdbTarget,
+    ) -> AxResult<Vec<u8>> {
+        let (range, data) = split_at_byte(args, b':')?;
+        let (addr, len) = parse_pair(range, b',')?;
+        let data = match command {
+            b'X' => packet::unescape(data),
+            _ => packet::decode_hex(data).ok_or(AxError::InvalidInput)?,
+        };
+        if data.len() as u64 != len {
+            return Err(AxError::InvalidInput);
+        }
+        target.write_memory(self.thread, addr, &data)?;
+        Ok(b"OK".to_vec())
+    }
+
+    /// `Z` and `z`: insert or remove a breakpoint or watchpoint.
+    fn set_breakpoint(
+        &mut self,
+        insert: bool,
+        args: &[u8],
+        target: &mut impl GdbTarget,
+    ) -> AxResult<Vec<u8>> {
+        let mut fields = args.split(|&b| b == b',');
+        let ty = fields.next().ok_or(AxError::InvalidInput)?;
+        let addr = fields.next().and_then(packet::parse_hex);
+        let kind = fields.next().and_then(packet::parse_hex);
+        let (Some(addr), Some(len)) = (addr, kind) else {
+            return Err(AxError::InvalidInput);
+        };
+        let kind = match ty {
+            b"0" => {
+                if insert {
+                    target.insert_sw_breakpoint(self.thread, addr)?;
+                } else {
+                    target.remove_sw_breakpoint(self.thread, addr)?;
+                }
+                return Ok(b"OK".to_vec());
+            }
+            b"1" => HwBreakpointKind::Execute,
+            b"2" => HwBreakpointKind::Write,
+            b"4" => HwBreakpointKind::ReadWrite,
+            // Read watchpoints are not supported by the hardware.
+            _ => return Ok(Vec::new()),
+        };
+        let breakpoint = HwBreakpoint {
+            addr,
+            kind,
+            len: if kind == HwBreakpointKind::Execute {
+                1
+            } else {
+                len as u8
+            },
+        };
+        if insert {
+            target.insert_hw_breakpoint(breakpoint)?;
+        } else {
+            target.remove_hw_breakpoint(breakpoint)?;
+        }
+        Ok(b"OK".to_vec())
+    }
+}
+
+/// The error reply for `err`, with its errno value.
+fn error_reply(err: AxError) -> Vec<u8> {
+    let errno = LinuxError::from(err).code() as u8;
+    fmt_bytes(format_args!("E{errno:02x}"))
+}
+
+/// Parse a thread ID: `None` for any or all threads (0 and -1), otherwise
+/// the thread index.
+fn parse_thread(id: &[u8], target: &impl GdbTarget) -> AxResult<Option<usize>> {
+    match id {
+        b"0" | b"-1" => Ok(None),
+        _ => match packet::parse_hex(id) {
+            Some(id) if id >= 1 && (id as usize) <= target.threads() => Ok(Some(id as usize - 1)),
+            _ => Err(AxError::NotFound),
+        },
+    }
+}
+
+/// Parse two hexadecimal numbers separated by `sep`.
+fn parse_pair(args: &[u8], sep: u8) -> AxResult<(u64, u64)> {
+    let (first, second) = split_at_byte(args, sep)?;
+    packet::parse_hex(first)
+        .zip(packet::parse_hex(second))
+        .ok_or(AxError::InvalidInput)
+}
+
+fn split_at_byte(args: &[u8], sep: u8) -> AxResult<(&[u8], &[u8])> {
+    let pos = args
+        .iter()
+        .position(|&b| b == sep)
+        .ok_or(AxError::InvalidInput)?;
+    Ok((&args[..pos], &args[pos + 1..]))
+}
+
+fn fmt_bytes(args: core::fmt::Arguments) -> Vec<u8> {
+    let mut s = alloc::string::String::new();
+    s.write_fmt(args).unwrap();
+    s.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::{BTreeMap, VecDeque};
+
+    use super::*;
+
+    /// The client end of a connection, whose packets are queued before the
+    /// stub runs. Acknowledgments are accepted as they come.
+    #[derive(Default)]
+    struct Loopback {
+        input: VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl GdbConnection for Loopback {
+        fn read(&mut self) -> AxResult<u8> {
+            // The script is over, as if the client went silent.
+            self.input.pop_front().ok_or(AxError::WouldBlock)
+        }
+
+        fn try_read(&mut self) -> AxResult<Option<u8>> {
+            Ok(match self.input.front() {
+                Some(&INTERRUPT) => self.input.pop_front(),
+                _ => None,
+            })
+        }
+
+        fn write_all(&mut self, data: &[u8]) -> AxResult {
+            self.output.extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    /// A stand-in client, framing packets like GDB does.
+    struct Client {
+        conn: Loopback,
+        no_ack: bool,
+    }
+
+    impl Client {
+        fn new() -> Self {
+            Self {
+                conn: Loopback::default(),
+                no_ack: false,
+            }
+        }
+
+        /// Queue a packet, and the acknowledgment of its reply.
+        fn send(&mut self, data: &[u8]) -> &mut Self {
+            let sum = packet::checksum(data);
+            self.conn.input.push_back(b'$');
+            self.conn.input.extend(data);
+            self.conn.input.push_back(b'#');
+            let mut hex = Vec::new();
+            packet::push_hex(&mut hex, &[sum]);
+            self.conn.input.extend(hex);
+            if !self.no_ack {
+                self.conn.input.push_back(b'+');
+            }
+            if data == b"QStartNoAckMode" {
+                self.no_ack = true;
+            }
+            self
+        }
+
+        /// Interrupt the target resumed by the last packet, before its stop
+        /// reply is acknowledged.
+        fn interrupt(&mut self) -> &mut Self {
+            let ack = if self.no_ack {
+                None
+            } else {
+                self.conn.input.pop_back()
+            };
+            self.conn.input.push_back(INTERRUPT);
+            self.conn.input.extend(ack);
+            self
+        }
+
+        /// Run the stub, and return the session end and the reply packets,
+        /// unescaped.
+        fn run(&mut self, target: &mut MockTarget) -> (AxResult<GdbSessionEnd>, Vec<Vec<u8>>) {
+            let conn = core::mem::take(&mut self.conn);
+            let mut stub = GdbStub::new(conn);
+            let end = stub.run(target);
+            let output = core::mem::take(&mut stub.connection().output);
+            let mut replies = Vec::new();
+            let mut bytes = output.iter().copied();
+            while let Some(byte) = bytes.next() {
+                if byte != b'$' {
+                    continue;
+                }
+                let data: Vec<u8> = bytes.by_ref().take_while(|&b| b != b'#').collect();
+                let sum: Vec<u8> = bytes.by_ref().take(2).collect();
+                assert_eq!(
+                    packet::parse_hex(&sum),
+                    Some(packet::checksum(&data) as u64)
+                );
+                replies.push(packet::unescape(&data));
+            }
+            (end, replies)
+        }
+    }
+
+    /// A guest of two threads with flat memory at address 0.
+    struct MockTarget {
+        regs: [GdbRegisters; 2],
+        memory: Vec<u8>,
+        sw_breakpoints: BTreeMap<u64, u8>,
+        hw_breakpoints: Vec<HwBreakpoint>,
+        resumes: Vec<GdbResume>,
+        stops: VecDeque<GdbStopReason>,
+    }
+
+    impl MockTarget {
+        fn new() -> Self {
+            Self {
+                regs: [GdbRegisters::default(), GdbRegisters::default()],
+                memory: (0..=0xff).collect(),
+                sw_breakpoints: BTreeMap::new(),
+                hw_breakpoints: Vec::new(),
+                resumes: Vec::new(),
+                stops: VecDeque::new(),
+            }
+        }
+
+        fn range(&self, addr: u64, len: usize) -> AxResult<core::ops::Range<usize>> {
+            let start = addr as usize;
+            match start.checked_add(len) {
+                Some(end) if end <= self.memory.len() => Ok(start..end),
+                _ => Err(AxError::BadAddress),
+            }
+        }
+    }
+
+    impl GdbTarget for MockTarget {
+        fn threads(&self) -> usize {
+            self.regs.len()
+        }
+
+        fn read_registers(&mut self, thread: usize) -> AxResult<GdbRegisters> {
+            Ok(self.regs[thread].clone())
+        }
+
+        fn write_registers(&mut self, thread: usize, regs: &GdbRegisters) -> AxResult {
+            self.regs[thread] = regs.clone();
+            Ok(())
+        }
+
+        fn read_memory(&mut self, _thread: usize, addr: u64, buf: &mut [u8]) -> AxResult {
+            let range = self.range(addr, buf.len())?;
+            buf.copy_from_slice(&self.memory[range]);
+            Ok(())
+        }
+
+        fn write_memory(&mut self, _thread: usize, addr: u64, data: &[u8]) -> AxResult {
+            let range = self.range(addr, data.len())?;
+            self.memory[range].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn insert_sw_breakpoint(&mut self, _thread: usize, addr: u64) -> AxResult {
+            let range = self.range(addr, 1)?;
+            let orig = core::mem::replace(&mut self.memory[range.start], 0xcc);
+            self.sw_breakpoints.insert(addr, orig);
+            Ok(())
+        }
+
+        fn remove_sw_breakpoint(&mut self, _thread: usize, addr: u64) -> AxResult {
+            let orig = self.sw_breakpoints.remove(&addr).ok_or(AxError::NotFound)?;
+            self.memory[addr as usize] = orig;
+            Ok(())
+        }
+
+        fn insert_hw_breakpoint(&mut self, breakpoint: HwBreakpoint) -> AxResult {
+            breakpoint.check()?;
+            self.hw_breakpoints.push(breakpoint);
+            Ok(())
+        }
+
+        fn remove_hw_breakpoint(&mut self, breakpoint: HwBreakpoint) -> AxResult {
+            self.hw_breakpoints.retain(|&bp| bp != breakpoint);
+            Ok(())
+        }
+
+        fn resume(
+            &mut self,
+            resume: GdbResume,
+            interrupted: &mut dyn FnMut() -> bool,
+        ) -> AxResult<GdbStopReason> {
+            self.resumes.push(resume);
+            if let GdbResume::Step(thread) = resume {
+                self.regs[thread].rip += 1;
+                return Ok(GdbStopReason::Step(thread));
+            }
+            match self.stops.pop_front() {
+                Some(stop) => Ok(stop),
+                None if interrupted() => Ok(GdbStopReason::Interrupted(0)),
+                None => Err(AxError::WouldBlock),
+            }
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        let mut target = MockTarget::new();
+        let mut client = Client::new();
+        client
+            .send(b"qSupported:multiprocess+;swbreak+;hwbreak+")
+            .send(b"QStartNoAckMode")
+            .send(b"qXfer:features:read:target.xml:0,20")
+            .send(b"qfThreadInfo")
+            .send(b"qsThreadInfo")
+            .send(b"Hg2")
+            .send(b"qC")
+            .send(b"T3")
+            .send(b"?")
+            .send(b"qUnknown")
+            .send(b"D");
+        let (end, replies) = client.run(&mut target);
+        assert_eq!(end, Ok(GdbSessionEnd::Detached));
+
+        assert!(replies[0].starts_with(b"PacketSize=1000;"));
+        assert_eq!(replies[1], b"OK");
+        assert_eq!(replies[2][0], b'm');
+        assert_eq!(&replies[2][1..], &target_xml().as_bytes()[..0x20]);
+        assert_eq!(replies[3], b"m1,2");
+        assert_eq!(replies[4], b"l");
+        assert_eq!(replies[5], b"OK");
+        assert_eq!(replies[6], b"QC2");
+        assert_eq!(replies[7], b"E02");
+        assert_eq!(replies[8], b"T05thread:1;");
+        assert_eq!(replies[9], b"");
+        assert_eq!(replies[10], b"OK");
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut target = MockTarget::new();
+        target.regs[1].gprs[0] = 0x1122_3344_5566_7788; // RAX
+        target.regs[1].gprs[3] = 0xabcd; // RBX
+        target.regs[1].rip = 0xffff_8000_0010_0000;
+
+        let mut client = Client::new();
+        let mut g = Vec::new();
+        client.send(b"Hg2").send(b"g").send(b"p10").send(b"p1");
+        // RAX, RBX and RIP through P.
+        client
+            .send(b"P0=0100000000000000")
+            .send(b"P1=ffffffffffffffff")
+            .send(b"P10=00100000000000")
+            .send(b"P41=0")
+            .send(b"k");
+        let (end, replies) = client.run(&mut target);
+        assert_eq!(end, Ok(GdbSessionEnd::Killed));
+        assert_eq!(replies[0], b"OK");
+        packet::push_hex(&mut g, &{
+            let mut regs = GdbRegisters::default();
+            regs.gprs[0] = 0x1122_3344_5566_7788;
+            regs.gprs[3] = 0xabcd;
+            regs.rip = 0xffff_8000_0010_0000;
+            regs.encode()
+        });
+        assert_eq!(replies[1], g);
+        assert_eq!(replies[2], b"000010000080ffff");
+        assert_eq!(replies[3], b"cdab000000000000");
+        assert_eq!(replies[4], b"OK");
+        assert_eq!(replies[5], b"OK");
+        // A register of the wrong size.
+        assert_eq!(replies[6], b"E16");
+        assert_eq!(replies[7], b"E16");
+        assert_eq!(target.regs[1].gprs[0], 1);
+        assert_eq!(target.regs[1].gprs[3], u64::MAX);
+        assert_eq!(target.regs[0], GdbRegisters::default());
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut target = MockTarget::new();
+        let mut client = Client::new();
+        client
+            .send(b"m10,4")
+            .send(b"M20,2:aabb")
+            .send(b"X30,3:}\x03}]}\x0a")
+            .send(b"M40,2:aa")
+            .send(b"mff,2")
+            .send(b"D");
+        let (_, replies) = client.run(&mut target);
+        assert_eq!(replies[0], b"10111213");
+        assert_eq!(replies[1], b"OK");
+        assert_eq!(replies[2], b"OK");
+        assert_eq!(replies[3], b"E16");
+        assert_eq!(replies[4], b"E0e");
+        assert_eq!(target.memory[0x20..0x22], [0xaa, 0xbb]);
+        assert_eq!(target.memory[0x30..0x33], *b"#}*");
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut target = MockTarget::new();
+        let mut client = Client::new();
+        client
+            .send(b"Z0,40,1")
+            .send(b"Z1,1000,1")
+            .send(b"Z2,2000,8")
+            .send(b"Z3,3000,4")
+            .send(b"Z4,4001,2")
+            .send(b"z2,2000,8")
+            .send(b"z0,40,1")
+            .send(b"z0,50,1")
+            .send(b"D");
+        let (_, replies) = client.run(&mut target);
+        assert_eq!(replies[0], b"OK");
+        assert_eq!(replies[1], b"OK");
+        assert_eq!(replies[2], b"OK");
+        // Read watchpoints are unsupported.
+        assert_eq!(replies[3], b"");
+        // Unaligned.
+        assert_eq!(replies[4], b"E16");
+        assert_eq!(replies[5], b"OK");
+        assert_eq!(replies[6], b"OK");
+        assert_eq!(replies[7], b"E02");
+        assert_eq!(target.memory[0x40], 0x40);
+        assert_eq!(
+            target.hw_breakpoints,
+            [HwBreakpoint {
+                addr: 0x1000,
+                kind: HwBreakpointKind::Execute,
+                len: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut target = MockTarget::new();
+        target.stops.extend([
+            GdbStopReason::SwBreakpoint(1),
+            GdbStopReason::Watchpoint {
+                thread: 0,
+                kind: HwBreakpointKind::Write,
+                addr: 0x2000,
+            },
+            GdbStopReason::Signal {
+                thread: 0,
+                signal: 11,
+            },
+        ]);
+        let mut client = Client::new();
+        client
+            .send(b"vCont?")
+            .send(b"c")
+            .send(b"s")
+            .send(b"vCont;s:1;c")
+            .send(b"c")
+            .send(b"C0b;100")
+            .send(b"c")
+            .interrupt()
+            .send(b"vCont;c")
+            .interrupt()
+            .send(b"?");
+        let mut tail = Client::new();
+        tail.send(b"c");
+        let (end, replies) = client.run(&mut target);
+        // The script ended while the target was running.
+        assert_eq!(end, Err(AxError::WouldBlock));
+        assert_eq!(replies[0], b"vCont;c;C;s;S");
+        assert_eq!(replies[1], b"T05thread:2;swbreak:;");
+        // Steps go to the thread that stopped, unless given.
+        assert_eq!(replies[2], b"T05thread:2;");
+        assert_eq!(replies[3], b"T05thread:1;");
+        assert_eq!(replies[4], b"T05thread:1;watch:2000;");
+        assert_eq!(replies[5], b"T0bthread:1;");
+        assert_eq!(replies[6], b"T02thread:1;");
+        assert_eq!(replies[7], b"T02thread:1;");
+        assert_eq!(replies[8], b"T02thread:1;");
+        assert_eq!(
+            target.resumes,
+            [
+                GdbResume::Continue,
+                GdbResume::Step(1),
+                GdbResume::Step(0),
+                GdbResume::Continue,
+                GdbResume::Continue,
+                GdbResume::Continue,
+                GdbResume::Continue,
+            ]
+        );
+        assert_eq!(target.regs[0].rip, 0x100);
+        assert_eq!(target.regs[1].rip, 1);
+
+        target.stops.push_back(GdbStopReason::Exited(3));
+        let (end, replies) = tail.run(&mut target);
+        assert_eq!(end, Ok(GdbSessionEnd::Exited(3)));
+        assert_eq!(replies[0], b"W03");
+    }
+}
@@ -0,0 +1,373 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use axaddrspace::{GuestPhysAddr, GuestVirtAddr};
+use axerrno::{AxResult, ax_err, ax_err_type};
+use axvcpu::{AxArchVCpu, AxVCpuExitReason};
+use x86::irq::{
+    DIVIDE_ERROR_VECTOR, GENERAL_PROTECTION_FAULT_VECTOR, INVALID_OPCODE_VECTOR, PAGE_FAULT_VECTOR,
+    SEGMENT_NOT_PRESENT_VECTOR, SIMD_FLOATING_POINT_VECTOR, STACK_SEGEMENT_FAULT_VECTOR,
+    X87_FPU_VECTOR,
+};
+
+use super::regs::GdbRegisters;
+use super::stub::{GdbResume, GdbStopReason, GdbTarget, signal};
+use crate::vmx::state::FXSAVE_AREA_SIZE;
+use crate::vmx::vcpu::{VmxExitEvent, VmxVcpu};
+use crate::vmx::{HW_BREAKPOINT_SLOTS, HwBreakpoint, HwBreakpointKind, SwBreakpointAddr};
+use crate::{ControlRegister, SegmentRegister};
+
+/// The segment registers in the order of [`GdbRegisters::selectors`].
+const SEGMENTS: [SegmentRegister; 6] = [
+    SegmentRegister::Cs,
+    SegmentRegister::Ss,
+    SegmentRegister::Ds,
+    SegmentRegister::Es,
+    SegmentRegister::Fs,
+    SegmentRegister::Gs,
+];
+
+/// The control registers in the order of [`GdbRegisters::cr`].
+const CONTROL_REGS: [ControlRegister; 5] = [
+    ControlRegister::Cr0,
+    ControlRegister::Cr2,
+    ControlRegister::Cr3,
+    ControlRegister::Cr4,
+    ControlRegister::Cr8,
+];
+
+/// A set of [`VmxVcpu`]s debugged as the threads of a [`GdbTarget`], with
+/// thread `i` for `vcpus[i]`.
+///
+/// The vCPUs must be set up, and not bound to any processor: each one is
+/// bound to the current processor around its accesses and runs. They run
+/// in turn on the current processor while the target is resumed, so the
+/// others are paused while one steps over a software breakpoint. Each one
+/// keeps its own FPU state and syscall MSRs, which are switched on VM entries
+/// and exits.
+///
+/// Software breakpoints are inserted by the vCPU of the thread, and attached
+/// to the others, see [`VmxVcpu::insert_sw_breakpoint`]. Hardware
+/// breakpoints and watchpoints are installed in the debug registers of all
+/// vCPUs, see [`VmxVcpu::set_hw_breakpoint`]. Steps use the monitor trap
+/// flag, see [`VmxVcpu::set_single_step`].
+///
+/// VM exits which are not debug events are passed to `on_exit` with the
+/// vCPU index, which emulates them and returns the guest exit status once
+/// the guest is done, e.g., on [`AxVCpuExitReason::SystemDown`].
+pub struct VcpuSet<'a, F> {
+    vcpus: &'a mut [VmxVcpu],
+    on_exit: F,
+    /// The guest physical addresses of the software breakpoints, by the
+    /// virtual addresses given by the client.
+    sw_breakpoints: BTreeMap<u64, GuestPhysAddr>,
+    /// The hardware breakpoints, in the same slot on all vCPUs.
+    hw_breakpoints: [Option<HwBreakpoint>; HW_BREAKPOINT_SLOTS],
+    /// The next vCPU to run when the target is continued.
+    next: usize,
+}
+
+impl<'a, F> VcpuSet<'a, F>
+where
+    F: FnMut(usize, &mut VmxVcpu, AxVCpuExitReason) -> AxResult<Option<u8>>,
+{
+    /// Debug `vcpus`, passing their other VM exits to `on_exit`. Their guest
+    /// exceptions intercepted with [`ExceptionPolicy::Exit`](crate::vmx::ExceptionPolicy::Exit)
+    /// are reported as signals.
+    pub fn new(vcpus: &'a mut [VmxVcpu], on_exit: F) -> Self {
+        Self {
+            vcpus,
+            on_exit,
+            sw_breakpoints: BTreeMap::new(),
+            hw_breakpoints: [None; HW_BREAKPOINT_SLOTS],
+            next: 0,
+        }
+    }
+
+    /// Remove all breakpoints, e.g., when the session ended without the
+    /// client removing them.
+    pub fn clear_breakpoints(&mut self) -> AxResult {
+        let addrs: Vec<u64> = self.sw_breakpoints.keys().copied().collect();
+        for addr in addrs {
+            self.remove_sw_breakpoint(0, addr)?;
+        }
+        for breakpoint in self.hw_breakpoints.into_iter().flatten() {
+            self.remove_hw_breakpoint(breakpoint)?;
+        }
+        Ok(())
+    }
+
+    fn vcpu(&mut self, thread: usize) -> AxResult<&mut VmxVcpu> {
+        self.vcpus
+            .get_mut(thread)
+            .ok_or_else(|| ax_err_type!(NotFound, "no such vCPU"))
+    }
+
+    /// Run `thread` until a VM exit, and return the stop it caused, if any.
+    fn run_once(&mut self, thread: usize, step: bool) -> AxResult<Option<GdbStopReason>> {
+        let Self {
+            vcpus,
+            on_exit,
+            hw_breakpoints,
+            ..
+        } = self;
+        bound(&mut vcpus[thread], |vcpu| {
+            let reason = vcpu.run()?;
+            if !matches!(reason, AxVCpuExitReason::Nothing) {
+                return Ok(match on_exit(thread, vcpu, reason)? {
+                    Some(status) => Some(GdbStopReason::Exited(status)),
+                    // The exit completed the instruction being stepped.
+                    None => step.then_some(GdbStopReason::Step(thread)),
+                });
+            }
+            Ok(match vcpu.exit_event() {
+                Some(VmxExitEvent::SingleStep { .. }) => {
+                    step.then_some(GdbStopReason::Step(thread))
+                }
+                Some(VmxExitEvent::HwBreakpoint { slots }) => {
+                    let slot = slots.trailing_zeros() as usize;
+                    hw_breakpoints[slot].map(|bp| match bp.kind {
+                        HwBreakpointKind::Execute => GdbStopReason::HwBreakpoint(thread),
+                        kind => GdbStopReason::Watchpoint {
+                            thread,
+                            kind,
+                            addr: bp.addr,
+                        },
+                    })
+                }
+                Some(VmxExitEvent::SwBreakpoint { .. }) => {
+                    Some(GdbStopReason::SwBreakpoint(thread))
+                }
+                Some(VmxExitEvent::Exception(info)) => Some(GdbStopReason::Signal {
+                    thread,
+                    signal: exception_signal(info.vector),
+                }),
+                _ => None,
+            })
+        })
+    }
+}
+
+impl<F> GdbTarget for VcpuSet<'_, F>
+where
+    F: FnMut(usize, &mut VmxVcpu, AxVCpuExitReason) -> AxResult<Option<u8>>,
+{
+    fn threads(&self) -> usize {
+        self.vcpus.len()
+    }
+
+    fn read_registers(&mut self, thread: usize) -> AxResult<GdbRegisters> {
+        bound(self.vcpu(thread)?, |vcpu| {
+            let mut regs = GdbRegisters::default();
+            for (index, gpr) in regs.gprs.iter_mut().enumerate() {
+                *gpr = match index {
+                    // RSP is kept in the VMCS.
+                    4 => vcpu.stack_pointer() as u64,
+                    _ => vcpu.regs().get_reg_of_index(index as u8),
+                };
+            }
+            regs.rip = vcpu.rip() as u64;
+            regs.rflags = vcpu.rflags() as u64;
+            for (selector, segment) in regs.selectors.iter_mut().zip(SEGMENTS) {
+                *selector = vcpu.guest_segment(segment).selector;
+            }
+            regs.fs_base = vcpu.guest_segment(SegmentRegister::Fs).base;
+            regs.gs_base = vcpu.guest_segment(SegmentRegister::Gs).base;
+            for (value, register) in regs.cr.iter_mut().zip(CONTROL_REGS) {
+                *value = vcpu.guest_cr(register);
+            }
+            regs.efer = vcpu.guest_efer();
+            let fpu = vcpu.guest_fpu();
+            regs.fxsave.copy_from_slice(&fpu[..FXSAVE_AREA_SIZE]);
+            Ok(regs)
+        })
+    }
+
+    /// Only the changed registers are written. Writing a selector does not
+    /// reload the hidden part of the segment register.
+    fn write_registers(&mut self, thread: usize, regs: &GdbRegisters) -> AxResult {
+        let old = self.read_registers(thread)?;
+        bound(self.vcpu(thread)?, |vcpu| {
+            for (index, &value) in regs.gprs.iter().enumerate() {
+                match index {
+                    4 => vcpu.set_stack_pointer(value as usize),
+                    _ => vcpu.regs_mut().set_reg_of_index(index as u8, value),
+                }
+            }
+            vcpu.set_rip(regs.rip as usize);
+            vcpu.set_rflags(regs.rflags as usize);
+            for (index, segment) in SEGMENTS.into_iter().enumerate() {
+                let mut value = vcpu.guest_segment(segment);
+                value.selector = regs.selectors[index];
+                match segment {
+                    SegmentRegister::Fs => value.base = regs.fs_base,
+                    SegmentRegister::Gs => value.base = regs.gs_base,
+                    _ => {}
+                }
+                if value != vcpu.guest_segment(segment) {
+                    vcpu.set_guest_segment(segment, value)?;
+                }
+            }
+            for (index, register) in CONTROL_REGS.into_iter().enumerate() {
+                if regs.cr[index] != old.cr[index] {
+                    vcpu.set_guest_cr(register, regs.cr[index])?;
+                }
+            }
+            if regs.efer != old.efer {
+                vcpu.set_guest_efer(regs.efer)?;
+            }
+            if regs.fxsave != old.fxsave {
+                let mut fpu = vcpu.guest_fpu();
+                fpu[..FXSAVE_AREA_SIZE].copy_from_slice(&regs.fxsave);
+                vcpu.set_guest_fpu(&fpu)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn read_memory(&mut self, thread: usize, addr: u64, buf: &mut [u8]) -> AxResult {
+        bound(self.vcpu(thread)?, |vcpu| {
+            vcpu.read_guest_virt(GuestVirtAddr::from_usize(addr as usize), buf)
+                .map_err(|err| ax_err_type!(BadAddress, alloc::format!("{err:?}")))
+        })
+    }
+
+    fn write_memory(&mut self, thread: usize, addr: u64, data: &[u8]) -> AxResult {
+        bound(self.vcpu(thread)?, |vcpu| {
+            vcpu.debug_write_guest_virt(GuestVirtAddr::from_usize(addr as usize), data)
+                .map_err(|err| ax_err_type!(BadAddress, alloc::format!("{err:?}")))
+        })
+    }
+
+    fn insert_sw_breakpoint(&mut self, thread: usize, addr: u64) -> AxResult {
+        if self.sw_breakpoints.contains_key(&addr) {
+            return Ok(());
+        }
+        let gva = GuestVirtAddr::from_usize(addr as usize);
+        let breakpoint = bound(self.vcpu(thread)?, |vcpu| {
+            vcpu.insert_sw_breakpoint(SwBreakpointAddr::Virt(gva))
+        })?;
+        for (index, vcpu) in self.vcpus.iter_mut().enumerate() {
+            if index != thread {
+                vcpu.attach_sw_breakpoint(breakpoint)?;
+            }
+        }
+        self.sw_breakpoints.insert(addr, breakpoint.gpa);
+        Ok(())
+    }
+
+    fn remove_sw_breakpoint(&mut self, thread: usize, addr: u64) -> AxResult {
+        let Some(gpa) = self.sw_breakpoints.remove(&addr) else {
+            return ax_err!(NotFound, "no software breakpoint at this address");
+        };
+        bound(self.vcpu(thread)?, |vcpu| {
+            vcpu.remove_sw_breakpoint(SwBreakpointAddr::Phys(gpa))
+        })?;
+        for (index, vcpu) in self.vcpus.iter_mut().enumerate() {
+            if index != thread {
+                vcpu.detach_sw_breakpoint(gpa)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_hw_breakpoint(&mut self, breakpoint: HwBreakpoint) -> AxResult {
+        breakpoint.check()?;
+        if self.hw_breakpoints.contains(&Some(breakpoint)) {
+            return Ok(());
+        }
+        let slot = self
+            .hw_breakpoints
+            .iter()
+            .position(Option::is_none)
+            .ok_or_else(|| ax_err_type!(NoMemory, "no free hardware breakpoint slot"))?;
+        for vcpu in self.vcpus.iter_mut() {
+            bound(vcpu, |vcpu| vcpu.set_hw_breakpoint(slot, Some(breakpoint)))?;
+        }
+        self.hw_breakpoints[slot] = Some(breakpoint);
+        Ok(())
+    }
+
+    fn remove_hw_breakpoint(&mut self, breakpoint: HwBreakpoint) -> AxResult {
+        let slot = self
+            .hw_breakpoints
+            .iter()
+            .position(|&bp| bp == Some(breakpoint))
+            .ok_or_else(|| ax_err_type!(NotFound, "no such hardware breakpoint"))?;
+        for vcpu in self.vcpus.iter_mut() {
+            bound(vcpu, |vcpu| vcpu.set_hw_breakpoint(slot, None))?;
+        }
+        self.hw_breakpoints[slot] = None;
+        Ok(())
+    }
+
+    fn resume(
+        &mut self,
+        resume: GdbResume,
+        interrupted: &mut dyn FnMut() -> bool,
+    ) -> AxResult<GdbStopReason> {
+        if self.vcpus.is_empty() {
+            return ax_err!(BadState, "no vCPU to run");
+        }
+        match resume {
+            GdbResume::Continue => loop {
+                let thread = self.next;
+                self.next = (thread + 1) % self.vcpus.len();
+                if interrupted() {
+                    return Ok(GdbStopReason::Interrupted(thread));
+                }
+                if let Some(stop) = self.run_once(thread, false)? {
+                    return Ok(stop);
+                }
+            },
+            GdbResume::Step(thread) => {
+                bound(self.vcpu(thread)?, |vcpu| vcpu.set_single_step(true))?;
+                let stop = loop {
+                    match self.run_once(thread, true) {
+                        Ok(Some(stop)) => break Ok(stop),
+                        Ok(None) if interrupted() => break Ok(GdbStopReason::Interrupted(thread)),
+                        Ok(None) => {}
+                        Err(err) => break Err(err),
+                    }
+                };
+                bound(self.vcpu(thread)?, |vcpu| vcpu.set_single_step(false))?;
+                stop
+            }
+        }
+    }
+}
+
+/// Call `f` with `vcpu` bound to the current processor.
+fn bound<T>(vcpu: &mut VmxVcpu, f: impl FnOnce(&mut VmxVcpu) -> AxResult<T>) -> AxResult<T> {
+    vcpu.bind()?;
+    let result = f(vcpu);
+    vcpu.unbind()?;
+    result
+}
+
+/// The GDB signal reported for a guest exception.
+fn exception_signal(vector: u8) -> u8 {
+    match vector {
+        DIVIDE_ERROR_VECTOR | X87_FPU_VECTOR | SIMD_FLOATING_POINT_VECTOR => signal::SIGFPE,
+        INVALID_OPCODE_VECTOR => signal::SIGILL,
+        SEGMENT_NOT_PRESENT_VECTOR
+        | STACK_SEGEMENT_FAULT_VECTOR
+        | GENERAL_PROTECTION_FAULT_VECTOR
+        | PAGE_FAULT_VECTOR => signal::SIGSEGV,
+        _ => signal::SIGTRAP,
+    }
+}
@@ -62,3 +62,37 @@ pub unsafe fn invept(inv_type: InvEptType, eptp: u64) -> Result<()> {
     }
     vmx_capture_status()
 }
+
+/// INVVPID type. (SDM Vol. 3C, Section 30.3)
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum InvVpidType {
+    /// The logical processor invalidates mappings for the linear address
+    /// specified in the INVVPID descriptor, tagged with its VPID.
+    IndividualAddress = 0,
+    /// The logical processor invalidates all mappings tagged with the VPID
+    /// specified in the INVVPID descriptor.
+    SingleContext = 1,
+    /// The logical processor invalidates all mappings tagged with all VPIDs
+    /// except VPID 0000H.
+    AllContext = 2,
+    /// Like [`InvVpidType::SingleContext`], but global translations are
+    /// retained.
+    SingleContextRetainingGlobals = 3,
+}
+
+/// Invalidate Translations Based on VPID. (SDM Vol. 3C, Section 30.3)
+///
+/// Invalidates mappings in the translation lookaside buffers (TLBs) and
+/// paging-structure caches based on virtual-processor identifier (VPID).
+/// (See Chapter 29, “VMX Support for Address Translation”.) Invalidation is
+/// based on the INVVPID type specified in the register operand and the
+/// INVVPID descriptor specified in the memory operand.
+pub unsafe fn invvpid(inv_type: InvVpidType, vpid: u16, addr: u64) -> Result<()> {
+    let invvpid_desc = [vpid as u64, addr];
+    unsafe {
+        asm!("invvpid {0}, [{1}]", in(reg) inv_type as u64, in(reg) &invvpid_desc);
+    }
+    vmx_capture_status()
+}
@@ -12,20 +12,43 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod coredump;
+mod debug;
 mod definitions;
+mod dirty_log;
+mod events;
+pub mod gdb;
 mod instructions;
 mod percpu;
+mod segmentation;
+mod shootdown;
+mod state;
 mod structs;
 mod vcpu;
 mod vmcs;
+mod vpid;
 
 use self::structs::VmxBasic;
 use axerrno::ax_err_type;
 
+pub use self::coredump::{CoreDump, CoreDumpSink};
+pub use self::debug::{
+    HW_BREAKPOINT_SLOTS, HwBreakpoint, HwBreakpointKind, SwBreakpoint, SwBreakpointAddr,
+};
 pub use self::definitions::VmxExitReason;
+pub use self::dirty_log::{DirtyLogMode, VmDirtyLog};
+pub use self::events::ExceptionPolicy;
 pub use self::percpu::VmxPerCpuState as VmxArchPerCpuState;
-pub use self::vcpu::VmxVcpu as VmxArchVCpu;
-pub use self::vmcs::{VmxExitInfo, VmxInterruptInfo, VmxIoExitInfo};
+pub use self::segmentation::{
+    GuestDescriptorTable, GuestSegment, SegmentAccess, SegmentAccessRights, SegmentRegister,
+};
+pub use self::shootdown::EptShootdown;
+pub use self::state::{VcpuEvents, VcpuMsrs, VcpuState};
+pub use self::structs::{EptConfig, EptMemoryType};
+pub use self::vcpu::{VmCpuMode, VmxExitEvent, VmxShutdownInfo, VmxVcpu as VmxArchVCpu};
+pub use self::vmcs::{
+    GuestActivityState, VmxExceptionExitInfo, VmxExitInfo, VmxInterruptInfo, VmxIoExitInfo,
+};
 
 /// Return if current platform support virtualization extension.
 pub fn has_hardware_support() -> bool {
@@ -20,7 +20,9 @@ use axvcpu::AxArchPerCpu;
 use memory_addr::PAGE_SIZE_4K as PAGE_SIZE;
 
 use crate::msr::Msr;
+use crate::vmx::as_axerr;
 use crate::vmx::has_hardware_support;
+use crate::vmx::instructions::{InvEptType, invept};
 use crate::vmx::structs::{FeatureControl, FeatureControlFlags, VmxBasic, VmxRegion};
 
 /// Represents the per-CPU state for Virtual Machine Extensions (VMX).
@@ -43,6 +45,18 @@ pub struct VmxPerCpuState {
     vmx_region: VmxRegion,
 }
 
+impl VmxPerCpuState {
+    /// Invalidate the EPT-derived translations of all EPTPs on the current
+    /// processor, e.g., after the EPT of a VM which is not running here was
+    /// changed or freed. (SDM Vol. 3C, Section 29.4.3.1)
+    pub fn invept_global(&self) -> AxResult {
+        if !self.is_enabled() {
+            return ax_err!(BadState, "VMX is not enabled");
+        }
+        unsafe { invept(InvEptType::Global, 0).map_err(as_axerr) }
+    }
+}
+
 impl AxArchPerCpu for VmxPerCpuState {
     fn new(_cpu_id: usize) -> AxResult<Self> {
         Ok(Self {
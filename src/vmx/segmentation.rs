@@ -0,0 +1,554 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use axerrno::{AxResult, ax_err};
+use bit_field::BitField;
+
+use super::vcpu::VmCpuMode;
+use crate::exception::GuestException;
+
+/// A segment register: those used for memory accesses in instruction
+/// encoding order, then LDTR and TR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentRegister {
+    /// Extra segment, used by string instructions.
+    Es = 0,
+    /// Code segment.
+    Cs = 1,
+    /// Stack segment.
+    Ss = 2,
+    /// Data segment, the default for most memory operands.
+    Ds = 3,
+    /// FS segment, whose base is used in 64-bit mode.
+    Fs = 4,
+    /// GS segment, whose base is used in 64-bit mode.
+    Gs = 5,
+    /// Local descriptor-table register.
+    Ldtr = 6,
+    /// Task register.
+    Tr = 7,
+}
+
+/// Unpacked segment access rights. (SDM Vol. 3C, Section 25.4.1, Table 25-2)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentAccessRights {
+    /// Segment type, 4 bits.
+    pub segment_type: u8,
+    /// Descriptor type (S): code or data segment if set, system otherwise.
+    pub s: bool,
+    /// Descriptor privilege level, 2 bits.
+    pub dpl: u8,
+    /// Segment present (P).
+    pub present: bool,
+    /// Available for use by system software (AVL).
+    pub avl: bool,
+    /// 64-bit code segment (L).
+    pub l: bool,
+    /// Default operation size (D/B): 32-bit if set, 16-bit otherwise.
+    pub db: bool,
+    /// Granularity (G): the limit is in 4-KiB units if set.
+    pub g: bool,
+    /// Segment unusable, e.g., loaded with a null selector.
+    pub unusable: bool,
+}
+
+impl SegmentAccessRights {
+    /// The bits of the VMX access-rights format which are reserved.
+    const RESERVED: u32 = 0xfffe_0f00;
+
+    /// Unpack access rights in the VMX format, which must have their
+    /// reserved bits clear.
+    pub fn from_bits(bits: u32) -> AxResult<Self> {
+        if bits & Self::RESERVED != 0 {
+            return ax_err!(InvalidInput, "reserved segment access-rights bits set");
+        }
+        Ok(Self {
+            segment_type: bits.get_bits(0..4) as u8,
+            s: bits.get_bit(4),
+            dpl: bits.get_bits(5..7) as u8,
+            present: bits.get_bit(7),
+            avl: bits.get_bit(12),
+            l: bits.get_bit(13),
+            db: bits.get_bit(14),
+            g: bits.get_bit(15),
+            unusable: bits.get_bit(16),
+        })
+    }
+
+    /// Pack the access rights in the VMX format, checking that the type and
+    /// DPL fit in their fields.
+    pub fn bits(&self) -> AxResult<u32> {
+        if self.segment_type >= 1 << 4 || self.dpl >= 1 << 2 {
+            return ax_err!(InvalidInput, "segment type or DPL out of range");
+        }
+        let mut bits = 0u32;
+        bits.set_bits(0..4, self.segment_type as u32);
+        bits.set_bit(4, self.s);
+        bits.set_bits(5..7, self.dpl as u32);
+        bits.set_bit(7, self.present);
+        bits.set_bit(12, self.avl);
+        bits.set_bit(13, self.l);
+        bits.set_bit(14, self.db);
+        bits.set_bit(15, self.g);
+        bits.set_bit(16, self.unusable);
+        Ok(bits)
+    }
+}
+
+/// The kind of a memory access through a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentAccess {
+    /// Data read.
+    Read,
+    /// Data write.
+    Write,
+    /// Instruction fetch, through CS.
+    Execute,
+}
+
+/// A guest segment register, with the hidden part held in the VMCS.
+/// (SDM Vol. 3C, Section 25.4.1)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GuestSegment {
+    /// Segment selector.
+    pub selector: u16,
+    /// Segment base address.
+    pub base: u64,
+    /// Segment limit in bytes, already scaled by the granularity flag.
+    pub limit: u32,
+    /// Access rights, in the VMX format. (SDM Vol. 3C, Table 25-2)
+    pub access_rights: u32,
+}
+
+/// A guest descriptor-table register, GDTR or IDTR.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GuestDescriptorTable {
+    /// Linear base address of the table.
+    pub base: u64,
+    /// Table limit in bytes.
+    pub limit: u16,
+}
+
+impl GuestDescriptorTable {
+    /// Check that the table can be loaded in the VMCS guest state, i.e.,
+    /// that its base is canonical. (SDM Vol. 3C, Section 27.3.1.3)
+    pub fn check(&self, la57: bool) -> AxResult {
+        if !is_canonical(self.base, la57) {
+            return ax_err!(InvalidInput, "descriptor-table base not canonical");
+        }
+        Ok(())
+    }
+}
+
+impl GuestSegment {
+    /// Build a segment from its selector, base, limit in bytes and unpacked
+    /// access rights.
+    pub fn new(
+        selector: u16,
+        base: u64,
+        limit: u32,
+        rights: SegmentAccessRights,
+    ) -> AxResult<Self> {
+        Ok(Self {
+            selector,
+            base,
+            limit,
+            access_rights: rights.bits()?,
+        })
+    }
+
+    /// The unpacked access rights.
+    pub fn rights(&self) -> AxResult<SegmentAccessRights> {
+        SegmentAccessRights::from_bits(self.access_rights)
+    }
+
+    /// Check that the segment can be loaded in `register` of the VMCS guest
+    /// state, i.e., that it passes the format checks of VM entries.
+    /// (SDM Vol. 3C, Section 27.3.1.2)
+    ///
+    /// `la57` tells whether linear addresses have 57 bits, for the
+    /// canonicality of bases. The checks which depend on other registers,
+    /// e.g., on the guest mode, are left to the processor.
+    pub fn check(&self, register: SegmentRegister, la57: bool) -> AxResult {
+        let rights = self.rights()?;
+        let canonical = is_canonical(self.base, la57);
+        match register {
+            SegmentRegister::Fs
+            | SegmentRegister::Gs
+            | SegmentRegister::Ldtr
+            | SegmentRegister::Tr
+                if !canonical =>
+            {
+                return ax_err!(InvalidInput, "segment base not canonical");
+            }
+            SegmentRegister::Cs => {
+                if self.base >> 32 != 0 {
+                    return ax_err!(InvalidInput, "CS base above 4 GiB");
+                }
+            }
+            SegmentRegister::Es | SegmentRegister::Ss | SegmentRegister::Ds
+                if !rights.unusable && self.base >> 32 != 0 =>
+            {
+                return ax_err!(InvalidInput, "segment base above 4 GiB");
+            }
+            _ => {}
+        }
+        if register == SegmentRegister::Tr && rights.unusable {
+            return ax_err!(InvalidInput, "TR cannot be unusable");
+        }
+        if rights.unusable {
+            return Ok(());
+        }
+        if !rights.present {
+            return ax_err!(InvalidInput, "usable segment not present");
+        }
+        // The granularity must agree with the limit in bytes.
+        let page_limit = self.limit & 0xfff == 0xfff;
+        if (rights.g && !page_limit) || (!rights.g && self.limit > 0xf_ffff) {
+            return ax_err!(InvalidInput, "segment limit and granularity mismatch");
+        }
+        Ok(())
+    }
+
+    /// Whether the segment is unusable, e.g., loaded with a null selector.
+    pub fn is_unusable(&self) -> bool {
+        self.access_rights.get_bit(16)
+    }
+
+    /// Whether the segment is a code segment.
+    fn is_code(&self) -> bool {
+        self.access_rights.get_bit(3)
+    }
+
+    /// Readable for code segments, writable for data segments.
+    fn is_readable_or_writable(&self) -> bool {
+        self.access_rights.get_bit(1)
+    }
+
+    /// Whether the segment is an expand-down data segment.
+    fn is_expand_down(&self) -> bool {
+        !self.is_code() && self.access_rights.get_bit(2)
+    }
+
+    /// Compute the linear address of an access of `size` bytes at `offset`
+    /// in this segment, which is loaded in `register`. (SDM Vol. 3A, Section
+    /// 3.4 and 5.3)
+    ///
+    /// `offset` must already be truncated to the address size, and `la57`
+    /// tells whether 57-bit linear addresses are in use. Returns the fault
+    /// the processor would raise otherwise: #SS(0) for accesses through SS,
+    /// and #GP(0) for the others.
+    pub fn linearize(
+        &self,
+        register: SegmentRegister,
+        mode: VmCpuMode,
+        la57: bool,
+        offset: u64,
+        size: usize,
+        access: SegmentAccess,
+    ) -> Result<u64, GuestException> {
+        let fault = if register == SegmentRegister::Ss {
+            GuestException::StackSegmentFault(0)
+        } else {
+            GuestException::GeneralProtection(0)
+        };
+        let last_offset = offset.wrapping_add(size.max(1) as u64 - 1);
+
+        if mode == VmCpuMode::Mode64 {
+            // Only the FS and GS bases are used, and there are no limit or
+            // rights checks, but addresses must be canonical.
+            let base = match register {
+                SegmentRegister::Fs | SegmentRegister::Gs => self.base,
+                _ => 0,
+            };
+            let linear = base.wrapping_add(offset);
+            if !is_canonical(linear, la57) || !is_canonical(base.wrapping_add(last_offset), la57) {
+                return Err(fault);
+            }
+            return Ok(linear);
+        }
+
+        if mode != VmCpuMode::Real {
+            if self.is_unusable() {
+                return Err(fault);
+            }
+            let allowed = match access {
+                SegmentAccess::Execute => self.is_code(),
+                SegmentAccess::Read => !self.is_code() || self.is_readable_or_writable(),
+                SegmentAccess::Write => !self.is_code() && self.is_readable_or_writable(),
+            };
+            if !allowed {
+                return Err(fault);
+            }
+        }
+
+        let limit = self.limit as u64;
+        let within_limit = if self.is_expand_down() {
+            // Valid offsets are above the limit, up to 4 GiB or 64 KiB
+            // depending on the B flag.
+            let upper = if self.access_rights.get_bit(14) {
+                0xffff_ffff
+            } else {
+                0xffff
+            };
+            offset > limit && last_offset <= upper && last_offset >= offset
+        } else {
+            last_offset <= limit && last_offset >= offset
+        };
+        if !within_limit {
+            return Err(fault);
+        }
+        Ok(self.base.wrapping_add(offset) & 0xffff_ffff)
+    }
+}
+
+/// Whether `addr` is a canonical linear address, of 57 bits if `la57` and
+/// 48 bits otherwise.
+fn is_canonical(addr: u64, la57: bool) -> bool {
+    let shift = if la57 { 64 - 57 } else { 64 - 48 };
+    ((addr as i64) << shift >> shift) as u64 == addr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GP: Result<u64, GuestException> = Err(GuestException::GeneralProtection(0));
+    const SS: Result<u64, GuestException> = Err(GuestException::StackSegmentFault(0));
+
+    /// Present, accessed, read/write data segment.
+    const DATA_RW: u32 = 0x93;
+    /// Present, accessed, execute/read code segment.
+    const CODE_XR: u32 = 0x9b;
+
+    fn segment(base: u64, limit: u32, access_rights: u32) -> GuestSegment {
+        GuestSegment {
+            selector: 0x10,
+            base,
+            limit,
+            access_rights,
+        }
+    }
+
+    #[test]
+    fn test_mode64() {
+        let ds = segment(0x1000, 0, DATA_RW);
+        let fs = segment(0xffff_8000_0000_0000, 0, DATA_RW);
+        let linearize = |seg: &GuestSegment, reg, la57, offset, size| {
+            seg.linearize(
+                reg,
+                VmCpuMode::Mode64,
+                la57,
+                offset,
+                size,
+                SegmentAccess::Write,
+            )
+        };
+        // No base and no limit outside FS and GS.
+        assert_eq!(
+            linearize(&ds, SegmentRegister::Ds, false, 0x10_0000, 8),
+            Ok(0x10_0000)
+        );
+        assert_eq!(
+            linearize(&fs, SegmentRegister::Fs, false, 0x10, 8),
+            Ok(0xffff_8000_0000_0010)
+        );
+        // Non-canonical, or crossing into the non-canonical hole.
+        assert_eq!(
+            linearize(&ds, SegmentRegister::Ds, false, 0x8000_0000_0000, 1),
+            GP
+        );
+        assert_eq!(
+            linearize(&ds, SegmentRegister::Ss, false, 0x7fff_ffff_fffc, 8),
+            SS
+        );
+        assert_eq!(
+            linearize(&ds, SegmentRegister::Ds, true, 0x8000_0000_0000, 1),
+            Ok(0x8000_0000_0000)
+        );
+        assert_eq!(
+            linearize(&ds, SegmentRegister::Ds, true, 0x100_0000_0000_0000, 1),
+            GP
+        );
+    }
+
+    #[test]
+    fn test_protected_limits() {
+        let ds = segment(0x1_0000, 0xfff, DATA_RW);
+        let linearize = |seg: &GuestSegment, offset, size| {
+            seg.linearize(
+                SegmentRegister::Ds,
+                VmCpuMode::Protected,
+                false,
+                offset,
+                size,
+                SegmentAccess::Read,
+            )
+        };
+        assert_eq!(linearize(&ds, 0xff8, 8), Ok(0x1_0ff8));
+        assert_eq!(linearize(&ds, 0xffc, 8), GP);
+        assert_eq!(linearize(&ds, 0x1000, 1), GP);
+
+        // Addresses wrap at 4 GiB.
+        let high = segment(0xffff_f000, 0xffff_ffff, DATA_RW);
+        assert_eq!(linearize(&high, 0x1000, 4), Ok(0));
+
+        // Expand-down, 16-bit: offsets from limit + 1 to 0xffff.
+        let down = segment(0, 0xfff, DATA_RW | 0b100);
+        assert_eq!(linearize(&down, 0x1000, 4), Ok(0x1000));
+        assert_eq!(linearize(&down, 0xfff, 1), GP);
+        assert_eq!(linearize(&down, 0xfffe, 4), GP);
+        // Expand-down, 32-bit (B set): up to 4 GiB.
+        let down32 = segment(0, 0xfff, DATA_RW | 0b100 | (1 << 14));
+        assert_eq!(linearize(&down32, 0xfffe, 4), Ok(0xfffe));
+        assert_eq!(linearize(&down32, 0xffff_fffe, 4), GP);
+    }
+
+    #[test]
+    fn test_protected_rights() {
+        let check = |ar, reg, access| {
+            segment(0, 0xffff, ar).linearize(reg, VmCpuMode::Compatibility, false, 0x100, 2, access)
+        };
+        let (ds, ss, cs) = (
+            SegmentRegister::Ds,
+            SegmentRegister::Ss,
+            SegmentRegister::Cs,
+        );
+        assert_eq!(check(DATA_RW, ds, SegmentAccess::Write), Ok(0x100));
+        // Read-only data.
+        assert_eq!(check(DATA_RW & !0b10, ds, SegmentAccess::Read), Ok(0x100));
+        assert_eq!(check(DATA_RW & !0b10, ds, SegmentAccess::Write), GP);
+        assert_eq!(check(DATA_RW & !0b10, ss, SegmentAccess::Write), SS);
+        // Code segments are never writable, and readable only if R is set.
+        assert_eq!(check(CODE_XR, ds, SegmentAccess::Read), Ok(0x100));
+        assert_eq!(check(CODE_XR, ds, SegmentAccess::Write), GP);
+        assert_eq!(check(CODE_XR & !0b10, ds, SegmentAccess::Read), GP);
+        assert_eq!(
+            check(CODE_XR & !0b10, cs, SegmentAccess::Execute),
+            Ok(0x100)
+        );
+        assert_eq!(check(DATA_RW, cs, SegmentAccess::Execute), GP);
+        // Unusable (null) segments.
+        assert_eq!(check(1 << 16, ds, SegmentAccess::Read), GP);
+        assert_eq!(check(1 << 16, ss, SegmentAccess::Read), SS);
+    }
+
+    #[test]
+    fn test_access_rights() {
+        let rights = SegmentAccessRights::from_bits(0xa09b).unwrap();
+        assert_eq!(
+            rights,
+            SegmentAccessRights {
+                segment_type: 0xb,
+                s: true,
+                dpl: 0,
+                present: true,
+                avl: false,
+                l: true,
+                db: false,
+                g: true,
+                unusable: false,
+            }
+        );
+        assert_eq!(rights.bits(), Ok(0xa09b));
+        let unusable = SegmentAccessRights::from_bits(1 << 16).unwrap();
+        assert!(unusable.unusable && !unusable.present);
+        assert_eq!(unusable.bits(), Ok(1 << 16));
+        // Reserved bits, and fields out of range.
+        assert!(SegmentAccessRights::from_bits(0x93 | 1 << 8).is_err());
+        assert!(SegmentAccessRights::from_bits(0x93 | 1 << 17).is_err());
+        let bad_dpl = SegmentAccessRights { dpl: 4, ..rights };
+        assert!(bad_dpl.bits().is_err());
+        assert!(GuestSegment::new(0x8, 0, 0xffff, bad_dpl).is_err());
+        let bad_type = SegmentAccessRights {
+            segment_type: 16,
+            ..rights
+        };
+        assert!(bad_type.bits().is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let code64 = segment(0, 0xffff_ffff, 0xa09b);
+        assert!(code64.check(SegmentRegister::Cs, false).is_ok());
+        // The limit must match the granularity.
+        assert!(
+            segment(0, 0xf_fffe, 0xa09b)
+                .check(SegmentRegister::Cs, false)
+                .is_err()
+        );
+        assert!(
+            segment(0, 0x10_0000, DATA_RW)
+                .check(SegmentRegister::Ds, false)
+                .is_err()
+        );
+        assert!(
+            segment(0, 0xf_ffff, DATA_RW)
+                .check(SegmentRegister::Ds, false)
+                .is_ok()
+        );
+        // Not present.
+        assert!(
+            segment(0, 0xffff, DATA_RW & !0x80)
+                .check(SegmentRegister::Ds, false)
+                .is_err()
+        );
+        // Bases: 32 bits, or canonical for FS, GS, LDTR and TR.
+        let high = segment(0x1_0000_0000, 0xffff, DATA_RW);
+        assert!(high.check(SegmentRegister::Ds, false).is_err());
+        assert!(high.check(SegmentRegister::Fs, false).is_ok());
+        let noncanonical = segment(0x8000_0000_0000, 0xffff, DATA_RW);
+        assert!(noncanonical.check(SegmentRegister::Gs, false).is_err());
+        assert!(noncanonical.check(SegmentRegister::Gs, true).is_ok());
+        // Unusable segments are only checked for their base.
+        let null = segment(0x1_0000_0000, 0, 1 << 16);
+        assert!(null.check(SegmentRegister::Ds, false).is_ok());
+        assert!(null.check(SegmentRegister::Cs, false).is_err());
+        assert!(
+            segment(0, 0, 1 << 16)
+                .check(SegmentRegister::Tr, false)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_real_mode() {
+        let es = segment(0x1234 << 4, 0xffff, DATA_RW);
+        let linearize = |offset, size, access| {
+            es.linearize(
+                SegmentRegister::Es,
+                VmCpuMode::Real,
+                false,
+                offset,
+                size,
+                access,
+            )
+        };
+        assert_eq!(linearize(0x10, 2, SegmentAccess::Write), Ok(0x12350));
+        assert_eq!(linearize(0xffff, 2, SegmentAccess::Read), GP);
+        // No rights checks in real mode.
+        let ro = segment(0, 0xffff, DATA_RW & !0b10);
+        assert_eq!(
+            ro.linearize(
+                SegmentRegister::Ss,
+                VmCpuMode::Real,
+                false,
+                0xfffe,
+                2,
+                SegmentAccess::Write
+            ),
+            Ok(0xfffe)
+        );
+    }
+}
@@ -0,0 +1,308 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use axerrno::{AxResult, ax_err};
+use axvisor_api::vmm::{VCpuId, VMId};
+
+/// No vCPU runs alone in the guest.
+const NO_VCPU: usize = usize::MAX;
+
+/// Per-vCPU shootdown state.
+#[derive(Debug, Default)]
+struct VcpuSlot {
+    /// Whether the vCPU is in, or about to enter, VMX non-root operation.
+    in_guest: AtomicBool,
+    /// The last generation the vCPU flushed its EPT translations for.
+    flushed: AtomicU64,
+}
+
+/// Coordinates the invalidation of EPT translations across all vCPUs of a
+/// VM, which share the EPT but may run on different host CPUs.
+///
+/// After changing the EPT, e.g., unmapping guest memory, the VMM calls
+/// [`EptShootdown::shootdown`]: every vCPU then flushes its EPT translations
+/// before its next VM entry, and vCPUs running in the guest are kicked out
+/// of it first by the kick set with [`EptShootdown::with_kick`], if any, or
+/// on their next VM exit otherwise. The flushes apply to the processor a
+/// vCPU runs on, and the others are flushed when the vCPU is bound to them
+/// again.
+///
+/// It also parks the vCPUs out of the guest while another one executes an
+/// instruction it must run alone, e.g., steps over a software breakpoint
+/// whose original instruction is back in guest memory.
+///
+/// As a kick must not be taken between the flush check and the VM entry,
+/// the vCPUs must be run with host interrupts disabled.
+///
+/// Shared by the vCPUs of the VM, see
+/// [`VmxVcpu::set_ept_shootdown`](super::VmxArchVCpu::set_ept_shootdown).
+#[derive(Debug)]
+pub struct EptShootdown {
+    vm_id: VMId,
+    /// Incremented by each shootdown request.
+    generation: AtomicU64,
+    vcpus: Box<[VcpuSlot]>,
+    /// The vCPU running alone in the guest, or [`NO_VCPU`].
+    exclusive: AtomicUsize,
+    /// Forces a vCPU out of the guest, see [`EptShootdown::with_kick`].
+    kick: Option<fn(VMId, VCpuId)>,
+}
+
+impl EptShootdown {
+    /// Create the shootdown state for the `vcpu_num` vCPUs of VM `vm_id`.
+    pub fn new(vm_id: VMId, vcpu_num: usize) -> Self {
+        Self {
+            vm_id,
+            generation: AtomicU64::new(0),
+            vcpus: (0..vcpu_num).map(|_| VcpuSlot::default()).collect(),
+            exclusive: AtomicUsize::new(NO_VCPU),
+            kick: None,
+        }
+    }
+
+    /// Use `kick` to force vCPU `vcpu_id` of VM `vm_id` out of VMX non-root
+    /// operation, e.g., by sending an IPI to the host CPU running it. The
+    /// interrupt only needs to cause a VM exit, its handler has nothing to
+    /// do.
+    ///
+    /// Without a kick, a shootdown waits for the running vCPUs to exit the
+    /// guest on their own.
+    pub fn with_kick(mut self, kick: fn(VMId, VCpuId)) -> Self {
+        self.kick = Some(kick);
+        self
+    }
+
+    /// The VM whose vCPUs are coordinated.
+    pub fn vm_id(&self) -> VMId {
+        self.vm_id
+    }
+
+    /// Request all vCPUs to flush their EPT translations before their next
+    /// VM entry, and kick those in the guest out of it. Returns the request
+    /// generation, to be passed to [`EptShootdown::is_complete`].
+    pub fn request(&self) -> u64 {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let Some(kick) = self.kick else {
+            return generation;
+        };
+        for (vcpu_id, slot) in self.vcpus.iter().enumerate() {
+            if slot.in_guest.load(Ordering::SeqCst)
+                && slot.flushed.load(Ordering::SeqCst) < generation
+            {
+                kick(self.vm_id, vcpu_id);
+            }
+        }
+        generation
+    }
+
+    /// Whether no vCPU may use EPT translations older than the request
+    /// `generation` anymore: each either left the guest, or flushed.
+    pub fn is_complete(&self, generation: u64) -> bool {
+        self.vcpus.iter().all(|slot| {
+            !slot.in_guest.load(Ordering::SeqCst)
+                || slot.flushed.load(Ordering::SeqCst) >= generation
+        })
+    }
+
+    /// Invalidate stale EPT translations on all vCPUs, waiting until none
+    /// of them can use them anymore.
+    ///
+    /// Must not be called from a context that a kicked vCPU waits for, e.g.,
+    /// with host interrupts disabled on a CPU which has to handle the kick.
+    pub fn shootdown(&self) {
+        let generation = self.request();
+        while !self.is_complete(generation) {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Keep the other vCPUs out of the guest until
+    /// [`EptShootdown::unpark_others`], waiting for those in the guest to
+    /// exit after kicking them. Waits first for another vCPU running alone
+    /// to be done.
+    pub(crate) fn park_others(&self, vcpu_id: VCpuId) -> AxResult {
+        if vcpu_id >= self.vcpus.len() {
+            return ax_err!(InvalidInput, "vCPU not covered by the EPT shootdown");
+        }
+        self.claim_exclusive(vcpu_id);
+        while !self.others_parked(vcpu_id) {
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    /// Let the other vCPUs enter the guest again after
+    /// [`EptShootdown::park_others`].
+    pub(crate) fn unpark_others(&self, vcpu_id: VCpuId) {
+        let _ =
+            self.exclusive
+                .compare_exchange(vcpu_id, NO_VCPU, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    /// Make `vcpu_id` the vCPU running alone, and kick the others out of the
+    /// guest.
+    fn claim_exclusive(&self, vcpu_id: VCpuId) {
+        while self
+            .exclusive
+            .compare_exchange(NO_VCPU, vcpu_id, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        let Some(kick) = self.kick else {
+            return;
+        };
+        for (other, slot) in self.vcpus.iter().enumerate() {
+            if other != vcpu_id && slot.in_guest.load(Ordering::SeqCst) {
+                kick(self.vm_id, other);
+            }
+        }
+    }
+
+    /// Whether all vCPUs but `vcpu_id` are out of the guest.
+    fn others_parked(&self, vcpu_id: VCpuId) -> bool {
+        self.vcpus
+            .iter()
+            .enumerate()
+            .all(|(other, slot)| other == vcpu_id || !slot.in_guest.load(Ordering::SeqCst))
+    }
+
+    /// Whether vCPU `vcpu_id` must stay out of the guest, as another one
+    /// runs alone.
+    fn is_parked(&self, vcpu_id: VCpuId) -> bool {
+        let exclusive = self.exclusive.load(Ordering::SeqCst);
+        exclusive != NO_VCPU && exclusive != vcpu_id
+    }
+
+    /// Called by vCPU `vcpu_id` before a VM entry: waits while it is parked
+    /// by another vCPU, then runs `flush` if a shootdown was requested since
+    /// its last flush.
+    ///
+    /// Host interrupts must stay disabled from here to the VM entry, so that
+    /// a kick sent in between is taken as a VM exit right after the entry,
+    /// rather than by the host before it.
+    pub(crate) fn enter(&self, vcpu_id: VCpuId, flush: impl FnOnce() -> AxResult) -> AxResult {
+        let Some(slot) = self.vcpus.get(vcpu_id) else {
+            return ax_err!(InvalidInput, "vCPU not covered by the EPT shootdown");
+        };
+        // Pairs with `request` and `claim_exclusive`: either they see
+        // `in_guest`, or we see their generation or exclusive vCPU.
+        slot.in_guest.store(true, Ordering::SeqCst);
+        while self.is_parked(vcpu_id) {
+            slot.in_guest.store(false, Ordering::SeqCst);
+            while self.is_parked(vcpu_id) {
+                core::hint::spin_loop();
+            }
+            slot.in_guest.store(true, Ordering::SeqCst);
+        }
+        let generation = self.generation.load(Ordering::SeqCst);
+        if slot.flushed.load(Ordering::SeqCst) < generation {
+            flush()?;
+            slot.flushed.store(generation, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Called by vCPU `vcpu_id` after a VM exit.
+    pub(crate) fn exit(&self, vcpu_id: VCpuId) {
+        if let Some(slot) = self.vcpus.get(vcpu_id) {
+            slot.in_guest.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::mock::MockShootdownIf;
+    use core::cell::Cell;
+
+    #[test]
+    fn test_ept_shootdown() {
+        let shootdown = EptShootdown::new(7, 3).with_kick(MockShootdownIf::kick_vcpu);
+        let flushes = Cell::new(0);
+        let flush = || {
+            flushes.set(flushes.get() + 1);
+            Ok(())
+        };
+
+        // Nothing requested yet.
+        shootdown.enter(0, flush).unwrap();
+        assert_eq!(flushes.get(), 0);
+
+        // vCPU 0 is in the guest: it is kicked, and the request completes
+        // once it flushed on its next entry, or while it is out of the guest.
+        MockShootdownIf::take_kicks();
+        let generation = shootdown.request();
+        assert_eq!(MockShootdownIf::take_kicks(), [(7, 0)]);
+        assert!(!shootdown.is_complete(generation));
+        shootdown.exit(0);
+        assert!(shootdown.is_complete(generation));
+        shootdown.enter(0, flush).unwrap();
+        assert_eq!(flushes.get(), 1);
+        shootdown.enter(0, flush).unwrap();
+        assert_eq!(flushes.get(), 1);
+
+        // vCPU 1 was not running: it flushes on its first entry.
+        shootdown.enter(1, flush).unwrap();
+        assert_eq!(flushes.get(), 2);
+        assert!(shootdown.enter(3, flush).is_err());
+
+        shootdown.exit(0);
+        shootdown.exit(1);
+        shootdown.shootdown();
+        assert!(MockShootdownIf::take_kicks().is_empty());
+    }
+
+    #[test]
+    fn test_park_others() {
+        let shootdown = EptShootdown::new(9, 3).with_kick(MockShootdownIf::kick_vcpu);
+        shootdown.enter(1, || Ok(())).unwrap();
+        assert!(!shootdown.is_parked(1));
+
+        // vCPU 1 in the guest is kicked, the others are parked once it exits.
+        MockShootdownIf::take_kicks();
+        shootdown.claim_exclusive(0);
+        assert_eq!(MockShootdownIf::take_kicks(), [(9, 1)]);
+        assert!(!shootdown.others_parked(0));
+        shootdown.exit(1);
+        assert!(shootdown.others_parked(0));
+        assert!(shootdown.is_parked(1) && shootdown.is_parked(2));
+
+        // vCPU 0 runs alone, and only it can let the others run.
+        assert!(!shootdown.is_parked(0));
+        shootdown.enter(0, || Ok(())).unwrap();
+        shootdown.exit(0);
+        shootdown.unpark_others(1);
+        assert!(shootdown.is_parked(1));
+        shootdown.unpark_others(0);
+        assert!(!shootdown.is_parked(1));
+        shootdown.enter(1, || Ok(())).unwrap();
+        assert!(shootdown.park_others(3).is_err());
+    }
+
+    #[test]
+    fn test_ept_shootdown_without_kick() {
+        let shootdown = EptShootdown::new(8, 1);
+        shootdown.enter(0, || Ok(())).unwrap();
+        // The vCPU is not kicked, the request completes once it exits.
+        let generation = shootdown.request();
+        assert!(!shootdown.is_complete(generation));
+        shootdown.exit(0);
+        assert!(shootdown.is_complete(generation));
+    }
+}
@@ -0,0 +1,996 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::vec::Vec;
+
+use axerrno::{AxResult, ax_err, ax_err_type};
+
+use super::definitions::VmxInterruptionType;
+use super::segmentation::{GuestDescriptorTable, GuestSegment, SegmentRegister};
+use super::vmcs::{GuestActivityState, VmxInterruptInfo};
+use crate::exception::GuestException;
+use crate::msr::Msr;
+use crate::regs::GeneralRegisters;
+use x86_64::registers::control::{Cr0Flags, Cr4Flags, EferFlags};
+
+/// The size of the legacy region of the XSAVE area, i.e., the FXSAVE area.
+pub(crate) const FXSAVE_AREA_SIZE: usize = 512;
+/// The size of the legacy region and the XSAVE header.
+pub(crate) const XSAVE_HEADER_END: usize = FXSAVE_AREA_SIZE + 64;
+
+/// The architectural state of a vCPU, saved by
+/// [`VmxArchVCpu::save_state`](crate::VmxArchVCpu::save_state) and loaded by
+/// [`VmxArchVCpu::restore_state`](crate::VmxArchVCpu::restore_state).
+///
+/// The state is plain data, independent of the processor it was saved on,
+/// except for the layout of the XSAVE area.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VcpuState {
+    /// General-purpose registers, except RSP.
+    pub regs: GeneralRegisters,
+    /// RSP.
+    pub rsp: u64,
+    /// RIP.
+    pub rip: u64,
+    /// RFLAGS.
+    pub rflags: u64,
+
+    /// ES segment register.
+    pub es: GuestSegment,
+    /// CS segment register.
+    pub cs: GuestSegment,
+    /// SS segment register.
+    pub ss: GuestSegment,
+    /// DS segment register.
+    pub ds: GuestSegment,
+    /// FS segment register.
+    pub fs: GuestSegment,
+    /// GS segment register.
+    pub gs: GuestSegment,
+    /// LDTR.
+    pub ldtr: GuestSegment,
+    /// Task register.
+    pub tr: GuestSegment,
+    /// GDTR.
+    pub gdtr: GuestDescriptorTable,
+    /// IDTR.
+    pub idtr: GuestDescriptorTable,
+
+    /// CR0, as seen by the guest.
+    pub cr0: u64,
+    /// CR2.
+    pub cr2: u64,
+    /// CR3.
+    pub cr3: u64,
+    /// PDPTE0 to PDPTE3, which PAE paging uses instead of the entries in
+    /// memory until CR3 is loaded again. (SDM Vol. 3A, Section 4.4.1)
+    pub pdptes: [u64; 4],
+    /// CR4, as seen by the guest.
+    pub cr4: u64,
+    /// CR8, bits 7:4 of the TPR in `lapic`.
+    pub cr8: u64,
+    /// XCR0, zero without XSAVE.
+    pub xcr0: u64,
+
+    /// DR0 to DR3.
+    pub dr: [u64; 4],
+    /// DR6.
+    pub dr6: u64,
+    /// DR7.
+    pub dr7: u64,
+
+    /// Model-specific registers.
+    pub msrs: VcpuMsrs,
+
+    /// Interruptibility state, in the VMCS format. (SDM Vol. 3C, Table 25-3)
+    pub interruptibility: u32,
+    /// Activity state.
+    pub activity_state: GuestActivityState,
+    /// Pending debug exceptions, in the VMCS format. (SDM Vol. 3C, Table 25-4)
+    pub pending_dbg_exceptions: u64,
+    /// Events pending injection.
+    pub events: VcpuEvents,
+
+    /// FPU and extended states, in the standard format of the XSAVE area
+    /// with the state components of `xcr0`, or in the FXSAVE format without
+    /// XSAVE. (SDM Vol. 1, Section 13.4)
+    pub fpu: Vec<u8>,
+    /// The 4-KiB virtual-APIC page, holding the local APIC registers.
+    pub lapic: Vec<u8>,
+}
+
+/// The model-specific registers in a [`VcpuState`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VcpuMsrs {
+    /// IA32_EFER.
+    pub efer: u64,
+    /// IA32_PAT.
+    pub pat: u64,
+    /// IA32_DEBUGCTL.
+    pub debugctl: u64,
+    /// IA32_SYSENTER_CS.
+    pub sysenter_cs: u64,
+    /// IA32_SYSENTER_ESP.
+    pub sysenter_esp: u64,
+    /// IA32_SYSENTER_EIP.
+    pub sysenter_eip: u64,
+    /// IA32_STAR.
+    pub star: u64,
+    /// IA32_LSTAR.
+    pub lstar: u64,
+    /// IA32_CSTAR.
+    pub cstar: u64,
+    /// IA32_FMASK.
+    pub fmask: u64,
+    /// IA32_KERNEL_GS_BASE.
+    pub kernel_gs_base: u64,
+    /// IA32_XSS, zero as the supervisor state components are not saved.
+    pub xss: u64,
+}
+
+/// The events pending injection in a [`VcpuState`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VcpuEvents {
+    /// The pending exception.
+    pub exception: Option<GuestException>,
+    /// The length of the instruction which raised the pending exception, if
+    /// it is a software one (#BP or #OF).
+    pub exception_len: u32,
+    /// An event whose delivery was interrupted by a VM exit.
+    pub reinject: Option<VmxInterruptInfo>,
+    /// The length of the instruction which raised the interrupted event, if
+    /// it is a software one.
+    pub reinject_len: u32,
+    /// Whether an NMI is pending.
+    pub nmi: bool,
+    /// Pending external interrupts, one bit per vector.
+    pub interrupts: [u64; 4],
+}
+
+/// Section identifiers of the encoded [`VcpuState`].
+mod section {
+    /// General-purpose registers, RIP and RFLAGS.
+    pub const REGS: u16 = 1;
+    /// Segment and descriptor-table registers.
+    pub const SEGMENTS: u16 = 2;
+    /// Control and debug registers.
+    pub const CONTROL: u16 = 3;
+    /// MSRs, as index and value pairs.
+    pub const MSRS: u16 = 4;
+    /// Interruptibility, activity and pending events.
+    pub const EVENTS: u16 = 5;
+    /// The XSAVE or FXSAVE area.
+    pub const FPU: u16 = 6;
+    /// The virtual-APIC page.
+    pub const LAPIC: u16 = 7;
+    /// The CRC-32 of all the preceding bytes, which ends the encoding.
+    pub const END: u16 = 0xffff;
+
+    /// The sections of a complete state, which must all be present.
+    pub const ALL: [u16; 7] = [REGS, SEGMENTS, CONTROL, MSRS, EVENTS, FPU, LAPIC];
+    /// Set in the flags of a section which cannot be skipped by decoders
+    /// which do not know it.
+    pub const FLAG_REQUIRED: u16 = 1 << 0;
+}
+
+impl VcpuState {
+    /// The magic number starting the encoding.
+    pub const MAGIC: [u8; 4] = *b"XVCS";
+    /// The version of the encoding, incremented on incompatible changes
+    /// only.
+    pub const FORMAT_VERSION: u16 = 1;
+
+    /// Encode the state in a versioned binary format, which can be decoded
+    /// by [`VcpuState::decode`] with other versions of this crate.
+    ///
+    /// The encoding starts with [`VcpuState::MAGIC`], the format version and
+    /// 16 bits of flags, followed by sections. Each section has a 16-bit
+    /// identifier, 16 bits of flags and a 32-bit payload length, then the
+    /// payload. Integers are little-endian. The last section holds the CRC-32
+    /// of all the preceding bytes.
+    ///
+    /// Compatible changes add sections, or fields at the end of a section
+    /// payload, which older decoders skip. Sections are skippable unless
+    /// flagged as required, as those of the complete state are.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.bytes(&Self::MAGIC);
+        w.u16(Self::FORMAT_VERSION);
+        w.u16(0);
+
+        w.required_section(section::REGS, |w| {
+            for index in 0..16 {
+                w.u64(match index {
+                    4 => self.rsp,
+                    _ => self.regs.get_reg_of_index(index),
+                });
+            }
+            w.u64(self.rip);
+            w.u64(self.rflags);
+        });
+        w.required_section(section::SEGMENTS, |w| {
+            for seg in self.segments() {
+                w.u16(seg.selector);
+                w.u32(seg.access_rights);
+                w.u32(seg.limit);
+                w.u64(seg.base);
+            }
+            for table in [self.gdtr, self.idtr] {
+                w.u16(table.limit);
+                w.u64(table.base);
+            }
+        });
+        w.required_section(section::CONTROL, |w| {
+            for value in [self.cr0, self.cr2, self.cr3, self.cr4, self.cr8, self.xcr0] {
+                w.u64(value);
+            }
+            for value in self.pdptes {
+                w.u64(value);
+            }
+            for value in self.dr {
+                w.u64(value);
+            }
+            w.u64(self.dr6);
+            w.u64(self.dr7);
+        });
+        w.required_section(section::MSRS, |w| {
+            let msrs = self.msrs.entries();
+            w.u32(msrs.len() as u32);
+            for (msr, value) in msrs {
+                w.u32(msr as u32);
+                w.u64(value);
+            }
+        });
+        w.required_section(section::EVENTS, |w| {
+            w.u32(self.interruptibility);
+            w.u32(self.activity_state as u32);
+            w.u64(self.pending_dbg_exceptions);
+            let events = &self.events;
+            w.u8(events.exception.is_some() as u8);
+            let exception = events.exception.unwrap_or(GuestException::DivideError);
+            w.u8(exception.vector());
+            w.option_u32(exception.error_code());
+            w.u64(match exception {
+                GuestException::PageFault { cr2, .. } => cr2,
+                GuestException::Debug { dr6 } => dr6,
+                _ => 0,
+            });
+            w.u32(events.exception_len);
+            w.u8(events.reinject.is_some() as u8);
+            let reinject = events.reinject.unwrap_or(VmxInterruptInfo::from(0, None));
+            w.u8(reinject.vector);
+            w.u8(reinject.int_type as u8);
+            w.option_u32(reinject.err_code);
+            w.u8(reinject.valid as u8);
+            w.u32(events.reinject_len);
+            w.u8(events.nmi as u8);
+            for bits in events.interrupts {
+                w.u64(bits);
+            }
+        });
+        w.required_section(section::FPU, |w| w.bytes(&self.fpu));
+        w.required_section(section::LAPIC, |w| w.bytes(&self.lapic));
+        let crc = crc32(&w.buf);
+        w.section(section::END, |w| w.u32(crc));
+        w.buf
+    }
+
+    /// Decode a state encoded by [`VcpuState::encode`], checking its
+    /// integrity and the validity of its fields, see [`VcpuState::check`].
+    ///
+    /// Unknown sections are skipped unless marked as required, and so are
+    /// unknown fields at the end of known sections.
+    pub fn decode(bytes: &[u8]) -> AxResult<Self> {
+        let mut r = Reader::new(bytes);
+        if r.bytes(4)? != Self::MAGIC {
+            return ax_err!(InvalidData, "not an encoded vCPU state");
+        }
+        if r.u16()? != Self::FORMAT_VERSION {
+            return ax_err!(Unsupported, "unsupported vCPU state format version");
+        }
+        r.u16()?;
+
+        let mut state = Self::empty();
+        let mut found = [false; section::ALL.len()];
+        loop {
+            let section_start = r.pos;
+            let id = r.u16()?;
+            let flags = r.u16()?;
+            let len = r.u32()? as usize;
+            let mut p = Reader::new(r.bytes(len)?);
+            match id {
+                section::END => {
+                    if p.u32()? != crc32(&bytes[..section_start]) {
+                        return ax_err!(InvalidData, "vCPU state checksum mismatch");
+                    }
+                    if r.pos != bytes.len() {
+                        return ax_err!(InvalidData, "trailing bytes after vCPU state");
+                    }
+                    break;
+                }
+                _ => match section::ALL.iter().position(|&known| known == id) {
+                    Some(index) if found[index] => {
+                        return ax_err!(InvalidData, "duplicate vCPU state section");
+                    }
+                    Some(index) => {
+                        found[index] = true;
+                        state.decode_section(id, &mut p)?;
+                    }
+                    None if flags & section::FLAG_REQUIRED != 0 => {
+                        return ax_err!(Unsupported, "unknown required vCPU state section");
+                    }
+                    None => {}
+                },
+            }
+        }
+        if found.contains(&false) {
+            return ax_err!(InvalidData, "missing vCPU state section");
+        }
+        state
+            .check()
+            .map_err(|_| ax_err_type!(InvalidData, "invalid vCPU register state"))?;
+        Ok(state)
+    }
+
+    /// Check the architectural constraints on the registers which do not
+    /// depend on the processor: the reserved bits of CR0, CR4, CR8, DR6, DR7
+    /// and IA32_EFER, their consistency, and the format of the segment and
+    /// descriptor-table registers. (SDM Vol. 3C, Section 27.3.1)
+    ///
+    /// Bases are checked for 57-bit linear addresses, the processor support
+    /// of LA57 and of the CR4 bits is checked when the state is restored.
+    pub fn check(&self) -> AxResult {
+        let cr0 = Cr0Flags::from_bits(self.cr0)
+            .ok_or_else(|| ax_err_type!(InvalidInput, "reserved CR0 bits set"))?;
+        if cr0.contains(Cr0Flags::PAGING) && !cr0.contains(Cr0Flags::PROTECTED_MODE_ENABLE) {
+            return ax_err!(InvalidInput, "CR0.PG set without CR0.PE");
+        }
+        if cr0.contains(Cr0Flags::NOT_WRITE_THROUGH) && !cr0.contains(Cr0Flags::CACHE_DISABLE) {
+            return ax_err!(InvalidInput, "CR0.NW set without CR0.CD");
+        }
+        let cr4 = Cr4Flags::from_bits(self.cr4)
+            .ok_or_else(|| ax_err_type!(InvalidInput, "reserved CR4 bits set"))?;
+        // CR8 holds the TPR in bits 3:0. (SDM Vol. 3A, Section 10.8.6.1)
+        if self.cr8 > 0xf {
+            return ax_err!(InvalidInput, "reserved CR8 bits set");
+        }
+        if self.dr6 >> 32 != 0 || self.dr7 >> 32 != 0 {
+            return ax_err!(InvalidInput, "reserved debug register bits set");
+        }
+        let efer = EferFlags::from_bits(self.msrs.efer)
+            .ok_or_else(|| ax_err_type!(InvalidInput, "reserved IA32_EFER bits set"))?;
+        let lma = efer.contains(EferFlags::LONG_MODE_ACTIVE);
+        if lma != (efer.contains(EferFlags::LONG_MODE_ENABLE) && cr0.contains(Cr0Flags::PAGING)) {
+            return ax_err!(
+                InvalidInput,
+                "IA32_EFER.LMA inconsistent with LME and CR0.PG"
+            );
+        }
+        if lma && !cr4.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION) {
+            return ax_err!(InvalidInput, "IA-32e mode without CR4.PAE");
+        }
+        use SegmentRegister::*;
+        for (register, segment) in [Es, Cs, Ss, Ds, Fs, Gs, Ldtr, Tr]
+            .into_iter()
+            .zip(self.segments())
+        {
+            segment.check(register, true)?;
+        }
+        self.gdtr.check(true)?;
+        self.idtr.check(true)
+    }
+
+    fn decode_section(&mut self, id: u16, p: &mut Reader) -> AxResult {
+        match id {
+            section::REGS => {
+                for index in 0..16 {
+                    let value = p.u64()?;
+                    match index {
+                        4 => self.rsp = value,
+                        _ => self.regs.set_reg_of_index(index, value),
+                    }
+                }
+                self.rip = p.u64()?;
+                self.rflags = p.u64()?;
+            }
+            section::SEGMENTS => {
+                for seg in self.segments_mut() {
+                    seg.selector = p.u16()?;
+                    seg.access_rights = p.u32()?;
+                    seg.limit = p.u32()?;
+                    seg.base = p.u64()?;
+                }
+                for table in [&mut self.gdtr, &mut self.idtr] {
+                    table.limit = p.u16()?;
+                    table.base = p.u64()?;
+                }
+            }
+            section::CONTROL => {
+                for value in [
+                    &mut self.cr0,
+                    &mut self.cr2,
+                    &mut self.cr3,
+                    &mut self.cr4,
+                    &mut self.cr8,
+                    &mut self.xcr0,
+                ] {
+                    *value = p.u64()?;
+                }
+                for value in &mut self.pdptes {
+                    *value = p.u64()?;
+                }
+                for value in &mut self.dr {
+                    *value = p.u64()?;
+                }
+                self.dr6 = p.u64()?;
+                self.dr7 = p.u64()?;
+            }
+            section::MSRS => {
+                for _ in 0..p.u32()? {
+                    let (msr, value) = (p.u32()?, p.u64()?);
+                    self.msrs.set(msr, value);
+                }
+            }
+            section::EVENTS => {
+                let invalid = || ax_err_type!(InvalidData, "invalid vCPU event state");
+                self.interruptibility = p.u32()?;
+                self.activity_state =
+                    GuestActivityState::try_from(p.u32()?).map_err(|_| invalid())?;
+                self.pending_dbg_exceptions = p.u64()?;
+                let (has_exception, vector) = (p.bool()?, p.u8()?);
+                let (err_code, payload) = (p.option_u32()?, p.u64()?);
+                self.events.exception_len = p.u32()?;
+                self.events.exception = if has_exception {
+                    let exception = GuestException::from_vector(vector, err_code, payload)
+                        .ok_or_else(invalid)?;
+                    if exception.error_code().is_some() != err_code.is_some() {
+                        return Err(invalid());
+                    }
+                    let info = VmxInterruptInfo::from_exception(&exception);
+                    if !instr_len_valid(&info, self.events.exception_len) {
+                        return Err(invalid());
+                    }
+                    Some(exception)
+                } else {
+                    None
+                };
+                let (has_reinject, vector, int_type) = (p.bool()?, p.u8()?, p.u8()?);
+                let (err_code, valid) = (p.option_u32()?, p.bool()?);
+                self.events.reinject_len = p.u32()?;
+                self.events.reinject = if has_reinject {
+                    let info = VmxInterruptInfo {
+                        vector,
+                        int_type: VmxInterruptionType::try_from(int_type).map_err(|_| invalid())?,
+                        err_code,
+                        valid,
+                    };
+                    if !instr_len_valid(&info, self.events.reinject_len) {
+                        return Err(invalid());
+                    }
+                    Some(info)
+                } else {
+                    None
+                };
+                self.events.nmi = p.bool()?;
+                for bits in &mut self.events.interrupts {
+                    *bits = p.u64()?;
+                }
+            }
+            section::FPU => {
+                self.fpu = p.rest().to_vec();
+                if self.fpu.len() < FXSAVE_AREA_SIZE {
+                    return ax_err!(InvalidData, "FPU state too small");
+                }
+            }
+            section::LAPIC => {
+                self.lapic = p.rest().to_vec();
+                if self.lapic.len() != 0x1000 {
+                    return ax_err!(InvalidData, "virtual-APIC page size mismatch");
+                }
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// A state with all fields zero, to be filled by the decoder.
+    pub(crate) fn empty() -> Self {
+        Self {
+            regs: GeneralRegisters::default(),
+            rsp: 0,
+            rip: 0,
+            rflags: 0,
+            es: GuestSegment::default(),
+            cs: GuestSegment::default(),
+            ss: GuestSegment::default(),
+            ds: GuestSegment::default(),
+            fs: GuestSegment::default(),
+            gs: GuestSegment::default(),
+            ldtr: GuestSegment::default(),
+            tr: GuestSegment::default(),
+            gdtr: GuestDescriptorTable::default(),
+            idtr: GuestDescriptorTable::default(),
+            cr0: 0,
+            cr2: 0,
+            cr3: 0,
+            pdptes: [0; 4],
+            cr4: 0,
+            cr8: 0,
+            xcr0: 0,
+            dr: [0; 4],
+            dr6: 0,
+            dr7: 0,
+            msrs: VcpuMsrs::default(),
+            interruptibility: 0,
+            activity_state: GuestActivityState::Active,
+            pending_dbg_exceptions: 0,
+            events: VcpuEvents::default(),
+            fpu: Vec::new(),
+            lapic: Vec::new(),
+        }
+    }
+
+    /// The segment registers, in encoding order.
+    fn segments(&self) -> [GuestSegment; 8] {
+        [
+            self.es, self.cs, self.ss, self.ds, self.fs, self.gs, self.ldtr, self.tr,
+        ]
+    }
+
+    fn segments_mut(&mut self) -> [&mut GuestSegment; 8] {
+        [
+            &mut self.es,
+            &mut self.cs,
+            &mut self.ss,
+            &mut self.ds,
+            &mut self.fs,
+            &mut self.gs,
+            &mut self.ldtr,
+            &mut self.tr,
+        ]
+    }
+}
+
+impl VcpuMsrs {
+    /// The MSRs with their indices.
+    fn entries(&self) -> [(Msr, u64); 12] {
+        [
+            (Msr::IA32_EFER, self.efer),
+            (Msr::IA32_PAT, self.pat),
+            (Msr::IA32_DEBUGCTL, self.debugctl),
+            (Msr::IA32_SYSENTER_CS, self.sysenter_cs),
+            (Msr::IA32_SYSENTER_ESP, self.sysenter_esp),
+            (Msr::IA32_SYSENTER_EIP, self.sysenter_eip),
+            (Msr::IA32_STAR, self.star),
+            (Msr::IA32_LSTAR, self.lstar),
+            (Msr::IA32_CSTAR, self.cstar),
+            (Msr::IA32_FMASK, self.fmask),
+            (Msr::IA32_KERNEL_GSBASE, self.kernel_gs_base),
+            (Msr::IA32_XSS, self.xss),
+        ]
+    }
+
+    /// Set the MSR with index `msr`, ignoring unknown ones.
+    fn set(&mut self, msr: u32, value: u64) {
+        let field = match msr {
+            _ if msr == Msr::IA32_EFER as u32 => &mut self.efer,
+            _ if msr == Msr::IA32_PAT as u32 => &mut self.pat,
+            _ if msr == Msr::IA32_DEBUGCTL as u32 => &mut self.debugctl,
+            _ if msr == Msr::IA32_SYSENTER_CS as u32 => &mut self.sysenter_cs,
+            _ if msr == Msr::IA32_SYSENTER_ESP as u32 => &mut self.sysenter_esp,
+            _ if msr == Msr::IA32_SYSENTER_EIP as u32 => &mut self.sysenter_eip,
+            _ if msr == Msr::IA32_STAR as u32 => &mut self.star,
+            _ if msr == Msr::IA32_LSTAR as u32 => &mut self.lstar,
+            _ if msr == Msr::IA32_CSTAR as u32 => &mut self.cstar,
+            _ if msr == Msr::IA32_FMASK as u32 => &mut self.fmask,
+            _ if msr == Msr::IA32_KERNEL_GSBASE as u32 => &mut self.kernel_gs_base,
+            _ if msr == Msr::IA32_XSS as u32 => &mut self.xss,
+            _ => return,
+        };
+        *field = value;
+    }
+}
+
+/// Little-endian encoder.
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn option_u32(&mut self, value: Option<u32>) {
+        self.u8(value.is_some() as u8);
+        self.u32(value.unwrap_or(0));
+    }
+
+    /// Write a section which decoders not knowing it skip, with the payload
+    /// written by `f`.
+    fn section(&mut self, id: u16, f: impl FnOnce(&mut Self)) {
+        self.section_with_flags(id, 0, f);
+    }
+
+    /// Write a section which decoders must know.
+    fn required_section(&mut self, id: u16, f: impl FnOnce(&mut Self)) {
+        self.section_with_flags(id, section::FLAG_REQUIRED, f);
+    }
+
+    fn section_with_flags(&mut self, id: u16, flags: u16, f: impl FnOnce(&mut Self)) {
+        self.u16(id);
+        self.u16(flags);
+        let len_offset = self.buf.len();
+        self.u32(0);
+        f(self);
+        let len = (self.buf.len() - len_offset - 4) as u32;
+        self.buf[len_offset..len_offset + 4].copy_from_slice(&len.to_le_bytes());
+    }
+}
+
+/// Little-endian decoder, failing on truncated input.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> AxResult<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| ax_err_type!(InvalidData, "truncated vCPU state"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.pos..];
+        self.pos = self.data.len();
+        rest
+    }
+
+    fn u8(&mut self) -> AxResult<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn bool(&mut self) -> AxResult<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => ax_err!(InvalidData, "invalid boolean in vCPU state"),
+        }
+    }
+
+    fn u16(&mut self) -> AxResult<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> AxResult<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> AxResult<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn option_u32(&mut self) -> AxResult<Option<u32>> {
+        let (present, value) = (self.bool()?, self.u32()?);
+        Ok(present.then_some(value))
+    }
+}
+
+/// CRC-32 (IEEE 802.3) of `data`.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+/// Check that `area` can be loaded by XRSTOR in the standard format with the
+/// state components of `xcr0`, which would raise #GP otherwise.
+/// (SDM Vol. 1, Section 13.8.1)
+pub(crate) fn check_xsave_area(area: &[u8], xcr0: u64, size: usize) -> AxResult {
+    if area.len() < size.max(XSAVE_HEADER_END) {
+        return ax_err!(InvalidInput, "XSAVE area too small");
+    }
+    check_mxcsr(area)?;
+    let header = |offset: usize| u64::from_le_bytes(area[offset..offset + 8].try_into().unwrap());
+    let xstate_bv = header(FXSAVE_AREA_SIZE);
+    let xcomp_bv = header(FXSAVE_AREA_SIZE + 8);
+    let reserved = (FXSAVE_AREA_SIZE + 16..XSAVE_HEADER_END).step_by(8);
+    if xstate_bv & !xcr0 != 0 || xcomp_bv != 0 || reserved.map(header).any(|v| v != 0) {
+        return ax_err!(InvalidData, "invalid XSAVE header");
+    }
+    Ok(())
+}
+
+/// Check that `area` can be loaded by FXRSTOR, which would raise #GP
+/// otherwise. (SDM Vol. 1, Section 10.5.1)
+pub(crate) fn check_fxsave_area(area: &[u8]) -> AxResult {
+    if area.len() != FXSAVE_AREA_SIZE {
+        return ax_err!(InvalidInput, "FXSAVE area size mismatch");
+    }
+    check_mxcsr(area)
+}
+
+/// Whether `instr_len` can be injected with the software event `info`, which
+/// VM entry requires to be 1 to 15 bytes long. (SDM Vol. 3C, Section 26.2.1.3)
+fn instr_len_valid(info: &VmxInterruptInfo, instr_len: u32) -> bool {
+    !info.int_type.is_soft() || (1..=15).contains(&instr_len)
+}
+
+fn check_mxcsr(area: &[u8]) -> AxResult {
+    // Bits 31:16 of MXCSR are reserved.
+    let mxcsr = u32::from_le_bytes(area[24..28].try_into().unwrap());
+    if mxcsr >> 16 != 0 {
+        return ax_err!(InvalidData, "invalid MXCSR");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn sample_state() -> VcpuState {
+        let mut state = VcpuState::empty();
+        for index in 0..16 {
+            if index != 4 {
+                state
+                    .regs
+                    .set_reg_of_index(index, 0x1111 * (index as u64 + 1));
+            }
+        }
+        state.rsp = 0xffff_8000_0000_8000;
+        state.rip = 0xffff_8000_0010_0000;
+        state.rflags = 0x246;
+        state.cs = GuestSegment {
+            selector: 0x8,
+            base: 0,
+            limit: 0xffff_ffff,
+            access_rights: 0xa09b,
+        };
+        state.tr.access_rights = 0x8b;
+        // The other segments are unusable.
+        for segment in [
+            &mut state.es,
+            &mut state.ss,
+            &mut state.ds,
+            &mut state.fs,
+            &mut state.gs,
+            &mut state.ldtr,
+        ] {
+            segment.access_rights = 0x1_0000;
+        }
+        state.gdtr = GuestDescriptorTable {
+            base: 0x1000,
+            limit: 0x27,
+        };
+        state.cr0 = 0x8005_0033;
+        state.cr3 = 0x20_0000;
+        state.cr4 = 0x20;
+        state.pdptes = [0x3001, 0x4001, 0, 0x6001];
+        state.cr8 = 0xa;
+        state.xcr0 = 0b111;
+        state.dr = [1, 2, 3, 4];
+        state.dr7 = 0x400;
+        state.msrs.efer = 0xd01;
+        state.msrs.lstar = 0xffff_8000_0000_1000;
+        state.msrs.xss = 0x100;
+        state.interruptibility = 1;
+        state.activity_state = GuestActivityState::Hlt;
+        state.events = VcpuEvents {
+            exception: Some(GuestException::PageFault {
+                err_code: 2,
+                cr2: 0xdead_b000,
+            }),
+            exception_len: 0,
+            reinject: Some(VmxInterruptInfo::from(3, None)),
+            reinject_len: 1,
+            nmi: true,
+            interrupts: [0, 1 << 48, 0, 1 << 63],
+        };
+        state.fpu = (0..832).map(|i| i as u8).collect();
+        state.lapic = alloc::vec![0x5a; 0x1000];
+        state
+    }
+
+    /// Append a section before the END section, and recompute the CRC.
+    fn insert_section(bytes: &[u8], id: u16, required: bool, payload: &[u8]) -> Vec<u8> {
+        let end = bytes.len() - 12;
+        let mut w = Writer::default();
+        w.bytes(&bytes[..end]);
+        if required {
+            w.required_section(id, |w| w.bytes(payload));
+        } else {
+            w.section(id, |w| w.bytes(payload));
+        }
+        let crc = crc32(&w.buf);
+        w.section(section::END, |w| w.u32(crc));
+        w.buf
+    }
+
+    /// Remove a section, and recompute the CRC.
+    fn remove_section(bytes: &[u8], id: u16) -> Vec<u8> {
+        let mut r = Reader::new(bytes);
+        r.bytes(8).unwrap();
+        let mut kept = bytes[..8].to_vec();
+        loop {
+            let start = r.pos;
+            let (section_id, _, len) = (r.u16().unwrap(), r.u16().unwrap(), r.u32().unwrap());
+            r.bytes(len as usize).unwrap();
+            if section_id == section::END {
+                kept.extend_from_slice(&bytes[start..]);
+                return insert_section(&kept, 0x100, false, &[]);
+            }
+            if section_id != id {
+                kept.extend_from_slice(&bytes[start..r.pos]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let state = sample_state();
+        let bytes = state.encode();
+        assert_eq!(bytes[..4], VcpuState::MAGIC);
+        assert_eq!(VcpuState::decode(&bytes).unwrap(), state);
+
+        let mut state = sample_state();
+        state.events = VcpuEvents::default();
+        state.fpu.truncate(512);
+        assert_eq!(VcpuState::decode(&state.encode()).unwrap(), state);
+    }
+
+    #[test]
+    fn test_decode_forward_compatible() {
+        let state = sample_state();
+        // The sections of the complete state are required, the others are
+        // skippable by default.
+        let bytes = state.encode();
+        assert_eq!(
+            bytes[8..12],
+            [section::REGS as u8, 0, section::FLAG_REQUIRED as u8, 0]
+        );
+        let bytes = insert_section(&bytes, 0x100, false, &[1, 2, 3]);
+        assert_eq!(bytes[bytes.len() - 23..bytes.len() - 19], [0, 1, 0, 0]);
+
+        // Unknown optional sections are skipped, unknown required ones not.
+        assert_eq!(VcpuState::decode(&bytes).unwrap(), state);
+        let bytes = insert_section(&state.encode(), 0x100, true, &[]);
+        assert!(VcpuState::decode(&bytes).is_err());
+
+        // Unknown fields at the end of a section, and unknown MSRs.
+        let mut w = Writer::default();
+        w.u32(2);
+        w.u32(Msr::IA32_LSTAR as u32);
+        w.u64(0x1234);
+        w.u32(0x4000_0000);
+        w.u64(1);
+        w.u64(0xdead);
+        let bytes = remove_section(&state.encode(), section::MSRS);
+        let bytes = insert_section(&bytes, section::MSRS, true, &w.buf);
+        let decoded = VcpuState::decode(&bytes).unwrap();
+        assert_eq!(decoded.msrs.lstar, 0x1234);
+        assert_eq!(decoded.msrs.efer, 0);
+    }
+
+    #[test]
+    fn test_decode_validation() {
+        let bytes = sample_state().encode();
+        // Truncated, or corrupted.
+        for len in [0, 3, 8, 100, bytes.len() - 1] {
+            assert!(VcpuState::decode(&bytes[..len]).is_err());
+        }
+        let mut corrupted = bytes.clone();
+        corrupted[40] ^= 1;
+        assert!(VcpuState::decode(&corrupted).is_err());
+        // Another version.
+        let mut other = bytes.clone();
+        other[4] = 2;
+        assert!(VcpuState::decode(&other).is_err());
+        // A duplicate section.
+        let dup = insert_section(&bytes, section::LAPIC, true, &[0; 0x1000]);
+        assert!(VcpuState::decode(&dup).is_err());
+        // A missing section.
+        let missing = remove_section(&bytes, section::EVENTS);
+        assert!(VcpuState::decode(&missing).is_err());
+        // An invalid field.
+        let mut state = sample_state();
+        state.lapic.pop();
+        assert!(VcpuState::decode(&state.encode()).is_err());
+        // A software event without the length of its instruction.
+        let mut state = sample_state();
+        state.events.reinject_len = 0;
+        assert!(VcpuState::decode(&state.encode()).is_err());
+    }
+
+    #[test]
+    fn test_check_registers() {
+        assert!(sample_state().check().is_ok());
+        let invalid: [fn(&mut VcpuState); 9] = [
+            |state| state.cr0 |= 1 << 32,
+            |state| state.cr0 &= !1,
+            |state| state.cr4 |= 1 << 15,
+            |state| state.cr8 = 0x10,
+            |state| state.dr7 |= 1 << 32,
+            |state| state.msrs.efer |= 1 << 2,
+            |state| state.msrs.efer &= !(1 << 10),
+            |state| state.cr4 = 0,
+            |state| state.ds.access_rights = 0x13,
+        ];
+        for change in invalid {
+            let mut state = sample_state();
+            change(&mut state);
+            assert!(state.check().is_err());
+            assert!(VcpuState::decode(&state.encode()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_check_xsave_area() {
+        let mut area = vec![0u8; 832];
+        area[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());
+        // x87 and SSE states in use, with XCR0 = x87 | SSE | AVX.
+        area[512] = 0b11;
+        assert!(check_xsave_area(&area, 0b111, 832).is_ok());
+        assert!(check_xsave_area(&area[..576], 0b111, 832).is_err());
+        // A state component not enabled in XCR0.
+        assert!(check_xsave_area(&area, 0b1, 832).is_err());
+        // The compacted format, or reserved header bytes.
+        area[512 + 15] = 0x80;
+        assert!(check_xsave_area(&area, 0b111, 832).is_err());
+        area[512 + 15] = 0;
+        area[560] = 1;
+        assert!(check_xsave_area(&area, 0b111, 832).is_err());
+        area[560] = 0;
+        // Reserved MXCSR bits.
+        area[26] = 1;
+        assert!(check_xsave_area(&area, 0b111, 832).is_err());
+        assert!(check_fxsave_area(&area[..512]).is_err());
+        area[26] = 0;
+        assert!(check_fxsave_area(&area[..512]).is_ok());
+        assert!(check_fxsave_area(&area).is_err());
+    }
+}
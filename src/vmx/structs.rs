@@ -18,7 +18,7 @@ use bitflags::bitflags;
 use memory_addr::PAGE_SIZE_4K as PAGE_SIZE;
 
 use axaddrspace::HostPhysAddr;
-use axerrno::AxResult;
+use axerrno::{AxResult, ax_err};
 use axvisor_api::memory::PhysFrame;
 
 use crate::msr::{Msr, MsrReadWrite};
@@ -253,6 +253,112 @@ impl FeatureControl {
     }
 }
 
+/// EPTP list, selecting the EPTP loaded by EPTP switching with VMFUNC.
+/// (SDM Vol. 3C, Section 26.5.6.3)
+#[derive(Debug)]
+pub struct EptpList {
+    frame: PhysFrame,
+}
+
+impl EptpList {
+    /// The number of EPTPs in the list.
+    pub const LEN: usize = 512;
+
+    pub fn new() -> AxResult<Self> {
+        Ok(Self {
+            frame: PhysFrame::alloc_zero()?,
+        })
+    }
+
+    pub fn phys_addr(&self) -> HostPhysAddr {
+        self.frame.start_paddr()
+    }
+
+    fn entries(&self) -> &[u64] {
+        unsafe { core::slice::from_raw_parts(self.frame.as_mut_ptr() as *const u64, Self::LEN) }
+    }
+
+    /// The EPTP at `index`, or `None` if the entry is unused.
+    pub fn get(&self, index: usize) -> Option<u64> {
+        self.entries().get(index).copied().filter(|&eptp| eptp != 0)
+    }
+
+    /// Set the EPTP at `index`.
+    pub fn set(&mut self, index: usize, eptp: u64) {
+        let entries = unsafe {
+            core::slice::from_raw_parts_mut(self.frame.as_mut_ptr() as *mut u64, Self::LEN)
+        };
+        entries[index] = eptp;
+    }
+
+    /// The EPTPs of the used entries.
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.entries().iter().copied().filter(|&eptp| eptp != 0)
+    }
+
+    /// The index of `eptp` in the list.
+    pub fn position(&self, eptp: u64) -> Option<usize> {
+        self.entries().iter().position(|&entry| entry == eptp)
+    }
+}
+
+bitflags! {
+    /// IA32_VMX_EPT_VPID_CAP flags. (SDM Vol. 3D, Appendix A.10)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct EptVpidCapFlags: u64 {
+        /// EPT translations may be execute-only.
+        const EXECUTE_ONLY = 1 << 0;
+        /// An EPT page-walk length of 4 is supported.
+        const PAGE_WALK_LENGTH_4 = 1 << 6;
+        /// An EPT page-walk length of 5 is supported.
+        const PAGE_WALK_LENGTH_5 = 1 << 7;
+        /// The EPT paging-structure memory type may be uncacheable (UC).
+        const MEM_TYPE_UC = 1 << 8;
+        /// The EPT paging-structure memory type may be write-back (WB).
+        const MEM_TYPE_WB = 1 << 14;
+        /// EPT PDEs may map 2-MByte pages.
+        const PAGE_2M = 1 << 16;
+        /// EPT PDPTEs may map 1-GByte pages.
+        const PAGE_1G = 1 << 17;
+        /// The INVEPT instruction is supported.
+        const INVEPT = 1 << 20;
+        /// Accessed and dirty flags for EPT are supported.
+        const ACCESSED_DIRTY = 1 << 21;
+        /// EPT violations may report advanced VM-exit information.
+        const ADVANCED_EXIT_INFO = 1 << 22;
+        /// The supervisor shadow-stack control of the EPTP is supported.
+        const SUPERVISOR_SHADOW_STACK = 1 << 23;
+        /// The single-context INVEPT type is supported.
+        const INVEPT_SINGLE_CONTEXT = 1 << 25;
+        /// The all-context INVEPT type is supported.
+        const INVEPT_ALL_CONTEXT = 1 << 26;
+        /// The INVVPID instruction is supported.
+        const INVVPID = 1 << 32;
+        /// The individual-address INVVPID type is supported.
+        const INVVPID_INDIVIDUAL_ADDRESS = 1 << 40;
+        /// The single-context INVVPID type is supported.
+        const INVVPID_SINGLE_CONTEXT = 1 << 41;
+        /// The all-context INVVPID type is supported.
+        const INVVPID_ALL_CONTEXT = 1 << 42;
+        /// The single-context-retaining-globals INVVPID type is supported.
+        const INVVPID_SINGLE_CONTEXT_RETAINING_GLOBALS = 1 << 43;
+    }
+}
+
+/// VMX capabilities for EPT and VPID. (SDM Vol. 3D, Appendix A.10)
+pub struct EptVpidCap;
+
+impl MsrReadWrite for EptVpidCap {
+    const MSR: Msr = Msr::IA32_VMX_EPT_VPID_CAP;
+}
+
+impl EptVpidCap {
+    /// Read the current IA32_VMX_EPT_VPID_CAP flags.
+    pub fn read() -> EptVpidCapFlags {
+        EptVpidCapFlags::from_bits_truncate(Self::read_raw())
+    }
+}
+
 bitflags! {
     /// Extended-Page-Table Pointer. (SDM Vol. 3C, Section 24.6.11)
     #[derive(Debug)]
@@ -271,16 +377,116 @@ bitflags! {
         const WALK_LENGTH_3 = 2 << 3;
         /// EPT page-walk length 4.
         const WALK_LENGTH_4 = 3 << 3;
+        /// EPT page-walk length 5.
+        const WALK_LENGTH_5 = 4 << 3;
         /// Setting this control to 1 enables accessed and dirty flags for EPT.
         const ENABLE_ACCESSED_DIRTY = 1 << 6;
+        /// Setting this control to 1 enables enforcement of access rights for
+        /// supervisor shadow-stack pages.
+        const ENABLE_SUPERVISOR_SHADOW_STACK = 1 << 7;
+    }
+}
+
+/// EPT paging-structure memory type. (SDM Vol. 3C, Section 25.6.11)
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EptMemoryType {
+    /// Uncacheable (UC).
+    Uncacheable = 0,
+    /// Write-back (WB).
+    WriteBack = 6,
+}
+
+/// The EPT pointer settings chosen by the VMM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptConfig {
+    /// The number of levels of the EPT, 4 or 5, which must be those of the
+    /// EPT root, see [`VmxArchVCpu::set_ept_levels`](crate::VmxArchVCpu::set_ept_levels).
+    pub levels: usize,
+    /// The memory type used to access the EPT paging structures.
+    pub mem_type: EptMemoryType,
+    /// Whether to enable accessed and dirty flags for EPT, or `None` to
+    /// enable them when supported.
+    pub accessed_dirty: Option<bool>,
+    /// Whether to enforce access rights for supervisor shadow-stack pages.
+    pub supervisor_shadow_stack: bool,
+}
+
+impl Default for EptConfig {
+    fn default() -> Self {
+        Self {
+            levels: 4,
+            mem_type: EptMemoryType::WriteBack,
+            accessed_dirty: None,
+            supervisor_shadow_stack: false,
+        }
+    }
+}
+
+impl EptConfig {
+    /// Build the EPTP for the EPT rooted at `root_paddr`, checking that the
+    /// settings are supported according to the `caps` read from
+    /// IA32_VMX_EPT_VPID_CAP.
+    pub fn eptp(&self, root_paddr: HostPhysAddr, caps: EptVpidCapFlags) -> AxResult<EPTPointer> {
+        let walk_length = match self.levels {
+            4 => EptVpidCapFlags::PAGE_WALK_LENGTH_4,
+            5 => EptVpidCapFlags::PAGE_WALK_LENGTH_5,
+            _ => return ax_err!(InvalidInput, "EPT must have 4 or 5 levels"),
+        };
+        if !caps.contains(walk_length) {
+            return ax_err!(Unsupported, "EPT page-walk length not supported");
+        }
+        let mem_type = match self.mem_type {
+            EptMemoryType::Uncacheable => EptVpidCapFlags::MEM_TYPE_UC,
+            EptMemoryType::WriteBack => EptVpidCapFlags::MEM_TYPE_WB,
+        };
+        if !caps.contains(mem_type) {
+            return ax_err!(Unsupported, "EPT memory type not supported");
+        }
+        let accessed_dirty = match self.accessed_dirty {
+            None => caps.contains(EptVpidCapFlags::ACCESSED_DIRTY),
+            Some(true) if !caps.contains(EptVpidCapFlags::ACCESSED_DIRTY) => {
+                return ax_err!(Unsupported, "EPT accessed and dirty flags not supported");
+            }
+            Some(enable) => enable,
+        };
+        if self.supervisor_shadow_stack && !caps.contains(EptVpidCapFlags::SUPERVISOR_SHADOW_STACK)
+        {
+            return ax_err!(
+                Unsupported,
+                "EPT supervisor shadow-stack control not supported"
+            );
+        }
+
+        let mut eptp = EPTPointer::from_table_phys(root_paddr, self.levels, accessed_dirty);
+        eptp.remove(EPTPointer::MEM_TYPE_WB);
+        eptp |= EPTPointer::from_bits_retain(self.mem_type as u64);
+        eptp.set(
+            EPTPointer::ENABLE_SUPERVISOR_SHADOW_STACK,
+            self.supervisor_shadow_stack,
+        );
+        Ok(eptp)
     }
 }
 
 impl EPTPointer {
-    pub fn from_table_phys(pml4_paddr: HostPhysAddr) -> Self {
-        let aligned_addr = pml4_paddr.as_usize() & !(PAGE_SIZE - 1);
+    /// Build an EPTP for a `levels`-level EPT (4 or 5) rooted at `root_paddr`,
+    /// with accessed and dirty flags if `accessed_dirty`.
+    ///
+    /// `root_paddr` must be the root of a `levels`-level EPT: the processor
+    /// walks as many levels as the EPTP says, so a 4-level root, such as the
+    /// ones of `axaddrspace`, with `levels` = 5 maps guest memory wrongly.
+    pub fn from_table_phys(root_paddr: HostPhysAddr, levels: usize, accessed_dirty: bool) -> Self {
+        let aligned_addr = root_paddr.as_usize() & !(PAGE_SIZE - 1);
         let flags = Self::from_bits_retain(aligned_addr as u64);
-        flags | Self::MEM_TYPE_WB | Self::WALK_LENGTH_4 | Self::ENABLE_ACCESSED_DIRTY
+        let walk_length = if levels == 5 {
+            Self::WALK_LENGTH_5
+        } else {
+            Self::WALK_LENGTH_4
+        };
+        let mut eptp = flags | Self::MEM_TYPE_WB | walk_length;
+        eptp.set(Self::ENABLE_ACCESSED_DIRTY, accessed_dirty);
+        eptp
     }
 }
 
@@ -386,8 +592,8 @@ mod tests {
     #[test]
     fn test_ept_pointer_creation() {
         // Test EPTPointer creation with from_table_phys method
-        let ept_ptr1 = EPTPointer::from_table_phys(memory_addr::PhysAddr::from(0x1000));
-        let ept_ptr2 = EPTPointer::from_table_phys(memory_addr::PhysAddr::from(0x2000));
+        let ept_ptr1 = EPTPointer::from_table_phys(memory_addr::PhysAddr::from(0x1000), 4, true);
+        let ept_ptr2 = EPTPointer::from_table_phys(memory_addr::PhysAddr::from(0x2000), 4, true);
 
         // Verify the EPT pointers were created successfully
         assert_ne!(ept_ptr1.0, ept_ptr2.0);
@@ -396,7 +602,7 @@ mod tests {
     #[test]
     fn test_ept_pointer_getters() {
         let phys_addr = memory_addr::PhysAddr::from(0x3000);
-        let ept_ptr = EPTPointer::from_table_phys(phys_addr);
+        let ept_ptr = EPTPointer::from_table_phys(phys_addr, 4, true);
 
         // Test that we can create EPT pointer and it has expected flags
         let bits = ept_ptr.bits();
@@ -441,7 +647,7 @@ mod tests {
     #[test]
     fn test_ept_pointer_from_table_phys() {
         let pml4_addr = HostPhysAddr::from(0x12345000_usize); // Page-aligned address
-        let ept_ptr = EPTPointer::from_table_phys(pml4_addr);
+        let ept_ptr = EPTPointer::from_table_phys(pml4_addr, 4, true);
 
         // Should have the correct flags set
         assert!(ept_ptr.contains(EPTPointer::MEM_TYPE_WB));
@@ -453,10 +659,85 @@ mod tests {
         assert_eq!(addr_part, 0x12345000);
     }
 
+    #[test]
+    fn test_ept_pointer_walk_length() {
+        let root = HostPhysAddr::from(0x12345000_usize);
+        let walk_length = |eptp: EPTPointer| (eptp.bits() >> 3) & 0x7;
+        assert_eq!(walk_length(EPTPointer::from_table_phys(root, 4, true)), 3);
+        assert_eq!(walk_length(EPTPointer::from_table_phys(root, 5, true)), 4);
+        assert_eq!(
+            EPTPointer::from_table_phys(root, 5, true).bits() & !0xfff,
+            0x12345000
+        );
+        assert!(
+            !EPTPointer::from_table_phys(root, 4, false)
+                .contains(EPTPointer::ENABLE_ACCESSED_DIRTY)
+        );
+    }
+
+    #[test]
+    fn test_eptp_list() {
+        MockMmHal::reset();
+        let mut list = EptpList::new().unwrap();
+        assert_eq!(list.get(0), None);
+        list.set(0, 0x1000_001e);
+        list.set(511, 0x2000_001e);
+        assert_eq!(list.get(0), Some(0x1000_001e));
+        assert_eq!(list.get(512), None);
+        assert_eq!(list.position(0x2000_001e), Some(511));
+        assert_eq!(list.position(0x3000_001e), None);
+    }
+
+    #[test]
+    fn test_ept_config() {
+        use EptVpidCapFlags as Cap;
+        let root = HostPhysAddr::from(0x12345000_usize);
+        let caps = Cap::PAGE_WALK_LENGTH_4 | Cap::MEM_TYPE_WB | Cap::ACCESSED_DIRTY;
+
+        let eptp = EptConfig::default().eptp(root, caps).unwrap();
+        assert_eq!(eptp.bits(), 0x12345000 | 6 | (3 << 3) | (1 << 6));
+        // A/D flags are only enabled by default when supported.
+        let eptp = EptConfig::default().eptp(root, caps - Cap::ACCESSED_DIRTY);
+        assert_eq!(eptp.unwrap().bits(), 0x12345000 | 6 | (3 << 3));
+
+        let config = EptConfig {
+            levels: 5,
+            mem_type: EptMemoryType::Uncacheable,
+            accessed_dirty: Some(false),
+            supervisor_shadow_stack: true,
+        };
+        assert!(config.eptp(root, caps).is_err());
+        let all = caps | Cap::PAGE_WALK_LENGTH_5 | Cap::MEM_TYPE_UC | Cap::SUPERVISOR_SHADOW_STACK;
+        let eptp = config.eptp(root, all).unwrap();
+        assert_eq!(eptp.bits(), 0x12345000 | (4 << 3) | (1 << 7));
+
+        let unsupported = |config: EptConfig| config.eptp(root, caps).is_err();
+        assert!(unsupported(EptConfig {
+            levels: 3,
+            ..Default::default()
+        }));
+        assert!(unsupported(EptConfig {
+            mem_type: EptMemoryType::Uncacheable,
+            ..Default::default()
+        }));
+        assert!(!unsupported(EptConfig {
+            accessed_dirty: Some(true),
+            ..Default::default()
+        }));
+        assert!(
+            EptConfig {
+                accessed_dirty: Some(true),
+                ..Default::default()
+            }
+            .eptp(root, caps - Cap::ACCESSED_DIRTY)
+            .is_err()
+        );
+    }
+
     #[test]
     fn test_ept_pointer_from_unaligned_addr() {
         let unaligned_addr = HostPhysAddr::from(0x12345678_usize); // Not page-aligned
-        let ept_ptr = EPTPointer::from_table_phys(unaligned_addr);
+        let ept_ptr = EPTPointer::from_table_phys(unaligned_addr, 4, true);
 
         // Address should be aligned down
         let addr_part = ept_ptr.bits() & !0xfff;
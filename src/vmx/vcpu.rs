@@ -12,13 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use alloc::collections::VecDeque;
+use alloc::{sync::Arc, vec::Vec};
 use bit_field::BitField;
 use core::{
     arch::naked_asm,
     fmt::{Debug, Formatter, Result},
     mem::size_of,
+    ops::Range,
 };
+use memory_addr::PAGE_SIZE_4K;
 use raw_cpuid::CpuId;
 use x86::{
     bits64::vmx,
@@ -27,26 +29,46 @@ use x86::{
     segmentation::SegmentSelector,
 };
 use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags, EferFlags};
+use x86_64::registers::rflags::RFlags;
 use x86_vlapic::EmulatedLocalApic;
 
 use axaddrspace::{
-    GuestPhysAddr, GuestVirtAddr, HostPhysAddr, NestedPageFaultInfo,
+    GuestPhysAddr, GuestVirtAddr, HostPhysAddr, MappingFlags, NestedPageFaultInfo,
     device::{AccessWidth, Port, SysRegAddr, SysRegAddrRange},
 };
 use axdevice_base::BaseDeviceOps;
 use axerrno::{AxResult, ax_err, ax_err_type};
 use axvcpu::{AxArchVCpu, AxVCpuExitReason};
+use axvisor_api::memory::phys_to_virt;
 use axvisor_api::vmm::{VCpuId, VMId};
 
 use super::VmxExitInfo;
 use super::as_axerr;
+use super::debug::{
+    DR7_INIT, DebugRegs, HW_BREAKPOINT_SLOTS, HwBreakpoint, INT3, SwBreakpoint, SwBreakpointAddr,
+    SwBreakpoints,
+};
 use super::definitions::VmxExitReason;
-use super::structs::{IOBitmap, MsrBitmap, VmxRegion};
+use super::definitions::VmxInterruptionType;
+use super::dirty_log::{DirtyLog, DirtyLogMode, PML_ENTRIES, VmDirtyLog};
+use super::events::{EventBlocking, ExceptionIntercepts, ExceptionPolicy, PendingEvents, VmxEvent};
+use super::instructions::{InvEptType, invept};
+use super::segmentation::{GuestDescriptorTable, GuestSegment, SegmentAccess, SegmentRegister};
+use super::shootdown::EptShootdown;
+use super::state::{self, VcpuMsrs, VcpuState};
+use super::structs::{
+    EPTPointer, EptConfig, EptVpidCap, EptVpidCapFlags, EptpList, IOBitmap, MsrBitmap, VmxRegion,
+};
 use super::vmcs::{
-    self, ApicAccessExitType, VmcsControl32, VmcsControl64, VmcsControlNW, VmcsGuest16,
-    VmcsGuest32, VmcsGuest64, VmcsGuestNW, VmcsHost16, VmcsHost32, VmcsHost64, VmcsHostNW,
+    self, ApicAccessExitType, GuestActivityState, VmcsControl16, VmcsControl32, VmcsControl64,
+    VmcsControlNW, VmcsGuest16, VmcsGuest32, VmcsGuest64, VmcsGuestNW, VmcsHost16, VmcsHost32,
+    VmcsHost64, VmcsHostNW, VmcsReadOnly32, VmxExceptionExitInfo, VmxInterruptInfo,
 };
-use crate::{ept::GuestPageWalkInfo, msr::Msr, regs::GeneralRegisters};
+use super::vpid::Vpid;
+use crate::ept::{self, EptGuestMemory, GuestAccessError, GuestAccessResult, GuestPageWalkInfo};
+use crate::exception::{ExceptionMerge, GuestException};
+use crate::msr::Msr;
+use crate::regs::{ControlRegister, DebugRegister, GeneralRegisters};
 
 const VMX_PREEMPTION_TIMER_SET_VALUE: u32 = 1_000_000;
 
@@ -61,9 +83,13 @@ pub struct XState {
 
     xsave_available: bool,
     xsaves_available: bool,
+
+    /// The guest FPU and extended states while the host runs, saved on VM
+    /// exits and loaded on VM entries, so that each vCPU keeps its own.
+    guest_fpu: Vec<XSaveChunk>,
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum VmCpuMode {
     Real,
     Protected,
@@ -71,6 +97,89 @@ pub enum VmCpuMode {
     Mode64,        // IA-32E mode (CS.L = 1)
 }
 
+/// VM exits that [`AxVCpuExitReason`] cannot express.
+///
+/// [`AxArchVCpu::run`] reports them as [`AxVCpuExitReason::Nothing`], and
+/// they are available through [`VmxVcpu::exit_event`] until the next run.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmxExitEvent {
+    /// A guest exception intercepted with [`ExceptionPolicy::Exit`].
+    Exception(VmxExceptionExitInfo),
+    /// Hardware breakpoints installed with [`VmxVcpu::set_hw_breakpoint`]
+    /// were hit. Guest RFLAGS.RF is set if one of them is an execution
+    /// breakpoint, so that the guest can be resumed past it.
+    HwBreakpoint {
+        /// The slots of the breakpoints hit, one bit per slot.
+        slots: u8,
+    },
+    /// A software breakpoint inserted with [`VmxVcpu::insert_sw_breakpoint`]
+    /// was hit. Guest RIP is at the breakpoint, and the original instruction
+    /// is executed when the guest resumes there, with all other vCPUs of the
+    /// VM paused, see [`VmxVcpu::insert_sw_breakpoint`].
+    SwBreakpoint {
+        /// The guest physical address of the breakpoint.
+        gpa: GuestPhysAddr,
+    },
+    /// A guest instruction was executed with [`VmxVcpu::set_single_step`].
+    SingleStep {
+        /// The vector of the event delivered before the instruction, which
+        /// is then the first one of its handler.
+        delivered: Option<u8>,
+    },
+}
+
+/// Guest state captured when the guest shut down, e.g., on a triple fault.
+///
+/// It is available through [`VmxVcpu::shutdown_info`] after a run returned
+/// [`AxVCpuExitReason::SystemDown`] for the shutdown, until the next run.
+#[derive(Debug, Clone)]
+pub struct VmxShutdownInfo {
+    /// The reason of the VM exit that reported the shutdown.
+    pub exit_reason: VmxExitReason,
+    /// The exception whose delivery caused a triple fault, if it was raised
+    /// by the hypervisor (see [`VmxVcpu::inject_exception`]).
+    pub exception: Option<GuestException>,
+    /// The event being delivered when the VM exit occurred, if any.
+    pub vectoring_info: Option<VmxInterruptInfo>,
+    /// The guest activity state.
+    pub activity_state: GuestActivityState,
+    /// The guest CPU mode.
+    pub cpu_mode: VmCpuMode,
+    /// Guest general-purpose registers.
+    pub regs: GeneralRegisters,
+    /// Guest RIP.
+    pub rip: u64,
+    /// Guest RSP.
+    pub rsp: u64,
+    /// Guest RFLAGS.
+    pub rflags: u64,
+    /// Guest CR0.
+    pub cr0: u64,
+    /// Guest CR2, i.e., the last page-fault linear address.
+    pub cr2: u64,
+    /// Guest CR3.
+    pub cr3: u64,
+    /// Guest CR4.
+    pub cr4: u64,
+    /// Guest IA32_EFER.
+    pub efer: u64,
+    /// Guest CS selector.
+    pub cs_selector: u16,
+    /// Guest CS base.
+    pub cs_base: u64,
+    /// Guest SS selector.
+    pub ss_selector: u16,
+    /// Guest GDTR base.
+    pub gdtr_base: u64,
+    /// Guest GDTR limit.
+    pub gdtr_limit: u32,
+    /// Guest IDTR base.
+    pub idtr_base: u64,
+    /// Guest IDTR limit.
+    pub idtr_limit: u32,
+}
+
 impl XState {
     /// Create a new [`XState`] instance with current host state
     fn new() -> Self {
@@ -96,6 +205,13 @@ impl XState {
             0
         };
 
+        // Large enough for all the state components XCR0 can enable.
+        let fpu_size = if xsave_available {
+            (raw_cpuid::cpuid!(0xd, 0).ecx as usize).max(state::XSAVE_HEADER_END)
+        } else {
+            state::FXSAVE_AREA_SIZE
+        };
+
         Self {
             host_xcr0: xcr0,
             guest_xcr0: xcr0,
@@ -103,9 +219,20 @@ impl XState {
             guest_xss: xss,
             xsave_available,
             xsaves_available,
+            guest_fpu: Self::reset_fpu_area(fpu_size),
         }
     }
 
+    /// An FPU state area of `size` bytes as left by FNINIT, with the default
+    /// MXCSR and the other state components in their initial configuration.
+    /// (SDM Vol. 1, Section 8.1.5 and 10.2.3.1)
+    fn reset_fpu_area(size: usize) -> Vec<XSaveChunk> {
+        let mut area = alloc::vec![XSaveChunk::default(); size.div_ceil(64)];
+        area[0].0[0..2].copy_from_slice(&0x37fu16.to_le_bytes());
+        area[0].0[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());
+        area
+    }
+
     /// Enable extended processor state management instructions, including XGETBV and XSAVE.
     pub fn enable_xsave() {
         if Self::xsave_available() {
@@ -131,7 +258,10 @@ impl XState {
             .unwrap_or(false)
     }
 
-    /// Save the current host XCR0 and IA32_XSS values and load the guest values.
+    /// Save the current host XCR0 and IA32_XSS values and load the guest
+    /// values, then the guest FPU and extended states.
+    ///
+    /// The host FPU state is not saved: the host must not use the FPU.
     pub fn switch_to_guest(&mut self) {
         unsafe {
             if self.xsave_available {
@@ -144,10 +274,13 @@ impl XState {
                 }
             }
         }
+        self.load_guest_fpu();
     }
 
-    /// Save the current guest XCR0 and IA32_XSS values and load the host values.
+    /// Save the guest FPU and extended states, then the current guest XCR0
+    /// and IA32_XSS values, and load the host values.
     pub fn switch_to_host(&mut self) {
+        self.save_guest_fpu();
         unsafe {
             if self.xsave_available {
                 self.guest_xcr0 = xcr0_read().bits();
@@ -160,6 +293,209 @@ impl XState {
             }
         }
     }
+
+    /// The size of the standard-format XSAVE area for the state components
+    /// of `xcr0`. (SDM Vol. 1, Section 13.4.3)
+    fn xsave_area_size(xcr0: u64) -> usize {
+        (2..64)
+            .filter(|&i| xcr0.get_bit(i))
+            .map(|i| {
+                let res = raw_cpuid::cpuid!(0xd, i as u32);
+                (res.ebx + res.eax) as usize
+            })
+            .fold(state::XSAVE_HEADER_END, usize::max)
+    }
+
+    /// The state components XCR0 can enable.
+    fn supported_xcr0() -> u64 {
+        let res = raw_cpuid::cpuid!(0xd, 0);
+        res.eax as u64 | (res.edx as u64) << 32
+    }
+
+    /// Load the guest FPU and extended states with XRSTOR, for the state
+    /// components of the guest XCR0 which must be loaded, or with FXRSTOR
+    /// without XSAVE.
+    fn load_guest_fpu(&mut self) {
+        let area = self.guest_fpu.as_ptr();
+        if !self.xsave_available {
+            // SAFETY: the area is 64-byte aligned, and was saved by FXSAVE or
+            // checked to be valid.
+            unsafe { core::arch::asm!("fxrstor64 [{}]", in(reg) area, options(nostack)) };
+            return;
+        }
+        self.mask_xstate_bv();
+        let mask = self.guest_xcr0;
+        // SAFETY: the area is 64-byte aligned and large enough for all the
+        // components of XCR0, and was saved by XSAVE or checked to be valid
+        // for the guest XCR0, which is loaded.
+        unsafe {
+            core::arch::asm!(
+                "xrstor64 [{}]",
+                in(reg) area,
+                in("eax") mask as u32,
+                in("edx") (mask >> 32) as u32,
+                options(nostack),
+            )
+        };
+    }
+
+    /// Save the guest FPU and extended states with XSAVE, for the user state
+    /// components of the guest XCR0 which must be loaded, or with FXSAVE
+    /// without XSAVE.
+    fn save_guest_fpu(&mut self) {
+        let area = self.guest_fpu.as_mut_ptr();
+        if !self.xsave_available {
+            // SAFETY: the area is 64-byte aligned and large enough.
+            unsafe { core::arch::asm!("fxsave64 [{}]", in(reg) area, options(nostack)) };
+            return;
+        }
+        let mask = self.guest_xcr0;
+        // SAFETY: the area is 64-byte aligned and large enough for all the
+        // components of XCR0.
+        unsafe {
+            core::arch::asm!(
+                "xsave64 [{}]",
+                in(reg) area,
+                in("eax") mask as u32,
+                in("edx") (mask >> 32) as u32,
+                options(nostack),
+            )
+        };
+    }
+
+    /// Clear the XSTATE_BV bits of the components that the guest XCR0 no
+    /// longer enables, for which XRSTOR would raise #GP.
+    /// (SDM Vol. 1, Section 13.8.1)
+    fn mask_xstate_bv(&mut self) {
+        let header = &mut self.guest_fpu[state::FXSAVE_AREA_SIZE / 64].0[..8];
+        let xstate_bv = u64::from_le_bytes((&*header).try_into().unwrap()) & self.guest_xcr0;
+        header.copy_from_slice(&xstate_bv.to_le_bytes());
+    }
+
+    /// The guest FPU and extended states saved on the last VM exit, as the
+    /// user state components of the guest XCR0 in the XSAVE format, or in
+    /// the FXSAVE format without XSAVE.
+    fn guest_fpu(&mut self) -> Vec<u8> {
+        if !self.xsave_available {
+            return XSaveChunk::bytes(&self.guest_fpu, state::FXSAVE_AREA_SIZE);
+        }
+        self.mask_xstate_bv();
+        XSaveChunk::bytes(&self.guest_fpu, Self::xsave_area_size(self.guest_xcr0))
+    }
+
+    /// Set the guest FPU and extended states, in the format returned by
+    /// [`XState::guest_fpu`], with the guest XCR0 and IA32_XSS values. They
+    /// are loaded on the next VM entry.
+    fn set_guest_fpu(&mut self, xcr0: u64, xss: u64, fpu: &[u8]) -> AxResult {
+        if !self.xsave_available {
+            state::check_fxsave_area(fpu)?;
+            self.guest_fpu = XSaveChunk::from_bytes(fpu);
+            return Ok(());
+        }
+        if !xcr0.get_bit(0) || xcr0 & !Self::supported_xcr0() != 0 {
+            return ax_err!(InvalidInput, "unsupported guest XCR0");
+        }
+        let size = Self::xsave_area_size(xcr0);
+        state::check_xsave_area(fpu, xcr0, size)?;
+        let area = XSaveChunk::from_bytes(&fpu[..size]);
+        self.guest_fpu.fill(XSaveChunk::default());
+        self.guest_fpu[..area.len()].copy_from_slice(&area);
+        self.guest_xcr0 = xcr0;
+        if self.xsaves_available {
+            self.guest_xss = xss;
+        }
+        Ok(())
+    }
+}
+
+/// The syscall MSRs, which the VMCS does not switch. The vCPU switches them
+/// around VM entries, so that each vCPU keeps its own values.
+#[derive(Debug, Default, Clone, Copy)]
+struct SyscallMsrs {
+    star: u64,
+    lstar: u64,
+    cstar: u64,
+    fmask: u64,
+    kernel_gs_base: u64,
+}
+
+impl SyscallMsrs {
+    /// Read the values of the processor.
+    fn read() -> Self {
+        Self {
+            star: Msr::IA32_STAR.read(),
+            lstar: Msr::IA32_LSTAR.read(),
+            cstar: Msr::IA32_CSTAR.read(),
+            fmask: Msr::IA32_FMASK.read(),
+            kernel_gs_base: Msr::IA32_KERNEL_GSBASE.read(),
+        }
+    }
+
+    /// Load the values into the processor.
+    ///
+    /// # Safety
+    ///
+    /// The values must be restored before the host uses SYSCALL or SWAPGS.
+    unsafe fn write(&self) {
+        // SAFETY: guaranteed by the caller.
+        unsafe {
+            Msr::IA32_STAR.write(self.star);
+            Msr::IA32_LSTAR.write(self.lstar);
+            Msr::IA32_CSTAR.write(self.cstar);
+            Msr::IA32_FMASK.write(self.fmask);
+            Msr::IA32_KERNEL_GSBASE.write(self.kernel_gs_base);
+        }
+    }
+}
+
+/// The guest control registers which are not part of the VMCS, and are
+/// switched by the vCPU around VM entries: CR2. CR8 is a view of the vLAPIC
+/// TPR, and the debug registers are switched by [`DebugRegs`].
+#[derive(Debug, Default)]
+struct GuestSpecialRegs {
+    cr2: u64,
+}
+
+impl GuestSpecialRegs {
+    /// Load the guest values. The host does not use CR2.
+    fn switch_to_guest(&mut self) {
+        // SAFETY: CR2 does not affect the host.
+        unsafe { x86::controlregs::cr2_write(self.cr2) };
+    }
+
+    /// Save the guest values.
+    fn switch_to_host(&mut self) {
+        // SAFETY: reading CR2 has no side effects.
+        self.cr2 = unsafe { x86::controlregs::cr2() as u64 };
+    }
+}
+
+/// A 64-byte aligned piece of an XSAVE area.
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+struct XSaveChunk([u8; 64]);
+
+impl Default for XSaveChunk {
+    fn default() -> Self {
+        Self([0; 64])
+    }
+}
+
+impl XSaveChunk {
+    fn bytes(area: &[Self], size: usize) -> Vec<u8> {
+        area.iter().flat_map(|chunk| chunk.0).take(size).collect()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Vec<Self> {
+        bytes
+            .chunks(64)
+            .map(|piece| {
+                let mut chunk = Self::default();
+                chunk.0[..piece.len()].copy_from_slice(piece);
+                chunk
+            })
+            .collect()
+    }
 }
 
 const MSR_IA32_EFER_LMA_BIT: u64 = 1 << 10;
@@ -180,10 +516,28 @@ pub struct VmxVcpu {
     // VCpu states and configurations
     /// Whether the VMCS has been launched. Used to determine whether to `vmx_launch` or `vmx_resume`.
     launched: bool,
+    /// Whether the VMCS has been set up.
+    set_up: bool,
     /// The guest entry point.
     entry: Option<GuestPhysAddr>,
     /// The EPT root address.
     ept_root: Option<HostPhysAddr>,
+    /// The EPT pointer settings.
+    ept_config: EptConfig,
+    /// The number of levels of the EPT roots, 4 or 5.
+    ept_levels: usize,
+    /// The VPID tagging the guest's cached translations, if supported.
+    vpid: Option<Vpid>,
+    /// The identifier of this vCPU in its VM.
+    vcpu_id: VCpuId,
+    /// The EPT shootdown state shared with the other vCPUs of the VM.
+    ept_shootdown: Option<Arc<EptShootdown>>,
+    /// Dirty page logging state, if enabled.
+    dirty_log: Option<DirtyLog>,
+    /// The guest page of the #VE information area, while #VE is enabled.
+    ve_info: Option<GuestPhysAddr>,
+    /// The EPT views for EPTP switching, if installed.
+    eptp_list: Option<EptpList>,
     // /// Whether this VCPU is a host VCpu. Used in type 1.5 hypervisor.
     // is_host: bool, temporary removed because we don't care about type 1.5 now
 
@@ -196,14 +550,39 @@ pub struct VmxVcpu {
     msr_bitmap: MsrBitmap,
 
     // Interrupt-related fields
-    /// Pending events to be injected to the guest.
-    pending_events: VecDeque<(u8, Option<u32>)>,
+    /// Pending events to be injected to the guest, by event class.
+    pending_events: PendingEvents,
+    /// Guest state of the last shutdown, cleared on the next run.
+    shutdown: Option<VmxShutdownInfo>,
+    /// How guest exceptions are intercepted.
+    exception_intercepts: ExceptionIntercepts,
+    /// Whether the exception intercepts must be written before next VM entry.
+    exception_intercepts_dirty: bool,
+    /// The last VM exit reported to the VMM, cleared on the next run.
+    exit_event: Option<VmxExitEvent>,
+    /// Whether the guest is single-stepped with the monitor trap flag.
+    single_step: bool,
+    /// The vector of the event injected on the last VM entry, while
+    /// single-stepping.
+    step_injected: Option<u8>,
+    /// The vector of the event delivered in the current step, if any.
+    step_delivered: Option<u8>,
     /// Emulated Local APIC.
     vlapic: EmulatedLocalApic,
 
     // Extra states
     /// The XState of the VCpu. Both host and guest.
     xstate: XState,
+    /// The guest registers switched by the vCPU besides the XState.
+    special_regs: GuestSpecialRegs,
+    /// The guest syscall MSRs, loaded while the guest runs.
+    guest_syscall_msrs: SyscallMsrs,
+    /// The host syscall MSRs, saved while the guest runs.
+    host_syscall_msrs: SyscallMsrs,
+    /// The guest debug registers, and the hardware breakpoints of the VMM.
+    debug_regs: DebugRegs,
+    /// The software breakpoints of the VMM.
+    sw_breakpoints: SwBreakpoints,
 
     // Tracing-related fields
     #[cfg(feature = "tracing")]
@@ -219,19 +598,44 @@ impl VmxVcpu {
             guest_regs: GeneralRegisters::default(),
             host_stack_top: 0,
             launched: false,
+            set_up: false,
             entry: None,
             ept_root: None,
+            ept_config: EptConfig::default(),
+            ept_levels: 4,
+            vpid: Vpid::alloc(),
+            vcpu_id,
+            ept_shootdown: None,
+            dirty_log: None,
+            ve_info: None,
+            eptp_list: None,
             // is_host: false,
             vmcs: VmxRegion::new(vmcs_revision_id, false)?,
             io_bitmap: IOBitmap::passthrough_all()?,
             msr_bitmap: MsrBitmap::passthrough_all()?,
-            pending_events: VecDeque::with_capacity(8),
+            pending_events: PendingEvents::new(),
+            shutdown: None,
+            exception_intercepts: ExceptionIntercepts::new(),
+            exception_intercepts_dirty: false,
+            exit_event: None,
+            single_step: false,
+            step_injected: None,
+            step_delivered: None,
             vlapic: EmulatedLocalApic::new(vm_id, vcpu_id),
             xstate: XState::new(),
+            special_regs: GuestSpecialRegs::default(),
+            guest_syscall_msrs: SyscallMsrs::default(),
+            host_syscall_msrs: SyscallMsrs::default(),
+            debug_regs: DebugRegs::new(),
+            sw_breakpoints: SwBreakpoints::default(),
             #[cfg(feature = "tracing")]
             guest_regs_exiting: GeneralRegisters::default(),
         };
-        info!("[HV] created VmxVcpu(vmcs: {:#x})", vcpu.vmcs.phys_addr());
+        info!(
+            "[HV] created VmxVcpu(vmcs: {:#x}, vpid: {:?})",
+            vcpu.vmcs.phys_addr(),
+            vcpu.vpid.as_ref().map(Vpid::id)
+        );
         Ok(vcpu)
     }
 
@@ -241,6 +645,64 @@ impl VmxVcpu {
         Ok(())
     }
 
+    /// Set the number of levels of the EPT roots given to [`VmxVcpu::setup`]
+    /// and [`VmxVcpu::set_ept_views`], and the EPT page-walk length with it:
+    /// 4 by default, as the roots built by `axaddrspace`, or 5 for 5-level
+    /// roots built by the VMM, to map guest physical addresses above 48 bits.
+    ///
+    /// The processor walks as many levels as the EPT pointer says, whatever
+    /// the root holds, so `levels` must be those of the roots.
+    ///
+    /// Must be called before the VMCS is set up, and fails otherwise, or if
+    /// the processor does not support this EPT page-walk length.
+    pub fn set_ept_levels(&mut self, levels: usize) -> AxResult {
+        if self.set_up {
+            return ax_err!(BadState, "EPT settings changed after the VMCS was set up");
+        }
+        let config = EptConfig {
+            levels,
+            ..self.ept_config
+        };
+        config.eptp(HostPhysAddr::from(0), EptVpidCap::read())?;
+        self.ept_levels = levels;
+        self.ept_config = config;
+        Ok(())
+    }
+
+    /// The number of levels of the EPT roots, 4 or 5.
+    pub fn ept_levels(&self) -> usize {
+        self.ept_levels
+    }
+
+    /// Choose the EPT pointer settings used by [`VmxVcpu::setup`] for the
+    /// EPT root given to it: page-walk length, memory type, accessed and
+    /// dirty flags, and supervisor shadow-stack control.
+    ///
+    /// The page-walk length must be the number of levels of the roots, see
+    /// [`VmxVcpu::set_ept_levels`].
+    ///
+    /// Must be called before the VMCS is set up, and fails otherwise, or if
+    /// the processor does not support these settings.
+    pub fn set_ept_config(&mut self, config: EptConfig) -> AxResult {
+        if self.set_up {
+            return ax_err!(BadState, "EPT settings changed after the VMCS was set up");
+        }
+        if config.levels != self.ept_levels {
+            return ax_err!(
+                InvalidInput,
+                "EPT page-walk length differs from the EPT root"
+            );
+        }
+        config.eptp(HostPhysAddr::from(0), EptVpidCap::read())?;
+        self.ept_config = config;
+        Ok(())
+    }
+
+    /// The EPT pointer settings.
+    pub fn ept_config(&self) -> EptConfig {
+        self.ept_config
+    }
+
     // /// Get the identifier of this [`VmxVcpu`].
     // pub fn vcpu_id(&self) -> usize {
     //     get_current_vcpu::<Self>().unwrap().id()
@@ -256,6 +718,16 @@ impl VmxVcpu {
             vmx::vmptrld(self.vmcs.phys_addr().as_usize() as u64).map_err(as_axerr)?;
         }
         self.setup_vmcs_host()?;
+        // This processor may hold stale translations tagged with our VPID,
+        // from an earlier run here or from a previous owner of the VPID.
+        if let Some(vpid) = &self.vpid {
+            vpid.flush(None)?;
+        }
+        // Likewise for our EPT, from an earlier run here: the shootdowns
+        // requested since then were only flushed where this vCPU ran.
+        if VmcsControl64::EPTP.read()? != 0 {
+            self.invept()?;
+        }
         Ok(())
     }
 
@@ -274,28 +746,55 @@ impl VmxVcpu {
 
     /// Get CPU mode of the guest.
     pub fn get_cpu_mode(&self) -> VmCpuMode {
-        let ia32_efer = Msr::IA32_EFER.read();
+        // The guest IA32_EFER is saved on VM exit, with LMA up to date.
+        let ia32_efer = VmcsGuest64::IA32_EFER.read().unwrap();
         let cs_access_right = VmcsGuest32::CS_ACCESS_RIGHTS.read().unwrap();
         let cr0 = VmcsGuestNW::CR0.read().unwrap();
-        if (ia32_efer & MSR_IA32_EFER_LMA_BIT) != 0 {
-            if (cs_access_right & 0x2000) != 0 {
-                // CS.L = 1
-                VmCpuMode::Mode64
-            } else {
-                VmCpuMode::Compatibility
-            }
-        } else if (cr0 & CR0_PE) != 0 {
-            VmCpuMode::Protected
-        } else {
-            VmCpuMode::Real
-        }
+        guest_cpu_mode(ia32_efer, cs_access_right, cr0)
     }
 
     /// Run the guest. It returns when a vm-exit happens and returns the vm-exit if it cannot be handled by this [`VmxVcpu`] itself.
     pub fn inner_run(&mut self) -> Option<VmxExitInfo> {
-        self.inject_pending_events().unwrap();
+        self.shutdown = None;
+        self.exit_event = None;
+        if let Some(exception) = self.pending_events.take_triple_fault() {
+            // The guest would shut down on this entry, report it without entering.
+            let exit_info = VmxExitInfo {
+                entry_failure: false,
+                exit_reason: VmxExitReason::TRIPLE_FAULT,
+                exit_instruction_length: 0,
+                guest_rip: self.rip(),
+            };
+            self.record_shutdown(exit_info.exit_reason, Some(exception), None)
+                .unwrap();
+            return Some(exit_info);
+        }
+
+        if self.exception_intercepts_dirty {
+            self.write_exception_intercepts().unwrap();
+            self.exception_intercepts_dirty = false;
+        }
+        let step_over = self.begin_step_over().unwrap();
+        if step_over.is_none() {
+            // Events wait until the instruction stepped over is executed.
+            self.inject_pending_events().unwrap();
+        }
+
+        if let Some(shootdown) = &self.ept_shootdown {
+            debug_assert!(
+                !x86_64::instructions::interrupts::are_enabled(),
+                "vCPUs sharing an EPT shootdown must run with host interrupts disabled"
+            );
+            shootdown.enter(self.vcpu_id, || self.invept()).unwrap();
+        }
+        self.update_ve_info().unwrap();
+
+        if self.single_step {
+            self.step_injected = vmcs::entry_injection_vector().unwrap();
+        }
 
         // Run guest
+        self.load_guest_debug_regs().unwrap();
         self.load_guest_xstate();
 
         #[cfg(feature = "tracing")]
@@ -323,6 +822,11 @@ impl VmxVcpu {
             }
         }
         self.load_host_xstate();
+        self.debug_regs
+            .switch_to_host(VmcsGuestNW::DR7.read().unwrap() as u64);
+        if let Some(shootdown) = &self.ept_shootdown {
+            shootdown.exit(self.vcpu_id);
+        }
 
         #[cfg(feature = "tracing")]
         {
@@ -333,6 +837,28 @@ impl VmxVcpu {
         let exit_info = self.exit_info().unwrap();
         // debug!("VM exit: {:#x?}", exit_info);
 
+        if let Some(breakpoint) = step_over {
+            self.end_step_over(breakpoint, &exit_info).unwrap();
+            if exit_info.exit_reason == VmxExitReason::MONITOR_TRAP_FLAG && !self.single_step {
+                // The breakpoint was stepped over, keep running.
+                return self.inner_run();
+            }
+        }
+
+        if !exit_info.entry_failure {
+            if exit_info.exit_reason == VmxExitReason::TRIPLE_FAULT
+                || vmcs::activity_state().unwrap() == GuestActivityState::Shutdown
+            {
+                // Nothing can be delivered to a guest that shut down.
+                let vectoring_info =
+                    Some(vmcs::idt_vectoring_info().unwrap()).filter(|info| info.valid);
+                self.record_shutdown(exit_info.exit_reason, None, vectoring_info)
+                    .unwrap();
+                return Some(exit_info);
+            }
+            self.save_vectoring_event().unwrap();
+        }
+
         match self.builtin_vmexit_handler(&exit_info) {
             Some(result) => {
                 if result.is_err() {
@@ -344,6 +870,20 @@ impl VmxVcpu {
                     );
                 }
 
+                if self.single_step {
+                    if exit_info.exit_reason == VmxExitReason::MONITOR_TRAP_FLAG {
+                        // An event was delivered, step the first instruction
+                        // of its handler.
+                        return self.inner_run();
+                    }
+                    if self.rip() != exit_info.guest_rip {
+                        // The instruction was emulated, which ends the step.
+                        self.exit_event = Some(VmxExitEvent::SingleStep {
+                            delivered: self.step_delivered.take(),
+                        });
+                        return Some(exit_info);
+                    }
+                }
                 None
             }
             None => Some(exit_info),
@@ -365,118 +905,1564 @@ impl VmxVcpu {
         vmcs::interrupt_exit_info()
     }
 
-    /// Information for VM exits due to I/O instructions.
-    pub fn io_exit_info(&self) -> AxResult<vmcs::VmxIoExitInfo> {
-        vmcs::io_exit_info()
+    /// Information for VM exits due to exceptions or NMIs.
+    pub fn exception_exit_info(&self) -> AxResult<VmxExceptionExitInfo> {
+        vmcs::exception_exit_info()
+    }
+
+    /// Information for VM exits due to I/O instructions.
+    pub fn io_exit_info(&self) -> AxResult<vmcs::VmxIoExitInfo> {
+        vmcs::io_exit_info()
+    }
+
+    /// Information for VM exits due to nested page table faults (EPT violation).
+    pub fn nested_page_fault_info(&self) -> AxResult<NestedPageFaultInfo> {
+        vmcs::ept_violation_info()
+    }
+
+    /// Information for VM exits due to APIC access.
+    pub fn apic_access_exit_info(&self) -> AxResult<vmcs::ApicAccessExitInfo> {
+        vmcs::apic_access_exit_info()
+    }
+
+    /// Guest general-purpose registers.
+    pub fn regs(&self) -> &GeneralRegisters {
+        &self.guest_regs
+    }
+
+    /// Mutable reference of guest general-purpose registers.
+    pub fn regs_mut(&mut self) -> &mut GeneralRegisters {
+        &mut self.guest_regs
+    }
+
+    /// Guest stack pointer. (`RSP`)
+    pub fn stack_pointer(&self) -> usize {
+        VmcsGuestNW::RSP.read().unwrap()
+    }
+
+    /// Set guest stack pointer. (`RSP`)
+    pub fn set_stack_pointer(&mut self, rsp: usize) {
+        VmcsGuestNW::RSP.write(rsp).unwrap()
+    }
+
+    /// Invalidate the EPT-derived translations of this vCPU's EPT, and of
+    /// its EPT views if installed, on the current processor, after the EPT
+    /// was changed. Other processors are not affected, see [`EptShootdown`]
+    /// for them.
+    pub fn invept(&self) -> AxResult {
+        if !EptVpidCap::read().contains(EptVpidCapFlags::INVEPT_SINGLE_CONTEXT) {
+            let eptp = VmcsControl64::EPTP.read()?;
+            return unsafe { invept(InvEptType::Global, eptp).map_err(as_axerr) };
+        }
+        for eptp in self.eptps()? {
+            unsafe { invept(InvEptType::SingleContext, eptp).map_err(as_axerr)? };
+        }
+        Ok(())
+    }
+
+    /// The EPT pointers the guest may use: the current one, and those of the
+    /// EPT views if installed, which the guest can switch to.
+    fn eptps(&self) -> AxResult<Vec<u64>> {
+        let mut eptps = Vec::from([VmcsControl64::EPTP.read()?]);
+        if let Some(list) = &self.eptp_list {
+            for eptp in list.iter() {
+                if !eptps.contains(&eptp) {
+                    eptps.push(eptp);
+                }
+            }
+        }
+        Ok(eptps)
+    }
+
+    /// Whether the processor sets accessed and dirty flags in the EPT, which
+    /// is enabled when supported.
+    pub fn ept_accessed_dirty_enabled(&self) -> bool {
+        EPTPointer::from_bits_retain(VmcsControl64::EPTP.read().unwrap())
+            .contains(EPTPointer::ENABLE_ACCESSED_DIRTY)
+    }
+
+    /// Log the guest pages written since the last call, for the VM owning
+    /// this vCPU's EPT and its EPT views: set the bits of `bitmap` for the
+    /// dirty 4-KiB pages from `start`, bit `i` standing for the page at
+    /// `start + i * 4 KiB`, and clear their dirty flags. Returns the number
+    /// of dirty pages found.
+    ///
+    /// The EPT translations are then invalidated on all vCPUs through the
+    /// [`EptShootdown`] if set, or on the current processor otherwise, so
+    /// that later writes are logged again.
+    pub fn harvest_dirty_pages(&self, start: GuestPhysAddr, bitmap: &mut [u64]) -> AxResult<usize> {
+        if !self.ept_accessed_dirty_enabled() {
+            return ax_err!(Unsupported, "EPT accessed and dirty flags are not enabled");
+        }
+        let found = |bitmap: &[u64]| bitmap.iter().map(|bits| bits.count_ones() as usize).sum();
+        let before: usize = found(bitmap);
+        for eptp in self.eptps()? {
+            ept::ept_harvest_dirty(eptp, start, bitmap);
+        }
+        let count = found(bitmap) - before;
+        if count > 0 {
+            self.flush_ept()?;
+        }
+        Ok(count)
+    }
+
+    /// Install EPT views: the EPT rooted at `roots[i]` becomes view `i`, with
+    /// the settings of [`VmxVcpu::set_ept_config`]. At most 512 views can be
+    /// installed, and an empty `roots` removes them.
+    ///
+    /// The guest can then switch views with VMFUNC leaf 0 (EPTP switching)
+    /// and the view index in ECX, and the host with
+    /// [`VmxVcpu::set_ept_view`]. The current EPT is left unchanged.
+    ///
+    /// The EPT changes made through this vCPU, e.g., for dirty logging or
+    /// #VE, then apply to every view, and so do the EPT invalidations.
+    pub fn set_ept_views(&mut self, roots: &[HostPhysAddr]) -> AxResult {
+        use vmcs::controls::SecondaryControls;
+        const VMFUNC_EPTP_SWITCHING: u64 = 1 << 0;
+
+        let ctrl = VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?;
+        if roots.is_empty() {
+            self.eptp_list = None;
+            VmcsControl64::VM_FUNCTION_CONTROLS.write(0)?;
+            return VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS
+                .write(ctrl & !SecondaryControls::ENABLE_VM_FUNCTIONS.bits());
+        }
+        if roots.len() > EptpList::LEN {
+            return ax_err!(InvalidInput, "too many EPT views");
+        }
+        if !vmcs::is_control_supported(
+            Msr::IA32_VMX_PROCBASED_CTLS2,
+            SecondaryControls::ENABLE_VM_FUNCTIONS.bits(),
+        ) || Msr::IA32_VMX_VMFUNC.read() & VMFUNC_EPTP_SWITCHING == 0
+        {
+            return ax_err!(Unsupported, "EPTP switching not supported");
+        }
+
+        let caps = EptVpidCap::read();
+        let mut list = EptpList::new()?;
+        for (index, &root) in roots.iter().enumerate() {
+            list.set(index, self.ept_config.eptp(root, caps)?.bits());
+        }
+        VmcsControl64::EPTP_LIST_ADDR.write(list.phys_addr().as_usize() as _)?;
+        VmcsControl64::VM_FUNCTION_CONTROLS.write(VMFUNC_EPTP_SWITCHING)?;
+        VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS
+            .write(ctrl | SecondaryControls::ENABLE_VM_FUNCTIONS.bits())?;
+        self.eptp_list = Some(list);
+        Ok(())
+    }
+
+    /// Switch to the EPT view `index` installed by
+    /// [`VmxVcpu::set_ept_views`].
+    ///
+    /// EPT translations are tagged by EPTP, and the EPT changes made through
+    /// this vCPU invalidate those of every view. The VMM must invalidate them
+    /// with [`VmxVcpu::invept`] after changing the tables of a view itself.
+    pub fn set_ept_view(&mut self, index: usize) -> AxResult {
+        let Some(eptp) = self.eptp_list.as_ref().and_then(|list| list.get(index)) else {
+            return ax_err!(InvalidInput, "no such EPT view");
+        };
+        VmcsControl64::EPTP.write(eptp)?;
+        VmcsControl16::EPTP_INDEX.write(index as _)
+    }
+
+    /// The index of the current EPT view, or `None` if no views are installed
+    /// or the current EPT is not one of them.
+    pub fn ept_view(&self) -> Option<usize> {
+        let eptp = VmcsControl64::EPTP.read().ok()?;
+        self.eptp_list.as_ref()?.position(eptp)
+    }
+
+    /// Start logging the guest pages written by this vCPU into `log`, the
+    /// dirty page logging state of its VM. PML is used if the processor
+    /// supports it and EPT dirty flags are enabled, and write protection of
+    /// the EPT otherwise, unless the VM already uses the other mode. Returns
+    /// the mode used.
+    ///
+    /// With PML, the EPT dirty flags are cleared, as only pages whose dirty
+    /// flag is clear are logged. With write protection, the EPT is only
+    /// write-protected once all vCPUs of the VM log writes, all pages being
+    /// reported dirty until then, see [`VmDirtyLog`]. The logged pages are collected by
+    /// [`VmxVcpu::harvest_dirty_log`].
+    pub fn enable_dirty_log(&mut self, log: Arc<VmDirtyLog>) -> AxResult<DirtyLogMode> {
+        use vmcs::controls::SecondaryControls;
+        if let Some(log) = &self.dirty_log {
+            return Ok(log.mode());
+        }
+        let eptps = self.eptps()?;
+        let pml = self.ept_accessed_dirty_enabled()
+            && vmcs::is_control_supported(
+                Msr::IA32_VMX_PROCBASED_CTLS2,
+                SecondaryControls::ENABLE_PML.bits(),
+            );
+        let preferred = if pml {
+            DirtyLogMode::Pml
+        } else {
+            DirtyLogMode::WriteProtect
+        };
+        let mode = log.attach(preferred, || {
+            for &eptp in &eptps {
+                ept::ept_write_protect(eptp, 0..u64::MAX);
+            }
+        })?;
+        let dirty_log = DirtyLog::new(mode, log.clone()).inspect_err(|_| {
+            log.detach(|| eptps.iter().for_each(|&eptp| ept::ept_unprotect_all(eptp)))
+        })?;
+        if let Some(pml_addr) = dirty_log.pml_addr() {
+            VmcsControl64::PML_ADDR.write(pml_addr.as_usize() as _)?;
+            VmcsGuest16::PML_INDEX.write((PML_ENTRIES - 1) as _)?;
+            for &eptp in &eptps {
+                ept::ept_clear_dirty(eptp, 0..u64::MAX);
+            }
+            let ctrl = VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?;
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS
+                .write(ctrl | SecondaryControls::ENABLE_PML.bits())?;
+        }
+        self.dirty_log = Some(dirty_log);
+        self.flush_ept()?;
+        Ok(mode)
+    }
+
+    /// Stop logging the guest pages written by this vCPU.
+    ///
+    /// With write protection, it is removed from the EPT as soon as one vCPU
+    /// of the VM stops logging writes, and all pages are reported dirty from
+    /// then on. The pages not harvested yet are
+    /// discarded once none of them logs writes anymore.
+    pub fn disable_dirty_log(&mut self) -> AxResult {
+        let Some(log) = self.dirty_log.take() else {
+            return Ok(());
+        };
+        let eptps = self.eptps()?;
+        let mut unprotected = false;
+        log.vm().detach(|| {
+            for &eptp in &eptps {
+                ept::ept_unprotect_all(eptp);
+            }
+            unprotected = true;
+        });
+        if log.mode() == DirtyLogMode::Pml {
+            let ctrl = VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?;
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS
+                .write(ctrl & !vmcs::controls::SecondaryControls::ENABLE_PML.bits())?;
+        }
+        if unprotected {
+            self.flush_ept()?;
+        }
+        Ok(())
+    }
+
+    /// How the guest pages written are logged, or `None` if they are not.
+    pub fn dirty_log_mode(&self) -> Option<DirtyLogMode> {
+        self.dirty_log.as_ref().map(DirtyLog::mode)
+    }
+
+    /// Collect the pages logged as written in the VM since the last call:
+    /// set the bits of `bitmap` for the dirty 4-KiB pages from `start`, bit
+    /// `i` standing for the page at `start + i * 4 KiB`. Returns the number
+    /// of pages found.
+    ///
+    /// The pages written by the guest and by the hypervisor through this
+    /// crate are logged. With PML, those written by other vCPUs are only
+    /// found once drained from their page-modification log, i.e., after
+    /// this is called on them or their log was full.
+    ///
+    /// The pages found are tracked again, their EPT dirty flag being cleared
+    /// or write permission removed, and the EPT translations are then
+    /// invalidated like in [`VmxVcpu::harvest_dirty_pages`].
+    pub fn harvest_dirty_log(
+        &mut self,
+        start: GuestPhysAddr,
+        bitmap: &mut [u64],
+    ) -> AxResult<usize> {
+        let eptps = self.eptps()?;
+        let Some(log) = &mut self.dirty_log else {
+            return ax_err!(BadState, "dirty page logging is not enabled");
+        };
+        if log.mode() == DirtyLogMode::Pml {
+            log.drain_pml(VmcsGuest16::PML_INDEX.read()?);
+            VmcsGuest16::PML_INDEX.write((PML_ENTRIES - 1) as _)?;
+        }
+        let mode = log.mode();
+        let count = log.vm().harvest(start.as_usize() as u64, bitmap, |page| {
+            for &eptp in &eptps {
+                Self::track_dirty(mode, eptp, page..page + 0x1000);
+            }
+        });
+        if count != 0 {
+            self.flush_ept()?;
+        }
+        Ok(count)
+    }
+
+    /// Track the guest physical range `range` again for dirty logging after
+    /// its EPT mappings changed, e.g., were remapped by the address space,
+    /// which drops their write protection or sets their dirty flags: its
+    /// pages are logged as written, and tracked like harvested pages.
+    pub fn dirty_log_remapped(&mut self, range: Range<GuestPhysAddr>) -> AxResult {
+        let Some(log) = &self.dirty_log else {
+            return ax_err!(BadState, "dirty page logging is not enabled");
+        };
+        let mode = log.mode();
+        let eptps = self.eptps()?;
+        let mut tracked = false;
+        let range = range.start.as_usize() as u64..range.end.as_usize() as u64;
+        log.vm().log_remapped(range, |range| {
+            for &eptp in &eptps {
+                Self::track_dirty(mode, eptp, range.clone());
+            }
+            tracked = true;
+        });
+        if tracked {
+            self.flush_ept()?;
+        }
+        Ok(())
+    }
+
+    /// Track the writes to `range` in the EPT referenced by `eptp`, by
+    /// clearing the dirty flags or removing write permission.
+    fn track_dirty(mode: DirtyLogMode, eptp: u64, range: Range<u64>) {
+        match mode {
+            DirtyLogMode::Pml => ept::ept_clear_dirty(eptp, range),
+            DirtyLogMode::WriteProtect => {
+                ept::ept_write_protect(eptp, range);
+            }
+        }
+    }
+
+    /// Log a write of the hypervisor to guest memory, which neither PML nor
+    /// the EPT write protection can see.
+    fn log_host_write(&self, gpa: GuestPhysAddr, len: usize) {
+        if let Some(log) = &self.dirty_log {
+            let start = gpa.as_usize() as u64;
+            log.vm().log(start..start + len as u64);
+        }
+    }
+
+    /// Deliver EPT violations to the guest as virtualization exceptions (#VE)
+    /// instead of causing VM exits, for the guest physical addresses selected
+    /// by [`VmxVcpu::set_ve_suppressed`]. (SDM Vol. 3C, Section 26.5.7)
+    ///
+    /// `info_gpa` is the page-aligned guest page where the processor stores
+    /// the #VE information. Its host physical address is looked up again
+    /// before each VM entry, so it may be remapped; #VE is not delivered
+    /// while it is not mapped writable. The guest must clear the dword at
+    /// offset 4 of the page to receive the next #VE, until then EPT
+    /// violations cause VM exits.
+    ///
+    /// #VE is suppressed for the guest memory mapped in the EPT at first.
+    ///
+    /// # Safety
+    ///
+    /// The EPT violations on not-present EPT entries are delivered as #VE,
+    /// as their suppress-#VE bit is clear: the EPT owner considers non-zero
+    /// entries in use. So are those on the entries mapped afterwards, until
+    /// [`VmxVcpu::set_ve_suppressed`] is called for them. The caller must own
+    /// the mapping policy of the guest physical address space, i.e., ensure
+    /// that the guest handles #VE on all unmapped addresses, e.g., emulated
+    /// MMIO, and suppress it on new mappings which must cause VM exits.
+    pub unsafe fn enable_ve(&mut self, info_gpa: GuestPhysAddr) -> AxResult {
+        use vmcs::controls::SecondaryControls;
+        if info_gpa.as_usize() % PAGE_SIZE_4K != 0 {
+            return ax_err!(InvalidInput, "#VE information area is not page-aligned");
+        }
+        if !vmcs::is_control_supported(
+            Msr::IA32_VMX_PROCBASED_CTLS2,
+            SecondaryControls::EPT_VIOLATION_VE.bits(),
+        ) {
+            return ax_err!(Unsupported, "EPT-violation #VE not supported");
+        }
+        let eptps = self.eptps()?;
+        if ept::ept_translate(eptps[0], info_gpa, true).is_none() {
+            return ax_err!(InvalidInput, "#VE information area is not writable");
+        }
+        for &eptp in &eptps {
+            ept::ept_set_suppress_ve(eptp, 0..u64::MAX, true);
+        }
+        self.ve_info = Some(info_gpa);
+        self.update_ve_info()?;
+        self.flush_ept()
+    }
+
+    /// Stop delivering EPT violations to the guest as #VE.
+    pub fn disable_ve(&mut self) -> AxResult {
+        self.ve_info = None;
+        let ctrl = VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?;
+        VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS
+            .write(ctrl & !vmcs::controls::SecondaryControls::EPT_VIOLATION_VE.bits())
+    }
+
+    /// Point the VMCS at the current host page of the #VE information area,
+    /// as its guest page may have been remapped, and deliver #VE only while
+    /// it is mapped writable.
+    fn update_ve_info(&self) -> AxResult {
+        use vmcs::controls::SecondaryControls;
+        let Some(info_gpa) = self.ve_info else {
+            return Ok(());
+        };
+        let info_hpa = ept::ept_translate(VmcsControl64::EPTP.read()?, info_gpa, true);
+        let ctrl = VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?;
+        let new_ctrl = match info_hpa {
+            Some(info_hpa) => {
+                let addr = info_hpa.as_usize() as u64;
+                if VmcsControl64::VIRT_EXCEPTION_INFO_ADDR.read()? != addr {
+                    VmcsControl64::VIRT_EXCEPTION_INFO_ADDR.write(addr)?;
+                }
+                ctrl | SecondaryControls::EPT_VIOLATION_VE.bits()
+            }
+            None => ctrl & !SecondaryControls::EPT_VIOLATION_VE.bits(),
+        };
+        if new_ctrl != ctrl {
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.write(new_ctrl)?;
+        }
+        Ok(())
+    }
+
+    /// Set whether EPT violations on the guest physical addresses in `range`
+    /// cause VM exits (`suppress`) or are delivered to the guest as #VE once
+    /// enabled by [`VmxVcpu::enable_ve`], through the suppress-#VE bit of the
+    /// EPT entries. Only the mapped addresses are changed, and the mappings
+    /// created afterwards need this to be called again.
+    ///
+    /// The EPT is shared by all vCPUs of the VM, so this applies to all of
+    /// them, and to every EPT view. Returns the number of EPT entries
+    /// changed.
+    pub fn set_ve_suppressed(
+        &mut self,
+        range: Range<GuestPhysAddr>,
+        suppress: bool,
+    ) -> AxResult<usize> {
+        let range = range.start.as_usize() as u64..range.end.as_usize() as u64;
+        let mut count = 0;
+        for eptp in self.eptps()? {
+            count += ept::ept_set_suppress_ve(eptp, range.clone(), suppress);
+        }
+        if count > 0 {
+            self.flush_ept()?;
+        }
+        Ok(count)
+    }
+
+    /// Invalidate the EPT translations on all vCPUs through the
+    /// [`EptShootdown`] if set, or on the current processor otherwise.
+    fn flush_ept(&self) -> AxResult {
+        match &self.ept_shootdown {
+            Some(shootdown) => {
+                shootdown.shootdown();
+                Ok(())
+            }
+            None => self.invept(),
+        }
+    }
+
+    /// Share the EPT shootdown state of the VM with this vCPU, so that it
+    /// flushes its EPT translations before entering the guest when requested
+    /// by [`EptShootdown::shootdown`], and stays out of the guest while
+    /// another vCPU steps over a software breakpoint.
+    ///
+    /// The vCPU must then be run with host interrupts disabled.
+    pub fn set_ept_shootdown(&mut self, shootdown: Arc<EptShootdown>) {
+        self.ept_shootdown = Some(shootdown);
+    }
+
+    /// The VPID of this vCPU, or `None` if VPIDs are not in use, in which
+    /// case every VM entry and exit flushes the guest's cached translations.
+    pub fn vpid(&self) -> Option<u16> {
+        self.vpid.as_ref().map(Vpid::id)
+    }
+
+    /// Invalidate the guest's cached linear translations on the current
+    /// processor: those for the linear address `gla` if given, or all of
+    /// them otherwise.
+    ///
+    /// Needed when the hypervisor changes guest paging structures behind the
+    /// guest's back, since the guest does not know to invalidate them. A
+    /// no-op without VPIDs.
+    pub fn flush_guest_tlb(&self, gla: Option<GuestVirtAddr>) -> AxResult {
+        match &self.vpid {
+            Some(vpid) => vpid.flush(gla.map(|gla| gla.as_usize() as u64)),
+            None => Ok(()),
+        }
+    }
+
+    /// Translate guest virtual addr to linear addr    
+    pub fn gla2gva(&self, guest_rip: GuestVirtAddr) -> GuestVirtAddr {
+        let cpu_mode = self.get_cpu_mode();
+        let seg_base = if cpu_mode == VmCpuMode::Mode64 {
+            0
+        } else {
+            VmcsGuestNW::CS_BASE.read().unwrap()
+        };
+        // debug!(
+        //     "seg_base: {:#x}, guest_rip: {:#x} cpu mode:{:?}",
+        //     seg_base, guest_rip, cpu_mode
+        // );
+        guest_rip + seg_base
+    }
+
+    /// The guest segment register `segment`, read from the VMCS.
+    pub fn guest_segment(&self, segment: SegmentRegister) -> GuestSegment {
+        let (selector, base, limit, access_rights) = Self::segment_fields(segment);
+        GuestSegment {
+            selector: selector.read().unwrap(),
+            base: base.read().unwrap() as u64,
+            limit: limit.read().unwrap(),
+            access_rights: access_rights.read().unwrap(),
+        }
+    }
+
+    /// Load the guest segment register `segment`, after checking it with
+    /// [`GuestSegment::check`].
+    pub fn set_guest_segment(&mut self, segment: SegmentRegister, value: GuestSegment) -> AxResult {
+        value.check(segment, Self::guest_la57_supported())?;
+        let (selector, base, limit, access_rights) = Self::segment_fields(segment);
+        selector.write(value.selector)?;
+        base.write(value.base as _)?;
+        limit.write(value.limit)?;
+        access_rights.write(value.access_rights)
+    }
+
+    /// The guest GDTR.
+    pub fn guest_gdtr(&self) -> GuestDescriptorTable {
+        GuestDescriptorTable {
+            base: VmcsGuestNW::GDTR_BASE.read().unwrap() as u64,
+            limit: VmcsGuest32::GDTR_LIMIT.read().unwrap() as u16,
+        }
+    }
+
+    /// Load the guest GDTR, whose base must be canonical.
+    pub fn set_guest_gdtr(&mut self, value: GuestDescriptorTable) -> AxResult {
+        value.check(Self::guest_la57_supported())?;
+        VmcsGuestNW::GDTR_BASE.write(value.base as _)?;
+        VmcsGuest32::GDTR_LIMIT.write(value.limit as _)
+    }
+
+    /// The guest IDTR.
+    pub fn guest_idtr(&self) -> GuestDescriptorTable {
+        GuestDescriptorTable {
+            base: VmcsGuestNW::IDTR_BASE.read().unwrap() as u64,
+            limit: VmcsGuest32::IDTR_LIMIT.read().unwrap() as u16,
+        }
+    }
+
+    /// Load the guest IDTR, whose base must be canonical.
+    pub fn set_guest_idtr(&mut self, value: GuestDescriptorTable) -> AxResult {
+        value.check(Self::guest_la57_supported())?;
+        VmcsGuestNW::IDTR_BASE.write(value.base as _)?;
+        VmcsGuest32::IDTR_LIMIT.write(value.limit as _)
+    }
+
+    /// The value of a guest control register, as seen by the guest.
+    pub fn guest_cr(&self, register: ControlRegister) -> u64 {
+        match register {
+            ControlRegister::Cr0 => self.cr(0) as u64,
+            ControlRegister::Cr2 => self.special_regs.cr2,
+            ControlRegister::Cr3 => self.cr(3) as u64,
+            ControlRegister::Cr4 => self.cr(4) as u64,
+            ControlRegister::Cr8 => (self.lapic_tpr() >> 4) as u64,
+        }
+    }
+
+    /// Set a guest control register, with the checks of a `MOV to CR` which
+    /// would raise #GP. (SDM Vol. 3A, Section 2.5)
+    ///
+    /// Setting CR0.PG updates IA32_EFER.LMA from IA32_EFER.LME, as the
+    /// processor would. Setting CR3 loads the PDPTEs with PAE paging, and
+    /// invalidates the guest's cached translations. CR8 is a view of bits
+    /// 7:4 of the vLAPIC TPR.
+    pub fn set_guest_cr(&mut self, register: ControlRegister, value: u64) -> AxResult {
+        match register {
+            ControlRegister::Cr0 => {
+                if value >> 32 != 0 {
+                    return ax_err!(InvalidInput, "reserved CR0 bits set");
+                }
+                let cr0 = Cr0Flags::from_bits_truncate(value);
+                if cr0.contains(Cr0Flags::PAGING) && !cr0.contains(Cr0Flags::PROTECTED_MODE_ENABLE)
+                {
+                    return ax_err!(InvalidInput, "CR0.PG set without CR0.PE");
+                }
+                if cr0.contains(Cr0Flags::NOT_WRITE_THROUGH)
+                    && !cr0.contains(Cr0Flags::CACHE_DISABLE)
+                {
+                    return ax_err!(InvalidInput, "CR0.NW set without CR0.CD");
+                }
+                self.set_cr(0, value);
+                self.set_guest_efer(self.guest_efer())?;
+            }
+            ControlRegister::Cr2 => self.special_regs.cr2 = value,
+            ControlRegister::Cr3 => {
+                if self.guest_pae_paging() {
+                    self.load_guest_pdptes(value)?;
+                }
+                VmcsGuestNW::CR3.write(value as _)?;
+                self.flush_guest_tlb(None)?;
+            }
+            ControlRegister::Cr4 => {
+                if value & Cr4Flags::L5_PAGING.bits() != 0 && !Self::guest_la57_supported() {
+                    return ax_err!(Unsupported, "CR4.LA57 is not supported");
+                }
+                if value & !Msr::IA32_VMX_CR4_FIXED1.read() != 0 {
+                    return ax_err!(InvalidInput, "unsupported CR4 bits set");
+                }
+                self.set_cr(4, value);
+            }
+            // CR8 holds the TPR in bits 3:0. (SDM Vol. 3A, Section 10.8.6.1)
+            ControlRegister::Cr8 => {
+                if value > 0xf {
+                    return ax_err!(InvalidInput, "reserved CR8 bits set");
+                }
+                self.set_lapic_tpr((value << 4) as u32);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the guest uses PAE paging, i.e., 32-bit paging with CR4.PAE
+    /// set, whose PDPTEs are loaded in the VMCS. (SDM Vol. 3C, Section
+    /// 27.3.1.6)
+    fn guest_pae_paging(&self) -> bool {
+        self.cr(0) as u64 & Cr0Flags::PAGING.bits() != 0
+            && self.cr(4) as u64 & Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits() != 0
+            && self.guest_efer() & EferFlags::LONG_MODE_ACTIVE.bits() == 0
+    }
+
+    /// Load the PDPTEs of the page-directory-pointer table referenced by
+    /// `cr3`, as a `MOV to CR3` with PAE paging does, failing where it would
+    /// raise #GP. (SDM Vol. 3A, Section 4.4.1)
+    fn load_guest_pdptes(&self, cr3: u64) -> AxResult {
+        // Bits 2:1 and 8:5 of present PDPTEs are reserved, and so is bit 63.
+        const PDPTE_RESERVED: u64 = 0x1e6 | (1 << 63);
+        let table = GuestPhysAddr::from((cr3 & 0xffff_ffe0) as usize);
+        let Some(hpa) = self.guest_phys_to_host_phys(table, false) else {
+            return ax_err!(InvalidInput, "PAE page-directory-pointer table not mapped");
+        };
+        // SAFETY: the 32-byte aligned table is in the guest page mapped at
+        // `hpa`.
+        let pdptes = unsafe { phys_to_virt(hpa).as_ptr_of::<[u64; 4]>().read_volatile() };
+        if pdptes
+            .iter()
+            .any(|&pdpte| pdpte & 1 != 0 && pdpte & PDPTE_RESERVED != 0)
+        {
+            return ax_err!(InvalidInput, "reserved PDPTE bits set");
+        }
+        for (field, pdpte) in [
+            VmcsGuest64::PDPTE0,
+            VmcsGuest64::PDPTE1,
+            VmcsGuest64::PDPTE2,
+            VmcsGuest64::PDPTE3,
+        ]
+        .into_iter()
+        .zip(pdptes)
+        {
+            field.write(pdpte)?;
+        }
+        Ok(())
+    }
+
+    /// The vLAPIC TPR, in its virtual-APIC page.
+    fn lapic_tpr(&self) -> u32 {
+        let page = phys_to_virt(self.vlapic.virtual_apic_page_addr());
+        // SAFETY: the virtual-APIC page is owned by the vLAPIC, and the TPR
+        // is at offset 0x80. (SDM Vol. 3A, Table 11-1)
+        unsafe { (page + 0x80).as_ptr_of::<u32>().read_volatile() }
+    }
+
+    /// Set the vLAPIC TPR, which the vLAPIC does not emulate writes to.
+    fn set_lapic_tpr(&mut self, tpr: u32) {
+        let page = phys_to_virt(self.vlapic.virtual_apic_page_addr());
+        // SAFETY: as in `lapic_tpr`, and the vCPU is not running.
+        unsafe { (page + 0x80).as_mut_ptr_of::<u32>().write_volatile(tpr) }
+    }
+
+    /// The guest IA32_EFER.
+    pub fn guest_efer(&self) -> u64 {
+        VmcsGuest64::IA32_EFER.read().unwrap()
+    }
+
+    /// Set the guest IA32_EFER. LMA is ignored, and set when both LME and
+    /// CR0.PG are set, which also selects an IA-32e mode guest on VM entry.
+    /// (SDM Vol. 3C, Section 27.3.2.1)
+    pub fn set_guest_efer(&mut self, value: u64) -> AxResult {
+        let lma = EferFlags::LONG_MODE_ACTIVE.bits();
+        let long_mode = value & EferFlags::LONG_MODE_ENABLE.bits() != 0
+            && self.cr(0) as u64 & Cr0Flags::PAGING.bits() != 0;
+        let value = if long_mode { value | lma } else { value & !lma };
+        VmcsGuest64::IA32_EFER.write(value)?;
+        Self::set_ia32e_mode_guest(long_mode)
+    }
+
+    /// The value of a guest debug register, as seen by the guest: the
+    /// breakpoints installed with [`VmxVcpu::set_hw_breakpoint`] are not
+    /// visible.
+    pub fn guest_dr(&self, register: DebugRegister) -> u64 {
+        match register {
+            DebugRegister::Dr0 => self.debug_regs.dr[0],
+            DebugRegister::Dr1 => self.debug_regs.dr[1],
+            DebugRegister::Dr2 => self.debug_regs.dr[2],
+            DebugRegister::Dr3 => self.debug_regs.dr[3],
+            DebugRegister::Dr6 => self.debug_regs.dr6,
+            DebugRegister::Dr7 if self.debug_regs.vmm_owned() => self.debug_regs.dr7,
+            DebugRegister::Dr7 => VmcsGuestNW::DR7.read().unwrap() as u64,
+        }
+    }
+
+    /// Set a guest debug register. The upper 32 bits of DR6 and DR7 are
+    /// reserved and must be 0. (SDM Vol. 3B, Section 18.2.6)
+    pub fn set_guest_dr(&mut self, register: DebugRegister, value: u64) -> AxResult {
+        match register {
+            DebugRegister::Dr0 => self.debug_regs.dr[0] = value,
+            DebugRegister::Dr1 => self.debug_regs.dr[1] = value,
+            DebugRegister::Dr2 => self.debug_regs.dr[2] = value,
+            DebugRegister::Dr3 => self.debug_regs.dr[3] = value,
+            DebugRegister::Dr6 | DebugRegister::Dr7 if value >> 32 != 0 => {
+                return ax_err!(InvalidInput, "reserved debug register bits set");
+            }
+            DebugRegister::Dr6 => self.debug_regs.dr6 = value,
+            DebugRegister::Dr7 if self.debug_regs.vmm_owned() => {
+                self.debug_regs.dr7 = value;
+                VmcsGuestNW::DR7.write(self.debug_regs.vmm_dr7() as _)?;
+            }
+            DebugRegister::Dr7 => VmcsGuestNW::DR7.write(value as _)?,
+        }
+        Ok(())
+    }
+
+    /// The hardware breakpoint installed in `slot`, if any.
+    pub fn hw_breakpoint(&self, slot: usize) -> Option<HwBreakpoint> {
+        if slot < HW_BREAKPOINT_SLOTS {
+            self.debug_regs.breakpoint(slot)
+        } else {
+            None
+        }
+    }
+
+    /// The slots where a breakpoint enabled in the guest DR7 is not armed,
+    /// as a breakpoint installed with [`VmxVcpu::set_hw_breakpoint`] uses
+    /// them, one bit per slot.
+    pub fn hw_breakpoint_conflicts(&self) -> u8 {
+        self.debug_regs.conflicts()
+    }
+
+    /// Install a hardware breakpoint or watchpoint in `slot`, below
+    /// [`HW_BREAKPOINT_SLOTS`], or remove it with `None`. Takes effect on
+    /// the next VM entry.
+    ///
+    /// Hits are reported as [`VmxExitEvent::HwBreakpoint`]. While any is
+    /// installed, the VMM owns the debug registers: the guest keeps reading
+    /// and writing its own values, and its breakpoints stay armed in the
+    /// other slots, their hits being reflected as #DB. A guest breakpoint in
+    /// the slot of a VMM breakpoint is not armed until the VMM removes it,
+    /// see [`VmxVcpu::hw_breakpoint_conflicts`].
+    pub fn set_hw_breakpoint(&mut self, slot: usize, breakpoint: Option<HwBreakpoint>) -> AxResult {
+        if slot >= HW_BREAKPOINT_SLOTS {
+            return ax_err!(InvalidInput, "invalid hardware breakpoint slot");
+        }
+        if let Some(breakpoint) = &breakpoint {
+            breakpoint.check()?;
+        }
+        let guest_dr7 = self.guest_dr(DebugRegister::Dr7);
+        self.debug_regs.set_breakpoint(slot, breakpoint);
+        if self.debug_regs.vmm_owned() {
+            self.debug_regs.dr7 = guest_dr7;
+            if self.debug_regs.conflicts().get_bit(slot) {
+                warn!("VMX hardware breakpoint {slot} disarms a guest breakpoint");
+            }
+            VmcsGuestNW::DR7.write(self.debug_regs.vmm_dr7() as _)?;
+        } else {
+            VmcsGuestNW::DR7.write(guest_dr7 as _)?;
+        }
+        // #DB is intercepted while the VMM owns the debug registers.
+        self.exception_intercepts_dirty = true;
+        Ok(())
+    }
+
+    /// Set or clear the IA-32e mode guest VM-entry control, whose value is
+    /// loaded into IA32_EFER.LMA on VM entry.
+    fn set_ia32e_mode_guest(enable: bool) -> AxResult {
+        let entry_ctrl = VmcsControl32::VMENTRY_CONTROLS.read()?;
+        let ia32e_mode = vmcs::controls::EntryControls::IA32E_MODE_GUEST.bits();
+        VmcsControl32::VMENTRY_CONTROLS.write(if enable {
+            entry_ctrl | ia32e_mode
+        } else {
+            entry_ctrl & !ia32e_mode
+        })
+    }
+
+    /// The VMCS fields of a guest segment register: selector, base, limit
+    /// and access rights.
+    fn segment_fields(
+        segment: SegmentRegister,
+    ) -> (VmcsGuest16, VmcsGuestNW, VmcsGuest32, VmcsGuest32) {
+        macro_rules! fields {
+            ($seg: ident) => {
+                paste::paste! {(
+                    VmcsGuest16::[<$seg _SELECTOR>],
+                    VmcsGuestNW::[<$seg _BASE>],
+                    VmcsGuest32::[<$seg _LIMIT>],
+                    VmcsGuest32::[<$seg _ACCESS_RIGHTS>],
+                )}
+            };
+        }
+        match segment {
+            SegmentRegister::Es => fields!(ES),
+            SegmentRegister::Cs => fields!(CS),
+            SegmentRegister::Ss => fields!(SS),
+            SegmentRegister::Ds => fields!(DS),
+            SegmentRegister::Fs => fields!(FS),
+            SegmentRegister::Gs => fields!(GS),
+            SegmentRegister::Ldtr => fields!(LDTR),
+            SegmentRegister::Tr => fields!(TR),
+        }
+    }
+
+    /// Save the architectural state of this vCPU, for checkpointing, cloning
+    /// or migration.
+    ///
+    /// Must be called on the processor this vCPU is bound to, while it is not
+    /// running.
+    ///
+    /// Fails if the guest IA32_XSS enables supervisor state components, which
+    /// are not part of the saved XSAVE area.
+    pub fn save_state(&mut self) -> AxResult<VcpuState> {
+        if self.xstate.guest_xss != 0 {
+            return ax_err!(Unsupported, "guest supervisor states cannot be saved");
+        }
+        let lapic_page = phys_to_virt(self.vlapic.virtual_apic_page_addr()).as_ptr();
+        // SAFETY: the virtual-APIC page is owned by the vLAPIC.
+        let lapic = unsafe { core::slice::from_raw_parts(lapic_page, PAGE_SIZE_4K) }.to_vec();
+        let fpu = self.xstate.guest_fpu();
+        let syscall_msrs = self.guest_syscall_msrs;
+
+        Ok(VcpuState {
+            regs: self.guest_regs,
+            rsp: VmcsGuestNW::RSP.read()? as u64,
+            rip: VmcsGuestNW::RIP.read()? as u64,
+            rflags: VmcsGuestNW::RFLAGS.read()? as u64,
+            es: self.guest_segment(SegmentRegister::Es),
+            cs: self.guest_segment(SegmentRegister::Cs),
+            ss: self.guest_segment(SegmentRegister::Ss),
+            ds: self.guest_segment(SegmentRegister::Ds),
+            fs: self.guest_segment(SegmentRegister::Fs),
+            gs: self.guest_segment(SegmentRegister::Gs),
+            ldtr: self.guest_segment(SegmentRegister::Ldtr),
+            tr: self.guest_segment(SegmentRegister::Tr),
+            gdtr: self.guest_gdtr(),
+            idtr: self.guest_idtr(),
+            cr0: self.cr(0) as u64,
+            cr2: self.special_regs.cr2,
+            cr3: VmcsGuestNW::CR3.read()? as u64,
+            pdptes: [
+                VmcsGuest64::PDPTE0.read()?,
+                VmcsGuest64::PDPTE1.read()?,
+                VmcsGuest64::PDPTE2.read()?,
+                VmcsGuest64::PDPTE3.read()?,
+            ],
+            cr4: self.cr(4) as u64,
+            cr8: self.guest_cr(ControlRegister::Cr8),
+            xcr0: self.xstate.guest_xcr0,
+            dr: self.debug_regs.dr,
+            dr6: self.debug_regs.dr6,
+            dr7: self.guest_dr(DebugRegister::Dr7),
+            msrs: VcpuMsrs {
+                efer: VmcsGuest64::IA32_EFER.read()?,
+                pat: VmcsGuest64::IA32_PAT.read()?,
+                debugctl: VmcsGuest64::IA32_DEBUGCTL.read()?,
+                sysenter_cs: VmcsGuest32::IA32_SYSENTER_CS.read()? as u64,
+                sysenter_esp: VmcsGuestNW::IA32_SYSENTER_ESP.read()? as u64,
+                sysenter_eip: VmcsGuestNW::IA32_SYSENTER_EIP.read()? as u64,
+                star: syscall_msrs.star,
+                lstar: syscall_msrs.lstar,
+                cstar: syscall_msrs.cstar,
+                fmask: syscall_msrs.fmask,
+                kernel_gs_base: syscall_msrs.kernel_gs_base,
+                xss: self.xstate.guest_xss,
+            },
+            interruptibility: VmcsGuest32::INTERRUPTIBILITY_STATE.read()?,
+            activity_state: vmcs::activity_state()?,
+            pending_dbg_exceptions: VmcsGuestNW::PENDING_DBG_EXCEPTIONS.read()? as u64,
+            events: self.pending_events.save(),
+            fpu,
+            lapic,
+        })
+    }
+
+    /// Load an architectural state saved by [`VmxVcpu::save_state`], possibly
+    /// from another vCPU or machine.
+    ///
+    /// Must be called on the processor this vCPU is bound to, while it is not
+    /// running. The XSAVE area must match the layout of this processor, and
+    /// IA32_XSS must be zero. The local APIC timer is restarted from its
+    /// initial count.
+    pub fn restore_state(&mut self, state: &VcpuState) -> AxResult {
+        if state.lapic.len() != PAGE_SIZE_4K {
+            return ax_err!(InvalidInput, "virtual-APIC page size mismatch");
+        }
+        if state.msrs.xss != 0 {
+            return ax_err!(Unsupported, "guest supervisor states cannot be restored");
+        }
+        self.xstate
+            .set_guest_fpu(state.xcr0, state.msrs.xss, &state.fpu)?;
+
+        self.guest_regs = state.regs;
+        VmcsGuestNW::RSP.write(state.rsp as _)?;
+        VmcsGuestNW::RIP.write(state.rip as _)?;
+        VmcsGuestNW::RFLAGS.write(state.rflags as _)?;
+        use SegmentRegister::*;
+        for (segment, value) in [
+            (Es, state.es),
+            (Cs, state.cs),
+            (Ss, state.ss),
+            (Ds, state.ds),
+            (Fs, state.fs),
+            (Gs, state.gs),
+            (Ldtr, state.ldtr),
+            (Tr, state.tr),
+        ] {
+            self.set_guest_segment(segment, value)?;
+        }
+        self.set_guest_gdtr(state.gdtr)?;
+        self.set_guest_idtr(state.idtr)?;
+
+        self.set_cr(0, state.cr0);
+        self.set_cr(4, state.cr4);
+        VmcsGuestNW::CR3.write(state.cr3 as _)?;
+        for (field, value) in [
+            VmcsGuest64::PDPTE0,
+            VmcsGuest64::PDPTE1,
+            VmcsGuest64::PDPTE2,
+            VmcsGuest64::PDPTE3,
+        ]
+        .into_iter()
+        .zip(state.pdptes)
+        {
+            field.write(value)?;
+        }
+        // The translations cached for the previous CR3 are tagged with the
+        // same VPID.
+        self.flush_guest_tlb(None)?;
+        self.set_guest_dr(DebugRegister::Dr7, state.dr7)?;
+        self.special_regs.cr2 = state.cr2;
+        self.debug_regs.dr = state.dr;
+        self.debug_regs.dr6 = state.dr6;
+        self.guest_syscall_msrs = SyscallMsrs {
+            star: state.msrs.star,
+            lstar: state.msrs.lstar,
+            cstar: state.msrs.cstar,
+            fmask: state.msrs.fmask,
+            kernel_gs_base: state.msrs.kernel_gs_base,
+        };
+
+        VmcsGuest64::IA32_EFER.write(state.msrs.efer)?;
+        Self::set_ia32e_mode_guest(state.msrs.efer & EferFlags::LONG_MODE_ACTIVE.bits() != 0)?;
+        VmcsGuest64::IA32_PAT.write(state.msrs.pat)?;
+        VmcsGuest64::IA32_DEBUGCTL.write(state.msrs.debugctl)?;
+        VmcsGuest32::IA32_SYSENTER_CS.write(state.msrs.sysenter_cs as _)?;
+        VmcsGuestNW::IA32_SYSENTER_ESP.write(state.msrs.sysenter_esp as _)?;
+        VmcsGuestNW::IA32_SYSENTER_EIP.write(state.msrs.sysenter_eip as _)?;
+
+        VmcsGuest32::INTERRUPTIBILITY_STATE.write(state.interruptibility)?;
+        VmcsGuest32::ACTIVITY_STATE.write(state.activity_state as _)?;
+        VmcsGuestNW::PENDING_DBG_EXCEPTIONS.write(state.pending_dbg_exceptions as _)?;
+        self.pending_events.restore(&state.events);
+
+        self.restore_lapic(&state.lapic)?;
+        // CR8 is a view of the TPR in the page, which keeps its bits 3:0
+        // unless the two disagree.
+        if self.guest_cr(ControlRegister::Cr8) != state.cr8 {
+            self.set_guest_cr(ControlRegister::Cr8, state.cr8)?;
+        }
+        Ok(())
+    }
+
+    /// Load the virtual-APIC page, then write again the registers which the
+    /// vLAPIC also tracks outside of the page: SVR, the LVT and the timer.
+    fn restore_lapic(&mut self, page: &[u8]) -> AxResult {
+        // x2APIC MSRs of SVR, LVT CMCI, LVT timer to LVT error, TDCR, TMICT.
+        const REPLAYED: [u32; 10] = [
+            0x80f, 0x82f, 0x832, 0x833, 0x834, 0x835, 0x836, 0x837, 0x83e, 0x838,
+        ];
+        let lapic_page = phys_to_virt(self.vlapic.virtual_apic_page_addr()).as_mut_ptr();
+        // SAFETY: the virtual-APIC page is owned by the vLAPIC, and the vCPU
+        // is not running.
+        unsafe { core::slice::from_raw_parts_mut(lapic_page, PAGE_SIZE_4K) }.copy_from_slice(page);
+        for msr in REPLAYED {
+            let offset = ((msr - 0x800) << 4) as usize;
+            let value = u32::from_le_bytes(page[offset..offset + 4].try_into().unwrap());
+            <EmulatedLocalApic as BaseDeviceOps<SysRegAddrRange>>::handle_write(
+                &self.vlapic,
+                SysRegAddr(msr as usize),
+                AccessWidth::Dword,
+                value as usize,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Compute the linear address of a guest access of `size` bytes at
+    /// `offset` in `segment`, with the limit and rights checks of the current
+    /// CPU mode. See [`GuestSegment::linearize`].
+    ///
+    /// Returns the #GP(0) or #SS(0) the guest would get otherwise, which can
+    /// be raised with [`VmxVcpu::inject_exception`].
+    pub fn linearize(
+        &self,
+        segment: SegmentRegister,
+        offset: u64,
+        size: usize,
+        access: SegmentAccess,
+    ) -> core::result::Result<GuestVirtAddr, GuestException> {
+        let la57 = VmcsGuestNW::CR4.read().unwrap() & Cr4Flags::L5_PAGING.bits() as usize != 0;
+        self.guest_segment(segment)
+            .linearize(segment, self.get_cpu_mode(), la57, offset, size, access)
+            .map(|linear| GuestVirtAddr::from(linear as usize))
+    }
+
+    /// Get Translate guest page table info
+    pub fn get_ptw_info(&self) -> GuestPageWalkInfo {
+        let top_entry = VmcsGuestNW::CR3.read().unwrap();
+        let level = self.get_paging_level();
+        let is_write_access = false;
+        let is_inst_fetch = false;
+        let is_user_mode_access = ((VmcsGuest32::SS_ACCESS_RIGHTS.read().unwrap() >> 5) & 0x3) == 3;
+        let mut pse = true;
+        let mut nxe =
+            (VmcsGuest64::IA32_EFER.read().unwrap() & EferFlags::NO_EXECUTE_ENABLE.bits()) != 0;
+        let wp = (VmcsGuestNW::CR0.read().unwrap() & Cr0Flags::WRITE_PROTECT.bits() as usize) != 0;
+        let is_smap_on = (VmcsGuestNW::CR4.read().unwrap()
+            & Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION.bits() as usize)
+            != 0;
+        let is_smep_on = (VmcsGuestNW::CR4.read().unwrap()
+            & Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION.bits() as usize)
+            != 0;
+        let width: u32;
+        if level >= 3 {
+            width = 9;
+        } else if level == 2 {
+            width = 10;
+            pse = VmcsGuestNW::CR4.read().unwrap() & Cr4Flags::PAGE_SIZE_EXTENSION.bits() as usize
+                != 0;
+            nxe = false;
+        } else {
+            width = 0;
+        }
+        GuestPageWalkInfo {
+            top_entry,
+            level,
+            width,
+            is_user_mode_access,
+            is_write_access,
+            is_inst_fetch,
+            pse,
+            wp,
+            nxe,
+            is_smap_on,
+            is_smep_on,
+        }
+    }
+
+    /// Translate a guest physical address to a host physical address through
+    /// the EPT, for a write access if `write`. Returns `None` if `gpa` is not
+    /// mapped with the required permission.
+    pub fn guest_phys_to_host_phys(&self, gpa: GuestPhysAddr, write: bool) -> Option<HostPhysAddr> {
+        ept::ept_translate(VmcsControl64::EPTP.read().unwrap(), gpa, write)
+    }
+
+    /// Translate the guest linear address `gla`, e.g., computed by
+    /// [`VmxVcpu::linearize`], to a guest physical address, by walking the
+    /// guest page tables for a data access at the current privilege level, a
+    /// write if `write`.
+    ///
+    /// The guest page tables are left untouched, see
+    /// [`VmxVcpu::access_guest_virt`].
+    pub fn guest_virt_to_phys(
+        &self,
+        gla: GuestVirtAddr,
+        write: bool,
+    ) -> GuestAccessResult<GuestPhysAddr> {
+        self.walk_guest_page_tables(gla, write, false)
+    }
+
+    /// Translate the guest linear address `gla` like
+    /// [`VmxVcpu::guest_virt_to_phys`], for an access emulated by the VMM:
+    /// the accessed flags of the guest paging-structure entries, and the
+    /// dirty flag of the page on writes, are set as the processor would.
+    pub fn access_guest_virt(
+        &mut self,
+        gla: GuestVirtAddr,
+        write: bool,
+    ) -> GuestAccessResult<GuestPhysAddr> {
+        self.walk_guest_page_tables(gla, write, true)
+    }
+
+    fn walk_guest_page_tables(
+        &self,
+        gla: GuestVirtAddr,
+        write: bool,
+        update_flags: bool,
+    ) -> GuestAccessResult<GuestPhysAddr> {
+        let mut info = self.get_ptw_info();
+        info.is_write_access = write;
+        if VmcsGuestNW::RFLAGS.read().unwrap() & RFlags::ALIGNMENT_CHECK.bits() as usize != 0 {
+            // Explicit supervisor accesses are allowed to user pages.
+            info.is_smap_on = false;
+        }
+        let mut mem = EptGuestMemory {
+            eptp: VmcsControl64::EPTP.read().unwrap(),
+            update_flags,
+        };
+        info.translate(gla, &mut mem)
+            .map_err(|err| GuestAccessError::from_walk(err, gla))
+    }
+
+    /// Read guest memory at the guest linear address `gla` into `buf`.
+    ///
+    /// The access is split at page boundaries, and each page is translated
+    /// by [`VmxVcpu::guest_virt_to_phys`] and then through the EPT. See
+    /// [`VmxVcpu::read_guest_seg`] for an address relative to a segment.
+    pub fn read_guest_virt(&self, gla: GuestVirtAddr, buf: &mut [u8]) -> GuestAccessResult {
+        for (_, hpa, range) in self.translate_guest_virt_range(gla, buf.len(), false, false)? {
+            let src = phys_to_virt(hpa).as_ptr();
+            // SAFETY: `hpa` maps guest memory for the whole chunk, within a page.
+            unsafe {
+                core::ptr::copy_nonoverlapping(src, buf[range.clone()].as_mut_ptr(), range.len())
+            };
+        }
+        Ok(())
+    }
+
+    /// Write `buf` into guest memory at the guest linear address `gla`.
+    ///
+    /// Like [`VmxVcpu::read_guest_virt`], but nothing is written if any page
+    /// of the range cannot be written.
+    pub fn write_guest_virt(&mut self, gla: GuestVirtAddr, buf: &[u8]) -> GuestAccessResult {
+        for (gpa, hpa, range) in self.translate_guest_virt_range(gla, buf.len(), true, true)? {
+            let dst = phys_to_virt(hpa).as_mut_ptr();
+            // SAFETY: `hpa` maps guest memory for the whole chunk, within a page.
+            unsafe {
+                core::ptr::copy_nonoverlapping(buf[range.clone()].as_ptr(), dst, range.len())
+            };
+            self.log_host_write(gpa, range.len());
+        }
+        Ok(())
+    }
+
+    /// Write `buf` into guest memory at the guest linear address `gla` for
+    /// a debugger, e.g., to patch breakpoints into code.
+    ///
+    /// Like [`VmxVcpu::write_guest_virt`], but the write protection of the
+    /// guest page tables is ignored. The EPT must still map the pages
+    /// writable, which keeps read-only memory, e.g., ROM, intact.
+    pub fn debug_write_guest_virt(&mut self, gla: GuestVirtAddr, buf: &[u8]) -> GuestAccessResult {
+        for (gpa, hpa, range) in self.translate_guest_virt_range(gla, buf.len(), false, true)? {
+            let dst = phys_to_virt(hpa).as_mut_ptr();
+            // SAFETY: `hpa` maps guest memory for the whole chunk, within a page.
+            unsafe {
+                core::ptr::copy_nonoverlapping(buf[range.clone()].as_ptr(), dst, range.len())
+            };
+            self.log_host_write(gpa, range.len());
+        }
+        Ok(())
+    }
+
+    /// Read guest memory at `offset` in `segment` into `buf`, as a guest
+    /// data read: the address is linearized by [`VmxVcpu::linearize`], then
+    /// read by [`VmxVcpu::read_guest_virt`].
+    pub fn read_guest_seg(
+        &self,
+        segment: SegmentRegister,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> GuestAccessResult {
+        let gla = self
+            .linearize(segment, offset, buf.len(), SegmentAccess::Read)
+            .map_err(GuestAccessError::Segmentation)?;
+        self.read_guest_virt(gla, buf)
+    }
+
+    /// Write `buf` into guest memory at `offset` in `segment`, as a guest
+    /// data write: the address is linearized by [`VmxVcpu::linearize`], then
+    /// written by [`VmxVcpu::write_guest_virt`].
+    pub fn write_guest_seg(
+        &mut self,
+        segment: SegmentRegister,
+        offset: u64,
+        buf: &[u8],
+    ) -> GuestAccessResult {
+        let gla = self
+            .linearize(segment, offset, buf.len(), SegmentAccess::Write)
+            .map_err(GuestAccessError::Segmentation)?;
+        self.write_guest_virt(gla, buf)
+    }
+
+    /// The guest FPU and extended states, in the XSAVE format for the user
+    /// state components of the guest XCR0, or in the FXSAVE format without
+    /// XSAVE.
+    pub fn guest_fpu(&mut self) -> Vec<u8> {
+        self.xstate.guest_fpu()
+    }
+
+    /// Load the guest FPU and extended states, in the format returned by
+    /// [`VmxVcpu::guest_fpu`].
+    pub fn set_guest_fpu(&mut self, fpu: &[u8]) -> AxResult {
+        let (xcr0, xss) = (self.xstate.guest_xcr0, self.xstate.guest_xss);
+        self.xstate.set_guest_fpu(xcr0, xss, fpu)
+    }
+
+    /// Guest rip. (`RIP`)
+    pub fn rip(&self) -> usize {
+        VmcsGuestNW::RIP.read().unwrap()
+    }
+
+    /// Set guest rip. (`RIP`)
+    pub fn set_rip(&mut self, rip: usize) {
+        VmcsGuestNW::RIP.write(rip).unwrap()
+    }
+
+    /// Guest rflags. (`RFLAGS`)
+    pub fn rflags(&self) -> usize {
+        VmcsGuestNW::RFLAGS.read().unwrap()
+    }
+
+    /// Set guest rflags. (`RFLAGS`)
+    pub fn set_rflags(&mut self, rflags: usize) {
+        VmcsGuestNW::RFLAGS.write(rflags).unwrap()
+    }
+
+    /// Guest cs. (`cs`)
+    pub fn cs(&self) -> u16 {
+        VmcsGuest16::CS_SELECTOR.read().unwrap()
+    }
+
+    /// Advance guest `RIP` by `instr_len` bytes.
+    pub fn advance_rip(&mut self, instr_len: u8) -> AxResult {
+        VmcsGuestNW::RIP.write(VmcsGuestNW::RIP.read()? + instr_len as usize)
+    }
+
+    /// Add a virtual interrupt or exception to the pending events,
+    /// and try to inject it before later VM entries.
+    ///
+    /// Vector 2 queues an NMI, other vectors below 32 raise an exception (see
+    /// [`VmxVcpu::inject_exception`]), and vectors from 32 up queue an
+    /// external interrupt. A #PF raised this way keeps the current guest CR2,
+    /// and a #DB reports no DR6 bits; use [`VmxVcpu::inject_exception`] to
+    /// provide them.
+    pub fn queue_event(&mut self, vector: u8, err_code: Option<u32>) {
+        use x86::irq::{NONMASKABLE_INTERRUPT_VECTOR, PAGE_FAULT_VECTOR};
+        match vector {
+            NONMASKABLE_INTERRUPT_VECTOR => self.pending_events.queue_nmi(),
+            0..32 => {
+                let payload = if vector == PAGE_FAULT_VECTOR {
+                    self.special_regs.cr2
+                } else {
+                    0
+                };
+                match GuestException::from_vector(vector, err_code, payload) {
+                    Some(exception) => self.inject_exception(exception),
+                    None => warn!("VmxVcpu: ignored queued event with reserved vector {vector}"),
+                }
+            }
+            _ => self.pending_events.queue_interrupt(vector),
+        }
+    }
+
+    /// Whether any event is waiting to be injected into the guest.
+    pub fn has_pending_events(&self) -> bool {
+        !self.pending_events.is_empty()
+    }
+
+    /// Raise `exception` in the guest on the next VM entry.
+    ///
+    /// If an exception is already pending, or was being delivered when the
+    /// last VM exit happened, the two are merged as the processor would do
+    /// (SDM Vol. 3A, Section 6.15): the result may be a double fault, or a
+    /// triple fault which is reported by the next run as a
+    /// [`VmxExitReason::TRIPLE_FAULT`] exit, see [`VmxVcpu::shutdown_info`].
+    ///
+    /// A #BP or #OF is delivered as if raised by a one-byte INT3 or INTO.
+    pub fn inject_exception(&mut self, exception: GuestException) {
+        self.raise_exception(exception, 1);
+    }
+
+    /// Raise `exception`, coming from an instruction of `instr_len` bytes if
+    /// it is a software exception.
+    fn raise_exception(&mut self, exception: GuestException, instr_len: u32) {
+        let merge = self.pending_events.raise_exception(exception, instr_len);
+        if merge == ExceptionMerge::TripleFault {
+            warn!("VmxVcpu: exception {exception:?} during #DF delivery, triple fault");
+        }
+    }
+
+    /// The exception that will be injected on the next VM entry, if any.
+    pub fn pending_exception(&self) -> Option<GuestException> {
+        self.pending_events.exception()
+    }
+
+    /// Set how the exception `vector` raised in the guest is handled, taking
+    /// effect on the next VM entry.
+    ///
+    /// NMIs (vector 2) and vectors from 32 up cannot be intercepted.
+    pub fn set_exception_policy(&mut self, vector: u8, policy: ExceptionPolicy) -> AxResult {
+        if !ExceptionIntercepts::is_valid_vector(vector) {
+            return ax_err!(InvalidInput, "vector cannot be intercepted");
+        }
+        self.exception_intercepts.set_policy(vector, policy);
+        self.exception_intercepts_dirty = true;
+        Ok(())
+    }
+
+    /// How the exception `vector` raised in the guest is handled.
+    pub fn exception_policy(&self, vector: u8) -> ExceptionPolicy {
+        self.exception_intercepts.policy(vector)
+    }
+
+    /// The exception bitmap: one bit per intercepted vector.
+    pub fn exception_bitmap(&self) -> u32 {
+        self.exception_intercepts.bitmap()
+    }
+
+    /// Intercept exactly the exception vectors set in `bitmap`, taking effect
+    /// on the next VM entry. Newly intercepted vectors are reported to the
+    /// VMM (see [`ExceptionPolicy::Exit`]), the others keep their policy.
+    ///
+    /// The NMI bit (bit 2) must be clear.
+    pub fn set_exception_bitmap(&mut self, bitmap: u32) -> AxResult {
+        if bitmap.get_bit(x86::irq::NONMASKABLE_INTERRUPT_VECTOR as usize) {
+            return ax_err!(InvalidInput, "NMIs cannot be intercepted");
+        }
+        self.exception_intercepts.set_bitmap(bitmap);
+        self.exception_intercepts_dirty = true;
+        Ok(())
+    }
+
+    /// The page-fault error-code mask and match, as `(mask, match)`.
+    pub fn page_fault_filter(&self) -> (u32, u32) {
+        self.exception_intercepts.page_fault_filter()
+    }
+
+    /// Set the page-fault error-code mask and match, taking effect on the
+    /// next VM entry. (SDM Vol. 3C, Section 26.2)
+    ///
+    /// While #PF is intercepted, a #PF with error code `err_code` is
+    /// intercepted if `err_code & mask == match`, and handled as the policy
+    /// of #PF says; the others are delivered to the guest. While #PF is not
+    /// intercepted, the filter has no effect and all page faults are
+    /// delivered. E.g., set #PF to [`ExceptionPolicy::Exit`] with mask =
+    /// match = `W | U` to only catch user-mode write faults.
+    pub fn set_page_fault_filter(&mut self, mask: u32, match_: u32) {
+        self.exception_intercepts
+            .set_page_fault_filter(mask, match_);
+        self.exception_intercepts_dirty = true;
+    }
+
+    /// Whether a guest page fault with `err_code` causes a VM exit, given
+    /// the exception bitmap and the page-fault error-code filter.
+    pub fn intercepts_page_fault(&self, err_code: u32) -> bool {
+        self.exception_intercepts.intercepts_page_fault(err_code)
+    }
+
+    /// The VM exit of the last run that [`AxVCpuExitReason`] cannot express.
+    pub fn exit_event(&self) -> Option<VmxExitEvent> {
+        self.exit_event
+    }
+
+    /// Guest state captured when the guest shut down on the last run, i.e.,
+    /// on a triple fault or on a VM exit in the shutdown activity state.
+    ///
+    /// The pending events are discarded on shutdown, and the VMM is expected
+    /// to reset the guest before running it again.
+    pub fn shutdown_info(&self) -> Option<&VmxShutdownInfo> {
+        self.shutdown.as_ref()
+    }
+
+    /// Whether the guest is single-stepped, see [`VmxVcpu::set_single_step`].
+    pub fn single_step(&self) -> bool {
+        self.single_step
     }
 
-    /// Information for VM exits due to nested page table faults (EPT violation).
-    pub fn nested_page_fault_info(&self) -> AxResult<NestedPageFaultInfo> {
-        vmcs::ept_violation_info()
+    /// Single-step the guest with the monitor trap flag: each run executes
+    /// one guest instruction, then returns with [`VmxExitEvent::SingleStep`].
+    /// (SDM Vol. 3C, Section 26.5.2)
+    ///
+    /// If an event is injected on VM entry, the step ends after the first
+    /// instruction of its handler. If the instruction causes a VM exit that
+    /// is reported to the VMM instead, the step ends once the VMM emulated
+    /// it, i.e., the next run executes the next instruction.
+    pub fn set_single_step(&mut self, enable: bool) -> AxResult {
+        if enable && !Self::monitor_trap_supported() {
+            return ax_err!(Unsupported, "monitor trap flag is not supported");
+        }
+        Self::set_monitor_trap(enable)?;
+        self.single_step = enable;
+        self.step_injected = None;
+        self.step_delivered = None;
+        Ok(())
     }
 
-    /// Information for VM exits due to APIC access.
-    pub fn apic_access_exit_info(&self) -> AxResult<vmcs::ApicAccessExitInfo> {
-        vmcs::apic_access_exit_info()
+    fn monitor_trap_supported() -> bool {
+        let bits = vmcs::controls::PrimaryControls::MONITOR_TRAP_FLAG.bits();
+        vmcs::is_control_supported(Msr::IA32_VMX_TRUE_PROCBASED_CTLS, bits)
     }
 
-    /// Guest general-purpose registers.
-    pub fn regs(&self) -> &GeneralRegisters {
-        &self.guest_regs
+    /// Set or clear the monitor trap flag VM-execution control.
+    fn set_monitor_trap(enable: bool) -> AxResult {
+        let bits = vmcs::controls::PrimaryControls::MONITOR_TRAP_FLAG.bits();
+        let ctrl = VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.read()?;
+        VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.write(if enable {
+            ctrl | bits
+        } else {
+            ctrl & !bits
+        })
     }
 
-    /// Mutable reference of guest general-purpose registers.
-    pub fn regs_mut(&mut self) -> &mut GeneralRegisters {
-        &mut self.guest_regs
+    /// Insert a software breakpoint at `addr`: its byte in guest memory is
+    /// saved and replaced with INT3, and #BP is intercepted while any
+    /// breakpoint is inserted. Virtual addresses are translated like with
+    /// [`VmxVcpu::guest_virt_to_phys`].
+    ///
+    /// Hits are reported as [`VmxExitEvent::SwBreakpoint`], while the INT3
+    /// instructions of the guest itself are reflected to the guest, unless
+    /// [`ExceptionPolicy::Exit`] is set for #BP. When the guest resumes at
+    /// the breakpoint, the original instruction is executed alone with the
+    /// monitor trap flag, and INT3 is patched in again after it.
+    ///
+    /// The guest page must be mapped writable in the EPT, the write
+    /// protection for dirty logging aside. The other vCPUs of the VM, which
+    /// share the guest memory, must be told about the breakpoint with
+    /// [`VmxVcpu::attach_sw_breakpoint`].
+    ///
+    /// While a vCPU steps over a breakpoint, the original instruction is in
+    /// guest memory, so the other vCPUs would miss the breakpoint, or see
+    /// the instruction change under them: those sharing its
+    /// [`EptShootdown`] are kept out of the guest meanwhile, see
+    /// [`VmxVcpu::set_ept_shootdown`]. A VM with several vCPUs must use one.
+    pub fn insert_sw_breakpoint(&mut self, addr: SwBreakpointAddr) -> AxResult<SwBreakpoint> {
+        if !Self::monitor_trap_supported() {
+            return ax_err!(Unsupported, "monitor trap flag is not supported");
+        }
+        let gpa = self.sw_breakpoint_gpa(addr)?;
+        if self.sw_breakpoints.get(gpa).is_some() {
+            return ax_err!(AlreadyExists, "software breakpoint already inserted");
+        }
+        let orig = self.patch_guest_byte(gpa, INT3)?;
+        let breakpoint = SwBreakpoint { gpa, orig };
+        self.attach_sw_breakpoint(breakpoint)?;
+        Ok(breakpoint)
     }
 
-    /// Guest stack pointer. (`RSP`)
-    pub fn stack_pointer(&self) -> usize {
-        VmcsGuestNW::RSP.read().unwrap()
+    /// Remove the software breakpoint at `addr`, restoring the original byte
+    /// in guest memory. It must be detached from the other vCPUs with
+    /// [`VmxVcpu::detach_sw_breakpoint`].
+    pub fn remove_sw_breakpoint(&mut self, addr: SwBreakpointAddr) -> AxResult<SwBreakpoint> {
+        let gpa = self.sw_breakpoint_gpa(addr)?;
+        let breakpoint = self.detach_sw_breakpoint(gpa)?;
+        self.patch_guest_byte(gpa, breakpoint.orig)?;
+        Ok(breakpoint)
     }
 
-    /// Set guest stack pointer. (`RSP`)
-    pub fn set_stack_pointer(&mut self, rsp: usize) {
-        VmcsGuestNW::RSP.write(rsp).unwrap()
+    /// Handle a software breakpoint inserted by another vCPU of the VM as one
+    /// of this vCPU, without patching guest memory.
+    pub fn attach_sw_breakpoint(&mut self, breakpoint: SwBreakpoint) -> AxResult {
+        self.sw_breakpoints.insert(breakpoint)?;
+        self.exception_intercepts_dirty = true;
+        Ok(())
     }
 
-    /// Translate guest virtual addr to linear addr    
-    pub fn gla2gva(&self, guest_rip: GuestVirtAddr) -> GuestVirtAddr {
-        let cpu_mode = self.get_cpu_mode();
-        let seg_base = if cpu_mode == VmCpuMode::Mode64 {
-            0
-        } else {
-            VmcsGuestNW::CS_BASE.read().unwrap()
-        };
-        // debug!(
-        //     "seg_base: {:#x}, guest_rip: {:#x} cpu mode:{:?}",
-        //     seg_base, guest_rip, cpu_mode
-        // );
-        guest_rip + seg_base
+    /// Forget the software breakpoint at `gpa`, without restoring guest
+    /// memory.
+    pub fn detach_sw_breakpoint(&mut self, gpa: GuestPhysAddr) -> AxResult<SwBreakpoint> {
+        let breakpoint = self.sw_breakpoints.remove(gpa)?;
+        self.exception_intercepts_dirty = true;
+        Ok(breakpoint)
     }
 
-    /// Get Translate guest page table info
-    pub fn get_ptw_info(&self) -> GuestPageWalkInfo {
-        let top_entry = VmcsGuestNW::CR3.read().unwrap();
-        let level = self.get_paging_level();
-        let is_write_access = false;
-        let is_inst_fetch = false;
-        let is_user_mode_access = ((VmcsGuest32::SS_ACCESS_RIGHTS.read().unwrap() >> 5) & 0x3) == 3;
-        let mut pse = true;
-        let mut nxe =
-            (VmcsGuest64::IA32_EFER.read().unwrap() & EferFlags::NO_EXECUTE_ENABLE.bits()) != 0;
-        let wp = (VmcsGuestNW::CR0.read().unwrap() & Cr0Flags::WRITE_PROTECT.bits() as usize) != 0;
-        let is_smap_on = (VmcsGuestNW::CR4.read().unwrap()
-            & Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION.bits() as usize)
-            != 0;
-        let is_smep_on = (VmcsGuestNW::CR4.read().unwrap()
-            & Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION.bits() as usize)
-            != 0;
-        let width: u32;
-        if level == 4 || level == 3 {
-            width = 9;
-        } else if level == 2 {
-            width = 10;
-            pse = VmcsGuestNW::CR4.read().unwrap() & Cr4Flags::PAGE_SIZE_EXTENSION.bits() as usize
-                != 0;
-            nxe = false;
-        } else {
-            width = 0;
+    /// The software breakpoint at `gpa`, if any.
+    pub fn sw_breakpoint(&self, gpa: GuestPhysAddr) -> Option<SwBreakpoint> {
+        self.sw_breakpoints.get(gpa)
+    }
+
+    fn sw_breakpoint_gpa(&self, addr: SwBreakpointAddr) -> AxResult<GuestPhysAddr> {
+        match addr {
+            SwBreakpointAddr::Virt(gva) => self
+                .guest_virt_to_phys(gva, false)
+                .map_err(|_| ax_err_type!(BadAddress, "breakpoint address not mapped")),
+            SwBreakpointAddr::Phys(gpa) => Ok(gpa),
         }
-        GuestPageWalkInfo {
-            top_entry,
-            level,
-            width,
-            is_user_mode_access,
-            is_write_access,
-            is_inst_fetch,
-            pse,
-            wp,
-            nxe,
-            is_smap_on,
-            is_smep_on,
+    }
+
+    /// The software breakpoint at guest RIP, if any.
+    fn sw_breakpoint_at_rip(&self) -> Option<SwBreakpoint> {
+        if self.sw_breakpoints.is_empty() {
+            return None;
         }
+        let gpa = self
+            .guest_virt_to_phys(self.gla2gva(GuestVirtAddr::from(self.rip())), false)
+            .ok()?;
+        self.sw_breakpoints.get(gpa)
     }
 
-    /// Guest rip. (`RIP`)
-    pub fn rip(&self) -> usize {
-        VmcsGuestNW::RIP.read().unwrap()
+    /// Replace the guest byte at `gpa` with `byte`, which must be mapped
+    /// writable, ignoring the EPT write protection for dirty logging, and
+    /// return the previous one.
+    fn patch_guest_byte(&mut self, gpa: GuestPhysAddr, byte: u8) -> AxResult<u8> {
+        let writable = self.guest_phys_to_host_phys(gpa, true).is_some()
+            || ept::ept_write_protected(VmcsControl64::EPTP.read()?, gpa.as_usize() as u64);
+        let hpa = self
+            .guest_phys_to_host_phys(gpa, false)
+            .filter(|_| writable)
+            .ok_or_else(|| ax_err_type!(BadAddress, "breakpoint address not writable"))?;
+        // SAFETY: `hpa` maps a byte of guest memory.
+        let orig = unsafe { core::ptr::replace(phys_to_virt(hpa).as_mut_ptr(), byte) };
+        self.log_host_write(gpa, 1);
+        Ok(orig)
     }
 
-    /// Guest cs. (`cs`)
-    pub fn cs(&self) -> u16 {
-        VmcsGuest16::CS_SELECTOR.read().unwrap()
+    /// If the guest resumes at the software breakpoint hit last, restore its
+    /// original instruction and set the monitor trap flag, so that it is
+    /// executed alone on this VM entry. The instruction is restored in the
+    /// guest memory shared by all vCPUs, which are parked out of the guest
+    /// until [`VmxVcpu::end_step_over`].
+    fn begin_step_over(&mut self) -> AxResult<Option<SwBreakpoint>> {
+        let Some(rip) = self.sw_breakpoints.step_over else {
+            return Ok(None);
+        };
+        let breakpoint = self.sw_breakpoint_at_rip().filter(|_| rip == self.rip());
+        let Some(breakpoint) = breakpoint else {
+            self.sw_breakpoints.step_over = None;
+            return Ok(None);
+        };
+        if let Some(shootdown) = &self.ept_shootdown {
+            shootdown.park_others(self.vcpu_id)?;
+        }
+        if let Err(err) = self.patch_guest_byte(breakpoint.gpa, breakpoint.orig) {
+            self.unpark_others();
+            return Err(err);
+        }
+        if !self.single_step {
+            Self::set_monitor_trap(true)?;
+        }
+        Ok(Some(breakpoint))
     }
 
-    /// Advance guest `RIP` by `instr_len` bytes.
-    pub fn advance_rip(&mut self, instr_len: u8) -> AxResult {
-        VmcsGuestNW::RIP.write(VmcsGuestNW::RIP.read()? + instr_len as usize)
+    /// Patch INT3 at the breakpoint again after a VM exit. The step over is
+    /// done once the monitor trap flag reports the instruction executed, it
+    /// is retried otherwise if the guest still resumes at the breakpoint.
+    fn end_step_over(&mut self, breakpoint: SwBreakpoint, exit_info: &VmxExitInfo) -> AxResult {
+        let patched = self.patch_guest_byte(breakpoint.gpa, INT3);
+        self.unpark_others();
+        patched?;
+        if !self.single_step {
+            Self::set_monitor_trap(false)?;
+        }
+        if exit_info.exit_reason == VmxExitReason::MONITOR_TRAP_FLAG {
+            self.sw_breakpoints.step_over = None;
+        }
+        Ok(())
     }
 
-    /// Add a virtual interrupt or exception to the pending events list,
-    /// and try to inject it before later VM entries.
-    pub fn queue_event(&mut self, vector: u8, err_code: Option<u32>) {
-        self.pending_events.push_back((vector, err_code));
+    /// Let the vCPUs parked by [`VmxVcpu::begin_step_over`] run again.
+    fn unpark_others(&self) {
+        if let Some(shootdown) = &self.ept_shootdown {
+            shootdown.unpark_others(self.vcpu_id);
+        }
     }
 
     /// If enable, a VM exit occurs at the beginning of any instruction if
@@ -494,6 +2480,21 @@ impl VmxVcpu {
         Ok(())
     }
 
+    /// If enable, a VM exit occurs at the beginning of any instruction if
+    /// there is no virtual-NMI blocking. Requires virtual NMIs.
+    /// (see SDM, Vol. 3C, Section 25.6.2)
+    pub fn set_nmi_window(&mut self, enable: bool) -> AxResult {
+        let mut ctrl = VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.read()?;
+        let bits = vmcs::controls::PrimaryControls::NMI_WINDOW_EXITING.bits();
+        if enable {
+            ctrl |= bits
+        } else {
+            ctrl &= !bits
+        }
+        VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.write(ctrl)?;
+        Ok(())
+    }
+
     /// Set I/O intercept by modifying I/O bitmap.
     pub fn set_io_intercept_of_range(&mut self, port_base: u32, count: u32, intercept: bool) {
         self.io_bitmap
@@ -556,6 +2557,7 @@ impl VmxVcpu {
         self.setup_vmcs_guest(entry)?;
         self.setup_vmcs_control(ept_root, true)?;
         self.unbind_from_current_processor()?;
+        self.set_up = true;
         Ok(())
     }
 
@@ -631,7 +2633,7 @@ impl VmxVcpu {
         VmcsGuest32::IDTR_LIMIT.write(0xffff)?;
 
         VmcsGuestNW::CR3.write(0)?;
-        VmcsGuestNW::DR7.write(0x400)?;
+        VmcsGuestNW::DR7.write(DR7_INIT as _)?;
         VmcsGuestNW::RSP.write(0)?;
         VmcsGuestNW::RIP.write(entry.as_usize())?;
         VmcsGuestNW::RFLAGS.write(0x2)?;
@@ -658,30 +2660,41 @@ impl VmxVcpu {
         use PinbasedControls as PinCtrl;
         let raw_cpuid = CpuId::new();
 
+        // Use virtual NMIs when available, to get NMI-window exiting.
+        let mut val = PinCtrl::NMI_EXITING | PinCtrl::EXTERNAL_INTERRUPT_EXITING;
+        if vmcs::is_control_supported(
+            Msr::IA32_VMX_TRUE_PINBASED_CTLS,
+            PinCtrl::VIRTUAL_NMIS.bits(),
+        ) {
+            val |= PinCtrl::VIRTUAL_NMIS;
+        }
         vmcs::set_control(
             VmcsControl32::PINBASED_EXEC_CONTROLS,
             Msr::IA32_VMX_TRUE_PINBASED_CTLS,
             Msr::IA32_VMX_PINBASED_CTLS.read() as u32,
-            (PinCtrl::NMI_EXITING | PinCtrl::EXTERNAL_INTERRUPT_EXITING).bits(),
+            val.bits(),
             // (PinCtrl::NMI_EXITING | PinCtrl::VMX_PREEMPTION_TIMER).bits(),
             // PinCtrl::NMI_EXITING.bits(),
             0,
         )?;
 
         // Intercept all I/O instructions, use MSR bitmaps, activate secondary controls,
-        // disable CR3 load/store interception.
+        // disable CR3 load/store interception, intercept CR8 accesses to emulate
+        // them on the vLAPIC TPR, intercept MOV DR until the guest debug
+        // registers are loaded.
         use PrimaryControls as CpuCtrl;
         vmcs::set_control(
             VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS,
             Msr::IA32_VMX_TRUE_PROCBASED_CTLS,
             Msr::IA32_VMX_PROCBASED_CTLS.read() as u32,
-            (CpuCtrl::USE_IO_BITMAPS | CpuCtrl::USE_MSR_BITMAPS | CpuCtrl::SECONDARY_CONTROLS)
-                .bits(),
-            (CpuCtrl::CR3_LOAD_EXITING
-                | CpuCtrl::CR3_STORE_EXITING
+            (CpuCtrl::USE_IO_BITMAPS
+                | CpuCtrl::USE_MSR_BITMAPS
+                | CpuCtrl::SECONDARY_CONTROLS
+                | CpuCtrl::MOV_DR_EXITING
                 | CpuCtrl::CR8_LOAD_EXITING
                 | CpuCtrl::CR8_STORE_EXITING)
                 .bits(),
+            (CpuCtrl::CR3_LOAD_EXITING | CpuCtrl::CR3_STORE_EXITING).bits(),
         )?;
 
         // Enable EPT, RDTSCP, INVPCID, and unrestricted guest.
@@ -704,6 +2717,9 @@ impl VmxVcpu {
         {
             val |= CpuCtrl2::ENABLE_XSAVES_XRSTORS;
         }
+        if self.vpid.is_some() {
+            val |= CpuCtrl2::ENABLE_VPID;
+        }
         vmcs::set_control(
             VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS,
             Msr::IA32_VMX_PROCBASED_CTLS2,
@@ -747,7 +2763,13 @@ impl VmxVcpu {
             0,
         )?;
 
-        vmcs::set_ept_pointer(ept_root)?;
+        if self.ept_config.levels != self.ept_levels {
+            return ax_err!(BadState, "EPT page-walk length differs from the EPT root");
+        }
+        vmcs::set_ept_pointer(self.ept_config.eptp(ept_root, EptVpidCap::read())?.bits())?;
+        if let Some(vpid) = &self.vpid {
+            VmcsControl16::VPID.write(vpid.id())?;
+        }
 
         // No MSR switches if hypervisor doesn't use and there is only one vCPU.
         VmcsControl32::VMEXIT_MSR_STORE_COUNT.write(0)?;
@@ -757,12 +2779,10 @@ impl VmxVcpu {
         // VmcsControlNW::CR4_GUEST_HOST_MASK.write(0)?;
         VmcsControl32::CR3_TARGET_COUNT.write(0)?;
 
-        // Pass-through exceptions (except #UD(6)), don't use I/O bitmap, set MSR bitmaps.
-        let exception_bitmap: u32 = 1 << 6;
-
+        // Intercept exceptions as configured, use I/O bitmap, set MSR bitmaps.
         self.setup_io_bitmap()?;
 
-        VmcsControl32::EXCEPTION_BITMAP.write(exception_bitmap)?;
+        self.write_exception_intercepts()?;
         VmcsControl64::IO_BITMAP_A_ADDR.write(self.io_bitmap.phys_addr().0.as_usize() as _)?;
         VmcsControl64::IO_BITMAP_B_ADDR.write(self.io_bitmap.phys_addr().1.as_usize() as _)?;
         VmcsControl64::MSR_BITMAPS_ADDR.write(self.msr_bitmap.phys_addr().as_usize() as _)?;
@@ -773,6 +2793,14 @@ impl VmxVcpu {
         Ok(())
     }
 
+    /// Whether the guest may use 5-level paging, i.e., CR4.LA57 is supported
+    /// by the processor and allowed to be 1 in VMX operation.
+    fn guest_la57_supported() -> bool {
+        let features = raw_cpuid::CpuId::new().get_extended_feature_info();
+        features.is_some_and(|f| f.has_la57())
+            && Msr::IA32_VMX_CR4_FIXED1.read() & Cr4Flags::L5_PAGING.bits() != 0
+    }
+
     fn get_paging_level(&self) -> usize {
         let mut level: u32 = 0; // non-paging
         let cr0 = VmcsGuestNW::CR0.read().unwrap();
@@ -783,7 +2811,11 @@ impl VmxVcpu {
             if cr4 & Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits() as usize != 0 {
                 // is long mode
                 if efer & EferFlags::LONG_MODE_ACTIVE.bits() != 0 {
-                    level = 4;
+                    level = if cr4 & Cr4Flags::L5_PAGING.bits() as usize != 0 {
+                        5
+                    } else {
+                        4
+                    };
                 } else {
                     level = 3;
                 }
@@ -836,11 +2868,14 @@ impl VmxVcpu {
         .expect("Failed to write guest control register")
     }
 
-    #[allow(dead_code)]
     fn cr(&self, cr_idx: usize) -> usize {
         (|| -> AxResult<usize> {
             Ok(match cr_idx {
-                0 => VmcsGuestNW::CR0.read()?,
+                0 => {
+                    let host_mask = VmcsControlNW::CR0_GUEST_HOST_MASK.read()?;
+                    (VmcsControlNW::CR0_READ_SHADOW.read()? & host_mask)
+                        | (VmcsGuestNW::CR0.read()? & !host_mask)
+                }
                 3 => VmcsGuestNW::CR3.read()?,
                 4 => {
                     let host_mask = VmcsControlNW::CR4_GUEST_HOST_MASK.read()?;
@@ -909,35 +2944,306 @@ impl VmxVcpu {
         );
     }
 
+    /// Translate the guest linear range of `len` bytes at `gla` page by page,
+    /// returning the guest and host physical addresses of each chunk and its
+    /// range in the buffer. The guest page tables are walked for a write if
+    /// `guest_write`, and the EPT must map the pages writable if `ept_write`.
+    fn translate_guest_virt_range(
+        &self,
+        gla: GuestVirtAddr,
+        len: usize,
+        guest_write: bool,
+        ept_write: bool,
+    ) -> GuestAccessResult<Vec<(GuestPhysAddr, HostPhysAddr, Range<usize>)>> {
+        if len > 0 && gla.as_usize().checked_add(len - 1).is_none() {
+            return Err(GuestAccessError::Wraparound(gla));
+        }
+        let mut chunks = Vec::new();
+        let mut offset = 0;
+        while offset < len {
+            let addr = gla + offset;
+            let chunk_len = (len - offset).min(PAGE_SIZE_4K - addr.as_usize() % PAGE_SIZE_4K);
+            let gpa = self.guest_virt_to_phys(addr, guest_write)?;
+            let hpa = self
+                .guest_phys_to_host_phys(gpa, ept_write)
+                .ok_or(GuestAccessError::NotMapped(gpa))?;
+            chunks.push((gpa, hpa, offset..offset + chunk_len));
+            offset += chunk_len;
+        }
+        Ok(chunks)
+    }
+
     fn vmx_entry_failed() -> ! {
         panic!("{}", vmcs::instruction_error().as_str())
     }
 
-    /// Whether the guest interrupts are blocked. (SDM Vol. 3C, Section 24.4.2, Table 24-3)
-    fn allow_interrupt(&self) -> bool {
-        let rflags = VmcsGuestNW::RFLAGS.read().unwrap();
-        let block_state = VmcsGuest32::INTERRUPTIBILITY_STATE.read().unwrap();
-        rflags as u64 & x86_64::registers::rflags::RFlags::INTERRUPT_FLAG.bits() != 0
-            && block_state == 0
+    /// Write the exception bitmap and the page-fault error-code filter.
+    fn write_exception_intercepts(&self) -> AxResult {
+        let (mask, match_) = self.exception_intercepts.vmcs_page_fault_filter();
+        let mut bitmap = self.exception_intercepts.bitmap();
+        if self.debug_regs.vmm_owned() {
+            bitmap.set_bit(x86::irq::DEBUG_VECTOR as usize, true);
+        }
+        if !self.sw_breakpoints.is_empty() {
+            bitmap.set_bit(x86::irq::BREAKPOINT_VECTOR as usize, true);
+        }
+        VmcsControl32::EXCEPTION_BITMAP.write(bitmap)?;
+        VmcsControl32::PAGE_FAULT_ERR_CODE_MASK.write(mask)?;
+        VmcsControl32::PAGE_FAULT_ERR_CODE_MATCH.write(match_)?;
+        Ok(())
+    }
+
+    /// Handle a VM exit due to an exception or NMI. Returns whether it is
+    /// handled here, otherwise it is reported to the VMM.
+    fn handle_exception_nmi(&mut self) -> AxResult<bool> {
+        let info = vmcs::exception_exit_info()?;
+        if info.is_nmi() {
+            // The NMI was meant for the host, deliver it to the host handler.
+            unsafe { core::arch::asm!("int 2") };
+            return Ok(true);
+        }
+
+        if info.nmi_unblocking_due_to_iret && info.vector != x86::irq::DOUBLE_FAULT_VECTOR {
+            // The faulting IRET will be executed again, so NMIs must stay
+            // blocked until then. (SDM Vol. 3C, Section 28.2.3)
+            let state = VmcsGuest32::INTERRUPTIBILITY_STATE.read()?;
+            VmcsGuest32::INTERRUPTIBILITY_STATE
+                .write(state | vmcs::InterruptibilityState::BLOCKING_BY_NMI.bits())?;
+        }
+
+        if info.vector == x86::irq::DEBUG_VECTOR {
+            let hits = self.debug_regs.vmm_hits(info.qualification);
+            if hits != 0 {
+                let guest_hits = self.debug_regs.guest_hits(info.qualification);
+                if guest_hits != 0 {
+                    // Guest breakpoints hit at the same time.
+                    self.inject_exception(GuestException::Debug {
+                        dr6: guest_hits as u64,
+                    });
+                }
+                if self.debug_regs.hits_execute(hits) {
+                    // Do not hit the instruction breakpoint again on resume.
+                    // (SDM Vol. 3B, Section 18.3.1.1)
+                    let rflags = VmcsGuestNW::RFLAGS.read()?;
+                    VmcsGuestNW::RFLAGS.write(rflags | RFlags::RESUME_FLAG.bits() as usize)?;
+                }
+                self.exit_event = Some(VmxExitEvent::HwBreakpoint { slots: hits });
+                return Ok(false);
+            }
+        }
+
+        if info.vector == x86::irq::BREAKPOINT_VECTOR {
+            // RIP is still at the INT3, as the exception was not delivered.
+            if let Some(breakpoint) = self.sw_breakpoint_at_rip() {
+                self.sw_breakpoints.step_over = Some(self.rip());
+                self.exit_event = Some(VmxExitEvent::SwBreakpoint {
+                    gpa: breakpoint.gpa,
+                });
+                return Ok(false);
+            }
+            // Otherwise an INT3 of the guest, reflected unless the VMM asked
+            // for guest breakpoints with `ExceptionPolicy::Exit`.
+        }
+
+        match (self.exception_policy(info.vector), info.exception()) {
+            // Only vectors intercepted by the vCPU itself can be delivered.
+            (ExceptionPolicy::Reflect | ExceptionPolicy::Deliver, Some(exception)) => {
+                self.raise_exception(exception, info.instruction_length);
+                Ok(true)
+            }
+            _ => {
+                self.exit_event = Some(VmxExitEvent::Exception(info));
+                Ok(false)
+            }
+        }
+    }
+
+    /// Handle a VM exit due to the monitor trap flag. Returns whether it is
+    /// handled here, i.e., whether the step continues after the delivery of
+    /// an event injected on VM entry. (SDM Vol. 3C, Section 27.7.2)
+    fn handle_monitor_trap(&mut self) -> bool {
+        if let Some(vector) = self.step_injected.take() {
+            self.step_delivered = Some(vector);
+            return true;
+        }
+        self.exit_event = Some(VmxExitEvent::SingleStep {
+            delivered: self.step_delivered.take(),
+        });
+        false
+    }
+
+    /// Drain the full page-modification log into the logged pages.
+    fn handle_pml_full(&mut self) -> AxResult {
+        if let Some(log) = &mut self.dirty_log {
+            log.drain_pml(VmcsGuest16::PML_INDEX.read()?);
+        }
+        VmcsGuest16::PML_INDEX.write((PML_ENTRIES - 1) as _)?;
+        self.restore_nmi_blocking()
+    }
+
+    /// Handle an EPT violation caused by the write protection for dirty
+    /// logging. Returns whether it is handled here, otherwise it is reported
+    /// to the VMM.
+    fn handle_dirty_log_write(&mut self) -> AxResult<bool> {
+        let Some(log) = &self.dirty_log else {
+            return Ok(false);
+        };
+        let fault = vmcs::ept_violation_info()?;
+        if log.mode() != DirtyLogMode::WriteProtect
+            || !fault.access_flags.contains(MappingFlags::WRITE)
+        {
+            return Ok(false);
+        }
+        let eptp = VmcsControl64::EPTP.read()?;
+        let gpa = fault.fault_guest_paddr.as_usize() as u64;
+        // Give write permission back in the other views too, as the page is
+        // logged already.
+        for other in self.eptps()?.into_iter().skip(1) {
+            ept::ept_unprotect(other, gpa);
+        }
+        match ept::ept_unprotect(eptp, gpa) {
+            Some(pages) => log.vm().log(pages),
+            // Given back by another vCPU, the translation used here is stale.
+            None if ept::ept_translate(eptp, fault.fault_guest_paddr, true).is_some() => {}
+            None => return Ok(false),
+        }
+        self.invept()?;
+        self.restore_nmi_blocking()?;
+        Ok(true)
+    }
+
+    /// Block NMIs again if the VM exit happened in an IRET that unblocked
+    /// them, since it will be executed again. The exit qualification of EPT
+    /// violations and PML-full exits reports it in bit 12.
+    /// (SDM Vol. 3C, Section 28.2.3)
+    fn restore_nmi_blocking(&self) -> AxResult {
+        if vmcs::VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?.get_bit(12) {
+            let state = VmcsGuest32::INTERRUPTIBILITY_STATE.read()?;
+            VmcsGuest32::INTERRUPTIBILITY_STATE
+                .write(state | vmcs::InterruptibilityState::BLOCKING_BY_NMI.bits())?;
+        }
+        Ok(())
+    }
+
+    /// Capture the guest state on shutdown, and discard the pending events.
+    fn record_shutdown(
+        &mut self,
+        exit_reason: VmxExitReason,
+        exception: Option<GuestException>,
+        vectoring_info: Option<VmxInterruptInfo>,
+    ) -> AxResult {
+        // A hypervisor-raised #PF carries its own CR2.
+        let cr2 = match exception {
+            Some(GuestException::PageFault { cr2, .. }) => cr2,
+            _ => self.special_regs.cr2,
+        };
+        let info = VmxShutdownInfo {
+            exit_reason,
+            exception,
+            vectoring_info,
+            activity_state: vmcs::activity_state()?,
+            cpu_mode: self.get_cpu_mode(),
+            regs: self.guest_regs,
+            rip: VmcsGuestNW::RIP.read()? as _,
+            rsp: VmcsGuestNW::RSP.read()? as _,
+            rflags: VmcsGuestNW::RFLAGS.read()? as _,
+            cr0: VmcsGuestNW::CR0.read()? as _,
+            cr2,
+            cr3: VmcsGuestNW::CR3.read()? as _,
+            cr4: VmcsGuestNW::CR4.read()? as _,
+            efer: VmcsGuest64::IA32_EFER.read()?,
+            cs_selector: VmcsGuest16::CS_SELECTOR.read()?,
+            cs_base: VmcsGuestNW::CS_BASE.read()? as _,
+            ss_selector: VmcsGuest16::SS_SELECTOR.read()?,
+            gdtr_base: VmcsGuestNW::GDTR_BASE.read()? as _,
+            gdtr_limit: VmcsGuest32::GDTR_LIMIT.read()?,
+            idtr_base: VmcsGuestNW::IDTR_BASE.read()? as _,
+            idtr_limit: VmcsGuest32::IDTR_LIMIT.read()?,
+        };
+        warn!(
+            "VmxVcpu: guest shut down ({exit_reason:?}) at {:#x}: {info:#x?}",
+            info.rip
+        );
+        self.pending_events.clear();
+        self.shutdown = Some(info);
+        Ok(())
+    }
+
+    /// Whether the guest accepts NMIs and interrupts. (SDM Vol. 3C, Section 24.4.2, Table 24-3)
+    fn event_blocking(&self) -> AxResult<EventBlocking> {
+        let rflags = VmcsGuestNW::RFLAGS.read()?;
+        let block_state = VmcsGuest32::INTERRUPTIBILITY_STATE.read()?;
+        Ok(EventBlocking::new(
+            rflags as u64 & x86_64::registers::rflags::RFlags::INTERRUPT_FLAG.bits() != 0,
+            vmcs::InterruptibilityState::from_bits_truncate(block_state),
+        ))
+    }
+
+    /// Record the event whose delivery was interrupted by the last VM exit,
+    /// so that it is re-injected (or merged with a new exception) later.
+    fn save_vectoring_event(&mut self) -> AxResult {
+        let info = vmcs::idt_vectoring_info()?;
+        if !info.valid {
+            return Ok(());
+        }
+        let exception = match info.int_type {
+            VmxInterruptionType::HardException | VmxInterruptionType::SoftException => {
+                // The CPU has already loaded CR2 for a #PF, keep it as is.
+                GuestException::from_vector(info.vector, info.err_code, self.special_regs.cr2)
+            }
+            _ => None,
+        };
+        // The length of the instruction which raised a software event.
+        let instr_len = VmcsReadOnly32::VMEXIT_INSTRUCTION_LEN.read()?;
+        self.pending_events
+            .set_vectoring(info, exception, instr_len);
+        Ok(())
+    }
+
+    /// Write the VM-entry interruption-information field for `exception`,
+    /// and deliver its payload. (SDM Vol. 3A, Section 6.15)
+    fn inject_exception_now(&mut self, exception: GuestException, instr_len: u32) -> AxResult {
+        use x86::debugregs::Dr6;
+        match exception {
+            // CR2 and DR6 are not part of the VMCS, the guest values are
+            // loaded by the vCPU on VM entry.
+            GuestException::PageFault { cr2, .. } => self.special_regs.cr2 = cr2,
+            GuestException::Debug { dr6: payload } => {
+                let trap_bits = (Dr6::B0 | Dr6::B1 | Dr6::B2 | Dr6::B3).bits() as u64;
+                self.debug_regs.dr6 = (self.debug_regs.dr6 & !trap_bits)
+                    | Dr6::from_bits_truncate(payload as usize).bits() as u64;
+            }
+            _ => {}
+        }
+        vmcs::inject_interrupt_info(&VmxInterruptInfo::from_exception(&exception), instr_len)
     }
 
-    /// Try to inject a pending event before next VM entry.
+    /// Try to inject a pending event before next VM entry, and open the
+    /// interrupt or NMI window for the events that have to wait.
     fn inject_pending_events(&mut self) -> AxResult {
-        if let Some(event) = self.pending_events.front() {
-            // trace!(
-            //     "pending event vector {:#x} allow_int {}",
-            //     event.0,
-            //     self.allow_interrupt()
-            // );
-            if event.0 < 32 || self.allow_interrupt() {
-                // if it's an exception, or an interrupt that is not blocked, inject it directly.
-                vmcs::inject_event(event.0, event.1)?;
-                self.pending_events.pop_front();
-            } else {
-                // interrupts are blocked, enable interrupt-window exiting.
-                self.set_interrupt_window(true)?;
+        use super::vmcs::controls::PinbasedControls as PinCtrl;
+
+        let plan = self.pending_events.plan(self.event_blocking()?);
+        match plan.event {
+            Some(VmxEvent::Exception(exception)) => {
+                self.inject_exception_now(exception, plan.instr_len)?
             }
+            Some(VmxEvent::Reinject(info)) => vmcs::inject_interrupt_info(&info, plan.instr_len)?,
+            Some(VmxEvent::Nmi) => {
+                vmcs::inject_event(x86::irq::NONMASKABLE_INTERRUPT_VECTOR, None, 0)?
+            }
+            Some(VmxEvent::Interrupt(vector)) => vmcs::inject_event(vector, None, 0)?,
+            None => {}
         }
+
+        // NMI-window exiting needs virtual NMIs, fall back to the interrupt
+        // window otherwise, which opens no earlier than the NMI one.
+        let virtual_nmis =
+            VmcsControl32::PINBASED_EXEC_CONTROLS.read()? & PinCtrl::VIRTUAL_NMIS.bits() != 0;
+        let nmi_window = plan.nmi_window && virtual_nmis;
+        let interrupt_window = plan.interrupt_window || (plan.nmi_window && !virtual_nmis);
+        self.set_nmi_window(nmi_window)?;
+        self.set_interrupt_window(interrupt_window)?;
         Ok(())
     }
 
@@ -948,14 +3254,39 @@ impl VmxVcpu {
         const X2APIC_MSR_BASE: u32 = 0x800;
         const X2APIC_MSR_END: u32 = 0x8ff; // SDM says 0x8ff, but actually 0x83f, we respect the SDM here.
         // Following vm-exits are handled here:
-        // - interrupt window: turn off interrupt window;
+        // - interrupt/NMI window: turn off the window, pending events are injected on next entry;
+        // - exception or NMI: re-raise host NMIs, reflect exceptions to the guest if configured;
+        // - EPT violation: log writes to pages write-protected for dirty logging;
+        // - PML full: drain the page-modification log;
+        // - monitor trap flag: step again after an event delivery, report the
+        //   step otherwise;
+        // - VMFUNC: raise #UD for invalid VM functions;
         // - xsetbv: set guest xcr;
         // - cr access: just panic;
+        // - dr access: load the guest debug registers, or emulate the access
+        //   while the VMM owns them;
         match exit_info.exit_reason {
             VmxExitReason::INTERRUPT_WINDOW => Some(self.set_interrupt_window(false)),
+            VmxExitReason::NMI_WINDOW => Some(self.set_nmi_window(false)),
+            VmxExitReason::EXCEPTION_NMI => match self.handle_exception_nmi() {
+                Ok(false) => None,
+                res => Some(res.map(|_| ())),
+            },
+            VmxExitReason::EPT_VIOLATION => match self.handle_dirty_log_write() {
+                Ok(false) => None,
+                res => Some(res.map(|_| ())),
+            },
+            VmxExitReason::PML_FULL => Some(self.handle_pml_full()),
+            VmxExitReason::MONITOR_TRAP_FLAG => self.handle_monitor_trap().then_some(Ok(())),
+            VmxExitReason::VMFUNC => {
+                // Invalid VM function or EPTP index. (SDM Vol. 3C, Section 26.5.6)
+                self.inject_exception(GuestException::InvalidOpcode);
+                Some(Ok(()))
+            }
             VmxExitReason::PREEMPTION_TIMER => Some(self.handle_vmx_preemption_timer()),
             VmxExitReason::XSETBV => Some(self.handle_xsetbv()),
             VmxExitReason::CR_ACCESS => Some(self.handle_cr()),
+            VmxExitReason::DR_ACCESS => Some(self.handle_dr_access(exit_info)),
             VmxExitReason::CPUID => Some(self.handle_cpuid()),
             msr_rw @ (VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE)
                 if {
@@ -1066,6 +3397,26 @@ impl VmxVcpu {
                 } else {
                     self.guest_regs.get_reg_of_index(reg)
                 };
+                if cr == 8 {
+                    if self.set_guest_cr(ControlRegister::Cr8, val).is_err() {
+                        self.inject_exception(GuestException::GeneralProtection(0));
+                        return Ok(());
+                    }
+                    return self.advance_rip(VmcsReadOnly32::VMEXIT_INSTRUCTION_LEN.read()? as u8);
+                }
+                if cr == 4 {
+                    // CR4.LA57 cannot be set if unsupported, nor changed in
+                    // IA-32e mode. (SDM Vol. 3A, Section 4.1.1)
+                    let la57 = Cr4Flags::L5_PAGING.bits();
+                    let long_mode =
+                        VmcsGuest64::IA32_EFER.read()? & EferFlags::LONG_MODE_ACTIVE.bits() != 0;
+                    if (val & la57 != 0 && !Self::guest_la57_supported())
+                        || (long_mode && (val ^ self.cr(4) as u64) & la57 != 0)
+                    {
+                        self.inject_exception(GuestException::GeneralProtection(0));
+                        return Ok(());
+                    }
+                }
                 if cr == 0 || cr == 4 {
                     self.advance_rip(VM_EXIT_INSTR_LEN_MV_TO_CR)?;
                     /* TODO: check for #GP reasons */
@@ -1077,6 +3428,15 @@ impl VmxVcpu {
                     return Ok(());
                 }
             }
+            /* move from cr */
+            1 if cr == 8 => {
+                let val = self.guest_cr(ControlRegister::Cr8);
+                match reg {
+                    4 => self.set_stack_pointer(val as _),
+                    _ => self.guest_regs.set_reg_of_index(reg, val),
+                }
+                return self.advance_rip(VmcsReadOnly32::VMEXIT_INSTRUCTION_LEN.read()? as u8);
+            }
             _ => {}
         };
 
@@ -1086,6 +3446,47 @@ impl VmxVcpu {
         );
     }
 
+    fn handle_dr_access(&mut self, exit_info: &VmxExitInfo) -> AxResult {
+        if !self.debug_regs.vmm_owned() {
+            // Execute the instruction again without exiting.
+            self.debug_regs.set_guest_loaded();
+            return Ok(());
+        }
+
+        let info = vmcs::dr_access_info()?;
+        // DR4 and DR5 are aliases of DR6 and DR7 unless CR4.DE is set.
+        // (SDM Vol. 3B, Section 18.2.2)
+        let register = match info.dr_number {
+            0 => DebugRegister::Dr0,
+            1 => DebugRegister::Dr1,
+            2 => DebugRegister::Dr2,
+            3 => DebugRegister::Dr3,
+            4 | 5 if self.cr(4) as u64 & Cr4Flags::DEBUGGING_EXTENSIONS.bits() != 0 => {
+                self.inject_exception(GuestException::InvalidOpcode);
+                return Ok(());
+            }
+            4 | 6 => DebugRegister::Dr6,
+            _ => DebugRegister::Dr7,
+        };
+        if info.is_read {
+            let value = self.guest_dr(register);
+            match info.gpr {
+                4 => self.set_stack_pointer(value as _),
+                gpr => self.guest_regs.set_reg_of_index(gpr, value),
+            }
+        } else {
+            let value = match info.gpr {
+                4 => self.stack_pointer() as u64,
+                gpr => self.guest_regs.get_reg_of_index(gpr),
+            };
+            if self.set_guest_dr(register, value).is_err() {
+                self.inject_exception(GuestException::GeneralProtection(0));
+                return Ok(());
+            }
+        }
+        self.advance_rip(exit_info.exit_instruction_length as _)
+    }
+
     fn handle_cpuid(&mut self) -> AxResult {
         use raw_cpuid::{CpuIdResult, cpuid};
 
@@ -1094,6 +3495,7 @@ impl VmxVcpu {
         const LEAF_STRUCTURED_EXTENDED_FEATURE_FLAGS_ENUMERATION: u32 = 0x7;
         const LEAF_PROCESSOR_EXTENDED_STATE_ENUMERATION: u32 = 0xd;
         const EAX_FREQUENCY_INFO: u32 = 0x16;
+        const LEAF_ADDRESS_SIZES: u32 = 0x8000_0008;
         const LEAF_HYPERVISOR_INFO: u32 = 0x4000_0000;
         const LEAF_HYPERVISOR_FEATURE: u32 = 0x4000_0001;
         const VENDOR_STR: &[u8; 12] = b"RVMRVMRVMRVM";
@@ -1119,7 +3521,10 @@ impl VmxVcpu {
                     // Bit 05: WAITPKG.
                     res.ecx.set_bit(5, false); // clear waitpkg
                     // Bit 16: LA57. Supports 57-bit linear addresses and five-level paging if 1.
-                    res.ecx.set_bit(16, false); // clear LA57
+                    // Only exposed if the guest may set CR4.LA57 in VMX non-root operation.
+                    if !Self::guest_la57_supported() {
+                        res.ecx.set_bit(16, false);
+                    }
                 }
 
                 res
@@ -1156,6 +3561,15 @@ impl VmxVcpu {
                 }
                 res
             }
+            LEAF_ADDRESS_SIZES => {
+                // Bits 07-00: physical address width. A 4-level EPT only maps
+                // 48-bit guest physical addresses.
+                let mut res = cpuid!(regs_clone.rax, regs_clone.rcx);
+                if self.ept_config.levels == 4 && res.eax.get_bits(0..8) > 48 {
+                    res.eax.set_bits(0..8, 48);
+                }
+                res
+            }
             _ => cpuid!(regs_clone.rax, regs_clone.rcx),
         };
 
@@ -1221,11 +3635,37 @@ impl VmxVcpu {
         }
     }
 
+    /// Load the guest debug registers, and set MOV-DR exiting accordingly.
+    fn load_guest_debug_regs(&mut self) -> AxResult {
+        let mov_dr_exiting = self
+            .debug_regs
+            .switch_to_guest(VmcsGuestNW::DR7.read()? as u64);
+        let ctrl = VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.read()?;
+        let bits = vmcs::controls::PrimaryControls::MOV_DR_EXITING.bits();
+        let new_ctrl = if mov_dr_exiting {
+            ctrl | bits
+        } else {
+            ctrl & !bits
+        };
+        if new_ctrl != ctrl {
+            VmcsControl32::PRIMARY_PROCBASED_EXEC_CONTROLS.write(new_ctrl)?;
+        }
+        Ok(())
+    }
+
     fn load_guest_xstate(&mut self) {
         self.xstate.switch_to_guest();
+        self.special_regs.switch_to_guest();
+        self.host_syscall_msrs = SyscallMsrs::read();
+        // SAFETY: the host values are restored by `load_host_xstate`.
+        unsafe { self.guest_syscall_msrs.write() };
     }
 
     fn load_host_xstate(&mut self) {
+        self.guest_syscall_msrs = SyscallMsrs::read();
+        // SAFETY: the host values were saved by `load_guest_xstate`.
+        unsafe { self.host_syscall_msrs.write() };
+        self.special_regs.switch_to_host();
         self.xstate.switch_to_host();
     }
 }
@@ -1237,6 +3677,22 @@ impl Drop for VmxVcpu {
     }
 }
 
+/// The CPU mode of a guest from its IA32_EFER, CS access rights and CR0.
+fn guest_cpu_mode(ia32_efer: u64, cs_access_right: u32, cr0: usize) -> VmCpuMode {
+    if (ia32_efer & MSR_IA32_EFER_LMA_BIT) != 0 {
+        if (cs_access_right & 0x2000) != 0 {
+            // CS.L = 1
+            VmCpuMode::Mode64
+        } else {
+            VmCpuMode::Compatibility
+        }
+    } else if (cr0 & CR0_PE) != 0 {
+        VmCpuMode::Protected
+    } else {
+        VmCpuMode::Real
+    }
+}
+
 fn get_tr_base(tr: SegmentSelector, gdt: &DescriptorTablePointer<u64>) -> u64 {
     let index = tr.index() as usize;
     let table_len = (gdt.limit as usize + 1) / core::mem::size_of::<u64>();
@@ -1299,6 +3755,8 @@ impl AxArchVCpu for VmxVcpu {
 
     fn run(&mut self) -> AxResult<AxVCpuExitReason> {
         match self.inner_run() {
+            Some(_) if self.shutdown.is_some() => Ok(AxVCpuExitReason::SystemDown),
+            Some(_) if self.exit_event.is_some() => Ok(AxVCpuExitReason::Nothing),
             Some(exit_info) => Ok(if exit_info.entry_failure {
                 AxVCpuExitReason::FailEntry {
                     // Todo: get `hardware_entry_failure_reason` somehow.
@@ -1438,6 +3896,66 @@ mod tests {
         assert!(debug_str.contains("Mode64"));
     }
 
+    #[test]
+    fn test_guest_cpu_mode_linearize() {
+        const LME_LMA: u64 = (1 << 8) | MSR_IA32_EFER_LMA_BIT;
+        assert_eq!(
+            guest_cpu_mode(LME_LMA, 0xa09b, 0x8000_0011),
+            VmCpuMode::Mode64
+        );
+        assert_eq!(
+            guest_cpu_mode(LME_LMA, 0xc09b, 0x8000_0011),
+            VmCpuMode::Compatibility
+        );
+        assert_eq!(guest_cpu_mode(0, 0xc09b, 0x11), VmCpuMode::Protected);
+
+        // A real-mode guest, whatever the mode of the host.
+        let mode = guest_cpu_mode(0, 0x9b, 0x10);
+        assert_eq!(mode, VmCpuMode::Real);
+        let cs = GuestSegment {
+            selector: 0xf000,
+            base: 0xf_0000,
+            limit: 0xffff,
+            access_rights: 0x9b,
+        };
+        // Data writes through CS are allowed in real mode, within the limit.
+        assert_eq!(
+            cs.linearize(
+                SegmentRegister::Cs,
+                mode,
+                false,
+                0xfff0,
+                2,
+                SegmentAccess::Write
+            ),
+            Ok(0xf_fff0)
+        );
+        assert_eq!(
+            cs.linearize(
+                SegmentRegister::Cs,
+                mode,
+                false,
+                0xffff,
+                2,
+                SegmentAccess::Write
+            ),
+            Err(GuestException::GeneralProtection(0))
+        );
+        // But not in protected mode.
+        let mode = guest_cpu_mode(0, 0x9b, 0x11);
+        assert_eq!(
+            cs.linearize(
+                SegmentRegister::Cs,
+                mode,
+                false,
+                0xfff0,
+                2,
+                SegmentAccess::Write
+            ),
+            Err(GuestException::GeneralProtection(0))
+        );
+    }
+
     #[test]
     fn test_general_registers_operations() {
         let mut regs = GeneralRegisters::default();
@@ -1668,6 +4186,48 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_guest_activity_state() {
+        assert_eq!(
+            GuestActivityState::try_from(2),
+            Ok(GuestActivityState::Shutdown)
+        );
+        assert_eq!(
+            GuestActivityState::try_from(0),
+            Ok(GuestActivityState::Active)
+        );
+        assert!(GuestActivityState::try_from(4).is_err());
+    }
+
+    #[test]
+    fn test_exception_exit_info() {
+        let pf = VmxExceptionExitInfo {
+            vector: 14,
+            int_type: VmxInterruptionType::HardException,
+            err_code: Some(0x2),
+            qualification: 0xdead_b000,
+            instruction_length: 0,
+            nmi_unblocking_due_to_iret: false,
+        };
+        assert!(!pf.is_nmi());
+        assert_eq!(
+            pf.exception(),
+            Some(GuestException::PageFault {
+                err_code: 0x2,
+                cr2: 0xdead_b000
+            })
+        );
+
+        let nmi = VmxExceptionExitInfo {
+            vector: 2,
+            int_type: VmxInterruptionType::NMI,
+            err_code: None,
+            ..pf
+        };
+        assert!(nmi.is_nmi());
+        assert_eq!(nmi.exception(), None);
+    }
+
     #[test]
     fn test_debug_implementations() {
         // Test Debug implementations for various types
@@ -20,12 +20,13 @@
 use bit_field::BitField;
 use x86::bits64::vmx;
 
-use axaddrspace::{GuestPhysAddr, HostPhysAddr, NestedPageFaultInfo};
-use axerrno::{AxResult, ax_err};
+use axaddrspace::{GuestPhysAddr, NestedPageFaultInfo};
+use axerrno::{AxResult, ax_err, ax_err_type};
 use page_table_entry::MappingFlags;
 
 use super::as_axerr;
 use super::definitions::{VmxExitReason, VmxInstructionError, VmxInterruptionType};
+use crate::exception::GuestException;
 use crate::msr::Msr;
 
 // HYGIENE: These macros are only used in this file, so we can use `as_axerr` directly.
@@ -514,7 +515,7 @@ pub struct VmxExitInfo {
 }
 
 /// VM-Entry/VM-Exit Interruption-Information Field. (SDM Vol. 3C, Section 24.8.3, 24.9.2)
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct VmxInterruptInfo {
     /// Vector of interrupt or exception.
     pub vector: u8,
@@ -537,6 +538,23 @@ impl VmxInterruptInfo {
         }
     }
 
+    /// Convert from a guest exception.
+    ///
+    /// Unlike [`VmxInterruptInfo::from`], #DB is injected as a hardware
+    /// exception rather than as INT1.
+    pub fn from_exception(exception: &GuestException) -> Self {
+        Self {
+            vector: exception.vector(),
+            int_type: if exception.is_soft() {
+                VmxInterruptionType::SoftException
+            } else {
+                VmxInterruptionType::HardException
+            },
+            err_code: exception.error_code(),
+            valid: true,
+        }
+    }
+
     /// Raw bits for writing to VMCS.
     pub fn bits(&self) -> u32 {
         let mut bits = self.vector as u32;
@@ -547,6 +565,59 @@ impl VmxInterruptInfo {
     }
 }
 
+/// Information for VM exits due to exceptions or NMIs.
+/// (SDM Vol. 3C, Section 28.2.1 and 28.2.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmxExceptionExitInfo {
+    /// Vector of the exception, 2 for NMIs.
+    pub vector: u8,
+    /// Hardware exception, software exception, or NMI.
+    pub int_type: VmxInterruptionType,
+    /// The error code of the exception, if it delivers one.
+    pub err_code: Option<u32>,
+    /// Exit qualification: the linear address for #PF, the debug
+    /// exception bits (DR6 format) for #DB, and 0 otherwise.
+    pub qualification: u64,
+    /// Length of the instruction that caused a software exception.
+    pub instruction_length: u32,
+    /// Whether NMIs were unblocked by an IRET that caused the exception.
+    pub nmi_unblocking_due_to_iret: bool,
+}
+
+impl VmxExceptionExitInfo {
+    /// Whether the VM exit was caused by an NMI.
+    pub fn is_nmi(&self) -> bool {
+        self.int_type == VmxInterruptionType::NMI
+    }
+
+    /// The guest exception that caused the VM exit, with the CR2 or DR6
+    /// payload taken from the exit qualification. `None` for NMIs.
+    pub fn exception(&self) -> Option<GuestException> {
+        if self.is_nmi() {
+            return None;
+        }
+        GuestException::from_vector(self.vector, self.err_code, self.qualification)
+    }
+}
+
+bitflags::bitflags! {
+    /// Guest Interruptibility State. (SDM Vol. 3C, Section 24.4.2, Table 24-3)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct InterruptibilityState: u32 {
+        /// Blocking by STI: interrupts are blocked for one instruction after STI.
+        const BLOCKING_BY_STI = 1 << 0;
+        /// Blocking by MOV SS: events are blocked for one instruction after
+        /// MOV to SS or POP SS.
+        const BLOCKING_BY_MOV_SS = 1 << 1;
+        /// Blocking by SMI.
+        const BLOCKING_BY_SMI = 1 << 2;
+        /// Blocking by NMI: NMIs are blocked until the next IRET.
+        const BLOCKING_BY_NMI = 1 << 3;
+        /// Enclave interruption.
+        const ENCLAVE_INTERRUPTION = 1 << 4;
+    }
+}
+
 /// Exit Qualification for I/O Instructions. (SDM Vol. 3C, Section 27.2.1, Table 27-5)
 #[derive(Debug)]
 pub struct VmxIoExitInfo {
@@ -595,6 +666,20 @@ pub struct CrAccessInfo {
     pub lmsw_source_data: u8,
 }
 
+/// Exit Qualification for MOV DR. (SDM Vol. 3C, Section 28.2.1)
+#[derive(Debug)]
+pub struct DrAccessInfo {
+    /// [2:0]
+    /// Number of debug register
+    pub dr_number: u8,
+    /// [4]
+    /// Direction of access (0 = MOV to DR; 1 = MOV from DR)
+    pub is_read: bool,
+    /// [11:8]
+    /// The general-purpose register, in the same encoding as for MOV CR
+    pub gpr: u8,
+}
+
 /// Type of APIC-access, used in Exit Qualification for APIC Accesses. (SDM Vol. 3C, Section 28.2.2, Table 28-6)
 #[derive(Debug)]
 pub enum ApicAccessExitType {
@@ -645,6 +730,24 @@ pub struct ApicAccessExitInfo {
     pub non_event_delivery_asynchronous: bool,
 }
 
+numeric_enum_macro::numeric_enum! {
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+/// Guest activity state. (SDM Vol. 3C, Section 25.4.2, Table 25-3)
+pub enum GuestActivityState {
+    /// The logical processor is executing instructions normally.
+    Active = 0,
+    /// The logical processor is inactive because it executed HLT.
+    Hlt = 1,
+    /// The logical processor is inactive because it incurred a triple fault
+    /// or some other serious error.
+    Shutdown = 2,
+    /// The logical processor is inactive because it is waiting for a
+    /// startup-IPI (SIPI).
+    WaitForSipi = 3,
+}
+}
+
 pub mod controls {
     pub use x86::vmx::vmcs::control::{EntryControls, ExitControls};
     pub use x86::vmx::vmcs::control::{PinbasedControls, PrimaryControls, SecondaryControls};
@@ -691,9 +794,15 @@ pub fn set_control(
     Ok(())
 }
 
-pub fn set_ept_pointer(pml4_paddr: HostPhysAddr) -> AxResult {
+/// Whether all of `bits` may be set to 1 in the VMX control reported by
+/// `capability_msr`. (SDM Vol. 3D, Appendix A.3)
+pub fn is_control_supported(capability_msr: Msr, bits: u32) -> bool {
+    let allowed1 = (capability_msr.read() >> 32) as u32;
+    allowed1 & bits == bits
+}
+
+pub fn set_ept_pointer(eptp: u64) -> AxResult {
     use super::instructions::{InvEptType, invept};
-    let eptp = super::structs::EPTPointer::from_table_phys(pml4_paddr).bits();
     VmcsControl64::EPTP.write(eptp)?;
     unsafe { invept(InvEptType::SingleContext, eptp).map_err(as_axerr)? };
     Ok(())
@@ -735,25 +844,81 @@ pub fn interrupt_exit_info() -> AxResult<VmxInterruptInfo> {
     })
 }
 
-pub fn inject_event(vector: u8, err_code: Option<u32>) -> AxResult {
+pub fn exception_exit_info() -> AxResult<VmxExceptionExitInfo> {
+    // SDM Vol. 3C, Section 24.9.2
+    let info = interrupt_exit_info()?;
+    let raw_info = VmcsReadOnly32::VMEXIT_INTERRUPTION_INFO.read()?;
+    Ok(VmxExceptionExitInfo {
+        vector: info.vector,
+        int_type: info.int_type,
+        err_code: info.err_code,
+        qualification: VmcsReadOnlyNW::EXIT_QUALIFICATION.read()? as _,
+        instruction_length: VmcsReadOnly32::VMEXIT_INSTRUCTION_LEN.read()?,
+        nmi_unblocking_due_to_iret: raw_info.get_bit(12),
+    })
+}
+
+/// The vector of the event to be injected on the next VM entry, if any.
+pub fn entry_injection_vector() -> AxResult<Option<u8>> {
+    // SDM Vol. 3C, Section 24.8.3
+    let info = VmcsControl32::VMENTRY_INTERRUPTION_INFO_FIELD.read()?;
+    Ok(info.get_bit(31).then_some(info.get_bits(0..8) as u8))
+}
+
+pub fn idt_vectoring_info() -> AxResult<VmxInterruptInfo> {
+    // SDM Vol. 3C, Section 24.9.3
+    let info = VmcsReadOnly32::IDT_VECTORING_INFO.read()?;
+    Ok(VmxInterruptInfo {
+        vector: info.get_bits(0..8) as u8,
+        int_type: VmxInterruptionType::try_from(info.get_bits(8..11) as u8).unwrap(),
+        err_code: if info.get_bit(11) {
+            Some(VmcsReadOnly32::IDT_VECTORING_ERR_CODE.read()?)
+        } else {
+            None
+        },
+        valid: info.get_bit(31),
+    })
+}
+
+/// Inject the event `vector` on the next VM entry. `instr_len` is the length
+/// of the instruction which raised it, used only for software events.
+pub fn inject_event(vector: u8, err_code: Option<u32>, instr_len: u32) -> AxResult {
     // SDM Vol. 3C, Section 24.8.3
     let err_code = if VmxInterruptionType::vector_has_error_code(vector) {
-        err_code.or_else(|| Some(VmcsReadOnly32::VMEXIT_INTERRUPTION_ERR_CODE.read().unwrap()))
+        Some(err_code.unwrap_or(0))
     } else {
         None
     };
-    let int_info = VmxInterruptInfo::from(vector, err_code);
+    inject_interrupt_info(&VmxInterruptInfo::from(vector, err_code), instr_len)
+}
+
+/// Inject the event described by `int_info` on the next VM entry.
+/// `instr_len` is the length of the instruction which raised it, used only
+/// for software events: it must come from the VM exit that produced the
+/// event, not from the last one.
+pub fn inject_interrupt_info(int_info: &VmxInterruptInfo, instr_len: u32) -> AxResult {
+    // SDM Vol. 3C, Section 24.8.3
     if let Some(err_code) = int_info.err_code {
         VmcsControl32::VMENTRY_EXCEPTION_ERR_CODE.write(err_code)?;
     }
     if int_info.int_type.is_soft() {
-        VmcsControl32::VMENTRY_INSTRUCTION_LEN
-            .write(VmcsReadOnly32::VMEXIT_INSTRUCTION_LEN.read()?)?;
+        // SDM Vol. 3C, Section 26.2.1.3
+        if !(1..=15).contains(&instr_len) {
+            return ax_err!(InvalidInput, "invalid software event instruction length");
+        }
+        VmcsControl32::VMENTRY_INSTRUCTION_LEN.write(instr_len)?;
     }
     VmcsControl32::VMENTRY_INTERRUPTION_INFO_FIELD.write(int_info.bits())?;
     Ok(())
 }
 
+/// The current guest activity state.
+pub fn activity_state() -> AxResult<GuestActivityState> {
+    let state = VmcsGuest32::ACTIVITY_STATE.read()?;
+    GuestActivityState::try_from(state)
+        .map_err(|_| ax_err_type!(BadState, "invalid guest activity state"))
+}
+
 pub fn io_exit_info() -> AxResult<VmxIoExitInfo> {
     // SDM Vol. 3C, Section 27.2.1, Table 27-5
     let qualification = VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?;
@@ -834,6 +999,15 @@ pub fn cr_access_info() -> AxResult<CrAccessInfo> {
     })
 }
 
+pub fn dr_access_info() -> AxResult<DrAccessInfo> {
+    let qualification = VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?;
+    Ok(DrAccessInfo {
+        dr_number: qualification.get_bits(0..3) as u8,
+        is_read: qualification.get_bit(4),
+        gpr: qualification.get_bits(8..12) as u8,
+    })
+}
+
 pub fn apic_access_exit_info() -> AxResult<ApicAccessExitInfo> {
     let qualification = VmcsReadOnlyNW::EXIT_QUALIFICATION.read()?;
     // debug!("apic_access_info qualification {:#x}", qualification);
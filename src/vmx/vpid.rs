@@ -0,0 +1,155 @@
+// Copyright 2025 The Axvisor Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use axerrno::AxResult;
+
+use super::as_axerr;
+use super::instructions::{InvVpidType, invvpid};
+use super::structs::{EptVpidCap, EptVpidCapFlags};
+use super::vmcs::{controls::SecondaryControls, is_control_supported};
+use crate::msr::Msr;
+
+/// The number of VPIDs, including VPID 0 which is reserved for VMX root
+/// operation.
+const VPID_COUNT: usize = 1 << 16;
+
+static VPID_ALLOCATOR: VpidAllocator = VpidAllocator::new();
+
+/// A global lock-free next-fit allocator of VPIDs.
+struct VpidAllocator {
+    bitmap: [AtomicU64; VPID_COUNT / 64],
+    next: AtomicUsize,
+}
+
+impl VpidAllocator {
+    const fn new() -> Self {
+        Self {
+            bitmap: [const { AtomicU64::new(0) }; VPID_COUNT / 64],
+            next: AtomicUsize::new(1),
+        }
+    }
+
+    fn alloc(&self) -> Option<u16> {
+        let next = self.next.load(Ordering::Relaxed);
+        for i in 0..VPID_COUNT {
+            let id = (next + i) % VPID_COUNT;
+            let bit = 1 << (id % 64);
+            if id != 0 && self.bitmap[id / 64].fetch_or(bit, Ordering::AcqRel) & bit == 0 {
+                self.next.store(id + 1, Ordering::Relaxed);
+                return Some(id as u16);
+            }
+        }
+        None
+    }
+
+    fn free(&self, id: u16) {
+        let id = id as usize;
+        self.bitmap[id / 64].fetch_and(!(1 << (id % 64)), Ordering::AcqRel);
+    }
+}
+
+/// A virtual-processor identifier, which tags the guest's cached linear
+/// translations so that they survive VM entries and exits. (SDM Vol. 3C,
+/// Section 29.1)
+///
+/// Released on drop.
+#[derive(Debug)]
+pub struct Vpid {
+    id: u16,
+    caps: EptVpidCapFlags,
+}
+
+impl Vpid {
+    /// Allocate a VPID, or return `None` if VPIDs are not supported by the
+    /// processor, or are all in use.
+    pub fn alloc() -> Option<Self> {
+        let caps = EptVpidCap::read();
+        if !is_control_supported(
+            Msr::IA32_VMX_PROCBASED_CTLS2,
+            SecondaryControls::ENABLE_VPID.bits(),
+        ) || !caps.contains(EptVpidCapFlags::INVVPID)
+            || !caps.intersects(
+                EptVpidCapFlags::INVVPID_SINGLE_CONTEXT | EptVpidCapFlags::INVVPID_ALL_CONTEXT,
+            )
+        {
+            return None;
+        }
+        let Some(id) = VPID_ALLOCATOR.alloc() else {
+            warn!("Vpid: all VPIDs are in use");
+            return None;
+        };
+        Some(Self { id, caps })
+    }
+
+    /// The VPID value.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Invalidate the cached translations tagged with this VPID on the
+    /// current logical processor: only those for the linear address `addr`
+    /// if given and supported, otherwise all of them.
+    pub fn flush(&self, addr: Option<u64>) -> AxResult {
+        let inv_type = match addr {
+            Some(_)
+                if self
+                    .caps
+                    .contains(EptVpidCapFlags::INVVPID_INDIVIDUAL_ADDRESS) =>
+            {
+                InvVpidType::IndividualAddress
+            }
+            _ if self.caps.contains(EptVpidCapFlags::INVVPID_SINGLE_CONTEXT) => {
+                InvVpidType::SingleContext
+            }
+            _ => InvVpidType::AllContext,
+        };
+        unsafe { invvpid(inv_type, self.id, addr.unwrap_or(0)).map_err(as_axerr) }
+    }
+}
+
+impl Drop for Vpid {
+    fn drop(&mut self) {
+        VPID_ALLOCATOR.free(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vpid_alloc_free() {
+        let allocator = VpidAllocator::new();
+        assert_eq!(allocator.alloc(), Some(1));
+        assert_eq!(allocator.alloc(), Some(2));
+        allocator.free(1);
+        // Next-fit: freed VPIDs are reused only after wrapping around.
+        assert_eq!(allocator.alloc(), Some(3));
+    }
+
+    #[test]
+    fn test_vpid_exhaustion() {
+        let allocator = VpidAllocator::new();
+        for id in 1..VPID_COUNT {
+            assert_eq!(allocator.alloc(), Some(id as u16));
+        }
+        // VPID 0 is never allocated.
+        assert_eq!(allocator.alloc(), None);
+        allocator.free(42);
+        assert_eq!(allocator.alloc(), Some(42));
+        assert_eq!(allocator.alloc(), None);
+    }
+}